@@ -1,9 +1,12 @@
 use std::{collections::BTreeMap, net::SocketAddr};
 
 use clap::{Parser, Subcommand};
+use time::{format_description::well_known::Iso8601, OffsetDateTime};
 
 use sozu_command_lib::{
-    proto::command::{LoadBalancingAlgorithms, TlsVersion},
+    proto::command::{
+        FallbackCertificatePolicy, LoadBalancingAlgorithms, StickySessionFallback, TlsVersion,
+    },
     state::ClusterId as StateClusterId,
 };
 
@@ -31,6 +34,13 @@ pub struct Args {
         help = "display responses to queries in a JSON format"
     )]
     pub json: bool,
+    #[clap(
+        long = "at",
+        global = true,
+        value_parser = parse_date,
+        help = "apply the change at this date instead of now (ISO 8601, like 2024-06-01T02:00Z), see `schedule list`"
+    )]
+    pub at: Option<i64>,
     #[clap(subcommand)]
     pub cmd: SubCmd,
 }
@@ -116,6 +126,12 @@ pub enum SubCmd {
     Upgrade {
         #[clap(long = "worker", help = "upgrade a specific worker")]
         worker: Option<u32>,
+        #[clap(
+            long = "binary",
+            conflicts_with = "worker",
+            help = "path to the new sozu binary for the main process. The old main process stands by until a first worker is upgraded, and comes back if the new one fails before"
+        )]
+        binary: Option<String>,
     },
 
     #[clap(name = "status", about = "gets information on the running workers")]
@@ -138,6 +154,16 @@ pub enum SubCmd {
         #[clap(subcommand)]
         cmd: StateCmd,
     },
+    #[clap(name = "schedule", about = "manage the changes scheduled with --at")]
+    Schedule {
+        #[clap(subcommand)]
+        cmd: ScheduleCmd,
+    },
+    #[clap(name = "check", about = "smoke tests of the running proxy")]
+    Check {
+        #[clap(subcommand)]
+        cmd: CheckCmd,
+    },
     #[clap(
         name = "reload",
         about = "Reloads routing configuration (clusters, frontends and backends)"
@@ -190,11 +216,32 @@ pub enum SubCmd {
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
 pub enum MetricsCmd {
     #[clap(name = "enable", about = "Enables local metrics collection")]
-    Enable,
+    Enable {
+        #[clap(long = "backends", help = "only enable the metrics of each backend")]
+        backends: bool,
+        #[clap(long = "time", help = "only enable time metrics, like response times")]
+        time: bool,
+    },
     #[clap(name = "disable", about = "Disables local metrics collection")]
-    Disable,
+    Disable {
+        #[clap(
+            long = "backends",
+            help = "only disable the metrics of each backend, locally and for statsd"
+        )]
+        backends: bool,
+        #[clap(
+            long = "time",
+            help = "only disable time metrics, like response times, locally and for statsd"
+        )]
+        time: bool,
+    },
     #[clap(name = "clear", about = "Deletes local metrics data")]
     Clear,
+    #[clap(name = "config", about = "metrics configuration of the workers")]
+    Config {
+        #[clap(subcommand)]
+        cmd: MetricsConfigCmd,
+    },
     #[clap(
         name = "get",
         about = "get all metrics, filtered, or a list of available metrics"
@@ -237,9 +284,23 @@ pub enum MetricsCmd {
             help = "display metrics of each worker, without merging by metric name or cluster id (takes more space)"
         )]
         workers: bool,
+        #[clap(
+            long = "tag",
+            help = "get the metrics partitioned by the metrics tag, format: key or key=value"
+        )]
+        tag: Option<String>,
     },
 }
 
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum MetricsConfigCmd {
+    #[clap(
+        name = "get",
+        about = "get the metrics toggles of each worker, and its number of metric series"
+    )]
+    Get,
+}
+
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
 pub enum StateCmd {
     #[clap(name = "save", about = "Save state to that file")]
@@ -257,6 +318,31 @@ pub enum StateCmd {
         about = "show the counts of requests that were received since startup"
     )]
     Stats,
+    #[clap(
+        name = "watch",
+        about = "print the changes of the state (clusters, frontends, backends, certificates, listeners) as they happen. Use --json for one JSON object per line"
+    )]
+    Watch,
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum ScheduleCmd {
+    #[clap(name = "list", about = "list the changes waiting for their date")]
+    List,
+    #[clap(name = "cancel", about = "cancel a scheduled change")]
+    Cancel {
+        #[clap(long = "id", help = "identifier of the scheduled change")]
+        id: u64,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
+pub enum CheckCmd {
+    #[clap(
+        name = "proxy",
+        about = "send a request through each listener to a temporary cluster served by the main process, then remove it"
+    )]
+    Proxy,
 }
 
 #[derive(Subcommand, PartialEq, Eq, Clone, Debug)]
@@ -299,6 +385,99 @@ pub enum ClusterCmd {
             help = "Configures the load balancing policy. Possible values are 'roundrobin', 'random' or 'leastconnections'"
         )]
         load_balancing_policy: LoadBalancingAlgorithms,
+        #[clap(
+            long = "retry-budget",
+            help = "maximum percentage of connection retries over the recent requests of the cluster"
+        )]
+        retry_budget: Option<u32>,
+        #[clap(
+            long = "hedge-delay",
+            help = "delay in milliseconds after which an idempotent request without response is sent to another backend"
+        )]
+        hedge_delay: Option<u32>,
+        #[clap(
+            long = "label",
+            help = "free-form label, like the owner or the environment, format: key=value. Can be repeated"
+        )]
+        labels: Vec<String>,
+        #[clap(
+            long = "idle-timeout",
+            help = "TCP only: seconds without traffic after which a connection is closed"
+        )]
+        idle_timeout: Option<u32>,
+        #[clap(
+            long = "max-connection-lifetime",
+            help = "TCP only: seconds after which a connection is closed, even if active"
+        )]
+        max_connection_lifetime: Option<u32>,
+        #[clap(
+            long = "sticky-session-fallback",
+            help = "when the backend of a sticky session is unavailable: 'rebalance' to another backend (default) or 'fail' with a 503"
+        )]
+        sticky_session_fallback: Option<StickySessionFallback>,
+        #[clap(
+            long = "failure-status",
+            help = "response status counted as a backend failure, on top of 5xx statuses. Can be repeated"
+        )]
+        failure_statuses: Vec<u32>,
+        #[clap(
+            long = "healthy-status",
+            help = "5xx response status not counted as a backend failure. Can be repeated"
+        )]
+        healthy_statuses: Vec<u32>,
+        #[clap(
+            long = "failure-header",
+            help = "response header marking a backend failure, format: name=value. Can be repeated"
+        )]
+        failure_headers: Vec<String>,
+        #[clap(
+            long = "stream-high-watermark",
+            help = "percentage of a buffer filled with data waiting to be sent, above which reads of a body pause"
+        )]
+        stream_high_watermark: Option<u32>,
+        #[clap(
+            long = "stream-low-watermark",
+            help = "percentage of a buffer under which paused reads resume, defaults to half the high watermark"
+        )]
+        stream_low_watermark: Option<u32>,
+        #[clap(
+            long = "https-redirect-exemption",
+            help = "path still served over HTTP with --https-redirect, like '/.well-known/acme-challenge/*'. A trailing * matches any suffix. Can be repeated"
+        )]
+        https_redirect_exemptions: Vec<String>,
+        #[clap(
+            long = "allowed-upgrade",
+            help = "protocol requests may switch to with the Upgrade header, like 'websocket'. Other upgrades are answered with a 403. All are allowed if unset. Can be repeated"
+        )]
+        allowed_upgrades: Vec<String>,
+    },
+    #[clap(
+        name = "fault-injection",
+        about = "Inject latency or errors in a percentage of the requests of a cluster, for chaos experiments. Workers forget it on restart"
+    )]
+    FaultInjection {
+        #[clap(short = 'i', long = "id", help = "cluster id")]
+        id: String,
+        #[clap(
+            long = "percentage",
+            help = "percentage of the requests receiving a fault, 0 disables the fault injection"
+        )]
+        percentage: u32,
+        #[clap(
+            long = "latency",
+            help = "delay in milliseconds added before connecting to the backend"
+        )]
+        latency: Option<u32>,
+        #[clap(
+            long = "jitter",
+            help = "maximum random delay in milliseconds added to the latency"
+        )]
+        jitter: Option<u32>,
+        #[clap(
+            long = "error-status",
+            help = "answer with this status (502, 503 or 504) instead of forwarding the request"
+        )]
+        error_status: Option<u32>,
     },
 }
 
@@ -337,6 +516,32 @@ pub enum BackendCmd {
         sticky_id: Option<String>,
         #[clap(short = 'b', long = "backup", help = "set backend as a backup backend")]
         backup: Option<bool>,
+        #[clap(
+            long = "max-connections",
+            help = "maximum number of simultaneous connections to the backend"
+        )]
+        max_connections: Option<u32>,
+        #[clap(
+            long = "queue-timeout",
+            help = "time in milliseconds a request waits for a saturated backend before a 503"
+        )]
+        queue_timeout: Option<u32>,
+        #[clap(
+            short = 'p',
+            long = "priority",
+            help = "priority tier of the backend, lower tiers receive traffic first"
+        )]
+        priority: Option<u32>,
+        #[clap(
+            long = "slow-start",
+            help = "duration in seconds over which the weight of the backend ramps up"
+        )]
+        slow_start: Option<u32>,
+        #[clap(
+            long = "alternate-address",
+            help = "other address of the same server, like its IPv6 address. Can be repeated"
+        )]
+        alternate_addresses: Vec<SocketAddr>,
     },
 }
 
@@ -371,6 +576,44 @@ pub enum FrontendCmd {
             help = "filter by domain name (for http & https frontends)"
         )]
         domain: Option<String>,
+        #[clap(long = "id", help = "filter by cluster id")]
+        cluster_id: Option<String>,
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "filter by listener address, format: IP:port"
+        )]
+        address: Option<SocketAddr>,
+        #[clap(
+            long = "path",
+            help = "filter by part of the path rule (for http & https frontends)"
+        )]
+        path: Option<String>,
+        #[clap(long = "tag", help = "filter by tag, format: key or key=value")]
+        tag: Option<String>,
+        #[clap(long = "offset", help = "number of matching frontends to skip")]
+        offset: Option<u32>,
+        #[clap(long = "limit", help = "maximum number of frontends to list")]
+        limit: Option<u32>,
+    },
+    #[clap(name = "remove", about = "Remove all the frontends having a tag")]
+    Remove {
+        #[clap(long = "tag", help = "tag of the frontends, format: key or key=value")]
+        tag: String,
+        #[clap(long = "http", help = "only remove http frontends")]
+        http: bool,
+        #[clap(long = "https", help = "only remove https frontends")]
+        https: bool,
+        #[clap(long = "tcp", help = "only remove tcp frontends")]
+        tcp: bool,
+        #[clap(long = "id", help = "only remove the frontends of this cluster")]
+        cluster_id: Option<String>,
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "only remove the frontends of this listener, format: IP:port"
+        )]
+        address: Option<SocketAddr>,
     },
 }
 
@@ -425,6 +668,26 @@ pub enum HttpFrontendCmd {
         method: Option<String>,
         #[clap(long = "tags", help = "Specify tag (key-value pair) to apply on front-end (example: 'key=value, other-key=other-value')", value_parser = parse_tags)]
         tags: Option<BTreeMap<String, String>>,
+        #[clap(
+            long = "case-insensitive",
+            help = "compare the hostname and path of requests regardless of their case"
+        )]
+        case_insensitive: bool,
+        #[clap(
+            long = "ignore-trailing-slash",
+            help = "the path matches with or without a trailing slash"
+        )]
+        ignore_trailing_slash: bool,
+        #[clap(
+            long = "percent-decode",
+            help = "decode the percent-encoded characters of the path before matching it"
+        )]
+        percent_decode: bool,
+        #[clap(
+            long = "debug-trace",
+            help = "the responses carry headers describing the matched rule, backend, retries and timings"
+        )]
+        debug_trace: bool,
     },
     #[clap(name = "remove")]
     Remove {
@@ -542,6 +805,37 @@ pub enum HttpListenerCmd {
             help = "Configures the client socket to receive a PROXY protocol header"
         )]
         expect_proxy: bool,
+        #[clap(
+            long = "v6only",
+            help = "on an IPv6 address, only accept IPv6 connections (true) or also IPv4 ones (false). Defaults to the system setting"
+        )]
+        v6only: Option<bool>,
+        #[clap(
+            long = "accept-rate",
+            help = "maximum number of new connections accepted per second, the others wait in the kernel backlog"
+        )]
+        accept_rate: Option<u32>,
+        #[clap(
+            long = "accept-burst",
+            help = "new connections accepted at once above the accept rate, defaults to the rate"
+        )]
+        accept_burst: Option<u32>,
+        #[clap(
+            long = "tags",
+            help = "tags added to those of every frontend of the listener (example: 'datacenter=par1, edge-pool=blue')",
+            value_parser = parse_tags
+        )]
+        tags: Option<BTreeMap<String, String>>,
+        #[clap(
+            long = "strict-responses",
+            help = "reject the backend responses that break HTTP framing with a 502 (true), or correct them (false, the default)"
+        )]
+        strict_responses: Option<bool>,
+        #[clap(
+            long = "debug-trace-key",
+            help = "key of the HMAC-SHA256 signatures of the Sozu-Debug-Trace request header, which enables the routing decision trace headers"
+        )]
+        debug_trace_key: Option<String>,
         #[clap(long = "sticky-name", help = "sticky session cookie name")]
         sticky_name: Option<String>,
         #[clap(
@@ -591,6 +885,11 @@ pub enum HttpListenerCmd {
             help = "listener address, format: IP:port"
         )]
         address: SocketAddr,
+        #[clap(
+            long = "drain-timeout",
+            help = "let the established connections finish, closing those still open after this many seconds"
+        )]
+        drain_timeout: Option<u32>,
     },
 }
 
@@ -622,11 +921,52 @@ pub enum HttpsListenerCmd {
             help = "List of TLS cipher list to use (TLSv1.2 and TLSv1.3)"
         )]
         cipher_list: Option<Vec<String>>,
+        #[clap(
+            long = "no-sni-policy",
+            help = "when the client sends no SNI: serve the 'default' certificate or 'reject' the handshake (default)"
+        )]
+        no_sni_policy: Option<FallbackCertificatePolicy>,
+        #[clap(
+            long = "unknown-sni-policy",
+            help = "when the SNI matches no certificate: serve the 'default' certificate (default) or 'reject' the handshake"
+        )]
+        unknown_sni_policy: Option<FallbackCertificatePolicy>,
         #[clap(
             long = "expect-proxy",
             help = "Configures the client socket to receive a PROXY protocol header"
         )]
         expect_proxy: bool,
+        #[clap(
+            long = "v6only",
+            help = "on an IPv6 address, only accept IPv6 connections (true) or also IPv4 ones (false). Defaults to the system setting"
+        )]
+        v6only: Option<bool>,
+        #[clap(
+            long = "accept-rate",
+            help = "maximum number of new connections accepted per second, the others wait in the kernel backlog"
+        )]
+        accept_rate: Option<u32>,
+        #[clap(
+            long = "accept-burst",
+            help = "new connections accepted at once above the accept rate, defaults to the rate"
+        )]
+        accept_burst: Option<u32>,
+        #[clap(
+            long = "tags",
+            help = "tags added to those of every frontend of the listener (example: 'datacenter=par1, edge-pool=blue')",
+            value_parser = parse_tags
+        )]
+        tags: Option<BTreeMap<String, String>>,
+        #[clap(
+            long = "strict-responses",
+            help = "reject the backend responses that break HTTP framing with a 502 (true), or correct them (false, the default)"
+        )]
+        strict_responses: Option<bool>,
+        #[clap(
+            long = "debug-trace-key",
+            help = "key of the HMAC-SHA256 signatures of the Sozu-Debug-Trace request header, which enables the routing decision trace headers"
+        )]
+        debug_trace_key: Option<String>,
         #[clap(long = "sticky-name", help = "sticky session cookie name")]
         sticky_name: Option<String>,
         #[clap(
@@ -676,6 +1016,11 @@ pub enum HttpsListenerCmd {
             help = "listener address, format: IP:port"
         )]
         address: SocketAddr,
+        #[clap(
+            long = "drain-timeout",
+            help = "let the established connections finish, closing those still open after this many seconds"
+        )]
+        drain_timeout: Option<u32>,
     },
 }
 
@@ -699,6 +1044,27 @@ pub enum TcpListenerCmd {
             help = "Configures the client socket to receive a PROXY protocol header"
         )]
         expect_proxy: bool,
+        #[clap(
+            long = "v6only",
+            help = "on an IPv6 address, only accept IPv6 connections (true) or also IPv4 ones (false). Defaults to the system setting"
+        )]
+        v6only: Option<bool>,
+        #[clap(
+            long = "accept-rate",
+            help = "maximum number of new connections accepted per second, the others wait in the kernel backlog"
+        )]
+        accept_rate: Option<u32>,
+        #[clap(
+            long = "accept-burst",
+            help = "new connections accepted at once above the accept rate, defaults to the rate"
+        )]
+        accept_burst: Option<u32>,
+        #[clap(
+            long = "tags",
+            help = "tags added to those of every frontend of the listener (example: 'datacenter=par1, edge-pool=blue')",
+            value_parser = parse_tags
+        )]
+        tags: Option<BTreeMap<String, String>>,
     },
     #[clap(name = "remove")]
     Remove {
@@ -726,6 +1092,11 @@ pub enum TcpListenerCmd {
             help = "listener address, format: IP:port"
         )]
         address: SocketAddr,
+        #[clap(
+            long = "drain-timeout",
+            help = "let the established connections finish, closing those still open after this many seconds"
+        )]
+        drain_timeout: Option<u32>,
     },
 }
 
@@ -774,6 +1145,11 @@ Use the --json option to get a much more verbose result, with certificate conten
         #[clap(long = "tls-versions", help = "accepted TLS versions for this certificate",
                 value_parser = parse_tls_versions)]
         tls_versions: Vec<TlsVersion>,
+        #[clap(
+            long = "tls-cipher-list",
+            help = "cipher suites for this certificate, in order of preference (those of the listener by default)"
+        )]
+        cipher_list: Vec<String>,
     },
     #[clap(name = "remove", about = "Remove a certificate")]
     Remove {
@@ -820,6 +1196,45 @@ Use the --json option to get a much more verbose result, with certificate conten
         #[clap(long = "tls-versions", help = "accepted TLS versions for this certificate",
                 value_parser = parse_tls_versions)]
         tls_versions: Vec<TlsVersion>,
+        #[clap(
+            long = "tls-cipher-list",
+            help = "cipher suites for this certificate, in order of preference (those of the listener by default)"
+        )]
+        cipher_list: Vec<String>,
+    },
+    #[clap(
+        name = "pin",
+        about = "Force the certificate of a domain, when several certificates match it"
+    )]
+    Pin {
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "listener address, format: IP:port"
+        )]
+        address: SocketAddr,
+        #[clap(short = 'd', long = "domain", help = "domain name")]
+        domain: String,
+        #[clap(
+            short = 'f',
+            long = "fingerprint",
+            help = "fingerprint of a certificate that covers the domain"
+        )]
+        fingerprint: String,
+    },
+    #[clap(
+        name = "unpin",
+        about = "Remove the pin of a domain, its certificate is chosen automatically again"
+    )]
+    Unpin {
+        #[clap(
+            short = 'a',
+            long = "address",
+            help = "listener address, format: IP:port"
+        )]
+        address: SocketAddr,
+        #[clap(short = 'd', long = "domain", help = "domain name")]
+        domain: String,
     },
 }
 
@@ -855,8 +1270,24 @@ fn parse_tags(string_to_parse: &str) -> Result<BTreeMap<String, String>, String>
     Ok(tags)
 }
 
+/// parses an ISO 8601 date into a unix timestamp, in seconds
+fn parse_date(date: &str) -> Result<i64, String> {
+    OffsetDateTime::parse(date, &Iso8601::DEFAULT)
+        .map(|date| date.unix_timestamp())
+        .map_err(|error| format!("could not parse the date '{date}': {error}"))
+}
+
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn parse_dates() {
+        use super::*;
+
+        assert_eq!(Ok(1717207200), parse_date("2024-06-01T02:00Z"));
+        assert_eq!(Ok(1717207200), parse_date("2024-06-01T04:00:00+02:00"));
+        assert!(parse_date("tomorrow").is_err());
+    }
+
     #[test]
     fn parse_tags_from_string() {
         use super::*;
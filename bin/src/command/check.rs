@@ -0,0 +1,773 @@
+//! Smoke tests of the data path, run by the main process after a deployment.
+//!
+//! The main process serves a temporary backend on a loopback port, adds a cluster
+//! routing a unique hostname to it on every active listener, and sends a request
+//! through each listener: a listener passes if the answer of the temporary backend
+//! comes back. The cluster, its frontends and its backend are removed afterwards.
+//!
+//! The requests are sent from a thread, which wakes the main loop up with their
+//! outcomes: the main process keeps serving its clients and workers meanwhile.
+//!
+//! A TCP listener routes all its connections to a single cluster, so the TCP
+//! listeners that already have a frontend are skipped. HTTPS checks accept any
+//! certificate: they test the data path, not the certificates.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use mio::{Registry, Token, Waker};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme, StreamOwned,
+};
+
+use sozu_command_lib::proto::command::{
+    request::RequestType, response_content::ContentType, AddBackend, Cluster, ListenerType,
+    PathRule, ProxyCheck, ProxyCheckStatus, ProxyChecks, RemoveBackend, Request,
+    RequestHttpFrontend, RequestTcpFrontend, RulePosition,
+};
+
+use crate::command::{
+    server::{DefaultGatherer, Gatherer, GatheringTask, MessageClient, Server, Timeout},
+    sessions::{ClientSession, OptionalClient},
+};
+
+/// how long a check waits to connect, and for each read and write
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// wakes the main loop up when the probes of a check are done
+pub const CHECK_WAKER: Token = Token(usize::MAX - 2);
+
+/// connections answered at the same time by the check backend, the others are closed
+const MAX_CHECK_BACKEND_CONNECTIONS: usize = 16;
+
+/// the round trip time through a listener, or why it failed
+type Outcome = Result<Duration, String>;
+
+pub fn check_proxy(server: &mut Server, client: &mut ClientSession) {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    let cluster_id = format!("sozu-check-{nanos}");
+    let hostname = format!("{cluster_id}.invalid");
+
+    let backend = match CheckBackend::start(cluster_id.clone()) {
+        Ok(backend) => backend,
+        Err(error) => {
+            client.finish_failure(format!("could not start the check backend: {error}"));
+            return;
+        }
+    };
+
+    let mut targets = Vec::new();
+    let mut skipped = Vec::new();
+    let listeners = server
+        .state
+        .http_listeners
+        .iter()
+        .map(|(address, listener)| (*address, ListenerType::Http, listener.active))
+        .chain(
+            server
+                .state
+                .https_listeners
+                .iter()
+                .map(|(address, listener)| (*address, ListenerType::Https, listener.active)),
+        )
+        .chain(
+            server
+                .state
+                .tcp_listeners
+                .iter()
+                .map(|(address, listener)| (*address, ListenerType::Tcp, listener.active)),
+        );
+    for (address, listener_type, active) in listeners {
+        let routed = listener_type == ListenerType::Tcp
+            && server
+                .state
+                .tcp_fronts
+                .values()
+                .flatten()
+                .any(|front| front.address == address);
+        let reason = match (active, routed) {
+            (false, _) => "the listener is not active",
+            (true, true) => "the listener already routes to a cluster",
+            (true, false) => {
+                targets.push((address, listener_type));
+                continue;
+            }
+        };
+        skipped.push(ProxyCheck {
+            address: address.into(),
+            listener_type: listener_type.into(),
+            status: ProxyCheckStatus::Skipped.into(),
+            message: Some(reason.to_owned()),
+            duration: None,
+        });
+    }
+
+    // the state is changed first, to scatter only the requests it accepted
+    let mut setup_error = None;
+    let mut applied = Vec::new();
+    for request in setup_requests(&cluster_id, &hostname, backend.address, &targets) {
+        if let Err(error) = server.dispatch_on_state(&request) {
+            setup_error = Some(format!("could not add the temporary cluster: {error}"));
+            break;
+        }
+        applied.push(request);
+    }
+
+    client.return_processing(format!(
+        "Adding the temporary cluster {cluster_id} on {} listeners...",
+        targets.len()
+    ));
+    let task_id = server.new_task(
+        Box::new(ProxyCheckTask {
+            client_token: client.token,
+            gatherer: DefaultGatherer::default(),
+            progress: CheckProgress::Setup,
+            run: CheckRun {
+                cluster_id,
+                hostname,
+                backend,
+                targets,
+                skipped,
+                setup_error,
+                applied: applied.clone(),
+            },
+        }),
+        Timeout::Default,
+    );
+    for (index, request) in applied.into_iter().enumerate() {
+        server.scatter_on(request, task_id, index, None);
+    }
+}
+
+/// the requests adding the temporary cluster, its backend, and a frontend on each listener
+fn setup_requests(
+    cluster_id: &str,
+    hostname: &str,
+    backend_address: SocketAddr,
+    targets: &[(SocketAddr, ListenerType)],
+) -> Vec<Request> {
+    let mut requests: Vec<Request> = vec![
+        RequestType::AddCluster(Cluster {
+            cluster_id: cluster_id.to_owned(),
+            ..Default::default()
+        })
+        .into(),
+        RequestType::AddBackend(AddBackend {
+            cluster_id: cluster_id.to_owned(),
+            backend_id: format!("{cluster_id}-0"),
+            address: backend_address.into(),
+            ..Default::default()
+        })
+        .into(),
+    ];
+
+    for (address, listener_type) in targets {
+        let http_frontend = RequestHttpFrontend {
+            cluster_id: Some(cluster_id.to_owned()),
+            address: (*address).into(),
+            hostname: hostname.to_owned(),
+            path: PathRule::prefix(""),
+            position: RulePosition::Tree.into(),
+            ..Default::default()
+        };
+        let request_type = match listener_type {
+            ListenerType::Http => RequestType::AddHttpFrontend(http_frontend),
+            ListenerType::Https => RequestType::AddHttpsFrontend(http_frontend),
+            ListenerType::Tcp => RequestType::AddTcpFrontend(RequestTcpFrontend {
+                cluster_id: cluster_id.to_owned(),
+                address: (*address).into(),
+                ..Default::default()
+            }),
+        };
+        requests.push(request_type.into());
+    }
+    requests
+}
+
+/// the request undoing a request of the setup
+fn removal_request(request: &Request) -> Option<Request> {
+    let request_type = match request.request_type.as_ref()? {
+        RequestType::AddCluster(cluster) => RequestType::RemoveCluster(cluster.cluster_id.clone()),
+        RequestType::AddBackend(backend) => RequestType::RemoveBackend(RemoveBackend {
+            cluster_id: backend.cluster_id.clone(),
+            backend_id: backend.backend_id.clone(),
+            address: backend.address,
+        }),
+        RequestType::AddHttpFrontend(front) => RequestType::RemoveHttpFrontend(front.clone()),
+        RequestType::AddHttpsFrontend(front) => RequestType::RemoveHttpsFrontend(front.clone()),
+        RequestType::AddTcpFrontend(front) => RequestType::RemoveTcpFrontend(front.clone()),
+        _ => return None,
+    };
+    Some(request_type.into())
+}
+
+#[derive(Debug)]
+enum CheckProgress {
+    /// the workers apply the temporary cluster
+    Setup,
+    /// the workers remove the temporary cluster, the results wait for them
+    Cleanup(Vec<ProxyCheck>),
+}
+
+/// what a check needs from its setup to its cleanup
+#[derive(Debug)]
+pub struct CheckRun {
+    cluster_id: String,
+    hostname: String,
+    backend: CheckBackend,
+    targets: Vec<(SocketAddr, ListenerType)>,
+    skipped: Vec<ProxyCheck>,
+    setup_error: Option<String>,
+    /// requests applied on the state, to undo
+    applied: Vec<Request>,
+}
+
+#[derive(Debug)]
+struct ProxyCheckTask {
+    client_token: Token,
+    gatherer: DefaultGatherer,
+    progress: CheckProgress,
+    run: CheckRun,
+}
+
+impl GatheringTask for ProxyCheckTask {
+    fn client_token(&self) -> Option<Token> {
+        Some(self.client_token)
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        let ProxyCheckTask {
+            client_token,
+            gatherer,
+            progress,
+            run,
+        } = *self;
+
+        match progress {
+            CheckProgress::Setup => {
+                let setup_error = run.setup_error.clone().or_else(|| {
+                    (gatherer.errors > 0 || timed_out).then(|| {
+                        "the workers could not apply the temporary configuration".to_owned()
+                    })
+                });
+
+                match setup_error {
+                    Some(error) => {
+                        let outcomes = run.targets.iter().map(|_| Err(error.clone())).collect();
+                        finish_probes(server, client, client_token, run, outcomes);
+                    }
+                    None => {
+                        client.return_processing(format!(
+                            "Sending a request through {} listeners...",
+                            run.targets.len()
+                        ));
+                        server.probing_checks.start(client_token, run);
+                    }
+                }
+            }
+            CheckProgress::Cleanup(mut checks) => {
+                run.backend.stop();
+
+                let failed = checks
+                    .iter()
+                    .filter(|check| check.status == ProxyCheckStatus::Failed as i32)
+                    .count();
+                let mut message = format!(
+                    "{} listeners passed, {} failed, {} skipped",
+                    checks.len() - failed,
+                    failed,
+                    run.skipped.len()
+                );
+                if gatherer.errors > 0 || timed_out {
+                    message.push_str(&format!(
+                        ". Some workers could not remove the temporary cluster {}",
+                        run.cluster_id
+                    ));
+                }
+
+                checks.extend(run.skipped);
+                let content = ContentType::ProxyChecks(ProxyChecks { checks }).into();
+                if failed > 0 {
+                    client.finish_failure_with_content(content, message);
+                } else {
+                    client.finish_ok_with_content(content, message);
+                }
+            }
+        }
+    }
+}
+
+/// Reports the outcome of each listener, and removes the temporary cluster
+pub fn finish_probes(
+    server: &mut Server,
+    client: &mut OptionalClient,
+    client_token: Token,
+    run: CheckRun,
+    outcomes: Vec<Outcome>,
+) {
+    let mut checks = Vec::new();
+    for ((address, listener_type), outcome) in run.targets.iter().zip(outcomes) {
+        client.return_processing(format!(
+            "{} listener {}: {}",
+            listener_type.as_str_name(),
+            address,
+            match &outcome {
+                Ok(_) => "passed".to_owned(),
+                Err(error) => format!("failed, {error}"),
+            }
+        ));
+        checks.push(ProxyCheck {
+            address: (*address).into(),
+            listener_type: (*listener_type).into(),
+            status: match outcome {
+                Ok(_) => ProxyCheckStatus::Passed,
+                Err(_) => ProxyCheckStatus::Failed,
+            }
+            .into(),
+            duration: outcome
+                .as_ref()
+                .ok()
+                .map(|duration| duration.as_millis() as u64),
+            message: outcome.err(),
+        });
+    }
+
+    client.return_processing(format!(
+        "Removing the temporary cluster {}...",
+        run.cluster_id
+    ));
+    let removals: Vec<Request> = run
+        .applied
+        .iter()
+        .rev()
+        .filter_map(removal_request)
+        .collect();
+    let task_id = server.new_task(
+        Box::new(ProxyCheckTask {
+            client_token,
+            gatherer: DefaultGatherer::default(),
+            progress: CheckProgress::Cleanup(checks),
+            run,
+        }),
+        Timeout::Default,
+    );
+    for (index, request) in removals.into_iter().enumerate() {
+        if let Err(error) = server.dispatch_on_state(&request) {
+            error!("could not remove the temporary check cluster: {}", error);
+            continue;
+        }
+        server.scatter_on(request, task_id, index, None);
+    }
+}
+
+/// The checks whose requests are sent from a thread. The thread sends back the
+/// outcomes, and wakes the main loop up to finish the check
+#[derive(Debug)]
+pub struct ProbingChecks {
+    waker: Arc<Waker>,
+    sender: Sender<(u64, Vec<Outcome>)>,
+    receiver: Receiver<(u64, Vec<Outcome>)>,
+    /// by id, with the client that asked for them
+    checks: HashMap<u64, (Token, CheckRun)>,
+    next_id: u64,
+}
+
+impl ProbingChecks {
+    pub fn new(registry: &Registry) -> std::io::Result<Self> {
+        let (sender, receiver) = channel();
+        Ok(Self {
+            waker: Arc::new(Waker::new(registry, CHECK_WAKER)?),
+            sender,
+            receiver,
+            checks: HashMap::new(),
+            next_id: 0,
+        })
+    }
+
+    /// sends the requests of a check through its listeners, one after the other
+    fn start(&mut self, client_token: Token, run: CheckRun) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let targets = run.targets.clone();
+        let hostname = run.hostname.clone();
+        let token = run.cluster_id.clone();
+        self.checks.insert(id, (client_token, run));
+
+        let sender = self.sender.clone();
+        let waker = self.waker.clone();
+        let spawned = thread::Builder::new()
+            .name("check-probes".to_owned())
+            .spawn(move || {
+                let outcomes = targets
+                    .iter()
+                    .map(|(address, listener_type)| {
+                        probe(*address, *listener_type, &hostname, &token)
+                    })
+                    .collect();
+                if sender.send((id, outcomes)).is_ok() {
+                    if let Err(error) = waker.wake() {
+                        error!("could not wake the main loop up after a check: {}", error);
+                    }
+                }
+            });
+
+        if let Err(error) = spawned {
+            error!("could not start the probes of a check: {}", error);
+            let count = self.checks.get(&id).map_or(0, |(_, run)| run.targets.len());
+            let outcomes = (0..count)
+                .map(|_| Err(format!("could not send the request: {error}")))
+                .collect();
+            let _ = self.sender.send((id, outcomes));
+            let _ = self.waker.wake();
+        }
+    }
+
+    /// the checks whose requests are done, with the client that asked for them
+    pub fn finished(&mut self) -> Vec<(Token, CheckRun, Vec<Outcome>)> {
+        self.receiver
+            .try_iter()
+            .filter_map(|(id, outcomes)| {
+                let (client_token, run) = self.checks.remove(&id)?;
+                Some((client_token, run, outcomes))
+            })
+            .collect()
+    }
+}
+
+/// Sends a request for the hostname of the check through a listener, returns
+/// the round trip time if the answer of the check backend came back
+fn probe(
+    address: SocketAddr,
+    listener_type: ListenerType,
+    hostname: &str,
+    token: &str,
+) -> Result<Duration, String> {
+    // a listener bound on all interfaces is reached on the loopback
+    let address = match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), address.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), address.port())
+        }
+        _ => address,
+    };
+
+    let started = Instant::now();
+    let stream = TcpStream::connect_timeout(&address, CHECK_TIMEOUT)
+        .map_err(|error| format!("could not connect: {error}"))?;
+    stream
+        .set_read_timeout(Some(CHECK_TIMEOUT))
+        .and_then(|()| stream.set_write_timeout(Some(CHECK_TIMEOUT)))
+        .map_err(|error| format!("could not set the timeouts: {error}"))?;
+
+    let request = format!("GET / HTTP/1.1\r\nHost: {hostname}\r\nConnection: close\r\n\r\n");
+    let response = match listener_type {
+        ListenerType::Https => {
+            let config = tls_config().map_err(|error| format!("TLS error: {error}"))?;
+            let server_name = ServerName::try_from(hostname.to_owned())
+                .map_err(|error| format!("invalid hostname: {error}"))?;
+            let connection = ClientConnection::new(config, server_name)
+                .map_err(|error| format!("TLS error: {error}"))?;
+            exchange(StreamOwned::new(connection, stream), &request, token)?
+        }
+        ListenerType::Http | ListenerType::Tcp => exchange(stream, &request, token)?,
+    };
+
+    if !response.starts_with(b"HTTP/1.1 200") {
+        let status_line = response
+            .split(|byte| *byte == b'\r')
+            .next()
+            .unwrap_or_default();
+        return Err(format!(
+            "unexpected response: {}",
+            String::from_utf8_lossy(status_line)
+        ));
+    }
+    if !contains(&response, token.as_bytes()) {
+        return Err("the response did not come from the check backend".to_owned());
+    }
+    Ok(started.elapsed())
+}
+
+/// writes the request, reads until the token of the check or the end of the response
+fn exchange<S: Read + Write>(mut stream: S, request: &str, token: &str) -> Result<Vec<u8>, String> {
+    stream
+        .write_all(request.as_bytes())
+        .and_then(|()| stream.flush())
+        .map_err(|error| format!("could not send the request: {error}"))?;
+
+    let mut response = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => {
+                response.extend_from_slice(&buffer[..size]);
+                if contains(&response, token.as_bytes()) {
+                    break;
+                }
+            }
+            Err(error) if response.is_empty() => {
+                return Err(format!("no response: {error}"));
+            }
+            Err(_) => break,
+        }
+    }
+    Ok(response)
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+fn tls_config() -> Result<Arc<ClientConfig>, rustls::Error> {
+    let provider = Arc::new(ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Accepts the certificate of any listener, but still checks the handshake signatures
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Answers every request with the token of the check, on threads of the main process.
+/// At most [`MAX_CHECK_BACKEND_CONNECTIONS`] connections are answered at once
+#[derive(Debug)]
+struct CheckBackend {
+    address: SocketAddr,
+    stopping: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl CheckBackend {
+    fn start(token: String) -> std::io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let address = listener.local_addr()?;
+        let stopping = Arc::new(AtomicBool::new(false));
+        let answering = Arc::new(AtomicUsize::new(0));
+
+        let stop = stopping.clone();
+        let handle = thread::Builder::new()
+            .name("check-backend".to_owned())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    if answering.fetch_add(1, Ordering::AcqRel) >= MAX_CHECK_BACKEND_CONNECTIONS {
+                        answering.fetch_sub(1, Ordering::AcqRel);
+                        debug!("check backend is busy, closing a connection");
+                        continue;
+                    }
+                    // health checks may keep a connection open without a request
+                    let token = token.clone();
+                    let answered = answering.clone();
+                    let spawned = thread::Builder::new()
+                        .name("check-backend-answer".to_owned())
+                        .spawn(move || {
+                            if let Err(error) = answer(stream, &token) {
+                                debug!("check backend could not answer: {}", error);
+                            }
+                            answered.fetch_sub(1, Ordering::AcqRel);
+                        });
+                    if spawned.is_err() {
+                        answering.fetch_sub(1, Ordering::AcqRel);
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            address,
+            stopping,
+            handle: Some(handle),
+        })
+    }
+
+    fn stop(mut self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        // wakes the thread up from accept
+        let _ = TcpStream::connect_timeout(&self.address, CHECK_TIMEOUT);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn answer(mut stream: TcpStream, token: &str) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CHECK_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+    while !contains(&request, b"\r\n\r\n") {
+        let size = stream.read(&mut buffer)?;
+        if size == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..size]);
+    }
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        token.len(),
+        token
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_backend_answers_the_token() {
+        let backend = CheckBackend::start("sozu-check-token".to_owned()).expect("could not start");
+        let response = probe(
+            backend.address,
+            ListenerType::Http,
+            "check.invalid",
+            "sozu-check-token",
+        );
+        assert!(response.is_ok(), "{response:?}");
+
+        let wrong_token = probe(
+            backend.address,
+            ListenerType::Tcp,
+            "check.invalid",
+            "other-token",
+        );
+        assert!(wrong_token.is_err());
+        backend.stop();
+    }
+
+    #[test]
+    fn probes_wake_the_main_loop_up() {
+        let mut poll = mio::Poll::new().expect("could not create a poll");
+        let mut probing = ProbingChecks::new(poll.registry()).expect("could not create a waker");
+
+        let backend = CheckBackend::start("sozu-check-2".to_owned()).expect("could not start");
+        let targets = vec![(backend.address, ListenerType::Http)];
+        probing.start(
+            Token(1),
+            CheckRun {
+                cluster_id: "sozu-check-2".to_owned(),
+                hostname: "sozu-check-2.invalid".to_owned(),
+                backend,
+                targets,
+                skipped: Vec::new(),
+                setup_error: None,
+                applied: Vec::new(),
+            },
+        );
+
+        let mut events = mio::Events::with_capacity(4);
+        poll.poll(&mut events, Some(CHECK_TIMEOUT))
+            .expect("could not poll");
+        assert!(events.iter().any(|event| event.token() == CHECK_WAKER));
+
+        let mut finished = probing.finished();
+        assert_eq!(finished.len(), 1);
+        let (client_token, run, outcomes) = finished.remove(0);
+        assert_eq!(client_token, Token(1));
+        assert!(outcomes[0].is_ok(), "{outcomes:?}");
+        run.backend.stop();
+    }
+
+    #[test]
+    fn setup_is_undone() {
+        let targets = vec![
+            ("0.0.0.0:8080".parse().unwrap(), ListenerType::Http),
+            ("0.0.0.0:8443".parse().unwrap(), ListenerType::Https),
+            ("0.0.0.0:5432".parse().unwrap(), ListenerType::Tcp),
+        ];
+        let setup = setup_requests(
+            "sozu-check-1",
+            "sozu-check-1.invalid",
+            "127.0.0.1:1026".parse().unwrap(),
+            &targets,
+        );
+        assert_eq!(setup.len(), 5);
+        assert!(setup
+            .iter()
+            .all(|request| removal_request(request).is_some()));
+    }
+}
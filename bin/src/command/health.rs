@@ -0,0 +1,279 @@
+//! Health endpoints of the main process
+//!
+//! A tiny HTTP listener, enabled with `health_check_address`, for orchestrators:
+//! - `/healthz` answers 200 if the main process and all workers are alive
+//! - `/readyz` answers 200 once the state is loaded and all listeners are activated
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::SocketAddr,
+    os::fd::FromRawFd,
+    time::{Duration, Instant},
+};
+
+use mio::{
+    net::{TcpListener, TcpStream},
+    Token,
+};
+
+/// the token of the health listener in the main process's event loop
+pub const HEALTH_LISTENER: Token = Token(usize::MAX);
+
+/// probes are local and small, close the ones that do not finish in time
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// health checks answered at the same time, the next ones are closed right away
+pub const MAX_HEALTH_PROBES: usize = 64;
+
+/// only the request line matters
+const MAX_REQUEST_SIZE: usize = 1024;
+
+/// What the main process knows of its own health
+#[derive(Debug, PartialEq, Eq)]
+pub struct HealthStatus {
+    pub running_workers: usize,
+    pub expected_workers: usize,
+    pub state_loaded: bool,
+    pub inactive_listeners: usize,
+}
+
+impl HealthStatus {
+    pub fn is_healthy(&self) -> bool {
+        self.running_workers >= self.expected_workers
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.is_healthy() && self.state_loaded && self.inactive_listeners == 0
+    }
+
+    /// status line and body of the answer to a health check on `path`
+    fn answer(&self, path: &str) -> (&'static str, String) {
+        let workers = format!(
+            "{}/{} workers running",
+            self.running_workers, self.expected_workers
+        );
+        match path {
+            "/healthz" if self.is_healthy() => ("200 OK", format!("ok: {workers}\n")),
+            "/healthz" => ("503 Service Unavailable", format!("unhealthy: {workers}\n")),
+            "/readyz" if self.is_ready() => ("200 OK", "ready\n".to_string()),
+            "/readyz" if !self.is_healthy() => {
+                ("503 Service Unavailable", format!("not ready: {workers}\n"))
+            }
+            "/readyz" if !self.state_loaded => (
+                "503 Service Unavailable",
+                "not ready: state not loaded\n".to_string(),
+            ),
+            "/readyz" => (
+                "503 Service Unavailable",
+                format!(
+                    "not ready: {} listeners not activated\n",
+                    self.inactive_listeners
+                ),
+            ),
+            _ => ("404 Not Found", "not found\n".to_string()),
+        }
+    }
+}
+
+/// accepts all pending health checks, they are answered on their own events
+pub fn accept_health_checks(listener: &TcpListener) -> Vec<(TcpStream, SocketAddr)> {
+    let mut accepted = Vec::new();
+    loop {
+        match listener.accept() {
+            Ok(probe) => accepted.push(probe),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            // the prober gave up before we accepted it, the next ones may still wait
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionAborted
+                        | ErrorKind::ConnectionReset
+                        | ErrorKind::Interrupted
+                ) =>
+            {
+                debug!("could not accept a health check: {}", e);
+            }
+            Err(e) => {
+                error!("could not accept health checks: {}", e);
+                break;
+            }
+        }
+    }
+    accepted
+}
+
+/// A health check connection, read and written without blocking the main process
+#[derive(Debug)]
+pub struct HealthProbe {
+    pub stream: TcpStream,
+    address: SocketAddr,
+    request: Vec<u8>,
+    response: Vec<u8>,
+    written: usize,
+    deadline: Instant,
+}
+
+impl HealthProbe {
+    pub fn new(stream: TcpStream, address: SocketAddr, now: Instant) -> Self {
+        Self {
+            stream,
+            address,
+            request: Vec::new(),
+            response: Vec::new(),
+            written: 0,
+            deadline: now + HEALTH_CHECK_TIMEOUT,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// reads the request then writes the answer, as far as the socket allows.
+    /// Returns true once the probe is finished and can be closed
+    pub fn ready(&mut self, status: &HealthStatus) -> bool {
+        if self.response.is_empty() {
+            let mut peer_closed = false;
+            let mut buffer = [0u8; MAX_REQUEST_SIZE];
+            while self.request.len() < MAX_REQUEST_SIZE {
+                let room = MAX_REQUEST_SIZE - self.request.len();
+                match self.stream.read(&mut buffer[..room]) {
+                    Ok(0) => {
+                        peer_closed = true;
+                        break;
+                    }
+                    Ok(size) => self.request.extend_from_slice(&buffer[..size]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        debug!(
+                            "could not read the health check from {}: {}",
+                            self.address, e
+                        );
+                        return true;
+                    }
+                }
+            }
+
+            if peer_closed && self.request.is_empty() {
+                return true;
+            }
+            let request_line_read = self.request.contains(&b'\n')
+                || self.request.len() >= MAX_REQUEST_SIZE
+                || peer_closed;
+            if !request_line_read {
+                return false;
+            }
+            self.response = answer_health_check(&self.request, status).into_bytes();
+        }
+
+        while self.written < self.response.len() {
+            match self.stream.write(&self.response[self.written..]) {
+                Ok(0) => return true,
+                Ok(size) => self.written += size,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!(
+                        "could not answer the health check from {}: {}",
+                        self.address, e
+                    );
+                    return true;
+                }
+            }
+        }
+        true
+    }
+}
+
+fn answer_health_check(request: &[u8], status: &HealthStatus) -> String {
+    // GET /healthz HTTP/1.1
+    let request = String::from_utf8_lossy(request);
+    let path = request
+        .split_whitespace()
+        .nth(1)
+        .and_then(|target| target.split('?').next())
+        .unwrap_or_default();
+
+    let (status_line, body) = status.answer(path);
+    format!(
+        "HTTP/1.1 {status_line}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// binds the health listener, or takes it over from the previous main process
+pub fn health_listener(
+    address: Option<SocketAddr>,
+    upgraded_fd: Option<i32>,
+) -> std::io::Result<Option<TcpListener>> {
+    match (upgraded_fd, address) {
+        (Some(fd), _) => Ok(Some(unsafe { TcpListener::from_raw_fd(fd) })),
+        (None, Some(address)) => {
+            info!("Listening for health checks on {}", address);
+            TcpListener::bind(address).map(Some)
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_checks() {
+        let mut status = HealthStatus {
+            running_workers: 2,
+            expected_workers: 2,
+            state_loaded: true,
+            inactive_listeners: 1,
+        };
+        assert_eq!(status.answer("/healthz").0, "200 OK");
+        assert_eq!(status.answer("/readyz").0, "503 Service Unavailable");
+        assert_eq!(status.answer("/metrics").0, "404 Not Found");
+
+        status.inactive_listeners = 0;
+        assert_eq!(status.answer("/readyz").0, "200 OK");
+
+        status.running_workers = 1;
+        assert_eq!(status.answer("/healthz").0, "503 Service Unavailable");
+        assert_eq!(status.answer("/readyz").0, "503 Service Unavailable");
+    }
+
+    #[test]
+    fn health_probes_do_not_block() {
+        let status = HealthStatus {
+            running_workers: 2,
+            expected_workers: 2,
+            state_loaded: true,
+            inactive_listeners: 0,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+        assert!(accept_health_checks(&listener).is_empty());
+
+        let mut prober = std::net::TcpStream::connect(address).unwrap();
+        let _idle_prober = std::net::TcpStream::connect(address).unwrap();
+        let mut accepted = accept_health_checks(&listener);
+        assert_eq!(accepted.len(), 2);
+
+        let (stream, peer) = accepted.remove(0);
+        let mut probe = HealthProbe::new(stream, peer, Instant::now());
+        // nothing sent yet, or only a part of the request line
+        assert!(!probe.ready(&status));
+        prober.write_all(b"GET /rea").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(!probe.ready(&status));
+
+        prober.write_all(b"dyz HTTP/1.1\r\n\r\n").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(probe.ready(&status));
+
+        let mut response = String::new();
+        drop(probe);
+        prober.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("ready\n"));
+    }
+}
@@ -1,4 +1,7 @@
+pub mod check;
+pub mod health;
 mod requests;
+mod schedule;
 pub mod server;
 pub mod sessions;
 pub mod upgrade;
@@ -20,7 +23,7 @@ use crate::{
     util::{get_config_file_path, get_executable_path, setup_metrics, write_pid_file, UtilError},
 };
 
-use self::server::{HubError, ServerError};
+use self::server::{key_uri_without_provider, HubError, ServerError};
 
 #[derive(thiserror::Error, Debug)]
 pub enum StartError {
@@ -54,12 +57,15 @@ pub enum StartError {
     LaunchWorker(ServerError),
     #[error("could not setup the logger: {0}")]
     SetupLogging(LogError),
+    #[error("invalid certificate in the configuration: {0}")]
+    Certificate(ServerError),
 }
 
 pub fn begin_main_process(args: &Args) -> Result<(), StartError> {
     let config_file_path = get_config_file_path(args).map_err(StartError::GetConfigPath)?;
 
     let config = Config::load_from_path(config_file_path).map_err(StartError::LoadConfig)?;
+    check_certificate_keys(&config)?;
 
     setup_logging_with_config(&config, "MAIN").map_err(StartError::SetupLogging)?;
     info!("Starting up");
@@ -114,6 +120,23 @@ pub fn begin_main_process(args: &Args) -> Result<(), StartError> {
     Ok(())
 }
 
+/// refuse the keys given as URIs before starting, instead of failing each worker
+fn check_certificate_keys(config: &Config) -> Result<(), StartError> {
+    let Ok(messages) = config.generate_config_messages() else {
+        // reported when the static configuration is loaded
+        return Ok(());
+    };
+    match messages
+        .iter()
+        .find_map(|message| key_uri_without_provider(&message.content))
+    {
+        Some(uri) => Err(StartError::Certificate(ServerError::NoKeyProvider(
+            uri.to_owned(),
+        ))),
+        None => Ok(()),
+    }
+}
+
 #[cfg(target_os = "linux")]
 /// We check the hard_limit. The soft_limit can be changed at runtime
 /// by the process or any user. hard_limit can only be changed by root
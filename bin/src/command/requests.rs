@@ -3,6 +3,7 @@ use std::{
     env,
     fs::File,
     io::{ErrorKind, Read},
+    time::Duration,
 };
 
 use mio::Token;
@@ -15,23 +16,28 @@ use sozu_command_lib::{
     parser::parse_several_requests,
     proto::command::{
         request::RequestType, response_content::ContentType, AggregatedMetrics, AvailableMetrics,
-        CertificatesWithFingerprints, ClusterHashes, ClusterInformations, FrontendFilters,
-        HardStop, QueryCertificatesFilters, QueryMetricsOptions, Request, ResponseContent,
-        ResponseStatus, RunState, SoftStop, Status, WorkerInfo, WorkerInfos, WorkerRequest,
-        WorkerResponses,
+        CertificatesWithFingerprints, ClusterHashes, ClusterInformations, DeactivateListener,
+        FaultInjection, FrontendFilters, HardStop, QueryCertificatesFilters,
+        QueryMetricsConfiguration, QueryMetricsOptions, Request, ResponseContent, ResponseStatus,
+        RunState, SoftStop, Status, WorkerInfo, WorkerInfos, WorkerRequest, WorkerResponses,
     },
 };
 use sozu_lib::metrics::METRICS;
 
 use crate::command::{
+    check::check_proxy,
+    schedule::{cancel_scheduled_request, list_scheduled_requests, schedule_request},
     server::{
-        DefaultGatherer, Gatherer, GatheringTask, MessageClient, Server, ServerState, Timeout,
-        WorkerId,
+        key_uri_without_provider, DefaultGatherer, Gatherer, GatheringTask, MessageClient, Server,
+        ServerError, ServerState, Timeout, WorkerId,
     },
     sessions::{ClientSession, OptionalClient},
-    upgrade::{upgrade_main, upgrade_worker},
+    upgrade::{finish_main_upgrade, upgrade_main, upgrade_worker},
 };
 
+/// while loading many requests, report progress to the client at this interval of responses
+const PROGRESS_INTERVAL: usize = 1000;
+
 impl Server {
     pub fn handle_client_request(&mut self, client: &mut ClientSession, request: Request) {
         let request_type = match request.request_type {
@@ -46,10 +52,17 @@ impl Server {
             RequestType::LoadState(path) => load_state(self, Some(client), &path),
             RequestType::ListWorkers(_) => list_workers(self, client),
             RequestType::ListFrontends(inner) => list_frontend_command(self, client, inner),
+            RequestType::RemoveFrontends(filters) => remove_frontends(self, client, filters),
             RequestType::ListListeners(_) => list_listeners(self, client),
-            RequestType::UpgradeMain(_) => upgrade_main(self, client),
+            RequestType::UpgradeMain(upgrade) => upgrade_main(self, client, upgrade.binary),
+            RequestType::FinishMainUpgrade(finish) => {
+                finish_main_upgrade(self, client, finish.rollback)
+            }
             RequestType::UpgradeWorker(worker_id) => upgrade_worker(self, client, worker_id),
             RequestType::SubscribeEvents(_) => subscribe_client_to_events(self, client),
+            RequestType::SubscribeStateChanges(_) => {
+                subscribe_client_to_state_changes(self, client)
+            }
             RequestType::ReloadConfiguration(path) => {
                 load_static_config(self, Some(client), Some(&path))
             }
@@ -73,7 +86,8 @@ impl Server {
             | RequestType::RemoveHttpsFrontend(_)
             | RequestType::RemoveListener(_)
             | RequestType::RemoveTcpFrontend(_)
-            | RequestType::ReplaceCertificate(_) => {
+            | RequestType::ReplaceCertificate(_)
+            | RequestType::PinCertificate(_) => {
                 worker_request(self, client, request_type);
             }
             RequestType::QueryClustersHashes(_)
@@ -83,6 +97,7 @@ impl Server {
                 query_clusters(self, client, request_type);
             }
             RequestType::QueryMetrics(inner) => query_metrics(self, client, inner),
+            RequestType::QueryMetricsConfiguration(_) => query_metrics_configuration(self, client),
             RequestType::SoftStop(_) => stop(self, client, false),
             RequestType::HardStop(_) => stop(self, client, true),
             RequestType::Logging(logging_filter) => set_logging_level(self, client, logging_filter),
@@ -90,9 +105,19 @@ impl Server {
                 query_certificates_from_main(self, client, filters)
             }
             RequestType::CountRequests(_) => count_requests(self, client),
+            RequestType::ScheduleRequest(scheduled) => schedule_request(self, client, *scheduled),
+            RequestType::ListScheduledRequests(_) => list_scheduled_requests(self, client),
+            RequestType::CancelScheduledRequest(id) => cancel_scheduled_request(self, client, id),
+            RequestType::CheckProxy(_) => check_proxy(self, client),
+            RequestType::ConfigureFaultInjection(fault_injection) => {
+                configure_fault_injection(self, client, fault_injection)
+            }
 
             RequestType::LaunchWorker(_) => {} // not yet implemented, nor used, anywhere
-            RequestType::ReturnListenSockets(_) => {} // This is only implemented by workers,
+            // These are only implemented by workers, sent during upgrades
+            RequestType::ReturnListenSockets(_)
+            | RequestType::ReturnSessions(_)
+            | RequestType::AdoptSessions(_) => {}
         }
     }
 
@@ -171,6 +196,103 @@ pub fn list_frontend_command(
     }
 }
 
+#[derive(Debug)]
+struct RemoveFrontendsTask {
+    pub client_token: Token,
+    pub gatherer: DefaultGatherer,
+    frontend_count: usize,
+}
+
+/// removes every frontend of the state matching the filters, on all workers
+fn remove_frontends(server: &mut Server, client: &mut ClientSession, filters: FrontendFilters) {
+    if !filters.selects_frontends() {
+        client.finish_failure(
+            "Refusing to remove frontends without a filter on the tag, cluster, address, domain or path",
+        );
+        return;
+    }
+
+    let listed = server.state.list_frontends(filters);
+
+    let requests: Vec<Request> = listed
+        .http_frontends
+        .into_iter()
+        .map(RequestType::RemoveHttpFrontend)
+        .chain(
+            listed
+                .https_frontends
+                .into_iter()
+                .map(RequestType::RemoveHttpsFrontend),
+        )
+        .chain(
+            listed
+                .tcp_frontends
+                .into_iter()
+                .map(RequestType::RemoveTcpFrontend),
+        )
+        .map(Into::into)
+        .collect();
+
+    if requests.is_empty() {
+        client.finish_ok("No frontend matches the filters");
+        return;
+    }
+
+    client.return_processing(format!("Removing {} frontends...", requests.len()));
+
+    let task_id = server.new_task(
+        Box::new(RemoveFrontendsTask {
+            client_token: client.token,
+            gatherer: DefaultGatherer::default(),
+            frontend_count: requests.len(),
+        }),
+        Timeout::Default,
+    );
+
+    let mut applied = Vec::new();
+    for request in requests {
+        if let Err(error) = server.dispatch_on_state(&request) {
+            client.return_processing(format!("Could not execute request on state: {:#}", error));
+            continue;
+        }
+        applied.push(request);
+    }
+    server.replay(applied, task_id);
+}
+
+impl GatheringTask for RemoveFrontendsTask {
+    fn client_token(&self) -> Option<Token> {
+        Some(self.client_token)
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        server: &mut Server,
+        client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        server.update_counts();
+
+        let outcomes = self.gatherer.outcomes(Some(self.frontend_count), timed_out);
+
+        if self.gatherer.errors > 0 || timed_out {
+            client.finish_failure_with_content(
+                outcomes,
+                format!("removing {} frontends failed", self.frontend_count),
+            );
+        } else {
+            client.finish_ok_with_content(
+                outcomes,
+                format!("Successfully removed {} frontends", self.frontend_count),
+            );
+        }
+    }
+}
+
 fn list_workers(server: &mut Server, client: &mut ClientSession) {
     let vec = server
         .workers
@@ -179,6 +301,7 @@ fn list_workers(server: &mut Server, client: &mut ClientSession) {
             id: worker.id,
             pid: worker.pid,
             run_state: worker.run_state as i32,
+            crash_report: worker.crash_report.clone(),
         })
         .collect();
 
@@ -247,11 +370,60 @@ fn set_logging_level(server: &mut Server, client: &mut ClientSession, logging_fi
     worker_request(server, client, RequestType::Logging(logging_filter));
 }
 
+fn configure_fault_injection(
+    server: &mut Server,
+    client: &mut ClientSession,
+    fault_injection: FaultInjection,
+) {
+    if !server
+        .state
+        .clusters
+        .contains_key(&fault_injection.cluster_id)
+    {
+        client.finish_failure(format!("no cluster with id {}", fault_injection.cluster_id));
+        return;
+    }
+    if fault_injection.percentage > 100 {
+        client.finish_failure("the percentage of requests can not exceed 100");
+        return;
+    }
+    if let Some(status) = fault_injection.error_status {
+        if !(502..=504).contains(&status) {
+            client.finish_failure(format!(
+                "can not inject a {status} error, only 502, 503 or 504"
+            ));
+            return;
+        }
+    }
+
+    info!(
+        "fault injection on cluster {}: {}% of the requests, latency {:?}ms, jitter {:?}ms, error {:?}",
+        fault_injection.cluster_id,
+        fault_injection.percentage,
+        fault_injection.latency,
+        fault_injection.jitter,
+        fault_injection.error_status
+    );
+    worker_request(
+        server,
+        client,
+        RequestType::ConfigureFaultInjection(fault_injection),
+    );
+}
+
 fn subscribe_client_to_events(server: &mut Server, client: &mut ClientSession) {
     info!("Subscribing client {:?} to listen to events", client.token);
     server.event_subscribers.insert(client.token);
 }
 
+fn subscribe_client_to_state_changes(server: &mut Server, client: &mut ClientSession) {
+    info!(
+        "Subscribing client {:?} to the changes of the state",
+        client.token
+    );
+    server.state_subscribers.insert(client.token);
+}
+
 //===============================================
 // Query clusters
 
@@ -333,7 +505,10 @@ struct LoadStaticConfigTask {
 pub fn load_static_config(server: &mut Server, mut client: OptionalClient, path: Option<&str>) {
     let task_id = server.new_task(
         Box::new(LoadStaticConfigTask {
-            gatherer: DefaultGatherer::default(),
+            gatherer: DefaultGatherer {
+                progress_interval: Some(PROGRESS_INTERVAL),
+                ..Default::default()
+            },
             client_token: client.as_ref().map(|c| c.token),
         }),
         Timeout::None,
@@ -367,9 +542,21 @@ pub fn load_static_config(server: &mut Server, mut client: OptionalClient, path:
         }
     };
 
-    for (request_index, message) in config_messages.into_iter().enumerate() {
+    if let Some(uri) = config_messages
+        .iter()
+        .find_map(|message| key_uri_without_provider(&message.content))
+    {
+        client.finish_failure(format!(
+            "could not load the new config: {}",
+            ServerError::NoKeyProvider(uri.to_owned())
+        ));
+        return;
+    }
+
+    let mut applied = Vec::new();
+    for message in config_messages {
         let request = message.content;
-        if let Err(error) = server.state.dispatch(&request) {
+        if let Err(error) = server.dispatch_on_state(&request) {
             client.return_processing(format!("Could not execute request on state: {:#}", error));
             continue;
         }
@@ -380,8 +567,9 @@ pub fn load_static_config(server: &mut Server, mut client: OptionalClient, path:
             debug!("config generated {:?}", request);
         }
 
-        server.scatter_on(request, task_id, request_index, None);
+        applied.push(request);
     }
+    server.replay(applied, task_id);
 }
 
 impl GatheringTask for LoadStaticConfigTask {
@@ -397,31 +585,15 @@ impl GatheringTask for LoadStaticConfigTask {
         self: Box<Self>,
         server: &mut Server,
         client: &mut OptionalClient,
-        _timed_out: bool,
+        timed_out: bool,
     ) {
-        let mut messages = vec![];
-        for (worker_id, response) in self.gatherer.responses {
-            match ResponseStatus::try_from(response.status) {
-                Ok(ResponseStatus::Failure) => {
-                    messages.push(format!("worker {worker_id}: {}", response.message))
-                }
-                Ok(ResponseStatus::Ok) | Ok(ResponseStatus::Processing) => {}
-                Err(e) => warn!("error decoding response status: {}", e),
-            }
-        }
+        let outcomes = self.gatherer.outcomes(None, timed_out);
 
-        if self.gatherer.errors > 0 {
-            client.finish_failure(format!(
-                "\nloading static configuration failed: {} OK, {} errors:\n- {}",
-                self.gatherer.ok,
-                self.gatherer.errors,
-                messages.join("\n- ")
-            ));
+        if self.gatherer.errors > 0 || timed_out {
+            client.finish_failure_with_content(outcomes, "loading static configuration failed");
         } else {
-            client.finish_ok(format!(
-                "Successfully loaded the config: {} ok, {} errors",
-                self.gatherer.ok, self.gatherer.errors,
-            ));
+            client.finish_ok_with_content(outcomes, "Successfully loaded the config");
+            server.state_loaded = true;
         }
 
         server.update_counts();
@@ -442,9 +614,19 @@ pub fn worker_request(
     client: &mut ClientSession,
     request_content: RequestType,
 ) {
+    // a draining worker answers once the sessions of the listener are closed
+    let timeout = match &request_content {
+        RequestType::DeactivateListener(DeactivateListener {
+            drain_timeout: Some(drain_timeout),
+            ..
+        }) => Timeout::Custom(Duration::from_secs(
+            u64::from(*drain_timeout) + server.config.worker_timeout as u64,
+        )),
+        _ => Timeout::Default,
+    };
     let request = request_content.into();
 
-    if let Err(error) = server.state.dispatch(&request) {
+    if let Err(error) = server.dispatch_on_state(&request) {
         client.finish_failure(format!(
             "could not dispatch request on the main process state: {error}",
         ));
@@ -458,7 +640,7 @@ pub fn worker_request(
             client_token: client.token,
             gatherer: DefaultGatherer::default(),
         }),
-        Timeout::Default,
+        timeout,
         None,
     )
 }
@@ -478,21 +660,13 @@ impl GatheringTask for WorkerTask {
         client: &mut OptionalClient,
         timed_out: bool,
     ) {
-        let mut messages = vec![];
-
-        for (worker_id, response) in self.gatherer.responses {
-            match ResponseStatus::try_from(response.status) {
-                Ok(ResponseStatus::Ok) => messages.push(format!("{worker_id}: OK")),
-                Ok(ResponseStatus::Failure) | Ok(ResponseStatus::Processing) | Err(_) => {
-                    messages.push(format!("{worker_id}: {}", response.message))
-                }
-            }
-        }
+        let outcomes = self.gatherer.outcomes(None, timed_out);
 
         if self.gatherer.errors > 0 || timed_out {
-            client.finish_failure(messages.join(", "));
+            client
+                .finish_failure_with_content(outcomes, "Some workers could not apply the request");
         } else {
-            client.finish_ok("Successfully applied request to all workers");
+            client.finish_ok_with_content(outcomes, "Successfully applied request to all workers");
         }
     }
 }
@@ -590,6 +764,7 @@ impl GatheringTask for QueryMetricsTask {
             clusters: BTreeMap::new(),
             workers: workers_metrics,
             proxying: BTreeMap::new(),
+            tags: BTreeMap::new(),
         };
 
         if !self.options.workers && server.workers.len() > 1 {
@@ -603,6 +778,62 @@ impl GatheringTask for QueryMetricsTask {
     }
 }
 
+// =========================================================
+// Query metrics configuration
+
+#[derive(Debug)]
+struct QueryMetricsConfigurationTask {
+    pub client_token: Token,
+    pub gatherer: DefaultGatherer,
+}
+
+fn query_metrics_configuration(server: &mut Server, client: &mut ClientSession) {
+    client.return_processing("Querying the metrics configuration...");
+
+    server.scatter(
+        RequestType::QueryMetricsConfiguration(QueryMetricsConfiguration {}).into(),
+        Box::new(QueryMetricsConfigurationTask {
+            client_token: client.token,
+            gatherer: DefaultGatherer::default(),
+        }),
+        Timeout::Default,
+        None,
+    );
+}
+
+impl GatheringTask for QueryMetricsConfigurationTask {
+    fn client_token(&self) -> Option<Token> {
+        Some(self.client_token)
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        _server: &mut Server,
+        client: &mut OptionalClient,
+        _timed_out: bool,
+    ) {
+        let map = self
+            .gatherer
+            .responses
+            .into_iter()
+            .filter_map(|(worker_id, response)| {
+                response
+                    .content
+                    .map(|content| (worker_id.to_string(), content))
+            })
+            .collect();
+
+        client.finish_ok_with_content(
+            ContentType::WorkerResponses(WorkerResponses { map }).into(),
+            "Successfully queried the metrics configuration",
+        );
+    }
+}
+
 // =========================================================
 // Load state
 
@@ -634,14 +865,17 @@ pub fn load_state(server: &mut Server, mut client: OptionalClient, path: &str) {
     let task_id = server.new_task(
         Box::new(LoadStateTask {
             client_token: client.as_ref().map(|c| c.token),
-            gatherer: DefaultGatherer::default(),
+            gatherer: DefaultGatherer {
+                progress_interval: Some(PROGRESS_INTERVAL),
+                ..Default::default()
+            },
             path: path.to_owned(),
         }),
         Timeout::None,
     );
 
     let mut buffer = Buffer::with_capacity(200000);
-    let mut scatter_request_counter = 0usize;
+    let mut applied = Vec::new();
 
     let status = loop {
         let previous = buffer.available_data();
@@ -668,9 +902,8 @@ pub fn load_state(server: &mut Server, mut client: OptionalClient, path: &str) {
                 offset = buffer.data().offset(i);
 
                 for request in requests {
-                    if server.state.dispatch(&request.content).is_ok() {
-                        scatter_request_counter += 1;
-                        server.scatter_on(request.content, task_id, scatter_request_counter, None);
+                    if server.dispatch_on_state(&request.content).is_ok() {
+                        applied.push(request.content);
                     }
                 }
             }
@@ -689,6 +922,9 @@ pub fn load_state(server: &mut Server, mut client: OptionalClient, path: &str) {
         buffer.consume(offset);
     };
 
+    // the workers follow the state, even if the file could not be read entirely
+    server.replay(applied, task_id);
+
     match status {
         Ok(()) => {
             client.return_processing("Applying state file...");
@@ -713,17 +949,20 @@ impl GatheringTask for LoadStateTask {
         self: Box<Self>,
         _server: &mut Server,
         client: &mut OptionalClient,
-        _timed_out: bool,
+        timed_out: bool,
     ) {
-        let DefaultGatherer { ok, errors, .. } = self.gatherer;
-        if errors == 0 {
-            client.finish_ok(format!(
-                "Successfully loaded state from path {}, {} ok messages, {} errors",
-                self.path, ok, errors
-            ));
+        let outcomes = self.gatherer.outcomes(None, timed_out);
+        if self.gatherer.errors == 0 && !timed_out {
+            client.finish_ok_with_content(
+                outcomes,
+                format!("Successfully loaded state from path {}", self.path),
+            );
             return;
         }
-        client.finish_failure(format!("loading state: {ok} ok messages, {errors} errors"));
+        client.finish_failure_with_content(
+            outcomes,
+            format!("loading state from path {} failed", self.path),
+        );
     }
 }
 
@@ -853,16 +1092,17 @@ impl GatheringTask for StopTask {
         client: &mut OptionalClient,
         timed_out: bool,
     ) {
+        let outcomes = self.gatherer.outcomes(None, timed_out);
         if timed_out && self.hardness {
-            client.finish_failure(format!(
-                "Workers take too long to stop ({} ok, {} errors), stopping the main process to sever the link",
-                self.gatherer.ok, self.gatherer.errors
-            ));
+            client.finish_failure_with_content(
+                outcomes.clone(),
+                "Workers take too long to stop, stopping the main process to sever the link",
+            );
         }
         server.run_state = ServerState::Stopping;
-        client.finish_ok(format!(
-            "Successfully closed {} workers, {} errors, stopping the main process...",
-            self.gatherer.ok, self.gatherer.errors
-        ));
+        client.finish_ok_with_content(
+            outcomes,
+            "Successfully closed the workers, stopping the main process...",
+        );
     }
 }
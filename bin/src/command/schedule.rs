@@ -0,0 +1,189 @@
+//! Requests applied by the main process at a given date, for change windows
+//! that happen while nobody is at the keyboard.
+//!
+//! Scheduled requests live in the memory of the main process: they survive an
+//! upgrade of the main process, but not a restart.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mio::Token;
+
+use sozu_command_lib::proto::command::{
+    response_content::ContentType, ScheduledRequest, ScheduledRequests,
+};
+
+use crate::command::{
+    server::{DefaultGatherer, Gatherer, GatheringTask, MessageClient, Server, Timeout},
+    sessions::{ClientSession, OptionalClient},
+};
+
+/// the instant matching a unix timestamp, in seconds
+fn system_time(timestamp: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64)
+}
+
+pub fn schedule_request(
+    server: &mut Server,
+    client: &mut ClientSession,
+    mut scheduled: ScheduledRequest,
+) {
+    if !scheduled.request.is_a_state_change() {
+        client.finish_failure(format!(
+            "only changes of the state can be scheduled, not {}",
+            scheduled.request.short_name()
+        ));
+        return;
+    }
+    if system_time(scheduled.execute_at) <= SystemTime::now() {
+        client.finish_failure("can not schedule a request in the past");
+        return;
+    }
+
+    let id = server.next_scheduled_id;
+    server.next_scheduled_id += 1;
+    scheduled.id = Some(id);
+
+    info!(
+        "scheduling request {} ({}) at timestamp {}",
+        id,
+        scheduled.request.short_name(),
+        scheduled.execute_at
+    );
+    server.scheduled_requests.insert(id, scheduled.clone());
+    gauge!(
+        "command.scheduled_requests",
+        server.scheduled_requests.len()
+    );
+
+    client.finish_ok_with_content(
+        ContentType::ScheduledRequests(ScheduledRequests {
+            requests: vec![scheduled],
+        })
+        .into(),
+        format!("Scheduled request {id}"),
+    );
+}
+
+pub fn list_scheduled_requests(server: &mut Server, client: &mut ClientSession) {
+    let mut requests: Vec<ScheduledRequest> = server.scheduled_requests.values().cloned().collect();
+    requests.sort_by_key(|scheduled| scheduled.execute_at);
+
+    client.finish_ok_with_content(
+        ContentType::ScheduledRequests(ScheduledRequests { requests }).into(),
+        "Scheduled requests",
+    );
+}
+
+pub fn cancel_scheduled_request(server: &mut Server, client: &mut ClientSession, id: u64) {
+    match server.scheduled_requests.remove(&id) {
+        Some(_) => {
+            info!("cancelled scheduled request {}", id);
+            gauge!(
+                "command.scheduled_requests",
+                server.scheduled_requests.len()
+            );
+            client.finish_ok(format!("Cancelled scheduled request {id}"));
+        }
+        None => client.finish_failure(format!("no scheduled request with id {id}")),
+    }
+}
+
+impl Server {
+    /// how long until the next scheduled request is due
+    pub fn next_scheduled_request(&self) -> Option<Duration> {
+        let next = self
+            .scheduled_requests
+            .values()
+            .map(|scheduled| scheduled.execute_at)
+            .min()?;
+        Some(
+            system_time(next)
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// applies the scheduled requests that are due on the state and the workers
+    pub fn apply_scheduled_requests(&mut self) {
+        let now = SystemTime::now();
+        let mut due: Vec<ScheduledRequest> = Vec::new();
+        self.scheduled_requests.retain(|_, scheduled| {
+            if system_time(scheduled.execute_at) <= now {
+                due.push(scheduled.clone());
+                return false;
+            }
+            true
+        });
+        if due.is_empty() {
+            return;
+        }
+        gauge!("command.scheduled_requests", self.scheduled_requests.len());
+
+        due.sort_by_key(|scheduled| (scheduled.execute_at, scheduled.id));
+        for scheduled in due {
+            let id = scheduled.id.unwrap_or_default();
+            let request = *scheduled.request;
+            info!(
+                "applying scheduled request {} ({})",
+                id,
+                request.short_name()
+            );
+
+            if let Err(error) = self.dispatch_on_state(&request) {
+                error!(
+                    "could not apply scheduled request {} on the state: {}",
+                    id, error
+                );
+                incr!("command.scheduled_requests.failed");
+                continue;
+            }
+
+            self.scatter(
+                request,
+                Box::new(ScheduledTask {
+                    id,
+                    gatherer: DefaultGatherer::default(),
+                }),
+                Timeout::Default,
+                None,
+            );
+        }
+    }
+}
+
+/// Applies a scheduled request on the workers, nobody waits for the outcome but the logs
+#[derive(Debug)]
+struct ScheduledTask {
+    id: u64,
+    gatherer: DefaultGatherer,
+}
+
+impl GatheringTask for ScheduledTask {
+    fn client_token(&self) -> Option<Token> {
+        None
+    }
+
+    fn get_gatherer(&mut self) -> &mut dyn Gatherer {
+        &mut self.gatherer
+    }
+
+    fn on_finish(
+        self: Box<Self>,
+        _server: &mut Server,
+        _client: &mut OptionalClient,
+        timed_out: bool,
+    ) {
+        if self.gatherer.errors > 0 || timed_out {
+            error!(
+                "scheduled request {} failed on some workers: {} ok, {} errors, {} timeouts",
+                self.id,
+                self.gatherer.ok,
+                self.gatherer.errors,
+                self.gatherer.timeouts.len()
+            );
+            incr!("command.scheduled_requests.failed");
+        } else {
+            info!("applied scheduled request {} on all workers", self.id);
+        }
+    }
+}
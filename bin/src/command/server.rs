@@ -1,15 +1,16 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fmt::{self, Debug},
     io::Error as IoError,
     ops::{Deref, DerefMut},
     os::fd::{AsRawFd, FromRawFd},
+    rc::Rc,
     time::{Duration, Instant},
 };
 
 use libc::pid_t;
 use mio::{
-    net::{UnixListener, UnixStream},
+    net::{TcpListener, UnixListener, UnixStream},
     Events, Interest, Poll, Token,
 };
 use nix::{
@@ -21,23 +22,30 @@ use sozu_command_lib::{
     channel::Channel,
     config::Config,
     proto::command::{
-        request::RequestType, response_content::ContentType, Request, ResponseContent,
-        ResponseStatus, RunState, Status, WorkerRequest, WorkerResponse,
+        request::RequestType, response_content::ContentType, CrashReport, Event, EventKind,
+        Request, ResponseContent, ResponseStatus, RunState, ScheduledRequest, StateChange, Status,
+        WorkerAnswers, WorkerFailure, WorkerOutcomes, WorkerRequest, WorkerResponse,
     },
     ready::Ready,
     scm_socket::{Listeners, ScmSocket, ScmSocketError},
-    state::ConfigState,
+    state::{ConfigState, StateError},
 };
+use sozu_lib::tls::{key_uri_for_logs, key_uri_scheme};
 
 use crate::{
     command::{
+        check::{finish_probes, ProbingChecks, CHECK_WAKER},
+        health::{
+            accept_health_checks, health_listener, HealthProbe, HealthStatus, HEALTH_LISTENER,
+            MAX_HEALTH_PROBES,
+        },
         sessions::{
             wants_to_tick, ClientResult, ClientSession, OptionalClient, WorkerResult, WorkerSession,
         },
-        upgrade::UpgradeData,
+        upgrade::{end_main_standby, MainStandby, UpgradeData, MAIN_STANDBY},
     },
     util::{disable_close_on_exec, enable_close_on_exec, get_executable_path, UtilError},
-    worker::{fork_main_into_worker, WorkerError},
+    worker::{fork_main_into_worker, read_crash_report, WorkerError},
 };
 
 use super::upgrade::SerializedWorkerSession;
@@ -48,6 +56,10 @@ pub type TaskId = usize;
 pub type WorkerId = u32;
 pub type RequestId = String;
 
+/// requests held back for one worker while its channel is saturated. Beyond that,
+/// new requests for this worker fail right away instead of piling up in memory
+pub const MAX_OUTGOING_REQUESTS: usize = 1024;
+
 /// Gather messages and notifies when there are no more left to read.
 #[allow(unused)]
 pub trait Gatherer {
@@ -65,6 +77,9 @@ pub trait Gatherer {
         worker_id: WorkerId,
         message: WorkerResponse,
     );
+
+    /// A worker did not answer a request before the timeout of the task
+    fn on_timeout(&mut self, client: &mut OptionalClient, worker_id: WorkerId, request_id: &str);
 }
 
 /// Must be satisfied by commands that need to wait for worker responses
@@ -97,6 +112,13 @@ pub trait MessageClient {
     /// return failure to the client
     fn finish_failure<T: Into<String>>(&mut self, message: T);
 
+    /// return failure to the client, with details
+    fn finish_failure_with_content<T: Into<String>>(
+        &mut self,
+        content: ResponseContent,
+        message: T,
+    );
+
     /// notify the client about an ongoing task
     fn return_processing<T: Into<String>>(&mut self, message: T);
 
@@ -108,11 +130,12 @@ pub trait MessageClient {
     );
 }
 
-/// A timeout for the tasks of the main process server
+/// A timeout for the tasks of the main process server, applied to each worker:
+/// a worker must answer a request of the task within this duration after it was
+/// queued for the worker, even if its channel was saturated
 pub enum Timeout {
     None,
     Default,
-    #[allow(unused)]
     Custom(Duration),
 }
 
@@ -120,7 +143,10 @@ pub enum Timeout {
 #[derive(Debug)]
 struct TaskContainer {
     job: Box<dyn GatheringTask>,
-    timeout: Option<Instant>,
+    /// how long each worker has to answer the requests of the task
+    timeout: Option<Duration>,
+    /// some worker did not answer in time
+    timed_out: bool,
 }
 
 /// Default strategy when gathering responses from workers
@@ -130,10 +156,61 @@ pub struct DefaultGatherer {
     pub ok: usize,
     /// number of failures received from workers
     pub errors: usize,
+    /// workers that did not answer a request in time, once per request
+    pub timeouts: Vec<WorkerId>,
     /// worker responses are accumulated here
     pub responses: Vec<(WorkerId, WorkerResponse)>,
     /// number of expected responses, excluding processing responses
     pub expected_responses: usize,
+    /// tell the client every time this many responses were received, for long tasks
+    pub progress_interval: Option<usize>,
+}
+
+impl DefaultGatherer {
+    /// counts of the worker responses, with the message of each failure
+    pub fn outcomes(&self, count: Option<usize>, timed_out: bool) -> ResponseContent {
+        let failures = self
+            .responses
+            .iter()
+            .filter(|(_, response)| response.status == ResponseStatus::Failure as i32)
+            .map(|(worker_id, response)| WorkerFailure {
+                worker_id: *worker_id,
+                message: response.message.clone(),
+            })
+            .collect();
+
+        let mut workers = BTreeMap::new();
+        for (worker_id, response) in &self.responses {
+            let answers = workers.entry(*worker_id).or_insert_with(|| WorkerAnswers {
+                worker_id: *worker_id,
+                ..Default::default()
+            });
+            match ResponseStatus::try_from(response.status) {
+                Ok(ResponseStatus::Ok) => answers.ok += 1,
+                Ok(ResponseStatus::Failure) => answers.errors += 1,
+                _ => {}
+            }
+        }
+        for worker_id in &self.timeouts {
+            workers
+                .entry(*worker_id)
+                .or_insert_with(|| WorkerAnswers {
+                    worker_id: *worker_id,
+                    ..Default::default()
+                })
+                .timeouts += 1;
+        }
+
+        ContentType::WorkerOutcomes(WorkerOutcomes {
+            ok: self.ok as u64,
+            errors: self.errors as u64,
+            failures,
+            count: count.map(|count| count as u64),
+            timed_out: timed_out || !self.timeouts.is_empty(),
+            workers: workers.into_values().collect(),
+        })
+        .into()
+    }
 }
 
 #[allow(unused)]
@@ -143,7 +220,7 @@ impl Gatherer for DefaultGatherer {
     }
 
     fn has_finished(&self) -> bool {
-        self.ok + self.errors >= self.expected_responses
+        self.ok + self.errors + self.timeouts.len() >= self.expected_responses
     }
 
     fn on_message(
@@ -163,6 +240,23 @@ impl Gatherer for DefaultGatherer {
             Err(e) => warn!("error decoding response status: {}", e),
         }
         self.responses.push((worker_id, message));
+
+        let received = self.ok + self.errors;
+        if let Some(interval) = self.progress_interval {
+            if interval > 0 && received > 0 && received % interval == 0 {
+                client.return_processing(format!(
+                    "{received} of {} worker responses received",
+                    self.expected_responses
+                ));
+            }
+        }
+    }
+
+    fn on_timeout(&mut self, client: &mut OptionalClient, worker_id: WorkerId, request_id: &str) {
+        client.return_processing(format!(
+            "Worker {worker_id} did not answer {request_id} in time"
+        ));
+        self.timeouts.push(worker_id);
     }
 }
 
@@ -174,6 +268,8 @@ pub enum HubError {
     GetExecutablePath(UtilError),
     #[error("could not create SCM socket for worker {0}: {1}")]
     CreateScmSocket(u32, ScmSocketError),
+    #[error("could not bind the health check listener: {0}")]
+    BindHealthListener(IoError),
 }
 
 /// A platform to receive client connections, pass orders to workers,
@@ -207,8 +303,11 @@ impl CommandHub {
         config: Config,
         executable_path: String,
     ) -> Result<Self, HubError> {
+        let health_listener = health_listener(config.health_check_address, None)
+            .map_err(HubError::BindHealthListener)?;
+
         Ok(Self {
-            server: Server::new(unix_listener, config, executable_path)
+            server: Server::new(unix_listener, config, executable_path, health_listener)
                 .map_err(HubError::CreateServer)?,
             clients: HashMap::new(),
             tasks: HashMap::new(),
@@ -238,6 +337,7 @@ impl CommandHub {
     pub fn from_upgrade_data(upgrade_data: UpgradeData) -> Result<Self, HubError> {
         let UpgradeData {
             command_socket_fd,
+            health_listener_fd,
             config,
             workers,
             state,
@@ -245,6 +345,7 @@ impl CommandHub {
             next_session_id,
             next_task_id,
             next_worker_id,
+            scheduled_requests,
         } = upgrade_data;
 
         let executable_path =
@@ -255,15 +356,29 @@ impl CommandHub {
         let command_buffer_size = config.command_buffer_size;
         let max_command_buffer_size = config.max_command_buffer_size;
 
-        let mut server =
-            Server::new(unix_listener, config, executable_path).map_err(HubError::CreateServer)?;
+        let health_listener = health_listener(config.health_check_address, health_listener_fd)
+            .map_err(HubError::BindHealthListener)?;
+
+        let mut server = Server::new(unix_listener, config, executable_path, health_listener)
+            .map_err(HubError::CreateServer)?;
 
         server.state = state;
+        // the previous main process already loaded it
+        server.state_loaded = true;
         server.update_counts();
         server.next_client_id = next_client_id;
         server.next_session_id = next_session_id;
         server.next_task_id = next_task_id;
         server.next_worker_id = next_worker_id;
+        server.next_scheduled_id = scheduled_requests
+            .iter()
+            .filter_map(|scheduled| scheduled.id)
+            .max()
+            .map_or(0, |id| id + 1);
+        server.scheduled_requests = scheduled_requests
+            .into_iter()
+            .filter_map(|scheduled| scheduled.id.map(|id| (id, scheduled)))
+            .collect();
 
         for worker in workers
             .iter()
@@ -298,38 +413,70 @@ impl CommandHub {
         debug!("running the command hub: {:?}", self);
 
         loop {
-            let run_state = self.run_state;
             let now = Instant::now();
 
+            if self.run_state == ServerState::Standby {
+                self.server.check_main_standby(now);
+                if self.run_state == ServerState::Running {
+                    // the clients that connected after the new main process was killed
+                    while let Ok((stream, _addr)) = self.unix_listener.accept() {
+                        self.register_client(stream);
+                    }
+                }
+            }
+            let run_state = self.run_state;
+
+            self.server.expire_health_probes(now);
+
+            // while standing by, the new main process talks to the workers
+            if run_state != ServerState::Standby {
+                self.send_outgoing();
+                self.expire_requests(now);
+                self.server.apply_scheduled_requests();
+            }
+            self.broadcast_state_changes();
+
             let mut tasks = std::mem::take(&mut self.tasks);
             let mut queued_tasks = std::mem::take(&mut self.server.queued_tasks);
             self.tasks = tasks
                 .drain()
                 .chain(queued_tasks.drain())
                 .filter_map(|(task_id, mut task)| {
-                    if task.job.get_gatherer().has_finished() {
-                        self.handle_finishing_task(task_id, task, false);
+                    // some requests of the task still wait for room in the worker channels
+                    let sending = self.server.is_sending(task_id);
+                    if !sending && task.job.get_gatherer().has_finished() {
+                        self.handle_finishing_task(task_id, task);
                         return None;
                     }
-                    if let Some(timeout) = task.timeout {
-                        if timeout < now {
-                            self.handle_finishing_task(task_id, task, true);
-                            return None;
-                        }
-                    }
                     Some((task_id, task))
                 })
                 .collect();
-
-            let next_timeout = self.tasks.values().filter_map(|t| t.timeout).max();
-            let mut poll_timeout = next_timeout.map(|t| t.saturating_duration_since(now));
-
-            if self.run_state == ServerState::Stopping {
+            // their tasks may finish now, check them without waiting for an event
+            let answered_refused = self.answer_refused_requests();
+
+            let mut poll_timeout = self
+                .next_request_deadline()
+                .map(|deadline| deadline.saturating_duration_since(now))
+                .into_iter()
+                .chain(self.server.next_scheduled_request())
+                .chain(
+                    self.main_standby
+                        .as_ref()
+                        .map(|standby| standby.deadline().saturating_duration_since(now)),
+                )
+                .chain(
+                    self.server
+                        .next_health_probe_deadline()
+                        .map(|deadline| deadline.saturating_duration_since(now)),
+                )
+                .chain(answered_refused.then_some(Duration::ZERO))
+                .min();
+
+            if run_state == ServerState::Stopping || run_state == ServerState::Standby {
                 // when closing, close all ClientSession which are not transfering data
-                self.clients
-                    .retain(|_, s| s.channel.back_buf.available_data() > 0);
+                self.clients.retain(|_, s| s.channel.has_pending_writes());
                 // when all ClientSession are closed, the CommandServer stops
-                if self.clients.is_empty() {
+                if run_state == ServerState::Stopping && self.clients.is_empty() {
                     break;
                 }
             }
@@ -345,7 +492,10 @@ impl CommandHub {
                     }
                 })
                 .chain(self.workers.iter().filter_map(|(token, session)| {
-                    if session.run_state != RunState::Stopped && wants_to_tick(&session.channel) {
+                    if run_state != ServerState::Standby
+                        && session.run_state != RunState::Stopped
+                        && wants_to_tick(&session.channel)
+                    {
                         Some((*token, Ready::EMPTY, None))
                     } else {
                         None
@@ -355,6 +505,8 @@ impl CommandHub {
 
             let workers_to_spawn = self.workers_to_spawn();
 
+            self.server.update_channel_gauges();
+
             // if we have sessions to tick or workers to spawn, we don't want to block on poll
             if !sessions_to_tick.is_empty() || workers_to_spawn > 0 {
                 poll_timeout = Some(Duration::default());
@@ -379,7 +531,7 @@ impl CommandHub {
             for (token, ready, event) in events {
                 match token {
                     Token(0) => {
-                        if run_state == ServerState::Stopping {
+                        if run_state == ServerState::Stopping || run_state == ServerState::Standby {
                             // do not accept new clients when stopping
                             continue;
                         }
@@ -389,6 +541,15 @@ impl CommandHub {
                             }
                         }
                     }
+                    // the new main process answers the health checks
+                    HEALTH_LISTENER if run_state == ServerState::Standby => {}
+                    HEALTH_LISTENER => self.server.accept_health_checks(now),
+                    // the decision of the new main process is read at the start of the loop
+                    MAIN_STANDBY => {}
+                    CHECK_WAKER => self.finish_probes(),
+                    token if self.server.health_probes.contains_key(&token) => {
+                        self.server.health_probe_ready(token);
+                    }
                     token => {
                         trace!("{:?} got event: {:?}", token, event);
                         if let Some((server, client)) = self.get_client_mut(&token) {
@@ -403,6 +564,7 @@ impl CommandHub {
                                     info!("Closing client {}", client.id);
                                     debug!("closing client {:?}", client);
                                     self.event_subscribers.remove(&token);
+                                    self.state_subscribers.remove(&token);
                                     self.clients.remove(&token);
                                 }
                             }
@@ -411,6 +573,12 @@ impl CommandHub {
                                 // do not read responses from workers when stopping
                                 continue;
                             }
+                            if run_state == ServerState::Standby {
+                                // the new main process reads the responses, but keep track
+                                // of the closed channels in case of a rollback
+                                worker.update_readiness(ready);
+                                continue;
+                            }
                             worker.update_readiness(ready);
                             let worker_id = worker.id;
                             match worker.ready() {
@@ -420,33 +588,196 @@ impl CommandHub {
                                         self.handle_worker_response(worker_id, response);
                                     }
                                 }
-                                WorkerResult::CloseSession => self.handle_worker_close(&token),
+                                WorkerResult::CloseSession => {
+                                    self.handle_worker_close(&token);
+                                    if let Some(crash_report) =
+                                        self.server.collect_crash_report(&token)
+                                    {
+                                        self.broadcast_event(
+                                            worker_id,
+                                            Event {
+                                                kind: EventKind::WorkerCrashed as i32,
+                                                cluster_id: None,
+                                                backend_id: None,
+                                                address: None,
+                                                crash_report: Some(crash_report),
+                                                tags: BTreeMap::new(),
+                                            },
+                                        );
+                                    }
+                                }
                             }
                         }
+                        self.broadcast_state_changes();
                     }
                 }
             }
         }
     }
 
+    /// report the proxy checks whose requests are done, and clean them up
+    fn finish_probes(&mut self) {
+        for (client_token, run, outcomes) in self.server.probing_checks.finished() {
+            let client = &mut self.clients.get_mut(&client_token);
+            finish_probes(&mut self.server, client, client_token, run, outcomes);
+        }
+    }
+
+    /// send the held back requests to the workers, the tasks count the responses to expect
+    fn send_outgoing(&mut self) {
+        self.server.send_outgoing();
+        for (task_id, count) in std::mem::take(&mut self.server.expected_responses) {
+            match self.tasks.get_mut(&task_id) {
+                Some(task) => task.job.get_gatherer().inc_expected_responses(count),
+                None => error!("no task found with id {}", task_id),
+            }
+        }
+    }
+
+    /// answer the requests that could not be sent, once their task is in the hub
+    fn answer_refused_requests(&mut self) -> bool {
+        let refused = std::mem::take(&mut self.server.refused);
+        let answered = !refused.is_empty();
+        for (worker_id, response) in refused {
+            self.handle_worker_response(worker_id, response);
+        }
+        answered
+    }
+
+    /// the deadline of a request to a worker, if its task has a timeout
+    fn request_deadline(&self, in_flight: &InFlight) -> Option<Instant> {
+        let timeout = self.tasks.get(&in_flight.task_id)?.timeout?;
+        Some(in_flight.queued_at + timeout)
+    }
+
+    /// a replay times out when its worker reads none of its requests for the timeout of the task
+    fn replay_deadline(&self, task_id: TaskId, progress_at: Instant) -> Option<Instant> {
+        let timeout = self.tasks.get(&task_id)?.timeout?;
+        Some(progress_at + timeout)
+    }
+
+    fn next_request_deadline(&self) -> Option<Instant> {
+        let replays = self
+            .server
+            .replay_progress()
+            .filter_map(|(_, task_id, progress_at)| self.replay_deadline(task_id, progress_at));
+        self.server
+            .in_flight
+            .values()
+            .filter_map(|in_flight| self.request_deadline(in_flight))
+            .chain(replays)
+            .min()
+    }
+
+    /// stop waiting for the requests that workers did not answer in time,
+    /// so that a stuck worker does not hold the whole task
+    fn expire_requests(&mut self, now: Instant) {
+        let expired: Vec<RequestId> = self
+            .server
+            .in_flight
+            .iter()
+            .filter(|(_, in_flight)| {
+                self.request_deadline(in_flight)
+                    .is_some_and(|deadline| deadline <= now)
+            })
+            .map(|(request_id, _)| request_id.to_owned())
+            .collect();
+
+        for request_id in expired {
+            let Some(InFlight {
+                task_id, worker_id, ..
+            }) = self.server.in_flight.remove(&request_id)
+            else {
+                continue;
+            };
+            // the worker may not even have read it
+            self.server.drop_outgoing(worker_id, &request_id);
+            self.time_out(task_id, worker_id, &request_id);
+        }
+
+        let stalled_replays: Vec<(WorkerId, TaskId)> = self
+            .server
+            .replay_progress()
+            .filter(|(_, task_id, progress_at)| {
+                self.replay_deadline(*task_id, *progress_at)
+                    .is_some_and(|deadline| deadline <= now)
+            })
+            .map(|(worker_id, task_id, _)| (worker_id, task_id))
+            .collect();
+
+        for (worker_id, task_id) in stalled_replays {
+            // the rest of the replay was not expected yet
+            self.server.drop_replay(worker_id, task_id);
+            self.time_out(task_id, worker_id, "the rest of the replay");
+        }
+    }
+
+    fn time_out(&mut self, task_id: TaskId, worker_id: WorkerId, request_id: &str) {
+        let Some(task) = self.tasks.get_mut(&task_id) else {
+            return;
+        };
+        warn!(
+            "worker {} did not answer request {} in time",
+            worker_id, request_id
+        );
+        incr!("command.worker_timeouts");
+        task.timed_out = true;
+
+        let client = &mut task
+            .job
+            .client_token()
+            .and_then(|token| self.clients.get_mut(&token));
+        task.job
+            .get_gatherer()
+            .on_timeout(client, worker_id, request_id);
+    }
+
+    /// transmit the changes of the state to the clients watching it
+    fn broadcast_state_changes(&mut self) {
+        for change in std::mem::take(&mut self.server.state_changes) {
+            for client_token in &self.server.state_subscribers {
+                if let Some(client) = self.clients.get_mut(client_token) {
+                    client.return_processing_with_content(
+                        "state change",
+                        ContentType::StateChange(change.clone()).into(),
+                    );
+                }
+            }
+        }
+    }
+
+    fn broadcast_event(&mut self, worker_id: WorkerId, event: Event) {
+        for client_token in &self.server.event_subscribers {
+            if let Some(client) = self.clients.get_mut(client_token) {
+                client.return_processing_with_content(
+                    format!("{worker_id}"),
+                    ContentType::Event(event.clone()).into(),
+                );
+            }
+        }
+    }
+
     fn handle_worker_response(&mut self, worker_id: WorkerId, response: WorkerResponse) {
         // transmit backend events to subscribing clients
         if let Some(ResponseContent {
             content_type: Some(ContentType::Event(event)),
         }) = response.content
         {
-            for client_token in &self.server.event_subscribers {
-                if let Some(client) = self.clients.get_mut(client_token) {
-                    client.return_processing_with_content(
-                        format!("{worker_id}"),
-                        ContentType::Event(event.clone()).into(),
-                    );
-                }
-            }
+            self.broadcast_event(worker_id, event);
             return;
         }
 
-        let Some(task_id) = self.in_flight.get(&response.id).copied() else {
+        // a worker answers Processing before the final response of a long request
+        let task_id = if response.status == ResponseStatus::Processing as i32 {
+            self.in_flight
+                .get(&response.id)
+                .map(|in_flight| in_flight.task_id)
+        } else {
+            self.in_flight
+                .remove(&response.id)
+                .map(|in_flight| in_flight.task_id)
+        };
+        let Some(task_id) = task_id else {
             // this will appear on startup, when requesting status. It is inconsequential.
             warn!("Got a response for an unknown task: {}", response);
             return;
@@ -469,7 +800,8 @@ impl CommandHub {
             .on_message(&mut self.server, client, worker_id, response);
     }
 
-    fn handle_finishing_task(&mut self, task_id: TaskId, task: TaskContainer, timed_out: bool) {
+    fn handle_finishing_task(&mut self, task_id: TaskId, task: TaskContainer) {
+        let timed_out = task.timed_out;
         if timed_out {
             debug!("Task timeout: {:?}", task);
         } else {
@@ -479,9 +811,9 @@ impl CommandHub {
             .job
             .client_token()
             .and_then(|token| self.clients.get_mut(&token));
-        task.job.on_finish(&mut self.server, client, false);
+        task.job.on_finish(&mut self.server, client, timed_out);
         self.in_flight
-            .retain(|_, in_flight_task_id| *in_flight_task_id != task_id);
+            .retain(|_, in_flight| in_flight.task_id != task_id);
     }
 }
 
@@ -499,15 +831,99 @@ pub enum ServerError {
     EnableCloexec(UtilError),
     #[error("could not disable cloexec: {0}")]
     DisableCloexec(UtilError),
+    #[error("{0}")]
+    DispatchOnState(StateError),
+    #[error(
+        "the key {0} is a URI, but the sozu binary has no key provider to load it. \
+        Use a PEM key, build sozu with the pkcs11 feature for pkcs11: URIs, \
+        or embed sozu-lib and register a provider with tls::register_key_provider"
+    )]
+    NoKeyProvider(String),
+}
+
+/// The key of a certificate given as a URI, like `pkcs11:token=sozu;object=example-org`.
+/// The workers of this binary only load `pkcs11:` URIs, when built with the pkcs11 feature.
+/// Other schemes need a key provider registered by a program embedding sozu-lib
+pub fn key_uri_without_provider(request: &Request) -> Option<&str> {
+    let certificate = match &request.request_type {
+        Some(RequestType::AddCertificate(add)) => &add.certificate,
+        Some(RequestType::ReplaceCertificate(replace)) => &replace.new_certificate,
+        _ => return None,
+    };
+    let scheme = key_uri_scheme(&certificate.key)?;
+    if cfg!(feature = "pkcs11") && scheme.eq_ignore_ascii_case("pkcs11") {
+        return None;
+    }
+    Some(key_uri_for_logs(&certificate.key))
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ServerState {
     Running,
     WorkersStopping,
+    /// a new main process took over, wait for it to retire or roll back
+    Standby,
     Stopping,
 }
 
+/// A request for a worker, waiting for its response
+#[derive(Debug)]
+struct InFlight {
+    task_id: TaskId,
+    worker_id: WorkerId,
+    /// when the request was queued for the worker, the timeout of its task starts there
+    queued_at: Instant,
+}
+
+/// Waits in the queue of a worker for room in its channel
+#[derive(Debug)]
+enum Outgoing {
+    Request {
+        request: WorkerRequest,
+        task_id: TaskId,
+    },
+    /// the requests of a replay not yet sent to this worker
+    Replay {
+        replay: Rc<Replay>,
+        next: usize,
+        /// when the worker last read a request of the replay
+        progress_at: Instant,
+    },
+}
+
+impl Outgoing {
+    fn task_id(&self) -> TaskId {
+        match self {
+            Outgoing::Request { task_id, .. } => *task_id,
+            Outgoing::Replay { replay, .. } => replay.task_id,
+        }
+    }
+}
+
+fn worker_request_id(
+    request: &Request,
+    worker_id: WorkerId,
+    task_id: TaskId,
+    request_id: usize,
+) -> RequestId {
+    format!(
+        "{}-{}-{}-{}",
+        request.short_name(),
+        worker_id,
+        task_id,
+        request_id
+    )
+}
+
+/// Many requests for all the workers, like a saved state or a configuration.
+/// Each worker gets them one by one as its channel drains, so that a large
+/// replay neither fills the memory of the main process nor delays other tasks
+#[derive(Debug)]
+struct Replay {
+    task_id: TaskId,
+    requests: Vec<Request>,
+}
+
 /// Manages workers
 /// Functions as an executer for tasks that have two steps:
 /// - scatter to workers
@@ -517,10 +933,20 @@ pub struct Server {
     pub config: Config,
     /// Sōzu clients that subscribed to events
     pub event_subscribers: HashSet<Token>,
+    /// Sōzu clients that watch the changes of the state
+    pub state_subscribers: HashSet<Token>,
+    /// changes of the state not yet sent to the state subscribers
+    state_changes: Vec<StateChange>,
     /// path to the executable binary of Sōzu (for upgrading)
     pub executable_path: String,
     /// keep track of the tasks
-    in_flight: HashMap<RequestId, TaskId>,
+    in_flight: HashMap<RequestId, InFlight>,
+    /// requests held back while the channel of their worker is saturated
+    outgoing: HashMap<WorkerId, VecDeque<Outgoing>>,
+    /// requests that could not be sent, with the error to answer in place of the worker
+    refused: Vec<(WorkerId, WorkerResponse)>,
+    /// responses to expect for tasks already in the hub, once their held back requests are sent
+    expected_responses: Vec<(TaskId, usize)>,
     next_client_id: ClientId,
     next_session_id: SessionId,
     next_task_id: TaskId,
@@ -531,14 +957,32 @@ pub struct Server {
     queued_tasks: HashMap<TaskId, TaskContainer>,
     /// contains all business logic of Sōzu (frontends, backends, routing, etc.)
     pub state: ConfigState,
+    /// set once the static configuration was loaded without errors
+    pub state_loaded: bool,
+    /// answers `/healthz` and `/readyz`, if configured
+    health_listener: Option<TcpListener>,
+    /// health checks being read or answered
+    health_probes: HashMap<Token, HealthProbe>,
+    /// proxy checks sending their requests through the listeners
+    pub probing_checks: ProbingChecks,
     /// used to shut down gracefully
     pub run_state: ServerState,
+    /// set in the previous main process while a new one finishes the upgrade
+    main_standby: Option<MainStandby>,
+    /// set in a new main process until it tells the previous one to retire or roll back
+    pub old_main: Option<Channel<bool, ()>>,
+    /// set in a new main process once the previous one retired, when a first worker
+    /// was upgraded: there is no rollback afterwards
+    pub old_main_retired: bool,
     /// the UNIX socket on which to receive clients
     unix_listener: UnixListener,
     /// the Sōzu processes running parallel to the main process.
     /// The workers perform the whole business of proxying and must be
     /// synchronized at all times.
     pub workers: HashMap<Token, WorkerSession>,
+    /// requests to apply at a later date, by id
+    pub scheduled_requests: BTreeMap<u64, ScheduledRequest>,
+    pub next_scheduled_id: u64,
 }
 
 impl Server {
@@ -546,6 +990,7 @@ impl Server {
         mut unix_listener: UnixListener,
         config: Config,
         executable_path: String,
+        mut health_listener: Option<TcpListener>,
     ) -> Result<Self, ServerError> {
         let poll = mio::Poll::new().map_err(ServerError::CreatePoll)?;
         poll.registry()
@@ -556,11 +1001,24 @@ impl Server {
             )
             .map_err(ServerError::RegisterChannel)?;
 
+        if let Some(listener) = &mut health_listener {
+            poll.registry()
+                .register(listener, HEALTH_LISTENER, Interest::READABLE)
+                .map_err(ServerError::RegisterChannel)?;
+        }
+        let probing_checks =
+            ProbingChecks::new(poll.registry()).map_err(ServerError::RegisterChannel)?;
+
         Ok(Self {
             config,
             event_subscribers: HashSet::new(),
+            state_subscribers: HashSet::new(),
+            state_changes: Vec::new(),
             executable_path,
             in_flight: HashMap::new(),
+            outgoing: HashMap::new(),
+            refused: Vec::new(),
+            expected_responses: Vec::new(),
             next_client_id: 0,
             next_session_id: 1, // 0 is reserved for the UnixListener
             next_task_id: 0,
@@ -568,12 +1026,37 @@ impl Server {
             poll,
             queued_tasks: HashMap::new(),
             state: ConfigState::new(),
+            state_loaded: false,
+            health_listener,
+            health_probes: HashMap::new(),
+            probing_checks,
             run_state: ServerState::Running,
+            main_standby: None,
+            old_main: None,
+            old_main_retired: false,
             unix_listener,
             workers: HashMap::new(),
+            scheduled_requests: BTreeMap::new(),
+            next_scheduled_id: 0,
         })
     }
 
+    /// Applies a request on the state, and keeps it for the clients watching the state
+    pub fn dispatch_on_state(&mut self, request: &Request) -> Result<(), ServerError> {
+        if let Some(uri) = key_uri_without_provider(request) {
+            return Err(ServerError::NoKeyProvider(uri.to_owned()));
+        }
+        self.state
+            .dispatch(request)
+            .map_err(ServerError::DispatchOnState)?;
+        if !self.state_subscribers.is_empty() && request.is_a_state_change() {
+            self.state_changes.push(StateChange {
+                request: request.clone(),
+            });
+        }
+        Ok(())
+    }
+
     /// - fork the main process into a new worker
     /// - register the worker in mio
     /// - send a Status request to the new worker
@@ -645,6 +1128,32 @@ impl Server {
             .map_err(ServerError::RegisterChannel)
     }
 
+    /// stop serving while the new main process finishes the upgrade
+    pub fn stand_by(&mut self, mut standby: MainStandby) -> Result<(), ServerError> {
+        self.register(MAIN_STANDBY, &mut standby.channel.sock)?;
+        self.main_standby = Some(standby);
+        self.run_state = ServerState::Standby;
+        Ok(())
+    }
+
+    /// retire or roll back, once the new main process decided
+    fn check_main_standby(&mut self, now: Instant) {
+        let verdict = match &mut self.main_standby {
+            Some(standby) => match standby.verdict(now) {
+                Some(verdict) => verdict,
+                None => return,
+            },
+            None => return,
+        };
+
+        if let Some(mut standby) = self.main_standby.take() {
+            if let Err(err) = self.poll.registry().deregister(&mut standby.channel.sock) {
+                error!("could not deregister the channel to the new main: {}", err);
+            }
+            end_main_standby(self, standby, verdict);
+        }
+    }
+
     /// returns None if the worker is not alive
     pub fn get_active_worker_by_id(&self, id: WorkerId) -> Option<&WorkerSession> {
         self.workers
@@ -678,10 +1187,15 @@ impl Server {
             Timeout::None => None,
             Timeout::Default => Some(Duration::from_secs(self.config.worker_timeout as u64)),
             Timeout::Custom(duration) => Some(duration),
-        }
-        .map(|duration| Instant::now() + duration);
-        self.queued_tasks
-            .insert(task_id, TaskContainer { job, timeout });
+        };
+        self.queued_tasks.insert(
+            task_id,
+            TaskContainer {
+                job,
+                timeout,
+                timed_out: false,
+            },
+        );
         task_id
     }
 
@@ -704,38 +1218,242 @@ impl Server {
         request_id: usize,
         target: Option<WorkerId>,
     ) {
-        let task = match self.queued_tasks.get_mut(&task_id) {
-            Some(task) => task,
-            None => {
-                error!("no task found with id {}", task_id);
-                return;
+        if !self.queued_tasks.contains_key(&task_id) {
+            error!("no task found with id {}", task_id);
+            return;
+        }
+
+        let worker_ids = self.target_workers(target);
+        for worker_id in &worker_ids {
+            let request = WorkerRequest {
+                id: worker_request_id(&request, *worker_id, task_id, request_id),
+                content: request.clone(),
+            };
+            self.in_flight.insert(
+                request.id.clone(),
+                InFlight {
+                    task_id,
+                    worker_id: *worker_id,
+                    queued_at: Instant::now(),
+                },
+            );
+            let queue = self.outgoing.entry(*worker_id).or_default();
+            if queue.len() >= MAX_OUTGOING_REQUESTS {
+                self.refuse(
+                    *worker_id,
+                    request.id,
+                    "too many requests are waiting for this worker",
+                );
+                continue;
             }
-        };
+            queue.push_back(Outgoing::Request { request, task_id });
+        }
+        self.expect_responses(task_id, worker_ids.len());
 
-        let mut worker_count = 0;
-        let mut worker_request = WorkerRequest {
-            id: String::new(),
-            content: request,
-        };
+        self.send_outgoing();
+    }
 
-        for worker in self.workers.values_mut().filter(|w| {
-            target
-                .map(|id| id == w.id && w.run_state != RunState::Stopped)
-                .unwrap_or(w.run_state != RunState::Stopped)
-        }) {
-            worker_count += 1;
-            worker_request.id = format!(
-                "{}-{}-{}-{}",
-                worker_request.content.short_name(),
-                worker.id,
-                task_id,
-                request_id,
-            );
-            debug!("scattering to worker {}: {:?}", worker.id, worker_request);
-            worker.send(&worker_request);
-            self.in_flight.insert(worker_request.id, task_id);
+    /// sends these requests to all the workers, as fast as each of them reads them
+    pub fn replay(&mut self, requests: Vec<Request>, task_id: TaskId) {
+        if !self.queued_tasks.contains_key(&task_id) {
+            error!("no task found with id {}", task_id);
+            return;
+        }
+        if requests.is_empty() {
+            return;
+        }
+
+        let replay = Rc::new(Replay { task_id, requests });
+        for worker_id in self.target_workers(None) {
+            self.outgoing
+                .entry(worker_id)
+                .or_default()
+                .push_back(Outgoing::Replay {
+                    replay: replay.clone(),
+                    next: 0,
+                    progress_at: Instant::now(),
+                });
+        }
+
+        self.send_outgoing();
+    }
+
+    /// the workers that receive a request, all those not stopped if there is no target
+    fn target_workers(&self, target: Option<WorkerId>) -> Vec<WorkerId> {
+        self.workers
+            .values()
+            .filter(|worker| {
+                worker.run_state != RunState::Stopped && target.map_or(true, |id| id == worker.id)
+            })
+            .map(|worker| worker.id)
+            .collect()
+    }
+
+    /// the task may already be in the hub, if the request was held back
+    fn expect_responses(&mut self, task_id: TaskId, count: usize) {
+        if count == 0 {
+            return;
+        }
+        match self.queued_tasks.get_mut(&task_id) {
+            Some(task) => task.job.get_gatherer().inc_expected_responses(count),
+            None => self.expected_responses.push((task_id, count)),
+        }
+    }
+
+    /// answers an error in place of the worker, for a request it will never receive
+    fn refuse(&mut self, worker_id: WorkerId, request_id: RequestId, reason: &str) {
+        warn!(
+            "could not send request {} to worker {}: {}",
+            request_id, worker_id, reason
+        );
+        incr!("command.refused_requests");
+        self.refused
+            .push((worker_id, WorkerResponse::error(request_id, reason)));
+    }
+
+    /// Sends the held back requests of each worker, in order, until its channel
+    /// is saturated. A saturated worker does not hold back the others. The event
+    /// loop calls it again once the channels drain.
+    pub fn send_outgoing(&mut self) {
+        let mut unreachable = Vec::new();
+
+        for (worker_id, queue) in self.outgoing.iter_mut() {
+            let Some(worker) = self
+                .workers
+                .values_mut()
+                .find(|worker| worker.id == *worker_id && worker.run_state != RunState::Stopped)
+            else {
+                unreachable.extend(queue.drain(..).map(|outgoing| (*worker_id, outgoing)));
+                continue;
+            };
+
+            let mut replayed = Vec::new();
+            while !worker.channel.is_saturated() {
+                let Some(outgoing) = queue.front_mut() else {
+                    break;
+                };
+                let request = match outgoing {
+                    Outgoing::Request { .. } => match queue.pop_front() {
+                        Some(Outgoing::Request { request, .. }) => request,
+                        _ => break,
+                    },
+                    Outgoing::Replay {
+                        replay,
+                        next,
+                        progress_at,
+                    } => {
+                        let request_id = *next;
+                        let content = replay.requests[request_id].clone();
+                        let task_id = replay.task_id;
+                        *next += 1;
+                        *progress_at = Instant::now();
+                        if *next == replay.requests.len() {
+                            queue.pop_front();
+                        }
+                        replayed.push(task_id);
+                        let request = WorkerRequest {
+                            id: worker_request_id(&content, *worker_id, task_id, request_id),
+                            content,
+                        };
+                        // a request of a replay is queued when it leaves the replay
+                        self.in_flight.insert(
+                            request.id.clone(),
+                            InFlight {
+                                task_id,
+                                worker_id: worker.id,
+                                queued_at: Instant::now(),
+                            },
+                        );
+                        request
+                    }
+                };
+
+                debug!("scattering to worker {}: {:?}", worker.id, request);
+                worker.send(&request);
+            }
+
+            // the requests of a replay are expected as they are sent
+            for task_id in replayed {
+                match self.queued_tasks.get_mut(&task_id) {
+                    Some(task) => task.job.get_gatherer().inc_expected_responses(1),
+                    None => self.expected_responses.push((task_id, 1)),
+                }
+            }
+        }
+        self.outgoing.retain(|_, queue| !queue.is_empty());
+
+        for (worker_id, outgoing) in unreachable {
+            // the rest of a replay was not expected yet
+            if let Outgoing::Request { request, .. } = outgoing {
+                self.refuse(
+                    worker_id,
+                    request.id,
+                    "the worker stopped before receiving the request",
+                );
+            }
+        }
+    }
+
+    /// true while some requests of the task are held back
+    fn is_sending(&self, task_id: TaskId) -> bool {
+        self.outgoing
+            .values()
+            .flatten()
+            .any(|outgoing| outgoing.task_id() == task_id)
+    }
+
+    /// forget a request that timed out before its worker could read it
+    fn drop_outgoing(&mut self, worker_id: WorkerId, request_id: &str) {
+        if let Some(queue) = self.outgoing.get_mut(&worker_id) {
+            queue.retain(|outgoing| {
+                !matches!(outgoing, Outgoing::Request { request, .. } if request.id == request_id)
+            });
+        }
+    }
+
+    /// the replays of each worker, with the last time the worker read one of their requests
+    fn replay_progress(&self) -> impl Iterator<Item = (WorkerId, TaskId, Instant)> + '_ {
+        self.outgoing.iter().flat_map(|(worker_id, queue)| {
+            queue.iter().filter_map(move |outgoing| match outgoing {
+                Outgoing::Replay {
+                    replay,
+                    progress_at,
+                    ..
+                } => Some((*worker_id, replay.task_id, *progress_at)),
+                Outgoing::Request { .. } => None,
+            })
+        })
+    }
+
+    /// stop sending a replay to a worker that does not read it
+    fn drop_replay(&mut self, worker_id: WorkerId, task_id: TaskId) {
+        if let Some(queue) = self.outgoing.get_mut(&worker_id) {
+            queue.retain(|outgoing| {
+                !matches!(outgoing, Outgoing::Replay { replay, .. } if replay.task_id == task_id)
+            });
         }
-        task.job.get_gatherer().inc_expected_responses(worker_count);
+    }
+
+    /// how much the main process waits on the workers to read its requests
+    fn update_channel_gauges(&self) {
+        gauge!(
+            "command.outgoing_requests",
+            self.outgoing.values().map(VecDeque::len).sum::<usize>()
+        );
+        gauge!(
+            "command.worker_channels.queued_messages",
+            self.workers
+                .values()
+                .map(|worker| worker.channel.queue_len())
+                .sum::<usize>()
+        );
+        gauge!(
+            "command.worker_channels.pending_bytes",
+            self.workers
+                .values()
+                .map(|worker| worker.channel.pending_bytes())
+                .sum::<usize>()
+        );
     }
 
     pub fn cancel_task(&mut self, task_id: TaskId) {
@@ -760,6 +1478,23 @@ impl Server {
         self.close_worker(token);
     }
 
+    /// reads the report a closed worker wrote if it crashed, and keeps it for the status
+    pub fn collect_crash_report(&mut self, token: &Token) -> Option<CrashReport> {
+        let crash_reports_dir = self.config.crash_reports_dir.as_deref()?;
+        let worker = self.workers.get_mut(token)?;
+        let crash_report = read_crash_report(crash_reports_dir, worker.id, worker.pid)?;
+
+        error!(
+            "worker {} crashed: {}, report in {}",
+            worker.id,
+            crash_report.reason,
+            crash_report.path()
+        );
+        incr!("command.worker_crashes");
+        worker.crash_report = Some(crash_report.clone());
+        Some(crash_report)
+    }
+
     /// returns how many workers should be started to reach config count
     pub fn workers_to_spawn(&self) -> u16 {
         if self.config.worker_automatic_restart && self.run_state == ServerState::Running {
@@ -816,6 +1551,10 @@ impl Server {
             self.unix_listener.as_raw_fd()
         );
 
+        if let Some(listener) = &self.health_listener {
+            disable_close_on_exec(listener.as_raw_fd()).map_err(ServerError::DisableCloexec)?;
+        }
+
         disable_close_on_exec(self.unix_listener.as_raw_fd()).map_err(ServerError::DisableCloexec)
     }
 
@@ -831,13 +1570,105 @@ impl Server {
                 });
             }
         }
+        if let Some(listener) = &self.health_listener {
+            enable_close_on_exec(listener.as_raw_fd()).map_err(ServerError::EnableCloexec)?;
+        }
         enable_close_on_exec(self.unix_listener.as_raw_fd()).map_err(ServerError::EnableCloexec)
     }
 
+    /// accept the pending health checks, they are answered on their own events
+    fn accept_health_checks(&mut self, now: Instant) {
+        let accepted = match &self.health_listener {
+            Some(listener) => accept_health_checks(listener),
+            None => return,
+        };
+
+        for (mut stream, address) in accepted {
+            if self.health_probes.len() >= MAX_HEALTH_PROBES {
+                debug!("too many health checks, closing the one from {}", address);
+                continue;
+            }
+            let token = self.next_session_token();
+            if let Err(e) = self.poll.registry().register(
+                &mut stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            ) {
+                error!(
+                    "could not register the health check from {}: {}",
+                    address, e
+                );
+                continue;
+            }
+            self.health_probes
+                .insert(token, HealthProbe::new(stream, address, now));
+        }
+    }
+
+    /// read or answer a health check, close it once answered
+    fn health_probe_ready(&mut self, token: Token) {
+        let status = self.health_status();
+        let finished = match self.health_probes.get_mut(&token) {
+            Some(probe) => probe.ready(&status),
+            None => return,
+        };
+        if finished {
+            self.close_health_probe(token);
+        }
+    }
+
+    fn close_health_probe(&mut self, token: Token) {
+        if let Some(mut probe) = self.health_probes.remove(&token) {
+            if let Err(e) = self.poll.registry().deregister(&mut probe.stream) {
+                debug!("could not deregister a health check: {}", e);
+            }
+        }
+    }
+
+    fn next_health_probe_deadline(&self) -> Option<Instant> {
+        self.health_probes
+            .values()
+            .map(|probe| probe.deadline())
+            .min()
+    }
+
+    /// close the health checks that take too long
+    fn expire_health_probes(&mut self, now: Instant) {
+        let expired = self
+            .health_probes
+            .iter()
+            .filter(|(_, probe)| probe.deadline() <= now)
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+        for token in expired {
+            debug!("closing a health check that did not finish in time");
+            self.close_health_probe(token);
+        }
+    }
+
+    pub fn health_status(&self) -> HealthStatus {
+        let state = &self.state;
+        let inactive_listeners = state.http_listeners.values().filter(|l| !l.active).count()
+            + state.https_listeners.values().filter(|l| !l.active).count()
+            + state.tcp_listeners.values().filter(|l| !l.active).count();
+
+        HealthStatus {
+            running_workers: self
+                .workers
+                .values()
+                .filter(|worker| worker.run_state == RunState::Running)
+                .count(),
+            expected_workers: self.config.worker_count as usize,
+            state_loaded: self.state_loaded,
+            inactive_listeners,
+        }
+    }
+
     /// summarize the server into what is needed to recreate it, when upgrading
     pub fn generate_upgrade_data(&self) -> UpgradeData {
         UpgradeData {
             command_socket_fd: self.unix_listener.as_raw_fd(),
+            health_listener_fd: self.health_listener.as_ref().map(|l| l.as_raw_fd()),
             config: self.config.clone(),
             workers: self
                 .workers
@@ -855,6 +1686,7 @@ impl Server {
             next_session_id: self.next_session_id,
             next_task_id: self.next_task_id,
             next_worker_id: self.next_worker_id,
+            scheduled_requests: self.scheduled_requests.values().cloned().collect(),
         }
     }
 }
@@ -864,6 +1696,8 @@ impl Debug for Server {
         f.debug_struct("Server")
             .field("config", &self.config)
             .field("event_subscribers", &self.event_subscribers)
+            .field("state_subscribers", &self.state_subscribers)
+            .field("state_changes", &self.state_changes)
             .field("executable_path", &self.executable_path)
             .field("in_flight", &self.in_flight)
             .field("next_client_id", &self.next_client_id)
@@ -873,8 +1707,11 @@ impl Debug for Server {
             .field("poll", &self.poll)
             .field("queued_tasks", &self.queued_tasks)
             .field("run_state", &self.run_state)
+            .field("state_loaded", &self.state_loaded)
+            .field("health_listener", &self.health_listener)
             .field("unix_listener", &self.unix_listener)
             .field("workers", &self.workers)
+            .field("scheduled_requests", &self.scheduled_requests)
             .finish()
     }
 }
@@ -7,8 +7,8 @@ use prost::Message;
 use sozu_command_lib::{
     channel::Channel,
     proto::command::{
-        Request, Response, ResponseContent, ResponseStatus, RunState, WorkerInfo, WorkerRequest,
-        WorkerResponse,
+        CrashReport, Request, Response, ResponseContent, ResponseStatus, RunState, WorkerInfo,
+        WorkerRequest, WorkerResponse,
     },
     ready::Ready,
     scm_socket::ScmSocket,
@@ -104,6 +104,20 @@ impl MessageClient for ClientSession {
         })
     }
 
+    fn finish_failure_with_content<T: Into<String>>(
+        &mut self,
+        content: ResponseContent,
+        message: T,
+    ) {
+        let message = message.into();
+        error!("{}", message);
+        self.send(Response {
+            status: ResponseStatus::Failure.into(),
+            message,
+            content: Some(content),
+        })
+    }
+
     fn return_processing<S: Into<String>>(&mut self, message: S) {
         let message = message.into();
         info!("{}", message);
@@ -153,6 +167,17 @@ impl MessageClient for OptionalClient<'_> {
         }
     }
 
+    fn finish_failure_with_content<T: Into<String>>(
+        &mut self,
+        content: ResponseContent,
+        message: T,
+    ) {
+        match self {
+            None => error!("{}", message.into()),
+            Some(client) => client.finish_failure_with_content(content, message),
+        }
+    }
+
     fn return_processing<T: Into<String>>(&mut self, message: T) {
         match self {
             None => info!("{}", message.into()),
@@ -182,6 +207,8 @@ pub struct WorkerSession {
     /// meant to send listeners to the worker upon start
     pub scm_socket: ScmSocket,
     pub token: Token,
+    /// written by the worker if it panicked or failed
+    pub crash_report: Option<CrashReport>,
 }
 
 /// The return type of the ready method
@@ -208,6 +235,7 @@ impl WorkerSession {
             run_state: RunState::Running,
             scm_socket,
             token,
+            crash_report: None,
         }
     }
 
@@ -254,6 +282,7 @@ impl WorkerSession {
             id: self.id,
             pid: self.pid,
             run_state: run_state as i32,
+            crash_report: self.crash_report.clone(),
         }
     }
 
@@ -288,6 +317,6 @@ where
 /// used by the event loop to know wether to call ready on a session,
 /// given the state of its channel
 pub fn wants_to_tick<Tx, Rx>(channel: &Channel<Tx, Rx>) -> bool {
-    (channel.readiness.is_writable() && channel.back_buf.available_data() > 0)
+    (channel.readiness.is_writable() && channel.has_pending_writes())
         || (channel.readiness.is_hup() || channel.readiness.is_error())
 }
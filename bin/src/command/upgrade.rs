@@ -1,15 +1,21 @@
-use std::os::fd::AsRawFd;
+use std::{
+    os::fd::AsRawFd,
+    time::{Duration, Instant},
+};
 
 use libc::pid_t;
 use mio::Token;
 use serde::{Deserialize, Serialize};
 
 use sozu_command_lib::{
+    channel::{Channel, ChannelError},
     config::Config,
     proto::command::{
-        request::RequestType, ResponseStatus, ReturnListenSockets, RunState, SoftStop,
-        WorkerResponse,
+        request::RequestType, AdoptSessions, ResponseStatus, ReturnListenSockets, ReturnSessions,
+        RunState, ScheduledRequest, SoftStop, WorkerResponse,
     },
+    ready::Ready,
+    scm_socket::SessionSockets,
     state::ConfigState,
 };
 
@@ -21,8 +27,8 @@ use crate::{
         },
         sessions::{ClientSession, OptionalClient},
     },
-    upgrade::{fork_main_into_new_main, UpgradeError},
-    util::disable_close_on_exec,
+    upgrade::{check_executable, fork_main_into_new_main, kill_new_main, UpgradeError},
+    util::{disable_close_on_exec, write_pid_file},
 };
 
 use super::sessions::WorkerSession;
@@ -35,11 +41,22 @@ enum UpgradeWorkerProgress {
         old_worker_token: Token,
         old_worker_id: WorkerId,
     },
-    /// 3. soft stop the old worker
-    /// 4. activate the listeners of the new worker
-    StopOldActivateNew {
+    /// 3. activate the listeners of the new worker
+    ActivatingNew {
+        old_worker_id: WorkerId,
+        new_worker_id: WorkerId,
+    },
+    /// 4. request the established sessions that can be handed off from the old worker
+    RequestingSessions {
+        old_worker_id: WorkerId,
+        new_worker_id: WorkerId,
+    },
+    /// 5. soft stop the old worker
+    /// 6. pass the handed off sessions to the new worker
+    StopOldAdoptSessions {
         old_worker_id: WorkerId,
         new_worker_id: WorkerId,
+        handed_off: usize,
     },
 }
 
@@ -71,28 +88,43 @@ pub fn upgrade_worker(server: &mut Server, client: &mut ClientSession, old_worke
         }
     };
 
+    if let Err(channel_error) = retire_old_main(server) {
+        client.finish_failure(format!(
+            "Could not tell the previous main process to retire, worker {} is not upgraded: {}",
+            old_worker_id, channel_error
+        ));
+        return;
+    }
+
     client.return_processing(format!(
         "Requesting listen sockets from worker {old_worker_id}"
     ));
     server.scatter(
         RequestType::ReturnListenSockets(ReturnListenSockets {}).into(),
-        Box::new(UpgradeWorkerTask {
-            client_token: client.token,
-            progress: UpgradeWorkerProgress::RequestingListenSockets {
+        Box::new(UpgradeWorkerTask::new(
+            client.token,
+            UpgradeWorkerProgress::RequestingListenSockets {
                 old_worker_token,
                 old_worker_id,
             },
-            ok: 0,
-            errors: 0,
-            responses: Vec::new(),
-            expected_responses: 0,
-        }),
+        )),
         Timeout::Default,
         Some(old_worker_id),
     );
 }
 
 impl UpgradeWorkerTask {
+    fn new(client_token: Token, progress: UpgradeWorkerProgress) -> Self {
+        Self {
+            client_token,
+            progress,
+            ok: 0,
+            errors: 0,
+            responses: Vec::new(),
+            expected_responses: 0,
+        }
+    }
+
     fn receive_listen_sockets(
         self,
         server: &mut Server,
@@ -139,19 +171,150 @@ impl UpgradeWorkerTask {
         client.return_processing(format!("Launched a new worker with id {}", new_worker.id));
         let new_worker_id = new_worker.id;
 
-        let finish_task = server.new_task(
-            Box::new(UpgradeWorkerTask {
-                client_token: self.client_token,
-                progress: UpgradeWorkerProgress::StopOldActivateNew {
+        let activate_requests = server.state.generate_activate_requests();
+        if activate_requests.is_empty() {
+            return self.request_sessions(server, client, old_worker_id, new_worker_id);
+        }
+
+        let activate_task = server.new_task(
+            Box::new(UpgradeWorkerTask::new(
+                self.client_token,
+                UpgradeWorkerProgress::ActivatingNew {
                     old_worker_id,
                     new_worker_id,
                 },
+            )),
+            Timeout::None,
+        );
+
+        // activate new worker
+        client.return_processing(format!("Activating new worker with id {new_worker_id}"));
+        for (count, request) in activate_requests.into_iter().enumerate() {
+            server.scatter_on(request, activate_task, count, Some(new_worker_id));
+        }
+    }
+
+    /// the old worker sends the sessions it can hand off on its SCM socket,
+    /// before answering
+    fn request_sessions(
+        self,
+        server: &mut Server,
+        client: &mut OptionalClient,
+        old_worker_id: WorkerId,
+        new_worker_id: WorkerId,
+    ) {
+        client.return_processing(format!(
+            "Requesting established sessions from worker {old_worker_id}"
+        ));
+        server.scatter(
+            RequestType::ReturnSessions(ReturnSessions {}).into(),
+            Box::new(UpgradeWorkerTask::new(
+                self.client_token,
+                UpgradeWorkerProgress::RequestingSessions {
+                    old_worker_id,
+                    new_worker_id,
+                },
+            )),
+            Timeout::Default,
+            Some(old_worker_id),
+        );
+    }
+
+    /// receives the sessions of the old worker and passes them to the new worker,
+    /// returns how many were passed
+    fn forward_sessions(
+        server: &mut Server,
+        client: &mut OptionalClient,
+        old_worker_id: WorkerId,
+        new_worker_id: WorkerId,
+    ) -> usize {
+        let Some(old_worker) = server
+            .workers
+            .values_mut()
+            .find(|worker| worker.id == old_worker_id)
+        else {
+            client.return_processing(format!(
+                "Worker {old_worker_id} died while handing off its sessions"
+            ));
+            return 0;
+        };
 
-                ok: 0,
-                errors: 0,
-                responses: Vec::new(),
-                expected_responses: 0,
-            }),
+        let sessions = match old_worker.scm_socket.receive_sessions() {
+            Ok(sessions) => sessions,
+            Err(error) => {
+                error!(
+                    "could not receive the sessions of worker {}: {}",
+                    old_worker_id, error
+                );
+                client.return_processing(format!(
+                    "Could not receive the sessions of worker {old_worker_id}"
+                ));
+                return 0;
+            }
+        };
+        if sessions.is_empty() {
+            return 0;
+        }
+
+        let Some(new_worker) = server
+            .workers
+            .values_mut()
+            .find(|worker| worker.id == new_worker_id && worker.is_active())
+        else {
+            client.return_processing(format!(
+                "New worker {new_worker_id} died before adopting the sessions"
+            ));
+            sessions.iter().for_each(SessionSockets::close);
+            return 0;
+        };
+
+        let was_blocking = new_worker.scm_socket.blocking;
+        let sent = new_worker
+            .scm_socket
+            .set_blocking(true)
+            .and_then(|()| new_worker.scm_socket.send_sessions(&sessions));
+        if let Err(error) = new_worker.scm_socket.set_blocking(was_blocking) {
+            error!(
+                "could not restore the scm socket of worker {}: {}",
+                new_worker_id, error
+            );
+        }
+
+        // the new worker has its own copy of the file descriptors
+        sessions.iter().for_each(SessionSockets::close);
+
+        match sent {
+            Ok(()) => sessions.len(),
+            Err(error) => {
+                error!(
+                    "could not pass the sessions to worker {}: {}",
+                    new_worker_id, error
+                );
+                client.return_processing(format!(
+                    "Could not pass the sessions to worker {new_worker_id}, they are closed"
+                ));
+                0
+            }
+        }
+    }
+
+    fn stop_old_adopt_sessions(
+        self,
+        server: &mut Server,
+        client: &mut OptionalClient,
+        old_worker_id: WorkerId,
+        new_worker_id: WorkerId,
+        handed_off: usize,
+    ) {
+        let finish_task = server.new_task(
+            Box::new(UpgradeWorkerTask::new(
+                self.client_token,
+                UpgradeWorkerProgress::StopOldAdoptSessions {
+                    old_worker_id,
+                    new_worker_id,
+                    handed_off,
+                },
+            )),
             Timeout::None,
         );
 
@@ -164,14 +327,16 @@ impl UpgradeWorkerTask {
             Some(old_worker_id),
         );
 
-        // activate new worker
-        for (count, request) in server
-            .state
-            .generate_activate_requests()
-            .into_iter()
-            .enumerate()
-        {
-            server.scatter_on(request, finish_task, count + 1, Some(new_worker_id));
+        if handed_off > 0 {
+            client.return_processing(format!(
+                "Passing {handed_off} sessions to worker {new_worker_id}"
+            ));
+            server.scatter_on(
+                RequestType::AdoptSessions(AdoptSessions {}).into(),
+                finish_task,
+                1,
+                Some(new_worker_id),
+            );
         }
     }
 }
@@ -205,14 +370,42 @@ impl GatheringTask for UpgradeWorkerTask {
                     ));
                 }
             }
-            UpgradeWorkerProgress::StopOldActivateNew {
+            UpgradeWorkerProgress::ActivatingNew {
+                old_worker_id,
+                new_worker_id,
+            } => {
+                self.request_sessions(server, client, old_worker_id, new_worker_id);
+            }
+            UpgradeWorkerProgress::RequestingSessions {
+                old_worker_id,
+                new_worker_id,
+            } => {
+                // an old worker that can not hand off its sessions drains them
+                let handed_off = if self.ok == 1 {
+                    Self::forward_sessions(server, client, old_worker_id, new_worker_id)
+                } else {
+                    client.return_processing(format!(
+                        "Worker {old_worker_id} did not hand off its sessions, they will be drained"
+                    ));
+                    0
+                };
+                self.stop_old_adopt_sessions(
+                    server,
+                    client,
+                    old_worker_id,
+                    new_worker_id,
+                    handed_off,
+                );
+            }
+            UpgradeWorkerProgress::StopOldAdoptSessions {
                 old_worker_id,
                 new_worker_id,
+                handed_off,
             } => {
                 client.finish_ok(
                     format!(
-                        "Upgrade successful:\n- finished soft stop of worker {:?}\n- finished activation of new worker {:?}",
-                        old_worker_id, new_worker_id
+                        "Upgrade successful:\n- finished soft stop of worker {:?}\n- finished activation of new worker {:?}\n- handed off {} sessions",
+                        old_worker_id, new_worker_id, handed_off
                     )
                 );
             }
@@ -240,13 +433,14 @@ impl Gatherer for UpgradeWorkerTask {
             Ok(ResponseStatus::Ok) => {
                 self.ok += 1;
                 match self.progress {
-                    UpgradeWorkerProgress::RequestingListenSockets { .. } => {}
-                    UpgradeWorkerProgress::StopOldActivateNew { .. } => {
-                        client.return_processing(format!(
+                    UpgradeWorkerProgress::RequestingListenSockets { .. }
+                    | UpgradeWorkerProgress::RequestingSessions { .. } => {}
+                    UpgradeWorkerProgress::ActivatingNew { .. }
+                    | UpgradeWorkerProgress::StopOldAdoptSessions { .. } => client
+                        .return_processing(format!(
                             "Worker {} answered OK to {}. {}",
                             worker_id, message.id, message.message
-                        ))
-                    }
+                        )),
                 }
             }
             Ok(ResponseStatus::Failure) => self.errors += 1,
@@ -258,6 +452,13 @@ impl Gatherer for UpgradeWorkerTask {
         }
         self.responses.push((worker_id, message));
     }
+
+    fn on_timeout(&mut self, client: &mut OptionalClient, worker_id: WorkerId, request_id: &str) {
+        client.return_processing(format!(
+            "Worker {worker_id} did not answer {request_id} in time"
+        ));
+        self.errors += 1;
+    }
 }
 
 //===============================================
@@ -301,6 +502,9 @@ impl TryFrom<&WorkerSession> for SerializedWorkerSession {
 pub struct UpgradeData {
     /// file descriptor of the unix command socket
     pub command_socket_fd: i32,
+    /// file descriptor of the health check listener
+    #[serde(default)]
+    pub health_listener_fd: Option<i32>,
     pub config: Config,
     pub next_client_id: ClientId,
     pub next_session_id: SessionId,
@@ -309,21 +513,61 @@ pub struct UpgradeData {
     /// JSON serialized workers
     pub workers: Vec<SerializedWorkerSession>,
     pub state: ConfigState,
+    /// requests waiting for their date to be applied
+    #[serde(default)]
+    pub scheduled_requests: Vec<ScheduledRequest>,
 }
 
-pub fn upgrade_main(server: &mut Server, client: &mut ClientSession) {
+/// token of the channel on which the previous main process waits for the end of the upgrade
+pub const MAIN_STANDBY: Token = Token(usize::MAX - 1);
+
+/// how long the previous main process waits for the upgrade to be finished,
+/// before it rolls back to itself
+const MAIN_UPGRADE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// how long a new main process that asked for a rollback has to exit by itself,
+/// before it is killed
+const ROLLBACK_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Forks a new main process with the given binary, or the current one.
+///
+/// The new main process gets the command socket, the state and the worker
+/// channels. If it does not confirm it is ready within the worker timeout,
+/// it is killed and the current main process stays in charge. Once it is
+/// ready, the current main process stands by until the new one upgrades a
+/// first worker or finishes the upgrade (then it retires), or asks for a rollback.
+pub fn upgrade_main(server: &mut Server, client: &mut ClientSession, binary: Option<String>) {
+    if server.old_main.is_some() {
+        client.finish_failure(
+            "The previous upgrade of the main process is not finished, finish or roll it back first",
+        );
+        return;
+    }
+
+    let executable_path = binary.unwrap_or_else(|| server.executable_path.clone());
+
+    if let Err(err) = check_executable(&executable_path) {
+        client.finish_failure(err.to_string());
+        return;
+    }
+
     if let Err(err) = server.disable_cloexec_before_upgrade() {
         client.finish_failure(err.to_string());
+        return;
     }
 
-    client.return_processing("Upgrading the main process...");
+    client.return_processing(format!(
+        "Upgrading the main process with {}...",
+        executable_path
+    ));
 
     let upgrade_data = server.generate_upgrade_data();
 
     let (new_main_pid, mut fork_confirmation_channel) =
-        match fork_main_into_new_main(server.executable_path.clone(), upgrade_data) {
+        match fork_main_into_new_main(executable_path, upgrade_data) {
             Ok(tuple) => tuple,
             Err(fork_error) => {
+                rollback_main_upgrade(server, None);
                 client.finish_failure(format!(
                     "Could not start a new main process by forking: {}",
                     fork_error
@@ -332,20 +576,311 @@ pub fn upgrade_main(server: &mut Server, client: &mut ClientSession) {
             }
         };
 
-    let received_ok_from_new_process = fork_confirmation_channel.read_message().unwrap_or(false);
+    let timeout = Duration::from_secs(server.config.worker_timeout as u64);
+    let confirmation = fork_confirmation_channel.read_message_blocking_timeout(Some(timeout));
 
     debug!(
         "new main process sent a fork confirmation: {:?}",
-        received_ok_from_new_process
+        confirmation
     );
 
-    if !received_ok_from_new_process {
-        client.finish_failure("Upgrade of main process failed: no feedback from the new main");
-    } else {
-        client.finish_ok(format!(
-            "Upgrade successful, closing main process. New main process has pid {}",
-            new_main_pid
+    match confirmation {
+        Ok(true) => {
+            if let Err(channel_error) = fork_confirmation_channel.nonblocking() {
+                rollback_main_upgrade(server, Some(new_main_pid));
+                client.finish_failure(format!(
+                    "Upgrade of main process failed: can not wait for the new main ({}), rolled back",
+                    channel_error
+                ));
+                return;
+            }
+            let standby = MainStandby::new(
+                new_main_pid,
+                fork_confirmation_channel,
+                Instant::now() + MAIN_UPGRADE_TIMEOUT,
+            );
+            if let Err(server_error) = server.stand_by(standby) {
+                rollback_main_upgrade(server, Some(new_main_pid));
+                client.finish_failure(format!(
+                    "Upgrade of main process failed: can not wait for the new main ({}), rolled back",
+                    server_error
+                ));
+                return;
+            }
+            client.finish_ok(format!(
+                "New main process {} is ready, the old one stands by until the upgrade is finished",
+                new_main_pid
+            ));
+        }
+        Ok(false) => {
+            rollback_main_upgrade(server, Some(new_main_pid));
+            client.finish_failure(
+                "Upgrade of main process failed: the new main is not ready, rolled back",
+            );
+        }
+        Err(channel_error) => {
+            rollback_main_upgrade(server, Some(new_main_pid));
+            client.finish_failure(format!(
+                "Upgrade of main process failed: no feedback from the new main ({}), rolled back",
+                channel_error
+            ));
+        }
+    }
+}
+
+/// Called in a new main process before it upgrades a worker. The previous main
+/// process could not take back a worker stopped by the upgrade, so the upgrade of
+/// the main process becomes final and the previous one retires
+fn retire_old_main(server: &mut Server) -> Result<(), ChannelError> {
+    let Some(mut old_main) = server.old_main.take() else {
+        return Ok(());
+    };
+    if let Err(channel_error) = old_main.write_message(&true) {
+        server.old_main = Some(old_main);
+        return Err(channel_error);
+    }
+    info!("upgrading a first worker, the previous main process retires");
+    server.old_main_retired = true;
+    Ok(())
+}
+
+/// the current main process takes back the command socket and the workers.
+/// The new main process did not upgrade any worker yet, see `retire_old_main`
+fn rollback_main_upgrade(server: &mut Server, new_main_pid: Option<pid_t>) {
+    if let Some(pid) = new_main_pid {
+        warn!("killing new main process {}", pid);
+        kill_new_main(pid);
+    }
+
+    if let Err(err) = server.enable_cloexec_after_upgrade() {
+        error!("could not enable cloexec after a failed upgrade: {}", err);
+    }
+
+    // the new main may have overwritten it
+    if let Err(err) = write_pid_file(&server.config) {
+        error!(
+            "could not write the PID file after a failed upgrade: {}",
+            err
+        );
+    }
+}
+
+/// The previous main process stops serving once the new one is ready, but
+/// stays alive to take back the command socket and the workers if the
+/// upgrade fails
+#[derive(Debug)]
+pub struct MainStandby {
+    pub new_main_pid: pid_t,
+    /// receives `true` to retire, `false` to roll back
+    pub channel: Channel<(), bool>,
+    deadline: Instant,
+    rollback_requested: bool,
+}
+
+/// How the upgrade of the main process ends, for the previous main process
+#[derive(Debug, PartialEq, Eq)]
+pub enum StandbyVerdict {
+    /// the new main process took over, the previous one stops
+    Retire,
+    /// kill the new main process, and resume with the given reason
+    RollBack(String),
+}
+
+impl MainStandby {
+    /// the channel has to be nonblocking
+    pub fn new(new_main_pid: pid_t, channel: Channel<(), bool>, deadline: Instant) -> Self {
+        Self {
+            new_main_pid,
+            channel,
+            deadline,
+            rollback_requested: false,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// read the decision of the new main process, if there is one yet
+    pub fn verdict(&mut self, now: Instant) -> Option<StandbyVerdict> {
+        // a few bytes at most, read until the socket would block
+        self.channel.handle_events(Ready::READABLE);
+        let closed = matches!(
+            self.channel.readable(),
+            Err(ChannelError::NoByteToRead) | Err(ChannelError::Read(_))
+        );
+
+        loop {
+            match self.channel.read_message() {
+                Ok(true) => return Some(StandbyVerdict::Retire),
+                Ok(false) if !self.rollback_requested => {
+                    // let the new main process answer its client and exit by itself
+                    self.rollback_requested = true;
+                    self.deadline = self.deadline.min(now + ROLLBACK_GRACE_PERIOD);
+                }
+                Ok(false) => {}
+                Err(_) => break,
+            }
+        }
+
+        let reason = if self.rollback_requested {
+            "the new main process asked for a rollback"
+        } else if closed {
+            "the new main process exited"
+        } else {
+            "the upgrade was not finished in time"
+        };
+
+        if closed || now >= self.deadline {
+            return Some(StandbyVerdict::RollBack(reason.to_owned()));
+        }
+        None
+    }
+}
+
+/// Called in the previous main process, when the standby ends
+pub fn end_main_standby(server: &mut Server, standby: MainStandby, verdict: StandbyVerdict) {
+    match verdict {
+        StandbyVerdict::Retire => {
+            info!(
+                "the upgrade to main process {} is finished, retiring",
+                standby.new_main_pid
+            );
+            server.run_state = ServerState::Stopping;
+        }
+        StandbyVerdict::RollBack(reason) => {
+            warn!(
+                "rolling back the upgrade to main process {}: {}",
+                standby.new_main_pid, reason
+            );
+            rollback_main_upgrade(server, Some(standby.new_main_pid));
+            server.run_state = ServerState::Running;
+        }
+    }
+}
+
+/// Called in the new main process, once the workers are upgraded and healthy,
+/// or to give control back to the previous main process. A rollback is refused
+/// once a worker was upgraded, since the previous main process retired then
+pub fn finish_main_upgrade(server: &mut Server, client: &mut ClientSession, rollback: bool) {
+    let mut old_main = match server.old_main.take() {
+        Some(channel) => channel,
+        None if server.old_main_retired => {
+            if rollback {
+                client.finish_failure(
+                    "The previous main process retired when a first worker was upgraded, can not roll back",
+                );
+            } else {
+                client.finish_ok("Upgrade of the main process finished, the previous one retired");
+            }
+            return;
+        }
+        None => {
+            client.finish_failure("No upgrade of the main process is waiting to be finished");
+            return;
+        }
+    };
+
+    if let Err(channel_error) = old_main.write_message(&!rollback) {
+        server.old_main = Some(old_main);
+        client.finish_failure(format!(
+            "Could not reach the previous main process: {}",
+            channel_error
         ));
+        return;
+    }
+
+    if rollback {
+        client.finish_ok("Rolling back to the previous main process, this one stops");
+        // the previous main process kills this one once the channel closes,
+        // keep it open until the response is sent
+        server.old_main = Some(old_main);
         server.run_state = ServerState::Stopping;
+    } else {
+        client.finish_ok("Upgrade of the main process finished, the previous one retires");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::net::UnixStream;
+    use nix::{sys::signal::kill, unistd::Pid};
+
+    fn new_standby(now: Instant) -> (MainStandby, Channel<bool, ()>) {
+        let (old_to_new, new_to_old) = UnixStream::pair().expect("could not create a socket pair");
+        let mut new_main: Channel<bool, ()> = Channel::new(new_to_old, 64, 128);
+        new_main.blocking().expect("could not block the channel");
+        let standby = MainStandby::new(
+            0,
+            Channel::new(old_to_new, 64, 128),
+            now + MAIN_UPGRADE_TIMEOUT,
+        );
+        (standby, new_main)
+    }
+
+    #[test]
+    fn main_standby_retires() {
+        let now = Instant::now();
+        let (mut standby, mut new_main) = new_standby(now);
+        assert_eq!(standby.verdict(now), None);
+
+        new_main.write_message(&true).unwrap();
+        assert_eq!(standby.verdict(now), Some(StandbyVerdict::Retire));
+    }
+
+    #[test]
+    fn main_standby_rolls_back() {
+        let now = Instant::now();
+
+        // the new main process asks for it, then exits
+        let (mut standby, mut new_main) = new_standby(now);
+        new_main.write_message(&false).unwrap();
+        assert_eq!(standby.verdict(now), None);
+        drop(new_main);
+        assert_eq!(
+            standby.verdict(now),
+            Some(StandbyVerdict::RollBack(
+                "the new main process asked for a rollback".to_owned()
+            ))
+        );
+
+        // the new main process asks for it, but does not exit
+        let (mut standby, mut new_main) = new_standby(now);
+        new_main.write_message(&false).unwrap();
+        assert_eq!(standby.verdict(now), None);
+        assert!(standby.verdict(now + ROLLBACK_GRACE_PERIOD).is_some());
+
+        // the new main process crashed
+        let (mut standby, new_main) = new_standby(now);
+        drop(new_main);
+        assert_eq!(
+            standby.verdict(now),
+            Some(StandbyVerdict::RollBack(
+                "the new main process exited".to_owned()
+            ))
+        );
+
+        // the upgrade of the workers never finished
+        let (mut standby, _new_main) = new_standby(now);
+        assert_eq!(
+            standby.verdict(now + MAIN_UPGRADE_TIMEOUT),
+            Some(StandbyVerdict::RollBack(
+                "the upgrade was not finished in time".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn rollback_kills_the_new_main() {
+        let new_main = std::process::Command::new("sleep")
+            .arg("60")
+            .spawn()
+            .expect("could not spawn a process");
+        let pid = new_main.id() as pid_t;
+
+        kill_new_main(pid);
+        // killed and reaped
+        assert!(kill(Pid::from_raw(pid), None).is_err());
     }
 }
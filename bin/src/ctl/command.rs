@@ -2,9 +2,13 @@ use std::time::Duration;
 
 use sozu_command_lib::{
     logging::setup_logging_with_config,
-    proto::command::{
-        request::RequestType, response_content::ContentType, ListWorkers, QueryMetricsOptions,
-        Request, Response, ResponseContent, ResponseStatus, UpgradeMain,
+    proto::{
+        command::{
+            request::RequestType, response_content::ContentType, FinishMainUpgrade, ListWorkers,
+            QueryMetricsOptions, Request, Response, ResponseContent, ResponseStatus, RunState,
+            ScheduledRequest, Status, SubscribeStateChanges, UpgradeMain,
+        },
+        DisplayError,
     },
 };
 
@@ -12,11 +16,28 @@ use crate::ctl::{create_channel, CommandManager, CtlError};
 
 impl CommandManager {
     fn write_request_on_channel(&mut self, request: Request) -> Result<(), CtlError> {
+        let request = self.schedule(request)?;
         self.channel
             .write_message(&request)
             .map_err(CtlError::WriteRequest)
     }
 
+    /// wraps the request in a scheduled request, if a date was given with --at
+    fn schedule(&self, request: Request) -> Result<Request, CtlError> {
+        let Some(execute_at) = self.execute_at else {
+            return Ok(request);
+        };
+        if !request.is_a_state_change() {
+            return Err(CtlError::NotSchedulable(request.short_name().to_owned()));
+        }
+        Ok(RequestType::ScheduleRequest(Box::new(ScheduledRequest {
+            id: None,
+            execute_at,
+            request: Box::new(request),
+        }))
+        .into())
+    }
+
     fn read_channel_message_with_timeout(&mut self) -> Result<Response, CtlError> {
         self.channel
             .read_message_blocking_timeout(Some(self.timeout))
@@ -28,9 +49,7 @@ impl CommandManager {
         request: Request,
         timeout: bool,
     ) -> Result<Response, CtlError> {
-        self.channel
-            .write_message(&request)
-            .map_err(CtlError::WriteRequest)?;
+        self.write_request_on_channel(request)?;
 
         loop {
             let response = if timeout {
@@ -53,7 +72,13 @@ impl CommandManager {
                         info!("{}, {}", response.message, event);
                     }
                 }
-                ResponseStatus::Failure => return Err(CtlError::Failure(response.message)),
+                ResponseStatus::Failure => {
+                    // the details of the failure, like the errors of each worker
+                    if let Some(content) = &response.content {
+                        content.display(self.json).map_err(CtlError::Display)?;
+                    }
+                    return Err(CtlError::Failure(response.message));
+                }
                 ResponseStatus::Ok => return Ok(response),
             }
         }
@@ -79,23 +104,10 @@ impl CommandManager {
 
     pub fn get_metrics(
         &mut self,
-        list: bool,
+        options: QueryMetricsOptions,
         refresh: Option<u32>,
-        metric_names: Vec<String>,
-        cluster_ids: Vec<String>,
-        backend_ids: Vec<String>,
-        no_clusters: bool,
-        workers: bool,
     ) -> Result<(), CtlError> {
-        let request: Request = RequestType::QueryMetrics(QueryMetricsOptions {
-            list,
-            cluster_ids,
-            backend_ids,
-            metric_names,
-            no_clusters,
-            workers,
-        })
-        .into();
+        let request: Request = RequestType::QueryMetrics(options).into();
 
         // a loop to reperform the query every refresh time
         loop {
@@ -135,13 +147,87 @@ impl CommandManager {
         Ok(())
     }
 
-    pub fn upgrade_main(&mut self) -> Result<(), CtlError> {
+    /// Prints the changes of the state as they happen, one per line, until interrupted
+    pub fn watch_state(&mut self) -> Result<(), CtlError> {
+        self.write_request_on_channel(
+            RequestType::SubscribeStateChanges(SubscribeStateChanges {}).into(),
+        )?;
+
+        loop {
+            let response = self
+                .channel
+                .read_message_blocking_timeout(None)
+                .map_err(CtlError::ReadBlocking)?;
+
+            match response.status() {
+                ResponseStatus::Processing => {
+                    if let Some(ResponseContent {
+                        content_type: Some(ContentType::StateChange(change)),
+                    }) = response.content
+                    {
+                        if self.json {
+                            let line = serde_json::to_string(&change)
+                                .map_err(|error| CtlError::Display(DisplayError::Json(error)))?;
+                            println!("{line}");
+                        } else {
+                            println!("{change}");
+                        }
+                    }
+                }
+                ResponseStatus::Failure => return Err(CtlError::Failure(response.message)),
+                ResponseStatus::Ok => return Ok(()),
+            }
+        }
+    }
+
+    /// Replaces the main process, with a new binary if provided, then rolls the workers
+    /// and checks that they are all running. The old main process kills a new main
+    /// process that does not get ready, and retires once a first worker is upgraded:
+    /// a failed worker upgrade is not rolled back then.
+    pub fn upgrade_main(&mut self, binary: Option<String>) -> Result<(), CtlError> {
         debug!("updating main process");
-        self.send_request(RequestType::UpgradeMain(UpgradeMain {}).into())?;
+        let binary = match binary {
+            // the main process may not have the same working directory
+            Some(path) => Some(
+                std::fs::canonicalize(&path)
+                    .map_err(|e| CtlError::Failure(format!("invalid binary path {path}: {e}")))?
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            None => None,
+        };
+        self.send_request(RequestType::UpgradeMain(UpgradeMain { binary }).into())?;
 
         info!("recreating a channel to reconnect with the new main process...");
         self.channel = create_channel(&self.config)?;
 
+        // the old main process stands by until it is told to retire or to come back
+        match self.upgrade_workers_of_new_main() {
+            Ok(running) => {
+                self.send_request(
+                    RequestType::FinishMainUpgrade(FinishMainUpgrade { rollback: false }).into(),
+                )?;
+                info!("Upgrade done, {} workers running", running);
+                Ok(())
+            }
+            Err(upgrade_error) => {
+                error!("Upgrade failed, rolling back: {}", upgrade_error);
+                match self.send_request(
+                    RequestType::FinishMainUpgrade(FinishMainUpgrade { rollback: true }).into(),
+                ) {
+                    Ok(()) => Err(CtlError::Failure(format!(
+                        "could not upgrade the workers ({upgrade_error}), rolled back to the previous main process"
+                    ))),
+                    Err(rollback_error) => Err(CtlError::Failure(format!(
+                        "could not upgrade the workers ({upgrade_error}), the new main process stays in charge: {rollback_error}"
+                    ))),
+                }
+            }
+        }
+    }
+
+    /// upgrade every worker from the new main process, returns how many run afterwards
+    fn upgrade_workers_of_new_main(&mut self) -> Result<usize, CtlError> {
         info!("requesting the list of workers from the new main");
         let response =
             self.send_request_get_response(RequestType::ListWorkers(ListWorkers {}).into(), true)?;
@@ -154,6 +240,7 @@ impl CommandManager {
         };
 
         info!("About to upgrade these workers: {:?}", workers);
+        let worker_count = workers.vec.len();
 
         let mut upgrade_jobs = Vec::new();
 
@@ -176,7 +263,7 @@ impl CommandManager {
                             "could not create channel to worker {}, this is critical: {}",
                             worker.id, e
                         );
-                        return;
+                        return false;
                     }
                 };
 
@@ -187,23 +274,62 @@ impl CommandManager {
                     timeout: Duration::from_secs(60), // overriden by upgrade_timeout anyway
                     config,
                     json: false,
+                    execute_at: None,
                 };
 
                 match command_manager.upgrade_worker(worker.id) {
-                    Ok(()) => info!("successfully upgraded worker {}", worker.id),
-                    Err(e) => error!("error upgrading worker {}: {}", worker.id, e),
+                    Ok(()) => {
+                        info!("successfully upgraded worker {}", worker.id);
+                        true
+                    }
+                    Err(e) => {
+                        error!("error upgrading worker {}: {}", worker.id, e);
+                        false
+                    }
                 }
             }));
         }
 
+        let mut failed = 0;
         for job in upgrade_jobs {
-            if let Err(e) = job.join() {
-                error!("an upgrading job panicked: {:?}", e)
+            match job.join() {
+                Ok(true) => {}
+                Ok(false) => failed += 1,
+                Err(e) => {
+                    error!("an upgrading job panicked: {:?}", e);
+                    failed += 1;
+                }
             }
         }
+        if failed > 0 {
+            return Err(CtlError::Failure(format!(
+                "{failed} workers out of {worker_count} could not be upgraded"
+            )));
+        }
 
-        info!("Finished upgrading");
+        info!("Finished upgrading, checking the status of the workers");
+        // the main process may wait for the old workers to exit, up to the worker timeout
+        let response =
+            self.send_request_get_response(RequestType::Status(Status {}).into(), false)?;
 
-        Ok(())
+        let workers = match response.content {
+            Some(ResponseContent {
+                content_type: Some(ContentType::Workers(worker_infos)),
+            }) => worker_infos,
+            _ => return Err(CtlError::WrongResponse(response)),
+        };
+
+        let running = workers
+            .vec
+            .iter()
+            .filter(|worker| worker.run_state == RunState::Running as i32)
+            .count();
+        if running < worker_count {
+            return Err(CtlError::Failure(format!(
+                "only {running} workers out of {worker_count} are running after the upgrade"
+            )));
+        }
+
+        Ok(running)
     }
 }
@@ -9,13 +9,14 @@ use sozu_command_lib::{
     config::{Config, ConfigError},
     logging::{setup_logging_with_config, LogError},
     proto::{
-        command::{Request, Response},
+        command::{FrontendFilters, QueryMetricsOptions, Request, Response},
         DisplayError,
     },
 };
 
 use crate::{
     cli::{self, *},
+    ctl::request_builder::split_tag_filter,
     util::{get_config_file_path, UtilError},
 };
 
@@ -55,6 +56,8 @@ pub enum CtlError {
     WrongResponse(Response),
     #[error("could not setup the logger: {0}")]
     SetupLogging(LogError),
+    #[error("only changes of the state can be scheduled, not {0}")]
+    NotSchedulable(String),
 }
 
 pub struct CommandManager {
@@ -63,6 +66,8 @@ pub struct CommandManager {
     config: Config,
     /// wether to display the response in JSON
     json: bool,
+    /// unix timestamp at which the main process should apply the changes, if not now
+    execute_at: Option<i64>,
 }
 
 pub fn ctl(args: cli::Args) -> Result<(), CtlError> {
@@ -96,6 +101,7 @@ pub fn ctl(args: cli::Args) -> Result<(), CtlError> {
         timeout,
         config,
         json: args.json,
+        execute_at: args.at,
     };
 
     command_manager.handle_command(args.cmd)
@@ -112,8 +118,8 @@ impl CommandManager {
                     self.soft_stop()
                 }
             }
-            SubCmd::Upgrade { worker } => match worker {
-                None => self.upgrade_main(),
+            SubCmd::Upgrade { worker, binary } => match worker {
+                None => self.upgrade_main(binary),
                 Some(worker_id) => self.upgrade_worker(worker_id),
             },
             SubCmd::Status {} => self.status(),
@@ -126,15 +132,32 @@ impl CommandManager {
                     backends,
                     no_clusters,
                     workers,
-                } => self.get_metrics(
-                    list,
-                    refresh,
-                    names,
-                    clusters,
-                    backends,
-                    no_clusters,
-                    workers,
-                ),
+                    tag,
+                } => {
+                    let (tag_key, tag_value) = match tag {
+                        Some(tag) => {
+                            let (key, value) = split_tag_filter(tag);
+                            (Some(key), value)
+                        }
+                        None => (None, None),
+                    };
+                    self.get_metrics(
+                        QueryMetricsOptions {
+                            list,
+                            cluster_ids: clusters,
+                            backend_ids: backends,
+                            metric_names: names,
+                            no_clusters,
+                            workers,
+                            tag_key,
+                            tag_value,
+                        },
+                        refresh,
+                    )
+                }
+                MetricsCmd::Config {
+                    cmd: MetricsConfigCmd::Get,
+                } => self.get_metrics_configuration(),
                 _ => self.configure_metrics(cmd),
             },
             SubCmd::Logging { filter } => self.logging_filter(filter),
@@ -142,6 +165,14 @@ impl CommandManager {
                 StateCmd::Save { file } => self.save_state(file),
                 StateCmd::Load { file } => self.load_state(file),
                 StateCmd::Stats => self.count_requests(),
+                StateCmd::Watch => self.watch_state(),
+            },
+            SubCmd::Schedule { cmd } => match cmd {
+                ScheduleCmd::List => self.list_scheduled_requests(),
+                ScheduleCmd::Cancel { id } => self.cancel_scheduled_request(id),
+            },
+            SubCmd::Check { cmd } => match cmd {
+                CheckCmd::Proxy => self.check_proxy(),
             },
             SubCmd::Reload { file } => self.reload_configuration(file),
             SubCmd::Cluster { cmd } => self.cluster_command(cmd),
@@ -155,7 +186,54 @@ impl CommandManager {
                     https,
                     tcp,
                     domain,
-                } => self.list_frontends(http, https, tcp, domain),
+                    cluster_id,
+                    address,
+                    path,
+                    tag,
+                    offset,
+                    limit,
+                } => {
+                    let (tag_key, tag_value) = match tag {
+                        Some(tag) => {
+                            let (key, value) = split_tag_filter(tag);
+                            (Some(key), value)
+                        }
+                        None => (None, None),
+                    };
+                    self.list_frontends(FrontendFilters {
+                        http,
+                        https,
+                        tcp,
+                        domain,
+                        cluster_id,
+                        address: address.map(Into::into),
+                        path,
+                        tag_key,
+                        tag_value,
+                        offset,
+                        limit,
+                    })
+                }
+                FrontendCmd::Remove {
+                    tag,
+                    http,
+                    https,
+                    tcp,
+                    cluster_id,
+                    address,
+                } => {
+                    let (tag_key, tag_value) = split_tag_filter(tag);
+                    self.remove_frontends(FrontendFilters {
+                        http,
+                        https,
+                        tcp,
+                        cluster_id,
+                        address: address.map(Into::into),
+                        tag_key: Some(tag_key),
+                        tag_value,
+                        ..Default::default()
+                    })
+                }
             },
             SubCmd::Listener { cmd } => match cmd {
                 ListenerCmd::Http { cmd } => self.http_listener_command(cmd),
@@ -170,7 +248,15 @@ impl CommandManager {
                     key,
                     address,
                     tls_versions,
-                } => self.add_certificate(address.into(), &certificate, &chain, &key, tls_versions),
+                    cipher_list,
+                } => self.add_certificate(
+                    address.into(),
+                    &certificate,
+                    &chain,
+                    &key,
+                    tls_versions,
+                    cipher_list,
+                ),
                 CertificateCmd::Remove {
                     certificate,
                     address,
@@ -188,6 +274,7 @@ impl CommandManager {
                     address,
                     old_fingerprint,
                     tls_versions,
+                    cipher_list,
                 } => self.replace_certificate(
                     address.into(),
                     &certificate,
@@ -196,12 +283,21 @@ impl CommandManager {
                     old_certificate.as_deref(),
                     old_fingerprint.as_deref(),
                     tls_versions,
+                    cipher_list,
                 ),
                 CertificateCmd::List {
                     fingerprint,
                     domain,
                     query_workers,
                 } => self.query_certificates(fingerprint, domain, query_workers),
+                CertificateCmd::Pin {
+                    address,
+                    domain,
+                    fingerprint,
+                } => self.pin_certificate(address.into(), domain, Some(&fingerprint)),
+                CertificateCmd::Unpin { address, domain } => {
+                    self.pin_certificate(address.into(), domain, None)
+                }
             },
             SubCmd::Config { cmd: _ } => Ok(()), // noop, handled at the beginning of the method
             SubCmd::Events => self.events(),
@@ -6,13 +6,14 @@ use sozu_command_lib::{
     },
     config::ListenerBuilder,
     proto::command::{
-        request::RequestType, ActivateListener, AddBackend, AddCertificate, Cluster, CountRequests,
-        DeactivateListener, FrontendFilters, HardStop, ListListeners, ListenerType,
-        LoadBalancingParams, MetricsConfiguration, PathRule, ProxyProtocolConfig,
-        QueryCertificatesFilters, QueryClusterByDomain, QueryClustersHashes, RemoveBackend,
-        RemoveCertificate, RemoveListener, ReplaceCertificate, RequestHttpFrontend,
-        RequestTcpFrontend, RulePosition, SocketAddress, SoftStop, Status, SubscribeEvents,
-        TlsVersion,
+        request::RequestType, ActivateListener, AddBackend, AddCertificate, CheckProxy, Cluster,
+        CountRequests, DeactivateListener, FaultInjection, FrontendFilters, HardStop,
+        ListListeners, ListScheduledRequests, ListenerType, LoadBalancingParams, MatchingOptions,
+        MetricsConfiguration, PathRule, PinCertificate, ProxyProtocolConfig,
+        QueryCertificatesFilters, QueryClusterByDomain, QueryClustersHashes,
+        QueryMetricsConfiguration, RemoveBackend, RemoveCertificate, RemoveListener,
+        ReplaceCertificate, RequestHttpFrontend, RequestTcpFrontend, ResponseClassification,
+        RulePosition, SocketAddress, SoftStop, Status, SubscribeEvents, TlsVersion,
     },
 };
 
@@ -43,6 +44,19 @@ impl CommandManager {
         self.send_request(RequestType::CountRequests(CountRequests {}).into())
     }
 
+    pub fn list_scheduled_requests(&mut self) -> Result<(), CtlError> {
+        self.send_request(RequestType::ListScheduledRequests(ListScheduledRequests {}).into())
+    }
+
+    pub fn cancel_scheduled_request(&mut self, id: u64) -> Result<(), CtlError> {
+        self.send_request(RequestType::CancelScheduledRequest(id).into())
+    }
+
+    /// the checks wait on the workers and on the network, the main process bounds them
+    pub fn check_proxy(&mut self) -> Result<(), CtlError> {
+        self.send_request_no_timeout(RequestType::CheckProxy(CheckProxy {}).into())
+    }
+
     pub fn soft_stop(&mut self) -> Result<(), CtlError> {
         debug!("shutting down proxy softly");
 
@@ -64,14 +78,45 @@ impl CommandManager {
     pub fn configure_metrics(&mut self, cmd: MetricsCmd) -> Result<(), CtlError> {
         debug!("Configuring metrics: {:?}", cmd);
 
-        let configuration = match cmd {
-            MetricsCmd::Enable => MetricsConfiguration::Enabled,
-            MetricsCmd::Disable => MetricsConfiguration::Disabled,
-            MetricsCmd::Clear => MetricsConfiguration::Clear,
+        let configurations = match cmd {
+            MetricsCmd::Enable {
+                backends: false,
+                time: false,
+            } => vec![MetricsConfiguration::Enabled],
+            MetricsCmd::Disable {
+                backends: false,
+                time: false,
+            } => vec![MetricsConfiguration::Disabled],
+            MetricsCmd::Enable { backends, time } => [
+                (backends, MetricsConfiguration::EnableBackendMetrics),
+                (time, MetricsConfiguration::EnableTimeMetrics),
+            ]
+            .into_iter()
+            .filter_map(|(toggled, configuration)| toggled.then_some(configuration))
+            .collect(),
+            MetricsCmd::Disable { backends, time } => [
+                (backends, MetricsConfiguration::DisableBackendMetrics),
+                (time, MetricsConfiguration::DisableTimeMetrics),
+            ]
+            .into_iter()
+            .filter_map(|(toggled, configuration)| toggled.then_some(configuration))
+            .collect(),
+            MetricsCmd::Clear => vec![MetricsConfiguration::Clear],
             _ => return Ok(()), // completely unlikely
         };
 
-        self.send_request(RequestType::ConfigureMetrics(configuration as i32).into())
+        for configuration in configurations {
+            self.send_request(RequestType::ConfigureMetrics(configuration as i32).into())?;
+        }
+        Ok(())
+    }
+
+    pub fn get_metrics_configuration(&mut self) -> Result<(), CtlError> {
+        debug!("Querying the metrics configuration");
+
+        self.send_request(
+            RequestType::QueryMetricsConfiguration(QueryMetricsConfiguration {}).into(),
+        )
     }
 
     pub fn reload_configuration(&mut self, path: Option<String>) -> Result<(), CtlError> {
@@ -83,24 +128,16 @@ impl CommandManager {
         self.send_request(RequestType::ReloadConfiguration(path).into())
     }
 
-    pub fn list_frontends(
-        &mut self,
-        http: bool,
-        https: bool,
-        tcp: bool,
-        domain: Option<String>,
-    ) -> Result<(), CtlError> {
+    pub fn list_frontends(&mut self, filters: FrontendFilters) -> Result<(), CtlError> {
         debug!("Listing frontends");
 
-        self.send_request(
-            RequestType::ListFrontends(FrontendFilters {
-                http,
-                https,
-                tcp,
-                domain,
-            })
-            .into(),
-        )
+        self.send_request(RequestType::ListFrontends(filters).into())
+    }
+
+    pub fn remove_frontends(&mut self, filters: FrontendFilters) -> Result<(), CtlError> {
+        debug!("Removing frontends matching {:?}", filters);
+
+        self.send_request(RequestType::RemoveFrontends(filters).into())
     }
 
     pub fn events(&mut self) -> Result<(), CtlError> {
@@ -115,14 +152,26 @@ impl CommandManager {
                 address,
                 sticky_id,
                 backup,
+                max_connections,
+                queue_timeout,
+                priority,
+                slow_start,
+                alternate_addresses,
             } => self.send_request(
                 RequestType::AddBackend(AddBackend {
                     cluster_id: id,
                     address: address.into(),
                     backend_id,
-                    load_balancing_parameters: Some(LoadBalancingParams::default()),
+                    load_balancing_parameters: Some(LoadBalancingParams {
+                        max_connections,
+                        queue_timeout,
+                        slow_start,
+                        ..Default::default()
+                    }),
                     sticky_id,
                     backup,
+                    priority,
+                    alternate_addresses: alternate_addresses.into_iter().map(Into::into).collect(),
                 })
                 .into(),
             ),
@@ -150,7 +199,53 @@ impl CommandManager {
                 send_proxy,
                 expect_proxy,
                 load_balancing_policy,
+                retry_budget,
+                hedge_delay,
+                labels,
+                idle_timeout,
+                max_connection_lifetime,
+                sticky_session_fallback,
+                failure_statuses,
+                healthy_statuses,
+                failure_headers,
+                stream_high_watermark,
+                stream_low_watermark,
+                https_redirect_exemptions,
+                allowed_upgrades,
             } => {
+                let labels = labels
+                    .into_iter()
+                    .map(|label| match label.split_once('=') {
+                        Some((key, value)) => Ok((key.to_owned(), value.to_owned())),
+                        None => Err(CtlError::Failure(format!(
+                            "invalid label '{label}', expected key=value"
+                        ))),
+                    })
+                    .collect::<Result<BTreeMap<_, _>, _>>()?;
+
+                let failure_headers = failure_headers
+                    .into_iter()
+                    .map(|header| match header.split_once('=') {
+                        Some((name, value)) => Ok((name.to_owned(), value.to_owned())),
+                        None => Err(CtlError::Failure(format!(
+                            "invalid failure header '{header}', expected name=value"
+                        ))),
+                    })
+                    .collect::<Result<BTreeMap<_, _>, _>>()?;
+
+                let response_classification = if failure_statuses.is_empty()
+                    && healthy_statuses.is_empty()
+                    && failure_headers.is_empty()
+                {
+                    None
+                } else {
+                    Some(ResponseClassification {
+                        failure_statuses,
+                        healthy_statuses,
+                        failure_headers,
+                    })
+                };
+
                 let proxy_protocol = match (send_proxy, expect_proxy) {
                     (true, true) => Some(ProxyProtocolConfig::RelayHeader),
                     (true, false) => Some(ProxyProtocolConfig::SendHeader),
@@ -164,12 +259,39 @@ impl CommandManager {
                         https_redirect,
                         proxy_protocol: proxy_protocol.map(|pp| pp as i32),
                         load_balancing: load_balancing_policy as i32,
+                        retry_budget,
+                        hedge_delay,
+                        labels,
+                        idle_timeout,
+                        max_connection_lifetime,
+                        sticky_session_fallback: sticky_session_fallback.map(|s| s as i32),
+                        response_classification,
+                        stream_high_watermark,
+                        stream_low_watermark,
+                        https_redirect_exemptions,
+                        allowed_upgrades,
                         ..Default::default()
                     })
                     .into(),
                 )
             }
             ClusterCmd::Remove { id } => self.send_request(RequestType::RemoveCluster(id).into()),
+            ClusterCmd::FaultInjection {
+                id,
+                percentage,
+                latency,
+                jitter,
+                error_status,
+            } => self.send_request(
+                RequestType::ConfigureFaultInjection(FaultInjection {
+                    cluster_id: id,
+                    percentage,
+                    latency,
+                    jitter,
+                    error_status,
+                })
+                .into(),
+            ),
             ClusterCmd::List {
                 id: cluster_id,
                 domain,
@@ -238,6 +360,10 @@ impl CommandManager {
                 method,
                 cluster_id: route,
                 tags,
+                case_insensitive,
+                ignore_trailing_slash,
+                percent_decode,
+                debug_trace,
             } => self.send_request(
                 RequestType::AddHttpFrontend(RequestHttpFrontend {
                     cluster_id: route.into(),
@@ -250,6 +376,12 @@ impl CommandManager {
                         Some(tags) => tags,
                         None => BTreeMap::new(),
                     },
+                    matching: MatchingOptions::from_cli_options(
+                        case_insensitive,
+                        ignore_trailing_slash,
+                        percent_decode,
+                    ),
+                    debug_trace: debug_trace.then_some(true),
                 })
                 .into(),
             ),
@@ -286,6 +418,10 @@ impl CommandManager {
                 method,
                 cluster_id: route,
                 tags,
+                case_insensitive,
+                ignore_trailing_slash,
+                percent_decode,
+                debug_trace,
             } => self.send_request(
                 RequestType::AddHttpsFrontend(RequestHttpFrontend {
                     cluster_id: route.into(),
@@ -298,6 +434,12 @@ impl CommandManager {
                         Some(tags) => tags,
                         None => BTreeMap::new(),
                     },
+                    matching: MatchingOptions::from_cli_options(
+                        case_insensitive,
+                        ignore_trailing_slash,
+                        percent_decode,
+                    ),
+                    debug_trace: debug_trace.then_some(true),
                 })
                 .into(),
             ),
@@ -332,7 +474,15 @@ impl CommandManager {
                 answer_503,
                 tls_versions,
                 cipher_list,
+                no_sni_policy,
+                unknown_sni_policy,
                 expect_proxy,
+                v6only,
+                accept_rate,
+                accept_burst,
+                tags,
+                strict_responses,
+                debug_trace_key,
                 sticky_name,
                 front_timeout,
                 back_timeout,
@@ -345,7 +495,14 @@ impl CommandManager {
                     .with_answer_503_path(answer_503)
                     .with_tls_versions(tls_versions)
                     .with_cipher_list(cipher_list)
+                    .with_no_sni_policy(no_sni_policy)
+                    .with_unknown_sni_policy(unknown_sni_policy)
                     .with_expect_proxy(expect_proxy)
+                    .with_v6only(v6only)
+                    .with_accept_pacing(accept_rate, accept_burst)
+                    .with_tags(tags)
+                    .with_strict_responses(strict_responses)
+                    .with_debug_trace_key(debug_trace_key)
                     .with_sticky_name(sticky_name)
                     .with_front_timeout(front_timeout)
                     .with_back_timeout(back_timeout)
@@ -362,9 +519,10 @@ impl CommandManager {
             HttpsListenerCmd::Activate { address } => {
                 self.activate_listener(address.into(), ListenerType::Https)
             }
-            HttpsListenerCmd::Deactivate { address } => {
-                self.deactivate_listener(address.into(), ListenerType::Https)
-            }
+            HttpsListenerCmd::Deactivate {
+                address,
+                drain_timeout,
+            } => self.deactivate_listener(address.into(), ListenerType::Https, drain_timeout),
         }
     }
 
@@ -376,6 +534,12 @@ impl CommandManager {
                 answer_404,
                 answer_503,
                 expect_proxy,
+                v6only,
+                accept_rate,
+                accept_burst,
+                tags,
+                strict_responses,
+                debug_trace_key,
                 sticky_name,
                 front_timeout,
                 back_timeout,
@@ -387,6 +551,11 @@ impl CommandManager {
                     .with_answer_404_path(answer_404)
                     .with_answer_503_path(answer_503)
                     .with_expect_proxy(expect_proxy)
+                    .with_v6only(v6only)
+                    .with_accept_pacing(accept_rate, accept_burst)
+                    .with_tags(tags)
+                    .with_strict_responses(strict_responses)
+                    .with_debug_trace_key(debug_trace_key)
                     .with_sticky_name(sticky_name)
                     .with_front_timeout(front_timeout)
                     .with_request_timeout(request_timeout)
@@ -403,9 +572,10 @@ impl CommandManager {
             HttpListenerCmd::Activate { address } => {
                 self.activate_listener(address.into(), ListenerType::Http)
             }
-            HttpListenerCmd::Deactivate { address } => {
-                self.deactivate_listener(address.into(), ListenerType::Http)
-            }
+            HttpListenerCmd::Deactivate {
+                address,
+                drain_timeout,
+            } => self.deactivate_listener(address.into(), ListenerType::Http, drain_timeout),
         }
     }
 
@@ -415,10 +585,17 @@ impl CommandManager {
                 address,
                 public_address,
                 expect_proxy,
+                v6only,
+                accept_rate,
+                accept_burst,
+                tags,
             } => {
                 let listener = ListenerBuilder::new_tcp(address.into())
                     .with_public_address(public_address)
                     .with_expect_proxy(expect_proxy)
+                    .with_v6only(v6only)
+                    .with_accept_pacing(accept_rate, accept_burst)
+                    .with_tags(tags)
                     .to_tcp(Some(&self.config))
                     .map_err(CtlError::CreateListener)?;
 
@@ -430,9 +607,10 @@ impl CommandManager {
             TcpListenerCmd::Activate { address } => {
                 self.activate_listener(address.into(), ListenerType::Tcp)
             }
-            TcpListenerCmd::Deactivate { address } => {
-                self.deactivate_listener(address.into(), ListenerType::Tcp)
-            }
+            TcpListenerCmd::Deactivate {
+                address,
+                drain_timeout,
+            } => self.deactivate_listener(address.into(), ListenerType::Tcp, drain_timeout),
         }
     }
 
@@ -473,15 +651,21 @@ impl CommandManager {
         &mut self,
         address: SocketAddress,
         listener_type: ListenerType,
+        drain_timeout: Option<u32>,
     ) -> Result<(), CtlError> {
-        self.send_request(
-            RequestType::DeactivateListener(DeactivateListener {
-                address,
-                proxy: listener_type.into(),
-                to_scm: false,
-            })
-            .into(),
-        )
+        let request = RequestType::DeactivateListener(DeactivateListener {
+            address,
+            proxy: listener_type.into(),
+            to_scm: false,
+            drain_timeout,
+        })
+        .into();
+
+        // draining lasts as long as the established connections
+        match drain_timeout {
+            Some(_) => self.send_request_no_timeout(request),
+            None => self.send_request(request),
+        }
     }
 
     pub fn logging_filter(&mut self, filter: String) -> Result<(), CtlError> {
@@ -495,12 +679,14 @@ impl CommandManager {
         certificate_chain_path: &str,
         key_path: &str,
         versions: Vec<TlsVersion>,
+        cipher_list: Vec<String>,
     ) -> Result<(), CtlError> {
         let new_certificate = load_full_certificate(
             certificate_path,
             certificate_chain_path,
             key_path,
             versions,
+            cipher_list,
             vec![],
         )
         .map_err(CtlError::LoadCertificate)?;
@@ -525,6 +711,7 @@ impl CommandManager {
         old_certificate_path: Option<&str>,
         old_fingerprint: Option<&str>,
         versions: Vec<TlsVersion>,
+        cipher_list: Vec<String>,
     ) -> Result<(), CtlError> {
         let old_fingerprint = match (old_certificate_path, old_fingerprint) {
             (None, None) | (Some(_), Some(_)) => {
@@ -547,6 +734,7 @@ impl CommandManager {
             new_certificate_chain_path,
             new_key_path,
             versions,
+            cipher_list,
             vec![],
         )
         .map_err(CtlError::LoadCertificate)?;
@@ -595,6 +783,27 @@ impl CommandManager {
         )
     }
 
+    pub fn pin_certificate(
+        &mut self,
+        address: SocketAddress,
+        domain: String,
+        fingerprint: Option<&str>,
+    ) -> Result<(), CtlError> {
+        let fingerprint = fingerprint
+            .map(decode_fingerprint)
+            .transpose()
+            .map_err(CtlError::DecodeFingerprint)?;
+
+        self.send_request(
+            RequestType::PinCertificate(PinCertificate {
+                address,
+                domain,
+                fingerprint: fingerprint.map(|fingerprint| fingerprint.to_string()),
+            })
+            .into(),
+        )
+    }
+
     pub fn query_certificates(
         &mut self,
         fingerprint: Option<String>,
@@ -618,3 +827,11 @@ impl CommandManager {
         self.send_request(RequestType::UpgradeWorker(worker_id).into())
     }
 }
+
+/// splits a `key=value` tag filter, a filter without value matches any value
+pub fn split_tag_filter(tag: String) -> (String, Option<String>) {
+    match tag.split_once('=') {
+        Some((key, value)) => (key.to_owned(), Some(value.to_owned())),
+        None => (tag, None),
+    }
+}
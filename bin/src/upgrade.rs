@@ -1,7 +1,8 @@
 use std::{
-    fs::File,
+    fs::{self, File},
     io::{Error as IoError, Write},
     io::{Read, Seek},
+    os::unix::fs::PermissionsExt,
     os::unix::io::{AsRawFd, FromRawFd},
     os::unix::process::CommandExt,
     process::Command,
@@ -11,7 +12,8 @@ use libc::pid_t;
 use mio::net::UnixStream;
 use nix::{
     errno::Errno,
-    unistd::{fork, ForkResult},
+    sys::signal::{kill, Signal},
+    unistd::{fork, ForkResult, Pid},
 };
 use serde_json::Error as SerdeError;
 use tempfile::tempfile;
@@ -19,6 +21,7 @@ use tempfile::tempfile;
 use sozu_command_lib::{
     channel::{Channel, ChannelError},
     logging::{setup_logging_with_config, LogError},
+    proto::command::RunState,
 };
 
 use crate::{
@@ -71,6 +74,39 @@ pub enum UpgradeError {
     EnableCloexec(ServerError),
     #[error("could not setup the logger: {0}")]
     SetupLogging(LogError),
+    #[error("could not access the new binary {path}: {io_error}")]
+    BinaryMetadata { path: String, io_error: IoError },
+    #[error("the new binary {0} is not an executable file")]
+    NotExecutable(String),
+    #[error("the new main process registered {registered} workers out of {expected}")]
+    MissingWorkers { expected: usize, registered: usize },
+}
+
+/// checks that the binary of the new main process can be executed, before forking
+pub fn check_executable(path: &str) -> Result<(), UpgradeError> {
+    let metadata = fs::metadata(path).map_err(|io_error| UpgradeError::BinaryMetadata {
+        path: path.to_owned(),
+        io_error,
+    })?;
+
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        return Err(UpgradeError::NotExecutable(path.to_owned()));
+    }
+    Ok(())
+}
+
+/// kills a new main process that did not reach the ready state,
+/// so that the old main process stays in charge
+pub fn kill_new_main(pid: pid_t) {
+    match kill(Pid::from_raw(pid), Signal::SIGKILL) {
+        Ok(()) | Err(Errno::ESRCH) => {}
+        Err(e) => error!("could not kill the new main process {}: {}", pid, e),
+    }
+
+    // reap the process so that it does not stay as a zombie
+    unsafe {
+        libc::waitpid(pid, std::ptr::null_mut(), 0);
+    }
 }
 
 /// unix-forks the main process
@@ -179,6 +215,11 @@ pub fn begin_new_main_process(
         serde_json::from_str(&content).map_err(UpgradeError::SerdeReadError)?;
 
     let config = upgrade_data.config.clone();
+    let expected_workers = upgrade_data
+        .workers
+        .iter()
+        .filter(|w| w.run_state != RunState::Stopped && w.run_state != RunState::Stopping)
+        .count();
 
     println!("Setting up logging");
 
@@ -192,6 +233,21 @@ pub fn begin_new_main_process(
         .enable_cloexec_after_upgrade()
         .map_err(UpgradeError::EnableCloexec)?;
 
+    // the old main process rolls back if we did not take over all its workers
+    let registered_workers = command_hub.workers.len();
+    if registered_workers < expected_workers {
+        fork_confirmation_channel
+            .write_message(&false)
+            .map_err(|channel_err| UpgradeError::SendConfirmation {
+                result: "failure".to_string(),
+                channel_err,
+            })?;
+        return Err(UpgradeError::MissingWorkers {
+            expected: expected_workers,
+            registered: registered_workers,
+        });
+    }
+
     util::write_pid_file(&config).map_err(UpgradeError::WritePidFile)?;
 
     fork_confirmation_channel
@@ -201,6 +257,15 @@ pub fn begin_new_main_process(
             channel_err,
         })?;
 
+    // the old main process stands by until the upgrade is finished, workers must not inherit it
+    if let Err(e) = util::enable_close_on_exec(new_to_old_channel_fd) {
+        error!(
+            "Could not enable cloexec on the channel to the old main: {}",
+            e
+        );
+    }
+    command_hub.old_main = Some(fork_confirmation_channel);
+
     info!("starting new main loop");
     command_hub.run();
 
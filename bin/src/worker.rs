@@ -1,14 +1,17 @@
-#[cfg(target_os = "freebsd")]
-use std::{ffi::c_void, iter::repeat, mem::size_of};
 use std::{
-    fs::File,
+    backtrace::Backtrace,
+    fs::{self, File},
     io::Error as IoError,
     io::Seek,
     net::SocketAddr,
-    os::unix::io::{AsRawFd, FromRawFd, IntoRawFd},
+    os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, IntoRawFd},
     os::unix::process::CommandExt,
+    panic,
+    path::{Path, PathBuf},
     process::Command,
 };
+#[cfg(target_os = "freebsd")]
+use std::{ffi::c_void, iter::repeat, mem::size_of};
 
 use libc::pid_t;
 
@@ -23,7 +26,7 @@ use sozu_command_lib::{
     channel::{Channel, ChannelError},
     config::Config,
     logging::{setup_logging, AccessLogFormat, LogError},
-    proto::command::{ServerConfig, WorkerRequest, WorkerResponse},
+    proto::command::{CrashReport, ServerConfig, WorkerRequest, WorkerResponse},
     ready::Ready,
     request::{read_initial_state_from_file, RequestError},
     scm_socket::{Listeners, ScmSocket, ScmSocketError},
@@ -31,6 +34,7 @@ use sozu_command_lib::{
 };
 
 use sozu_lib::{
+    crash,
     metrics::{self, MetricError},
     server::{Server, ServerError as LibServerError},
 };
@@ -100,7 +104,7 @@ pub fn begin_worker_process(
             channel_err,
         })?;
 
-    let mut configuration_state_file = unsafe { File::from_raw_fd(configuration_state_fd) };
+    let configuration_state_file = unsafe { File::from_raw_fd(configuration_state_fd) };
 
     let worker_config = worker_to_main_channel
         .read_message()
@@ -129,6 +133,39 @@ pub fn begin_worker_process(
     );
     info!("worker {} starting...", id);
 
+    let crash_reports_dir = worker_config.crash_reports_dir.clone();
+    // the main process reads the crash report once the channel closes, this
+    // duplicate keeps it open until the report of a fatal error is written
+    let mut channel_guard = None;
+    if let Some(dir) = &crash_reports_dir {
+        register_crash_report_hook(dir.to_owned(), id as u32);
+        channel_guard = unsafe { BorrowedFd::borrow_raw(worker_to_main_channel_fd) }
+            .try_clone_to_owned()
+            .ok();
+    }
+
+    let result = run_worker(
+        worker_to_main_channel,
+        worker_to_main_scm_fd,
+        configuration_state_file,
+        worker_config,
+        worker_id,
+    );
+
+    if let (Err(error), Some(dir)) = (&result, &crash_reports_dir) {
+        write_crash_report(dir, crash::collect(id as u32, error.to_string(), None));
+    }
+    drop(channel_guard);
+    result
+}
+
+fn run_worker(
+    mut worker_to_main_channel: Channel<WorkerResponse, ServerConfig>,
+    worker_to_main_scm_fd: i32,
+    mut configuration_state_file: File,
+    worker_config: ServerConfig,
+    worker_id: String,
+) -> Result<(), WorkerError> {
     let initial_state = read_initial_state_from_file(&mut configuration_state_file)
         .map_err(WorkerError::ReadRequestsFromFile)?;
 
@@ -163,6 +200,10 @@ pub fn begin_worker_process(
             scm_err,
         })?;
 
+    // the keys of the initial state are loaded when the server is created
+    #[cfg(feature = "pkcs11")]
+    sozu_lib::pkcs11::register();
+
     let mut server = Server::try_new_from_config(
         worker_to_main_channel,
         worker_to_main_scm_socket,
@@ -178,6 +219,51 @@ pub fn begin_worker_process(
     Ok(())
 }
 
+/// writes a crash report when the worker panics, before the previous hook runs
+fn register_crash_report_hook(crash_reports_dir: String, worker_id: u32) {
+    let previous_hook = panic::take_hook();
+
+    panic::set_hook(Box::new(move |panic_info| {
+        let backtrace = Backtrace::force_capture().to_string();
+        let report = crash::collect(worker_id, panic_info.to_string(), Some(backtrace));
+        write_crash_report(&crash_reports_dir, report);
+
+        (*previous_hook)(panic_info)
+    }));
+}
+
+/// path of the crash report of a worker process
+fn crash_report_path(crash_reports_dir: &str, worker_id: u32, pid: i32) -> PathBuf {
+    Path::new(crash_reports_dir).join(format!("worker-{worker_id}-{pid}.json"))
+}
+
+fn write_crash_report(crash_reports_dir: &str, mut report: CrashReport) {
+    let path = crash_report_path(crash_reports_dir, report.worker_id, report.pid);
+    report.path = Some(path.to_string_lossy().into_owned());
+
+    let written = fs::create_dir_all(crash_reports_dir).and_then(|()| {
+        let json = serde_json::to_vec_pretty(&report)?;
+        fs::write(&path, json)
+    });
+    match written {
+        Ok(()) => error!("wrote crash report to {}", path.display()),
+        Err(e) => error!("could not write crash report to {}: {}", path.display(), e),
+    }
+}
+
+/// reads the crash report of a worker process, if it wrote one
+pub fn read_crash_report(crash_reports_dir: &str, worker_id: u32, pid: i32) -> Option<CrashReport> {
+    let path = crash_report_path(crash_reports_dir, worker_id, pid);
+    let content = fs::read(&path).ok()?;
+    match serde_json::from_slice(&content) {
+        Ok(report) => Some(report),
+        Err(e) => {
+            error!("could not parse crash report {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
 /// unix-forks the main process
 ///
 /// - Parent: sends config, state and listeners to the new worker
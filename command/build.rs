@@ -6,6 +6,8 @@ pub fn main() {
         .enum_attribute(".", "#[serde(rename_all = \"SCREAMING_SNAKE_CASE\")]")
         .enum_attribute("Order", "#[derive(Hash, Eq, Ord, PartialOrd)]")
         .enum_attribute("request_type", "#[derive(Hash, Eq, Ord, PartialOrd)]")
+        // listener configurations are much larger than other requests, and rarely sent
+        .enum_attribute("request_type", "#[allow(clippy::large_enum_variant)]")
         .enum_attribute("inner", "#[derive(Hash, Eq, Ord, PartialOrd)]")
         .enum_attribute("content_type", "#[derive(Hash, Eq, Ord, PartialOrd)]")
         .out_dir("src/proto")
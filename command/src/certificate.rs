@@ -213,6 +213,16 @@ pub fn get_fingerprint_from_certificate_path(
     Ok(Fingerprint(parsed_bytes))
 }
 
+/// true if a name of a certificate, possibly a wildcard like `*.example.org`, covers the domain
+pub fn name_covers_domain(name: &str, domain: &str) -> bool {
+    match name.strip_prefix("*.") {
+        Some(parent) => domain
+            .split_once('.')
+            .is_some_and(|(_, domain_parent)| domain_parent.eq_ignore_ascii_case(parent)),
+        None => name.eq_ignore_ascii_case(domain),
+    }
+}
+
 pub fn decode_fingerprint(fingerprint: &str) -> Result<Fingerprint, CertificateError> {
     let bytes = hex::decode(fingerprint).map_err(CertificateError::DecodeError)?;
     Ok(Fingerprint(bytes))
@@ -223,6 +233,7 @@ pub fn load_full_certificate(
     certificate_chain_path: &str,
     key_path: &str,
     versions: Vec<TlsVersion>,
+    cipher_list: Vec<String>,
     names: Vec<String>,
 ) -> Result<CertificateAndKey, CertificateError> {
     let certificate =
@@ -251,6 +262,7 @@ pub fn load_full_certificate(
         key,
         versions,
         names,
+        cipher_list,
     })
 }
 
@@ -1,5 +1,6 @@
 use std::{
     cmp::min,
+    collections::VecDeque,
     fmt::Debug,
     io::{self, ErrorKind, Read, Write},
     marker::PhantomData,
@@ -29,6 +30,8 @@ pub enum ChannelError {
     Write(std::io::Error),
     #[error("channel buffer is full ({0} bytes), cannot grow more")]
     BufferFull(usize),
+    #[error("the peer does not read the channel, {0} bytes are already waiting")]
+    Saturated(usize),
     #[error("Timeout is reached: {0:?}")]
     TimeoutReached(Duration),
     #[error("Could not read anything on the channel")]
@@ -52,6 +55,12 @@ pub enum ChannelError {
 /// by serializing them in a binary format, with a fix-sized delimiter.
 /// To function, channels must come in pairs, one for each agent.
 /// They can function in a blocking or non-blocking way.
+///
+/// A nonblocking channel does not refuse a message because its back buffer is full:
+/// messages that do not fit are queued, and streamed into the back buffer as the
+/// socket drains. Once `max_buffer_size` bytes wait for the peer, the channel is
+/// saturated and refuses new messages: check [`Channel::is_saturated`] before sending
+/// more to a slow peer.
 pub struct Channel<Tx, Rx> {
     pub sock: MioUnixStream,
     pub front_buf: Buffer,
@@ -60,6 +69,12 @@ pub struct Channel<Tx, Rx> {
     pub readiness: Ready,
     pub interest: Ready,
     blocking: bool,
+    /// serialized messages, with their delimiter, waiting for room in the back buffer
+    queue: VecDeque<Vec<u8>>,
+    /// how much of the first queued message was already copied in the back buffer
+    queue_offset: usize,
+    /// bytes of the queue not yet copied in the back buffer
+    queued_bytes: usize,
     phantom_tx: PhantomData<Tx>,
     phantom_rx: PhantomData<Rx>,
 }
@@ -104,6 +119,9 @@ impl<Tx: Debug + ProstMessage + Default, Rx: Debug + ProstMessage + Default> Cha
             readiness: Ready::EMPTY,
             interest: Ready::READABLE,
             blocking: false,
+            queue: VecDeque::new(),
+            queue_offset: 0,
+            queued_bytes: 0,
             phantom_tx: PhantomData,
             phantom_rx: PhantomData,
         }
@@ -120,6 +138,9 @@ impl<Tx: Debug + ProstMessage + Default, Rx: Debug + ProstMessage + Default> Cha
             readiness: self.readiness,
             interest: self.interest,
             blocking: self.blocking,
+            queue: self.queue,
+            queue_offset: self.queue_offset,
+            queued_bytes: self.queued_bytes,
             phantom_tx: PhantomData,
             phantom_rx: PhantomData,
         }
@@ -252,6 +273,7 @@ impl<Tx: Debug + ProstMessage + Default, Rx: Debug + ProstMessage + Default> Cha
 
         let mut count = 0usize;
         loop {
+            self.fill_back_buffer();
             let size = self.back_buf.available_data();
             if size == 0 {
                 self.interest.remove(Ready::WRITABLE);
@@ -364,6 +386,15 @@ impl<Tx: Debug + ProstMessage + Default, Rx: Debug + ProstMessage + Default> Cha
                 self.front_buf.consume(message_len);
                 return Ok(Some(message));
             }
+
+            // make room for the whole message at once, instead of growing step by step
+            if message_len > self.front_buf.capacity() {
+                if self.front_buf.capacity() as u64 >= self.max_buffer_size {
+                    return Err(ChannelError::BufferFull(self.front_buf.capacity()));
+                }
+                self.front_buf
+                    .grow(min(message_len, self.max_buffer_size as usize));
+            }
         }
 
         if self.front_buf.available_space() == 0 {
@@ -392,14 +423,70 @@ impl<Tx: Debug + ProstMessage + Default, Rx: Debug + ProstMessage + Default> Cha
 
     /// Writes the message in the buffer, but NOT on the socket.
     /// you have to call channel.run() afterwards
+    ///
+    /// The message is queued if the back buffer can not hold it, or if other
+    /// messages are already queued, to keep them in order. It is refused if
+    /// the channel is saturated.
     fn write_message_nonblocking(&mut self, message: &Tx) -> Result<(), ChannelError> {
-        self.write_delimited_message(message)?;
+        if self.is_saturated() {
+            return Err(ChannelError::Saturated(self.pending_bytes()));
+        }
+        if self.queue.is_empty() {
+            match self.write_delimited_message(message) {
+                Ok(()) => {}
+                Err(ChannelError::MessageTooLarge(_)) => self.enqueue(message),
+                Err(error) => return Err(error),
+            }
+        } else {
+            self.enqueue(message);
+        }
 
         self.interest.insert(Ready::WRITABLE);
 
         Ok(())
     }
 
+    fn enqueue(&mut self, message: &Tx) {
+        let payload = message.encode_to_vec();
+        let payload_len = payload.len() + delimiter_size();
+
+        let mut delimited = Vec::with_capacity(payload_len);
+        delimited.extend_from_slice(&payload_len.to_le_bytes());
+        delimited.extend_from_slice(&payload);
+
+        self.queued_bytes += payload_len;
+        self.queue.push_back(delimited);
+    }
+
+    /// Copies as much of the queue as possible in the back buffer. Large messages
+    /// are copied in several chunks, the back buffer never grows for them.
+    fn fill_back_buffer(&mut self) {
+        if self.queue.is_empty() {
+            return;
+        }
+        self.back_buf.shift();
+
+        while let Some(message) = self.queue.front() {
+            let chunk_len = min(
+                message.len() - self.queue_offset,
+                self.back_buf.available_space(),
+            );
+            if chunk_len == 0 {
+                break;
+            }
+            let chunk = &message[self.queue_offset..self.queue_offset + chunk_len];
+            self.back_buf.space()[..chunk_len].copy_from_slice(chunk);
+            self.back_buf.fill(chunk_len);
+
+            self.queued_bytes -= chunk_len;
+            self.queue_offset += chunk_len;
+            if self.queue_offset == message.len() {
+                self.queue.pop_front();
+                self.queue_offset = 0;
+            }
+        }
+    }
+
     /// fills the back buffer with data AND writes on the socket
     fn write_message_blocking(&mut self, message: &Tx) -> Result<(), ChannelError> {
         self.write_delimited_message(message)?;
@@ -457,6 +544,27 @@ impl<Tx: Debug + ProstMessage + Default, Rx: Debug + ProstMessage + Default> Cha
     }
 }
 
+impl<Tx, Rx> Channel<Tx, Rx> {
+    /// number of messages waiting for room in the back buffer
+    pub fn queue_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// bytes not yet written on the socket, in the back buffer and in the queue
+    pub fn pending_bytes(&self) -> usize {
+        self.back_buf.available_data() + self.queued_bytes
+    }
+
+    pub fn has_pending_writes(&self) -> bool {
+        self.pending_bytes() > 0
+    }
+
+    /// the peer does not read as fast as we write, more messages would only pile up in memory
+    pub fn is_saturated(&self) -> bool {
+        self.pending_bytes() >= self.max_buffer_size as usize
+    }
+}
+
 /// the payload is prefixed with a delimiter of sizeof(usize) bytes
 pub const fn delimiter_size() -> usize {
     std::mem::size_of::<usize>()
@@ -687,4 +795,68 @@ mod tests {
             .expect("Could not read message on channel");
         assert_eq!(message_2, ProtobufMessage { inner: 2 });
     }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LargeMessage {
+        #[prost(bytes = "vec", required, tag = "1")]
+        payload: Vec<u8>,
+    }
+
+    #[test]
+    fn queue_messages_larger_than_the_back_buffer() {
+        let (writer, reader) = MioUnixStream::pair().expect("could not create a socket pair");
+        let mut writing_channel: Channel<LargeMessage, LargeMessage> =
+            Channel::new(writer, 1000, 2000);
+        let mut reading_channel: Channel<LargeMessage, LargeMessage> =
+            Channel::new(reader, 1000, 100_000);
+        reading_channel.blocking().expect("Could not block channel");
+
+        let messages: Vec<LargeMessage> = (0..10u8)
+            .map(|i| LargeMessage {
+                payload: vec![i; 5000],
+            })
+            .collect();
+        writing_channel
+            .write_message(&messages[0])
+            .expect("a nonblocking channel should queue large messages");
+        assert_eq!(writing_channel.queue_len(), 1);
+        assert!(writing_channel.is_saturated());
+        assert!(matches!(
+            writing_channel.write_message(&messages[1]),
+            Err(ChannelError::Saturated(_))
+        ));
+
+        let reading = thread::spawn(move || {
+            (0..10)
+                .map(|_| {
+                    reading_channel
+                        .read_message()
+                        .expect("Could not read message on channel")
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let mut to_send = messages[1..].iter().peekable();
+        while to_send.peek().is_some() || writing_channel.has_pending_writes() {
+            if let Some(message) = to_send.peek() {
+                if !writing_channel.is_saturated() {
+                    writing_channel
+                        .write_message(message)
+                        .expect("an unsaturated channel should accept messages");
+                    to_send.next();
+                }
+            }
+            writing_channel.handle_events(Ready::WRITABLE);
+            writing_channel
+                .writable()
+                .expect("Could not write on the channel");
+            // at most one message beyond the saturation threshold
+            assert!(writing_channel.pending_bytes() < 2000 + 5100);
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(writing_channel.back_buf.capacity(), 1000);
+
+        let received = reading.join().expect("the reading thread panicked");
+        assert_eq!(received, messages);
+    }
 }
@@ -0,0 +1,445 @@
+//! Asynchronous client for the command socket of the main process
+//!
+//! [`CommandClient`] frames the requests the same way as [`Channel`](crate::channel::Channel),
+//! waits for the final answer of the main process and extracts the typed content
+//! of the response, so that a controller running on tokio does not have to block
+//! on a channel like `sozu ctl` does.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), sozu_command_lib::client::ClientError> {
+//! use sozu_command_lib::{
+//!     client::CommandClient,
+//!     proto::command::{AddBackend, FrontendFilters, SocketAddress},
+//! };
+//!
+//! let mut client = CommandClient::connect("/run/sozu/sozu.sock").await?;
+//!
+//! client
+//!     .add_backend(AddBackend {
+//!         cluster_id: "my-cluster".to_string(),
+//!         backend_id: "my-backend".to_string(),
+//!         address: SocketAddress::new_v4(127, 0, 0, 1, 8000),
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//!
+//! let frontends = client
+//!     .list_frontends(FrontendFilters {
+//!         http: true,
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//! println!("{} HTTP frontends", frontends.http_frontends.len());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Available with the `async-client` feature.
+
+use std::{path::Path, time::Duration};
+
+use prost::{DecodeError, Message};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+
+use crate::{
+    channel::delimiter_size,
+    config::DEFAULT_MAX_COMMAND_BUFFER_SIZE,
+    proto::command::{
+        request::RequestType, response_content::ContentType, AddBackend, AddCertificate, Cluster,
+        Event, FrontendFilters, HardStop, ListListeners, ListWorkers, ListedFrontends,
+        ListenersList, RemoveBackend, RemoveCertificate, Request, RequestHttpFrontend,
+        RequestTcpFrontend, Response, ResponseStatus, SoftStop, Status, SubscribeEvents,
+        WorkerInfos,
+    },
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ClientError {
+    #[error("could not connect to the command socket: {0}")]
+    Connect(std::io::Error),
+    #[error("could not write the request: {0}")]
+    Write(std::io::Error),
+    #[error("could not read the response: {0}")]
+    Read(std::io::Error),
+    #[error("the response is too large: {0} bytes")]
+    MessageTooLarge(usize),
+    #[error("invalid protobuf message: {0}")]
+    InvalidProtobufMessage(DecodeError),
+    #[error("no response after {0:?}")]
+    Timeout(Duration),
+    #[error("the request failed: {0}")]
+    Failure(String),
+    #[error("expected a response with {expected}, got {got:?}")]
+    UnexpectedContent {
+        expected: &'static str,
+        got: Option<ContentType>,
+    },
+}
+
+/// Sends requests to the main process over its unix socket, one at a time
+#[derive(Debug)]
+pub struct CommandClient {
+    stream: UnixStream,
+    max_message_size: usize,
+    timeout: Option<Duration>,
+}
+
+impl CommandClient {
+    /// connects to the command socket, given its path (the `command_socket` of the configuration)
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self, ClientError> {
+        let stream = UnixStream::connect(path)
+            .await
+            .map_err(ClientError::Connect)?;
+        Ok(Self::from_stream(stream))
+    }
+
+    pub fn from_stream(stream: UnixStream) -> Self {
+        Self {
+            stream,
+            max_message_size: DEFAULT_MAX_COMMAND_BUFFER_SIZE as usize,
+            timeout: None,
+        }
+    }
+
+    /// maximum size of a response, like `max_command_buffer_size` in the configuration
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// fail the requests not answered within this duration, they wait forever by default
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Writes a request and resolves to the final response of the main process,
+    /// skipping the `Processing` ones. A `Failure` resolves to an error.
+    pub async fn send(&mut self, request: impl Into<Request>) -> Result<Response, ClientError> {
+        self.write_request(&request.into()).await?;
+
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.read_final_response())
+                .await
+                .map_err(|_| ClientError::Timeout(timeout))?,
+            None => self.read_final_response().await,
+        }
+    }
+
+    async fn read_final_response(&mut self) -> Result<Response, ClientError> {
+        loop {
+            let response = self.read_response().await?;
+            match response.status() {
+                ResponseStatus::Processing => {
+                    debug!("processing: {}", response.message);
+                }
+                ResponseStatus::Failure => return Err(ClientError::Failure(response.message)),
+                ResponseStatus::Ok => return Ok(response),
+            }
+        }
+    }
+
+    async fn write_request(&mut self, request: &Request) -> Result<(), ClientError> {
+        let payload = request.encode_to_vec();
+        // the delimiter counts its own size, see Channel::write_delimited_message
+        let delimiter = (payload.len() + delimiter_size()).to_le_bytes();
+
+        let mut message = Vec::with_capacity(delimiter.len() + payload.len());
+        message.extend_from_slice(&delimiter);
+        message.extend_from_slice(&payload);
+
+        self.stream
+            .write_all(&message)
+            .await
+            .map_err(ClientError::Write)
+    }
+
+    /// reads the next message of the main process, whatever its status
+    pub async fn read_response(&mut self) -> Result<Response, ClientError> {
+        let mut delimiter = [0u8; std::mem::size_of::<usize>()];
+        self.stream
+            .read_exact(&mut delimiter)
+            .await
+            .map_err(ClientError::Read)?;
+
+        let message_len = usize::from_le_bytes(delimiter);
+        if message_len > self.max_message_size {
+            return Err(ClientError::MessageTooLarge(message_len));
+        }
+
+        let mut payload = vec![0u8; message_len.saturating_sub(delimiter_size())];
+        self.stream
+            .read_exact(&mut payload)
+            .await
+            .map_err(ClientError::Read)?;
+
+        Response::decode(payload.as_slice()).map_err(ClientError::InvalidProtobufMessage)
+    }
+
+    /// sends a request that does not return any content
+    async fn execute(&mut self, request: RequestType) -> Result<(), ClientError> {
+        self.send(request).await.map(|_| ())
+    }
+
+    pub async fn status(&mut self) -> Result<WorkerInfos, ClientError> {
+        match self
+            .send(RequestType::Status(Status {}))
+            .await?
+            .into_content()
+        {
+            Some(ContentType::Workers(worker_infos)) => Ok(worker_infos),
+            got => Err(ClientError::UnexpectedContent {
+                expected: "worker infos",
+                got,
+            }),
+        }
+    }
+
+    pub async fn list_workers(&mut self) -> Result<WorkerInfos, ClientError> {
+        match self
+            .send(RequestType::ListWorkers(ListWorkers {}))
+            .await?
+            .into_content()
+        {
+            Some(ContentType::Workers(worker_infos)) => Ok(worker_infos),
+            got => Err(ClientError::UnexpectedContent {
+                expected: "worker infos",
+                got,
+            }),
+        }
+    }
+
+    pub async fn list_frontends(
+        &mut self,
+        filters: FrontendFilters,
+    ) -> Result<ListedFrontends, ClientError> {
+        match self
+            .send(RequestType::ListFrontends(filters))
+            .await?
+            .into_content()
+        {
+            Some(ContentType::FrontendList(frontends)) => Ok(frontends),
+            got => Err(ClientError::UnexpectedContent {
+                expected: "a list of frontends",
+                got,
+            }),
+        }
+    }
+
+    pub async fn list_listeners(&mut self) -> Result<ListenersList, ClientError> {
+        match self
+            .send(RequestType::ListListeners(ListListeners {}))
+            .await?
+            .into_content()
+        {
+            Some(ContentType::ListenersList(listeners)) => Ok(listeners),
+            got => Err(ClientError::UnexpectedContent {
+                expected: "a list of listeners",
+                got,
+            }),
+        }
+    }
+
+    pub async fn add_cluster(&mut self, cluster: Cluster) -> Result<(), ClientError> {
+        self.execute(RequestType::AddCluster(cluster)).await
+    }
+
+    pub async fn remove_cluster(&mut self, cluster_id: String) -> Result<(), ClientError> {
+        self.execute(RequestType::RemoveCluster(cluster_id)).await
+    }
+
+    pub async fn add_http_frontend(
+        &mut self,
+        frontend: RequestHttpFrontend,
+    ) -> Result<(), ClientError> {
+        self.execute(RequestType::AddHttpFrontend(frontend)).await
+    }
+
+    pub async fn remove_http_frontend(
+        &mut self,
+        frontend: RequestHttpFrontend,
+    ) -> Result<(), ClientError> {
+        self.execute(RequestType::RemoveHttpFrontend(frontend))
+            .await
+    }
+
+    pub async fn add_https_frontend(
+        &mut self,
+        frontend: RequestHttpFrontend,
+    ) -> Result<(), ClientError> {
+        self.execute(RequestType::AddHttpsFrontend(frontend)).await
+    }
+
+    pub async fn remove_https_frontend(
+        &mut self,
+        frontend: RequestHttpFrontend,
+    ) -> Result<(), ClientError> {
+        self.execute(RequestType::RemoveHttpsFrontend(frontend))
+            .await
+    }
+
+    pub async fn add_tcp_frontend(
+        &mut self,
+        frontend: RequestTcpFrontend,
+    ) -> Result<(), ClientError> {
+        self.execute(RequestType::AddTcpFrontend(frontend)).await
+    }
+
+    pub async fn remove_tcp_frontend(
+        &mut self,
+        frontend: RequestTcpFrontend,
+    ) -> Result<(), ClientError> {
+        self.execute(RequestType::RemoveTcpFrontend(frontend)).await
+    }
+
+    pub async fn add_backend(&mut self, backend: AddBackend) -> Result<(), ClientError> {
+        self.execute(RequestType::AddBackend(backend)).await
+    }
+
+    pub async fn remove_backend(&mut self, backend: RemoveBackend) -> Result<(), ClientError> {
+        self.execute(RequestType::RemoveBackend(backend)).await
+    }
+
+    pub async fn add_certificate(
+        &mut self,
+        certificate: AddCertificate,
+    ) -> Result<(), ClientError> {
+        self.execute(RequestType::AddCertificate(certificate)).await
+    }
+
+    pub async fn remove_certificate(
+        &mut self,
+        certificate: RemoveCertificate,
+    ) -> Result<(), ClientError> {
+        self.execute(RequestType::RemoveCertificate(certificate))
+            .await
+    }
+
+    /// path on the machine of the main process
+    pub async fn save_state(&mut self, path: String) -> Result<(), ClientError> {
+        self.execute(RequestType::SaveState(path)).await
+    }
+
+    /// path on the machine of the main process
+    pub async fn load_state(&mut self, path: String) -> Result<(), ClientError> {
+        self.execute(RequestType::LoadState(path)).await
+    }
+
+    pub async fn soft_stop(&mut self) -> Result<(), ClientError> {
+        self.execute(RequestType::SoftStop(SoftStop {})).await
+    }
+
+    pub async fn hard_stop(&mut self) -> Result<(), ClientError> {
+        self.execute(RequestType::HardStop(HardStop {})).await
+    }
+
+    /// Asks for the events of the workers, to be read with [`CommandClient::next_event`].
+    /// The main process does not answer this request.
+    pub async fn subscribe_events(&mut self) -> Result<(), ClientError> {
+        self.write_request(&RequestType::SubscribeEvents(SubscribeEvents {}).into())
+            .await
+    }
+
+    /// waits for the next event of the workers, once subscribed
+    pub async fn next_event(&mut self) -> Result<Event, ClientError> {
+        loop {
+            if let Some(ContentType::Event(event)) = self.read_response().await?.into_content() {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::runtime::Builder;
+
+    use crate::{
+        channel::Channel,
+        proto::command::{ListedFrontends, ResponseContent},
+    };
+
+    #[test]
+    fn typed_requests() {
+        let (client_stream, server_stream) =
+            std::os::unix::net::UnixStream::pair().expect("could not create a socket pair");
+
+        // a main process answering with a blocking channel
+        let server = std::thread::spawn(move || {
+            let mut channel: Channel<Response, Request> =
+                Channel::new(mio::net::UnixStream::from_std(server_stream), 1000, 10000);
+            channel.blocking().expect("could not block the channel");
+
+            let request = channel.read_message().expect("could not read the request");
+            assert!(matches!(
+                request.request_type,
+                Some(RequestType::ListFrontends(_))
+            ));
+            channel
+                .write_message(&Response {
+                    status: ResponseStatus::Processing.into(),
+                    message: "listing".to_string(),
+                    content: None,
+                })
+                .expect("could not write");
+            channel
+                .write_message(&Response {
+                    status: ResponseStatus::Ok.into(),
+                    message: String::new(),
+                    content: Some(ResponseContent {
+                        content_type: Some(ContentType::FrontendList(ListedFrontends {
+                            http_frontends: vec![RequestHttpFrontend {
+                                hostname: "example.com".to_string(),
+                                ..Default::default()
+                            }],
+                            ..Default::default()
+                        })),
+                    }),
+                })
+                .expect("could not write");
+
+            let request = channel.read_message().expect("could not read the request");
+            assert!(matches!(
+                request.request_type,
+                Some(RequestType::RemoveCluster(_))
+            ));
+            channel
+                .write_message(&Response {
+                    status: ResponseStatus::Failure.into(),
+                    message: "no such cluster".to_string(),
+                    content: None,
+                })
+                .expect("could not write");
+        });
+
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("could not build the runtime");
+
+        runtime.block_on(async {
+            client_stream
+                .set_nonblocking(true)
+                .expect("could not unblock the stream");
+            let mut client = CommandClient::from_stream(
+                UnixStream::from_std(client_stream).expect("could not register the stream"),
+            );
+
+            let frontends = client
+                .list_frontends(FrontendFilters::default())
+                .await
+                .expect("could not list the frontends");
+            assert_eq!(frontends.http_frontends[0].hostname, "example.com");
+
+            let failure = client.remove_cluster("cluster_1".to_string()).await;
+            assert!(matches!(failure, Err(ClientError::Failure(message)) if message == "no such cluster"));
+        });
+
+        server.join().expect("the server panicked");
+    }
+}
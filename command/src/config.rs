@@ -62,11 +62,12 @@ use crate::{
     logging::AccessLogFormat,
     proto::command::{
         request::RequestType, ActivateListener, AddBackend, AddCertificate, CertificateAndKey,
-        Cluster, CustomHttpAnswers, HttpListenerConfig, HttpsListenerConfig, ListenerType,
-        LoadBalancingAlgorithms, LoadBalancingParams, LoadMetric, MetricsConfiguration, PathRule,
-        ProtobufAccessLogFormat, ProxyProtocolConfig, Request, RequestHttpFrontend,
-        RequestTcpFrontend, RulePosition, ServerConfig, ServerMetricsConfig, SocketAddress,
-        TcpListenerConfig, TlsVersion, WorkerRequest,
+        Cluster, CustomHttpAnswers, FallbackCertificatePolicy, HttpListenerConfig,
+        HttpsListenerConfig, ListenerType, LoadBalancingAlgorithms, LoadBalancingParams,
+        LoadMetric, MatchingOptions, MetricsConfiguration, PathRule, ProtobufAccessLogFormat,
+        ProxyProtocolConfig, Request, RequestHttpFrontend, RequestTcpFrontend,
+        ResponseClassification, RulePosition, ServerConfig, ServerMetricsConfig, SocketAddress,
+        StickySessionFallback, TcpListenerConfig, TlsVersion, WorkerRequest,
     },
     ObjectKind,
 };
@@ -243,6 +244,7 @@ pub struct ListenerBuilder {
     pub answer_301: Option<String>,
     pub answer_400: Option<String>,
     pub answer_401: Option<String>,
+    pub answer_403: Option<String>,
     pub answer_404: Option<String>,
     pub answer_408: Option<String>,
     pub answer_413: Option<String>,
@@ -273,6 +275,25 @@ pub struct ListenerBuilder {
     /// The ticket allow the client to resume a session. This protects the client
     /// agains session tracking. Defaults to 4.
     pub send_tls13_tickets: Option<u64>,
+    /// on an IPv6 address, only accept IPv6 connections. If false, IPv4 clients are
+    /// accepted as IPv4-mapped addresses. Defaults to the system setting
+    pub v6only: Option<bool>,
+    /// HTTPS only: what to do with a handshake without SNI. Defaults to REJECT
+    pub no_sni_policy: Option<FallbackCertificatePolicy>,
+    /// HTTPS only: what to do with a handshake whose SNI matches no certificate.
+    /// Defaults to DEFAULT_CERTIFICATE, the certificate of the listener if it has one
+    pub unknown_sni_policy: Option<FallbackCertificatePolicy>,
+    /// maximum number of new connections accepted per second, unlimited if unset
+    pub accept_rate: Option<u32>,
+    /// new connections accepted at once above the rate. Defaults to the rate
+    pub accept_burst: Option<u32>,
+    /// HTTP and HTTPS only: reject the backend responses that break HTTP framing
+    /// instead of correcting them. Defaults to false
+    pub strict_responses: Option<bool>,
+    /// HTTP and HTTPS only: key of the signatures of the Sozu-Debug-Trace header
+    pub debug_trace_key: Option<String>,
+    /// tags added to those of every frontend of the listener, like the datacenter
+    pub tags: Option<BTreeMap<String, String>>,
 }
 
 pub fn default_sticky_name() -> String {
@@ -301,10 +322,13 @@ impl ListenerBuilder {
     /// starts building a Listener
     fn new(address: SocketAddress, protocol: ListenerProtocol) -> ListenerBuilder {
         ListenerBuilder {
+            accept_burst: None,
+            accept_rate: None,
             address: address.into(),
             answer_301: None,
             answer_401: None,
             answer_400: None,
+            answer_403: None,
             answer_404: None,
             answer_408: None,
             answer_413: None,
@@ -322,12 +346,18 @@ impl ListenerBuilder {
             expect_proxy: None,
             front_timeout: None,
             key: None,
+            no_sni_policy: None,
             protocol: Some(protocol),
             public_address: None,
             request_timeout: None,
             send_tls13_tickets: None,
             sticky_name: DEFAULT_STICKY_NAME.to_string(),
+            strict_responses: None,
+            debug_trace_key: None,
+            tags: None,
             tls_versions: None,
+            unknown_sni_policy: None,
+            v6only: None,
         }
     }
 
@@ -378,6 +408,52 @@ impl ListenerBuilder {
         self
     }
 
+    pub fn with_v6only(&mut self, v6only: Option<bool>) -> &mut Self {
+        self.v6only = v6only;
+        self
+    }
+
+    pub fn with_accept_pacing(
+        &mut self,
+        accept_rate: Option<u32>,
+        accept_burst: Option<u32>,
+    ) -> &mut Self {
+        self.accept_rate = accept_rate;
+        self.accept_burst = accept_burst;
+        self
+    }
+
+    pub fn with_strict_responses(&mut self, strict_responses: Option<bool>) -> &mut Self {
+        self.strict_responses = strict_responses;
+        self
+    }
+
+    pub fn with_debug_trace_key<S>(&mut self, debug_trace_key: Option<S>) -> &mut Self
+    where
+        S: ToString,
+    {
+        self.debug_trace_key = debug_trace_key.map(|key| key.to_string());
+        self
+    }
+
+    pub fn with_tags(&mut self, tags: Option<BTreeMap<String, String>>) -> &mut Self {
+        self.tags = tags;
+        self
+    }
+
+    pub fn with_no_sni_policy(&mut self, policy: Option<FallbackCertificatePolicy>) -> &mut Self {
+        self.no_sni_policy = policy;
+        self
+    }
+
+    pub fn with_unknown_sni_policy(
+        &mut self,
+        policy: Option<FallbackCertificatePolicy>,
+    ) -> &mut Self {
+        self.unknown_sni_policy = policy;
+        self
+    }
+
     pub fn with_sticky_name<S>(&mut self, sticky_name: Option<S>) -> &mut Self
     where
         S: ToString,
@@ -442,6 +518,7 @@ impl ListenerBuilder {
             answer_503: read_http_answer_file(&self.answer_503)?,
             answer_504: read_http_answer_file(&self.answer_504)?,
             answer_507: read_http_answer_file(&self.answer_507)?,
+            answer_403: read_http_answer_file(&self.answer_403)?,
         };
         Ok(Some(http_answers))
     }
@@ -479,6 +556,12 @@ impl ListenerBuilder {
             connect_timeout: self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
             request_timeout: self.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT),
             http_answers,
+            v6only: self.v6only,
+            accept_rate: self.accept_rate,
+            accept_burst: self.accept_burst,
+            strict_responses: self.strict_responses,
+            debug_trace_key: self.debug_trace_key.clone(),
+            tags: self.tags.clone().unwrap_or_default(),
             ..Default::default()
         };
 
@@ -578,6 +661,14 @@ impl ListenerBuilder {
                 .send_tls13_tickets
                 .unwrap_or(DEFAULT_SEND_TLS_13_TICKETS),
             http_answers,
+            v6only: self.v6only,
+            no_sni_policy: self.no_sni_policy.map(|policy| policy as i32),
+            unknown_sni_policy: self.unknown_sni_policy.map(|policy| policy as i32),
+            accept_rate: self.accept_rate,
+            accept_burst: self.accept_burst,
+            strict_responses: self.strict_responses,
+            debug_trace_key: self.debug_trace_key.clone(),
+            tags: self.tags.clone().unwrap_or_default(),
         };
 
         Ok(https_listener_config)
@@ -604,6 +695,10 @@ impl ListenerBuilder {
             back_timeout: self.back_timeout.unwrap_or(DEFAULT_BACK_TIMEOUT),
             connect_timeout: self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
             active: false,
+            v6only: self.v6only,
+            accept_rate: self.accept_rate,
+            accept_burst: self.accept_burst,
+            tags: self.tags.clone().unwrap_or_default(),
         })
     }
 }
@@ -664,9 +759,20 @@ pub struct FileClusterFrontendConfig {
     pub certificate_chain: Option<String>,
     #[serde(default)]
     pub tls_versions: Vec<TlsVersion>,
+    /// cipher suites of the handshakes with the certificate, those of the listener if empty
+    #[serde(default)]
+    pub cipher_list: Vec<String>,
     #[serde(default)]
     pub position: RulePosition,
     pub tags: Option<BTreeMap<String, String>>,
+    /// compare the hostname and path of requests regardless of their case
+    pub case_insensitive: Option<bool>,
+    /// the path matches with or without a trailing slash
+    pub ignore_trailing_slash: Option<bool>,
+    /// decode the percent-encoded characters of the request path before matching it
+    pub percent_decode: Option<bool>,
+    /// the responses carry headers describing the routing decision, for debugging
+    pub debug_trace: Option<bool>,
 }
 
 impl FileClusterFrontendConfig {
@@ -748,10 +854,27 @@ impl FileClusterFrontendConfig {
             key: key_opt,
             certificate_chain,
             tls_versions: self.tls_versions.clone(),
+            cipher_list: self.cipher_list.clone(),
             position: self.position,
             path,
             method: self.method.clone(),
             tags: self.tags.clone(),
+            matching: self.matching_options(),
+            debug_trace: self.debug_trace,
+        })
+    }
+
+    fn matching_options(&self) -> Option<MatchingOptions> {
+        if self.case_insensitive.is_none()
+            && self.ignore_trailing_slash.is_none()
+            && self.percent_decode.is_none()
+        {
+            return None;
+        }
+        Some(MatchingOptions {
+            case_insensitive: self.case_insensitive,
+            ignore_trailing_slash: self.ignore_trailing_slash,
+            percent_decode: self.percent_decode,
         })
     }
 }
@@ -786,6 +909,59 @@ pub struct FileClusterConfig {
     pub answer_503: Option<String>,
     #[serde(default)]
     pub load_metric: Option<LoadMetric>,
+    /// maximum percentage of connection retries over recent requests
+    pub retry_budget: Option<u32>,
+    /// delay in milliseconds before hedging an idempotent request to another backend
+    pub hedge_delay: Option<u32>,
+    /// free-form labels, like the owner, the environment or a ticket URL
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// TCP only: seconds without traffic after which a connection is closed
+    pub idle_timeout: Option<u32>,
+    /// TCP only: seconds after which a connection is closed, even if active
+    pub max_connection_lifetime: Option<u32>,
+    /// HTTP only: what to do when the backend of a sticky session is unavailable
+    pub sticky_session_fallback: Option<StickySessionFallback>,
+    /// HTTP only: which responses count as backend failures
+    pub response_classification: Option<FileResponseClassification>,
+    /// HTTP only: percentage of a buffer above which reads of a body pause
+    pub stream_high_watermark: Option<u32>,
+    /// HTTP only: percentage of a buffer under which paused reads resume
+    pub stream_low_watermark: Option<u32>,
+    /// HTTP only: paths still served over HTTP when `https_redirect` is set
+    #[serde(default)]
+    pub https_redirect_exemptions: Vec<String>,
+    /// HTTP only: protocols of the `Upgrade` header allowed, all of them if empty
+    #[serde(default)]
+    pub allowed_upgrades: Vec<String>,
+}
+
+/// A response is a backend failure if its status is in `failure_statuses`,
+/// or is a 5xx not in `healthy_statuses`, or if it has one of the `failure_headers`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileResponseClassification {
+    pub failure_statuses: Vec<u16>,
+    pub healthy_statuses: Vec<u16>,
+    pub failure_headers: BTreeMap<String, String>,
+}
+
+impl From<FileResponseClassification> for ResponseClassification {
+    fn from(classification: FileResponseClassification) -> Self {
+        ResponseClassification {
+            failure_statuses: classification
+                .failure_statuses
+                .into_iter()
+                .map(u32::from)
+                .collect(),
+            healthy_statuses: classification
+                .healthy_statuses
+                .into_iter()
+                .map(u32::from)
+                .collect(),
+            failure_headers: classification.failure_headers,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -796,6 +972,28 @@ pub struct BackendConfig {
     pub sticky_id: Option<String>,
     pub backup: Option<bool>,
     pub backend_id: Option<String>,
+    /// maximum number of simultaneous connections to this backend
+    pub max_connections: Option<u32>,
+    /// time in milliseconds a request may wait for a saturated backend
+    pub queue_timeout: Option<u32>,
+    /// priority tier of the backend, lower tiers are used first
+    pub priority: Option<u32>,
+    /// duration in seconds over which the weight of a new backend is ramped up
+    pub slow_start: Option<u32>,
+    /// other addresses of the backend, tried when connecting to the main one is slow or fails
+    #[serde(default)]
+    pub alternate_addresses: Vec<SocketAddr>,
+}
+
+impl BackendConfig {
+    pub fn load_balancing_parameters(&self) -> LoadBalancingParams {
+        LoadBalancingParams {
+            weight: self.weight.unwrap_or(100) as i32,
+            max_connections: self.max_connections,
+            queue_timeout: self.queue_timeout,
+            slow_start: self.slow_start,
+        }
+    }
 }
 
 impl FileClusterConfig {
@@ -854,6 +1052,10 @@ impl FileClusterConfig {
                     proxy_protocol,
                     load_balancing: self.load_balancing,
                     load_metric: self.load_metric,
+                    retry_budget: self.retry_budget,
+                    labels: self.labels,
+                    idle_timeout: self.idle_timeout,
+                    max_connection_lifetime: self.max_connection_lifetime,
                 }))
             }
             FileClusterProtocolConfig::Http => {
@@ -880,7 +1082,16 @@ impl FileClusterConfig {
                     https_redirect: self.https_redirect.unwrap_or(false),
                     load_balancing: self.load_balancing,
                     load_metric: self.load_metric,
+                    retry_budget: self.retry_budget,
+                    hedge_delay: self.hedge_delay,
                     answer_503,
+                    labels: self.labels,
+                    sticky_session_fallback: self.sticky_session_fallback,
+                    response_classification: self.response_classification.map(Into::into),
+                    stream_high_watermark: self.stream_high_watermark,
+                    stream_low_watermark: self.stream_low_watermark,
+                    https_redirect_exemptions: self.https_redirect_exemptions,
+                    allowed_upgrades: self.allowed_upgrades,
                 }))
             }
         }
@@ -900,8 +1111,12 @@ pub struct HttpFrontendConfig {
     #[serde(default)]
     pub tls_versions: Vec<TlsVersion>,
     #[serde(default)]
+    pub cipher_list: Vec<String>,
+    #[serde(default)]
     pub position: RulePosition,
     pub tags: Option<BTreeMap<String, String>>,
+    pub matching: Option<MatchingOptions>,
+    pub debug_trace: Option<bool>,
 }
 
 impl HttpFrontendConfig {
@@ -927,6 +1142,7 @@ impl HttpFrontendConfig {
                         // As a result, we will reject legit traffic for others domains as the certificate resolver will
                         // not load twice the same certificate and then do not register the certificate for others domains.
                         names: vec![],
+                        cipher_list: self.cipher_list.clone(),
                     },
                     expired_at: None,
                 })
@@ -942,6 +1158,8 @@ impl HttpFrontendConfig {
                     method: self.method.clone(),
                     position: self.position.into(),
                     tags,
+                    matching: self.matching.clone(),
+                    debug_trace: self.debug_trace,
                 })
                 .into(),
             );
@@ -956,6 +1174,8 @@ impl HttpFrontendConfig {
                     method: self.method.clone(),
                     position: self.position.into(),
                     tags,
+                    matching: self.matching.clone(),
+                    debug_trace: self.debug_trace,
                 })
                 .into(),
             );
@@ -976,6 +1196,15 @@ pub struct HttpClusterConfig {
     pub load_balancing: LoadBalancingAlgorithms,
     pub load_metric: Option<LoadMetric>,
     pub answer_503: Option<String>,
+    pub retry_budget: Option<u32>,
+    pub hedge_delay: Option<u32>,
+    pub labels: BTreeMap<String, String>,
+    pub sticky_session_fallback: Option<StickySessionFallback>,
+    pub response_classification: Option<ResponseClassification>,
+    pub stream_high_watermark: Option<u32>,
+    pub stream_low_watermark: Option<u32>,
+    pub https_redirect_exemptions: Vec<String>,
+    pub allowed_upgrades: Vec<String>,
 }
 
 impl HttpClusterConfig {
@@ -988,6 +1217,17 @@ impl HttpClusterConfig {
             load_balancing: self.load_balancing as i32,
             answer_503: self.answer_503.clone(),
             load_metric: self.load_metric.map(|s| s as i32),
+            retry_budget: self.retry_budget,
+            hedge_delay: self.hedge_delay,
+            labels: self.labels.clone(),
+            idle_timeout: None,
+            max_connection_lifetime: None,
+            sticky_session_fallback: self.sticky_session_fallback.map(|s| s as i32),
+            response_classification: self.response_classification.clone(),
+            stream_high_watermark: self.stream_high_watermark,
+            stream_low_watermark: self.stream_low_watermark,
+            https_redirect_exemptions: self.https_redirect_exemptions.clone(),
+            allowed_upgrades: self.allowed_upgrades.clone(),
         })
         .into()];
 
@@ -997,9 +1237,7 @@ impl HttpClusterConfig {
         }
 
         for (backend_count, backend) in self.backends.iter().enumerate() {
-            let load_balancing_parameters = Some(LoadBalancingParams {
-                weight: backend.weight.unwrap_or(100) as i32,
-            });
+            let load_balancing_parameters = Some(backend.load_balancing_parameters());
 
             v.push(
                 RequestType::AddBackend(AddBackend {
@@ -1011,6 +1249,12 @@ impl HttpClusterConfig {
                     load_balancing_parameters,
                     sticky_id: backend.sticky_id.clone(),
                     backup: backend.backup,
+                    priority: backend.priority,
+                    alternate_addresses: backend
+                        .alternate_addresses
+                        .iter()
+                        .map(|address| (*address).into())
+                        .collect(),
                 })
                 .into(),
             );
@@ -1035,6 +1279,13 @@ pub struct TcpClusterConfig {
     pub proxy_protocol: Option<ProxyProtocolConfig>,
     pub load_balancing: LoadBalancingAlgorithms,
     pub load_metric: Option<LoadMetric>,
+    pub retry_budget: Option<u32>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub idle_timeout: Option<u32>,
+    #[serde(default)]
+    pub max_connection_lifetime: Option<u32>,
 }
 
 impl TcpClusterConfig {
@@ -1047,6 +1298,17 @@ impl TcpClusterConfig {
             load_balancing: self.load_balancing as i32,
             load_metric: self.load_metric.map(|s| s as i32),
             answer_503: None,
+            retry_budget: self.retry_budget,
+            hedge_delay: None,
+            labels: self.labels.clone(),
+            idle_timeout: self.idle_timeout,
+            max_connection_lifetime: self.max_connection_lifetime,
+            sticky_session_fallback: None,
+            response_classification: None,
+            stream_high_watermark: None,
+            stream_low_watermark: None,
+            https_redirect_exemptions: Vec::new(),
+            allowed_upgrades: Vec::new(),
         })
         .into()];
 
@@ -1062,9 +1324,7 @@ impl TcpClusterConfig {
         }
 
         for (backend_count, backend) in self.backends.iter().enumerate() {
-            let load_balancing_parameters = Some(LoadBalancingParams {
-                weight: backend.weight.unwrap_or(100) as i32,
-            });
+            let load_balancing_parameters = Some(backend.load_balancing_parameters());
 
             v.push(
                 RequestType::AddBackend(AddBackend {
@@ -1076,6 +1336,12 @@ impl TcpClusterConfig {
                     load_balancing_parameters,
                     sticky_id: backend.sticky_id.clone(),
                     backup: backend.backup,
+                    priority: backend.priority,
+                    alternate_addresses: backend
+                        .alternate_addresses
+                        .iter()
+                        .map(|address| (*address).into())
+                        .collect(),
                 })
                 .into(),
             );
@@ -1127,6 +1393,8 @@ pub struct FileConfig {
     pub worker_automatic_restart: Option<bool>,
     pub metrics: Option<MetricsConfig>,
     pub disable_cluster_metrics: Option<bool>,
+    pub metrics_tag: Option<String>,
+    pub crash_reports_dir: Option<String>,
     pub listeners: Option<Vec<ListenerBuilder>>,
     pub clusters: Option<HashMap<String, FileClusterConfig>>,
     pub handle_process_affinity: Option<bool>,
@@ -1147,6 +1415,9 @@ pub struct FileConfig {
     pub request_timeout: Option<u32>,
     #[serde(default)]
     pub worker_timeout: Option<u32>,
+    /// address of the `/healthz` and `/readyz` endpoints of the main process
+    #[serde(default)]
+    pub health_check_address: Option<SocketAddr>,
 }
 
 impl FileConfig {
@@ -1256,6 +1527,8 @@ impl ConfigBuilder {
             disable_cluster_metrics: file_config
                 .disable_cluster_metrics
                 .unwrap_or(DEFAULT_DISABLE_CLUSTER_METRICS),
+            metrics_tag: file_config.metrics_tag.clone(),
+            crash_reports_dir: file_config.crash_reports_dir.clone(),
             min_buffers: std::cmp::min(
                 file_config.min_buffers.unwrap_or(DEFAULT_MIN_BUFFERS),
                 file_config.max_buffers.unwrap_or(DEFAULT_MAX_BUFFERS),
@@ -1273,6 +1546,7 @@ impl ConfigBuilder {
                 .zombie_check_interval
                 .unwrap_or(DEFAULT_ZOMBIE_CHECK_INTERVAL),
             worker_timeout: file_config.worker_timeout.unwrap_or(DEFAULT_WORKER_TIMEOUT),
+            health_check_address: file_config.health_check_address,
             ..Default::default()
         };
 
@@ -1493,6 +1767,12 @@ pub struct Config {
     pub metrics: Option<MetricsConfig>,
     #[serde(default = "default_disable_cluster_metrics")]
     pub disable_cluster_metrics: bool,
+    /// frontend tag whose values partition the metrics, for instance "tenant"
+    #[serde(default)]
+    pub metrics_tag: Option<String>,
+    /// workers write a report in this directory when they panic or fail
+    #[serde(default)]
+    pub crash_reports_dir: Option<String>,
     pub http_listeners: Vec<HttpListenerConfig>,
     pub https_listeners: Vec<HttpsListenerConfig>,
     pub tcp_listeners: Vec<TcpListenerConfig>,
@@ -1515,6 +1795,9 @@ pub struct Config {
     pub request_timeout: u32,
     #[serde(default = "default_worker_timeout")]
     pub worker_timeout: u32,
+    /// address of the `/healthz` and `/readyz` endpoints of the main process
+    #[serde(default)]
+    pub health_check_address: Option<SocketAddr>,
 }
 
 fn default_front_timeout() -> u32 {
@@ -1565,7 +1848,7 @@ impl Config {
     /// yields requests intended to recreate a proxy that match the config
     pub fn generate_config_messages(&self) -> Result<Vec<WorkerRequest>, ConfigError> {
         let mut v = Vec::new();
-        let mut count = 0u8;
+        let mut count = 0usize;
 
         for listener in &self.http_listeners {
             v.push(WorkerRequest {
@@ -1801,6 +2084,8 @@ impl fmt::Debug for Config {
             .field("worker_automatic_restart", &self.worker_automatic_restart)
             .field("metrics", &self.metrics)
             .field("disable_cluster_metrics", &self.disable_cluster_metrics)
+            .field("metrics_tag", &self.metrics_tag)
+            .field("crash_reports_dir", &self.crash_reports_dir)
             .field("handle_process_affinity", &self.handle_process_affinity)
             .field("ctl_command_timeout", &self.ctl_command_timeout)
             .field("pid_file_path", &self.pid_file_path)
@@ -1812,6 +2097,7 @@ impl fmt::Debug for Config {
             .field("accept_queue_timeout", &self.accept_queue_timeout)
             .field("request_timeout", &self.request_timeout)
             .field("worker_timeout", &self.worker_timeout)
+            .field("health_check_address", &self.health_check_address)
             .finish()
     }
 }
@@ -1856,6 +2142,8 @@ impl From<&Config> for ServerConfig {
             metrics,
             access_log_format: ProtobufAccessLogFormat::from(&config.access_logs_format) as i32,
             log_colored: config.log_colored,
+            metrics_tag: config.metrics_tag.clone(),
+            crash_reports_dir: config.crash_reports_dir.clone(),
         }
     }
 }
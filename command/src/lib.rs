@@ -12,6 +12,9 @@ pub mod buffer;
 pub mod certificate;
 /// channels used for communication between main process and workers
 pub mod channel;
+/// async client for the command socket
+#[cfg(feature = "async-client")]
+pub mod client;
 /// parse TOML config and generate requests from it
 pub mod config;
 /// parse Requests
@@ -90,6 +90,16 @@ impl CachedTags {
             .join(", ");
         Self { tags, concatenated }
     }
+
+    /// the tags of a frontend, on top of the default tags of its listener
+    pub fn with_defaults(
+        defaults: &BTreeMap<String, String>,
+        tags: BTreeMap<String, String>,
+    ) -> Self {
+        let mut merged = defaults.clone();
+        merged.extend(tags);
+        Self::new(merged)
+    }
 }
 
 #[derive(Debug)]
@@ -5,20 +5,22 @@ use std::{
 };
 
 use prettytable::{cell, row, Row, Table};
-use time::format_description;
+use time::{format_description, OffsetDateTime};
 use x509_parser::time::ASN1Time;
 
 use crate::{
+    certificate::{calculate_fingerprint, Fingerprint},
     proto::{
         command::{
             filtered_metrics, protobuf_endpoint, request::RequestType,
             response_content::ContentType, AggregatedMetrics, AvailableMetrics, CertificateAndKey,
-            CertificateSummary, CertificatesWithFingerprints, ClusterMetrics, CustomHttpAnswers,
-            Event, EventKind, FilteredMetrics, HttpEndpoint, HttpListenerConfig,
-            HttpsListenerConfig, ListOfCertificatesByAddress, ListedFrontends, ListenersList,
-            ProtobufEndpoint, QueryCertificatesFilters, RequestCounts, Response, ResponseContent,
-            ResponseStatus, RunState, SocketAddress, TlsVersion, WorkerInfos, WorkerMetrics,
-            WorkerResponses,
+            CertificateSummary, CertificatesWithFingerprints, ClusterMetrics, CrashReport,
+            CustomHttpAnswers, Event, EventKind, FilteredMetrics, HttpEndpoint, HttpListenerConfig,
+            HttpsListenerConfig, ListOfCertificatesByAddress, ListedFrontends, ListenerType,
+            ListenersList, MetricsStatus, ProtobufEndpoint, ProxyCheckStatus, ProxyChecks,
+            QueryCertificatesFilters, RequestCounts, Response, ResponseContent, ResponseStatus,
+            RunState, ScheduledRequests, SocketAddress, StateChange, TagMetrics, TlsVersion,
+            WorkerInfos, WorkerMetrics, WorkerOutcomes, WorkerResponses,
         },
         DisplayError,
     },
@@ -38,8 +40,9 @@ impl Display for CertificateAndKey {
         });
         write!(
             f,
-            "\tcertificate: {}\n\tcertificate_chain: {:?}\n\tkey: {}\n\tTLS versions: {}\n\tnames: {:?}",
+            "\tcertificate: {}\n\tcertificate_chain: {:?}\n\tkey: {}\n\tTLS versions: {}\n\tcipher list: {:?}\n\tnames: {:?}",
             self.certificate, self.certificate_chain, self.key, versions,
+            concatenate_vector(&self.cipher_list),
             concatenate_vector(&self.names)
         )
     }
@@ -74,11 +77,15 @@ pub fn format_request_type(request_type: &RequestType) -> &str {
         RequestType::CountRequests(_) => "CountRequests",
         RequestType::ListWorkers(_) => "ListWorkers",
         RequestType::ListFrontends(_) => "ListFrontends",
+        RequestType::RemoveFrontends(_) => "RemoveFrontends",
+        RequestType::PinCertificate(_) => "PinCertificate",
         RequestType::ListListeners(_) => "ListListeners",
         RequestType::LaunchWorker(_) => "LaunchWorker",
         RequestType::UpgradeMain(_) => "UpgradeMain",
+        RequestType::FinishMainUpgrade(_) => "FinishMainUpgrade",
         RequestType::UpgradeWorker(_) => "UpgradeWorker",
         RequestType::SubscribeEvents(_) => "SubscribeEvents",
+        RequestType::SubscribeStateChanges(_) => "SubscribeStateChanges",
         RequestType::ReloadConfiguration(_) => "ReloadConfiguration",
         RequestType::Status(_) => "Status",
         RequestType::AddCluster(_) => "AddCluster",
@@ -111,6 +118,14 @@ pub fn format_request_type(request_type: &RequestType) -> &str {
         RequestType::ReturnListenSockets(_) => "ReturnListenSockets",
         RequestType::QueryCertificatesFromTheState(_) => "QueryCertificatesFromTheState",
         RequestType::QueryCertificatesFromWorkers(_) => "QueryCertificatesFromWorkers",
+        RequestType::ScheduleRequest(_) => "ScheduleRequest",
+        RequestType::ListScheduledRequests(_) => "ListScheduledRequests",
+        RequestType::CancelScheduledRequest(_) => "CancelScheduledRequest",
+        RequestType::ConfigureFaultInjection(_) => "ConfigureFaultInjection",
+        RequestType::ReturnSessions(_) => "ReturnSessions",
+        RequestType::AdoptSessions(_) => "AdoptSessions",
+        RequestType::CheckProxy(_) => "CheckProxy",
+        RequestType::QueryMetricsConfiguration(_) => "QueryMetricsConfiguration",
     }
 }
 
@@ -152,7 +167,7 @@ impl Response {
 }
 
 impl ResponseContent {
-    fn display(&self, json: bool) -> Result<(), DisplayError> {
+    pub fn display(&self, json: bool) -> Result<(), DisplayError> {
         let content_type = match &self.content_type {
             Some(content_type) => content_type,
             None => return Ok(println!("No content")),
@@ -179,6 +194,8 @@ impl ResponseContent {
                     print_cluster_infos(worker_responses)
                 } else if worker_responses.contain_cluster_hashes() {
                     print_cluster_hashes(worker_responses)
+                } else if worker_responses.contain_metrics_statuses() {
+                    print_metrics_statuses(worker_responses)
                 } else {
                     print_responses_by_worker(worker_responses, json)
                 }
@@ -186,6 +203,13 @@ impl ResponseContent {
             ContentType::Clusters(_) | ContentType::ClusterHashes(_) => Ok(()), // not displayed directly, see print_cluster_responses
             ContentType::CertificatesByAddress(certs) => print_certificates_by_address(certs),
             ContentType::Event(_event) => Ok(()), // not event displayed yet!
+            ContentType::StateChange(change) => Ok(println!("{change}")),
+            ContentType::WorkerOutcomes(outcomes) => print_worker_outcomes(outcomes),
+            ContentType::ScheduledRequests(scheduled) => print_scheduled_requests(scheduled),
+            ContentType::ProxyChecks(checks) => print_proxy_checks(checks),
+            ContentType::MetricsStatus(status) => {
+                print_metrics_status_table(vec![(String::new(), status)])
+            }
         }
     }
 }
@@ -212,23 +236,34 @@ impl WorkerResponses {
         }
         false
     }
+
+    fn contain_metrics_statuses(&self) -> bool {
+        self.map
+            .values()
+            .any(|response| matches!(response.content_type, Some(ContentType::MetricsStatus(_))))
+    }
 }
 
 pub fn print_status(worker_infos: &WorkerInfos) -> Result<(), DisplayError> {
     let mut table = Table::new();
     table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
-    table.add_row(row!["worker id", "pid", "run state"]);
+    table.add_row(row!["worker id", "pid", "run state", "crash report"]);
 
     let mut sorted_infos = worker_infos.vec.clone();
     sorted_infos.sort_by_key(|worker| worker.id);
 
     for worker_info in &sorted_infos {
+        let crash_report = match &worker_info.crash_report {
+            Some(report) => format!("{}\n{}", report.reason, report.path()),
+            None => String::new(),
+        };
         let row = row!(
             worker_info.id,
             worker_info.pid,
             RunState::try_from(worker_info.run_state)
                 .map_err(DisplayError::DecodeError)?
-                .as_str_name()
+                .as_str_name(),
+            crash_report
         );
         table.add_row(row);
     }
@@ -249,7 +284,7 @@ pub fn print_metrics(aggregated_metrics: &AggregatedMetrics) -> Result<(), Displ
 
     // workers
     for (worker_id, worker) in aggregated_metrics.workers.iter() {
-        if !worker.clusters.is_empty() && !worker.proxy.is_empty() {
+        if (!worker.clusters.is_empty() && !worker.proxy.is_empty()) || !worker.tags.is_empty() {
             println!("\nWorker {worker_id}\n=========");
             print_worker_metrics(worker)?;
         }
@@ -261,6 +296,12 @@ pub fn print_metrics(aggregated_metrics: &AggregatedMetrics) -> Result<(), Displ
         print_cluster_metrics(&aggregated_metrics.clusters);
     }
 
+    // tags
+    if !aggregated_metrics.tags.is_empty() {
+        println!("\nTags\n====");
+        print_tag_metrics(&aggregated_metrics.tags);
+    }
+
     Ok(())
 }
 
@@ -274,10 +315,22 @@ fn print_proxy_metrics(proxy_metrics: &BTreeMap<String, FilteredMetrics>) {
 fn print_worker_metrics(worker_metrics: &WorkerMetrics) -> Result<(), DisplayError> {
     print_proxy_metrics(&worker_metrics.proxy);
     print_cluster_metrics(&worker_metrics.clusters);
+    print_tag_metrics(&worker_metrics.tags);
 
     Ok(())
 }
 
+fn print_tag_metrics(tag_metrics: &BTreeMap<String, TagMetrics>) {
+    for (tag, tag_metrics_data) in tag_metrics.iter() {
+        println!("\nTag {tag}\n--------");
+
+        let filtered = filter_metrics(&tag_metrics_data.metrics);
+        print_gauges_and_counts(&filtered);
+        print_percentiles(&filtered);
+        print_histograms(&filtered);
+    }
+}
+
 fn print_cluster_metrics(cluster_metrics: &BTreeMap<String, ClusterMetrics>) {
     for (cluster_id, cluster_metrics_data) in cluster_metrics.iter() {
         println!("\nCluster {cluster_id}\n--------");
@@ -570,6 +623,15 @@ fn print_frontends(frontends: &ListedFrontends) -> Result<(), DisplayError> {
         }
         table.printstd();
     }
+
+    if let Some(total) = frontends.total {
+        let listed = frontends.http_frontends.len()
+            + frontends.https_frontends.len()
+            + frontends.tcp_frontends.len();
+        if listed < total as usize {
+            println!("{listed} frontends listed out of {total} matching the filters");
+        }
+    }
     Ok(())
 }
 
@@ -619,7 +681,7 @@ pub fn print_listeners(listeners_list: &ListenersList) -> Result<(), DisplayErro
 
 fn print_cluster_infos(worker_responses: &WorkerResponses) -> Result<(), DisplayError> {
     let mut cluster_table = create_cluster_table(
-        vec!["id", "sticky_session", "https_redirect"],
+        vec!["id", "sticky_session", "https_redirect", "labels"],
         &worker_responses.map,
     );
 
@@ -683,22 +745,21 @@ fn print_cluster_infos(worker_responses: &WorkerResponses) -> Result<(), Display
     println!("Cluster level configuration:\n");
 
     for (cluster_info, workers_the_cluster_is_present_on) in cluster_infos.iter() {
-        let mut row = Vec::new();
-        row.push(cell!(cluster_info
-            .configuration
-            .as_ref()
-            .map(|conf| conf.cluster_id.to_owned())
-            .unwrap_or_else(|| String::from("None"))));
-        row.push(cell!(cluster_info
-            .configuration
-            .as_ref()
-            .map(|conf| conf.sticky_session)
-            .unwrap_or_else(|| false)));
-        row.push(cell!(cluster_info
-            .configuration
-            .as_ref()
-            .map(|conf| conf.https_redirect)
-            .unwrap_or_else(|| false)));
+        let configuration = cluster_info.configuration.as_ref();
+        let mut row = vec![
+            cell!(configuration
+                .map(|conf| conf.cluster_id.to_owned())
+                .unwrap_or_else(|| String::from("None"))),
+            cell!(configuration
+                .map(|conf| conf.sticky_session)
+                .unwrap_or_else(|| false)),
+            cell!(configuration
+                .map(|conf| conf.https_redirect)
+                .unwrap_or_else(|| false)),
+            cell!(configuration
+                .map(|conf| format_tags_to_string(&conf.labels))
+                .unwrap_or_default()),
+        ];
 
         for worker in workers_the_cluster_is_present_on {
             if worker_ids.contains(worker) {
@@ -925,6 +986,158 @@ fn print_request_counts(request_counts: &RequestCounts) -> Result<(), DisplayErr
     Ok(())
 }
 
+fn print_worker_outcomes(outcomes: &WorkerOutcomes) -> Result<(), DisplayError> {
+    let mut summary = format!("{} ok, {} errors", outcomes.ok, outcomes.errors);
+    if let Some(count) = outcomes.count {
+        summary.push_str(&format!(", {count} items"));
+    }
+    if outcomes.timed_out {
+        summary.push_str(", timed out");
+    }
+    println!("{summary}");
+
+    if outcomes.workers.iter().any(|worker| worker.timeouts > 0) {
+        let mut table = Table::new();
+        table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+        table.add_row(row!["worker id", "ok", "errors", "timeouts"]);
+        for worker in &outcomes.workers {
+            table.add_row(row!(
+                worker.worker_id,
+                worker.ok,
+                worker.errors,
+                worker.timeouts
+            ));
+        }
+        table.printstd();
+    }
+
+    if outcomes.failures.is_empty() {
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.add_row(row!["worker id", "error"]);
+    for failure in &outcomes.failures {
+        table.add_row(row!(failure.worker_id, failure.message));
+    }
+    table.printstd();
+    Ok(())
+}
+
+fn print_scheduled_requests(scheduled: &ScheduledRequests) -> Result<(), DisplayError> {
+    if scheduled.requests.is_empty() {
+        println!("No scheduled request");
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.add_row(row!["id", "execute at", "request"]);
+    for scheduled_request in &scheduled.requests {
+        let execute_at = OffsetDateTime::from_unix_timestamp(scheduled_request.execute_at)
+            .map_err(|_| DisplayError::DateTime)?
+            .format(&format_description::well_known::Rfc3339)
+            .map_err(|_| DisplayError::DateTime)?;
+        let change = StateChange {
+            request: scheduled_request.request.as_ref().clone(),
+        };
+        table.add_row(row!(
+            scheduled_request.id.unwrap_or_default(),
+            execute_at,
+            change
+        ));
+    }
+    table.printstd();
+    Ok(())
+}
+
+fn print_metrics_statuses(worker_responses: &WorkerResponses) -> Result<(), DisplayError> {
+    let statuses = worker_responses
+        .map
+        .iter()
+        .filter_map(|(worker_id, response)| match &response.content_type {
+            Some(ContentType::MetricsStatus(status)) => Some((worker_id.to_owned(), status)),
+            _ => None,
+        })
+        .collect();
+    print_metrics_status_table(statuses)
+}
+
+fn print_metrics_status_table(statuses: Vec<(String, &MetricsStatus)>) -> Result<(), DisplayError> {
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.add_row(row![
+        "worker",
+        "cluster metrics",
+        "backend metrics",
+        "time metrics",
+        "statsd",
+        "proxy series",
+        "cluster series",
+        "backend series"
+    ]);
+    for (worker_id, status) in statuses {
+        table.add_row(row!(
+            worker_id,
+            enabled(status.cluster_metrics),
+            enabled(status.backend_metrics),
+            enabled(status.time_metrics),
+            status.address.as_deref().unwrap_or("-"),
+            status.proxy_series,
+            status.cluster_series,
+            status.backend_series
+        ));
+    }
+    table.printstd();
+    Ok(())
+}
+
+fn enabled(enabled: bool) -> &'static str {
+    if enabled {
+        "enabled"
+    } else {
+        "disabled"
+    }
+}
+
+fn print_proxy_checks(checks: &ProxyChecks) -> Result<(), DisplayError> {
+    if checks.checks.is_empty() {
+        println!("No listener to check");
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
+    table.add_row(row![
+        "listener",
+        "protocol",
+        "status",
+        "duration (ms)",
+        "message"
+    ]);
+    for check in &checks.checks {
+        let protocol = ListenerType::try_from(check.listener_type)
+            .map(|listener_type| listener_type.as_str_name())
+            .unwrap_or("UNKNOWN");
+        let status = ProxyCheckStatus::try_from(check.status)
+            .map(|status| status.as_str_name())
+            .unwrap_or("UNKNOWN");
+        table.add_row(row!(
+            check.address,
+            protocol,
+            status,
+            check
+                .duration
+                .map(|duration| duration.to_string())
+                .unwrap_or_default(),
+            check.message.as_deref().unwrap_or_default()
+        ));
+    }
+    table.printstd();
+    Ok(())
+}
+
 fn format_tags_to_string(tags: &BTreeMap<String, String>) -> String {
     tags.iter()
         .map(|(k, v)| format!("{k}={v}"))
@@ -1048,6 +1261,11 @@ impl Display for HttpsListenerConfig {
         ]);
         table.add_row(row!["groups list", list_string_vec(&self.groups_list),]);
         table.add_row(row!["key", format!("{:?}", self.key),]);
+        table.add_row(row!["no SNI policy", format!("{:?}", self.no_sni_policy())]);
+        table.add_row(row![
+            "unknown SNI policy",
+            format!("{:?}", self.unknown_sni_policy())
+        ]);
         table.add_row(row!["expect proxy", self.expect_proxy]);
         table.add_row(row!["sticky name", self.sticky_name]);
         table.add_row(row!["front timeout", self.front_timeout]);
@@ -1069,6 +1287,9 @@ impl CustomHttpAnswers {
             if let Some(a) = &answers.answer_400 {
                 rows.push(row!("400", a));
             }
+            if let Some(a) = &answers.answer_403 {
+                rows.push(row!("403", a));
+            }
             if let Some(a) = &answers.answer_404 {
                 rows.push(row!("404", a));
             }
@@ -1102,7 +1323,13 @@ impl Display for Event {
             EventKind::BackendUp => "backend up",
             EventKind::NoAvailableBackends => "no available backends",
             EventKind::RemovedBackendHasNoConnections => "removed backend has no connections",
+            EventKind::BackendSaturated => "backend saturated",
+            EventKind::RetryBudgetExhausted => "retry budget exhausted",
+            EventKind::WorkerCrashed => "worker crashed",
         };
+        if let Some(report) = &self.crash_report {
+            return write!(f, "{kind}, {report}");
+        }
         let address = match &self.address {
             Some(a) => a.to_string(),
             None => String::new(),
@@ -1114,6 +1341,93 @@ impl Display for Event {
             self.backend_id(),
             self.cluster_id(),
             address,
+        )?;
+        if !self.tags.is_empty() {
+            write!(f, ", tags={}", format_tags_to_string(&self.tags))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for CrashReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let crashed_at = OffsetDateTime::from_unix_timestamp(self.crashed_at)
+            .ok()
+            .and_then(|date| date.format(&format_description::well_known::Rfc3339).ok())
+            .unwrap_or_else(|| self.crashed_at.to_string());
+        let sessions = self
+            .sessions
+            .iter()
+            .map(|(protocol, count)| format!("{protocol}={count}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        write!(
+            f,
+            "worker={}, pid={}, at={}, reason={}, last request={}, sessions=[{}], report={}",
+            self.worker_id,
+            self.pid,
+            crashed_at,
+            self.reason,
+            self.last_request_id(),
+            sessions,
+            self.path(),
         )
     }
 }
+
+impl Display for StateChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Some(request_type) = &self.request.request_type else {
+            return write!(f, "empty request");
+        };
+        let details = match request_type {
+            RequestType::AddCluster(cluster) => cluster.cluster_id.to_owned(),
+            RequestType::RemoveCluster(cluster_id) => cluster_id.to_owned(),
+            RequestType::AddHttpFrontend(front)
+            | RequestType::RemoveHttpFrontend(front)
+            | RequestType::AddHttpsFrontend(front)
+            | RequestType::RemoveHttpsFrontend(front) => format!(
+                "{} -> {}",
+                front,
+                front.cluster_id.as_deref().unwrap_or("deny")
+            ),
+            RequestType::AddTcpFrontend(front) | RequestType::RemoveTcpFrontend(front) => {
+                format!("{} -> {}", front.address, front.cluster_id)
+            }
+            RequestType::AddBackend(backend) => format!(
+                "{}/{} {}",
+                backend.cluster_id, backend.backend_id, backend.address
+            ),
+            RequestType::RemoveBackend(backend) => format!(
+                "{}/{} {}",
+                backend.cluster_id, backend.backend_id, backend.address
+            ),
+            RequestType::AddCertificate(add) => {
+                match calculate_fingerprint(add.certificate.certificate.as_bytes()) {
+                    Ok(fingerprint) => format!("{} {}", add.address, Fingerprint(fingerprint)),
+                    Err(_) => add.address.to_string(),
+                }
+            }
+            RequestType::ReplaceCertificate(replace) => {
+                format!("{} {}", replace.address, replace.old_fingerprint)
+            }
+            RequestType::RemoveCertificate(remove) => {
+                format!("{} {}", remove.address, remove.fingerprint)
+            }
+            RequestType::PinCertificate(pin) => format!(
+                "{} {} {}",
+                pin.address,
+                pin.domain,
+                pin.fingerprint.as_deref().unwrap_or("unpinned")
+            ),
+            RequestType::AddHttpListener(listener) => listener.address.to_string(),
+            RequestType::AddHttpsListener(listener) => listener.address.to_string(),
+            RequestType::AddTcpListener(listener) => listener.address.to_string(),
+            RequestType::RemoveListener(remove) => remove.address.to_string(),
+            RequestType::ActivateListener(activate) => activate.address.to_string(),
+            RequestType::DeactivateListener(deactivate) => deactivate.address.to_string(),
+            _ => String::new(),
+        };
+        write!(f, "{} {}", format_request_type(request_type), details)
+    }
+}
@@ -64,6 +64,20 @@ impl AggregatedMetrics {
                 }
             }
 
+            for (tag, tag_metrics) in worker.tags {
+                for (metric_name, new_value) in tag_metrics.metrics {
+                    if new_value.is_mergeable() {
+                        self.tags
+                            .entry(tag.to_owned())
+                            .or_default()
+                            .metrics
+                            .entry(metric_name)
+                            .and_modify(|old_value| old_value.merge(&new_value))
+                            .or_insert(new_value);
+                    }
+                }
+            }
+
             for (cluster_id, mut cluster_metrics) in worker.clusters {
                 for (metric_name, new_value) in cluster_metrics.cluster {
                     if new_value.is_mergeable() {
@@ -13,8 +13,9 @@ use rusty_ulid::Ulid;
 use crate::{
     proto::{
         command::{
-            ip_address, request::RequestType, InitialState, IpAddress, LoadBalancingAlgorithms,
-            PathRuleKind, Request, RequestHttpFrontend, RulePosition, SocketAddress, Uint128,
+            ip_address, request::RequestType, FallbackCertificatePolicy, FrontendFilters,
+            InitialState, IpAddress, LoadBalancingAlgorithms, PathRuleKind, Request,
+            RequestHttpFrontend, RulePosition, SocketAddress, StickySessionFallback, Uint128,
             WorkerRequest,
         },
         display::format_request_type,
@@ -55,7 +56,8 @@ impl Request {
             | RequestType::AddCertificate(_)
             | RequestType::QueryCertificatesFromWorkers(_)
             | RequestType::ReplaceCertificate(_)
-            | RequestType::RemoveCertificate(_) => proxy_destination.to_https_proxy = true,
+            | RequestType::RemoveCertificate(_)
+            | RequestType::PinCertificate(_) => proxy_destination.to_https_proxy = true,
 
             RequestType::AddTcpFrontend(_) | RequestType::RemoveTcpFrontend(_) => {
                 proxy_destination.to_tcp_proxy = true
@@ -75,7 +77,9 @@ impl Request {
 
             // handled at worker level prior to this call
             RequestType::ConfigureMetrics(_)
+            | RequestType::ConfigureFaultInjection(_)
             | RequestType::QueryMetrics(_)
+            | RequestType::QueryMetricsConfiguration(_)
             | RequestType::Logging(_)
             | RequestType::QueryClustersHashes(_)
             | RequestType::QueryClusterById(_)
@@ -89,7 +93,9 @@ impl Request {
             | RequestType::RemoveListener(_)
             | RequestType::ActivateListener(_)
             | RequestType::DeactivateListener(_)
-            | RequestType::ReturnListenSockets(_) => {}
+            | RequestType::ReturnListenSockets(_)
+            | RequestType::ReturnSessions(_)
+            | RequestType::AdoptSessions(_) => {}
 
             // These won't ever reach a worker anyway
             RequestType::SaveState(_)
@@ -98,12 +104,19 @@ impl Request {
             | RequestType::LoadState(_)
             | RequestType::ListWorkers(_)
             | RequestType::ListFrontends(_)
+            | RequestType::RemoveFrontends(_)
             | RequestType::ListListeners(_)
             | RequestType::LaunchWorker(_)
             | RequestType::UpgradeMain(_)
+            | RequestType::FinishMainUpgrade(_)
             | RequestType::UpgradeWorker(_)
             | RequestType::SubscribeEvents(_)
-            | RequestType::ReloadConfiguration(_) => {}
+            | RequestType::SubscribeStateChanges(_)
+            | RequestType::ReloadConfiguration(_)
+            | RequestType::ScheduleRequest(_)
+            | RequestType::ListScheduledRequests(_)
+            | RequestType::CancelScheduledRequest(_)
+            | RequestType::CheckProxy(_) => {}
         }
         proxy_destination
     }
@@ -116,6 +129,33 @@ impl Request {
         )
     }
 
+    /// True if the request modifies the state once dispatched on it
+    pub fn is_a_state_change(&self) -> bool {
+        matches!(
+            self.request_type,
+            Some(RequestType::AddCluster(_))
+                | Some(RequestType::RemoveCluster(_))
+                | Some(RequestType::AddHttpListener(_))
+                | Some(RequestType::AddHttpsListener(_))
+                | Some(RequestType::AddTcpListener(_))
+                | Some(RequestType::RemoveListener(_))
+                | Some(RequestType::ActivateListener(_))
+                | Some(RequestType::DeactivateListener(_))
+                | Some(RequestType::AddHttpFrontend(_))
+                | Some(RequestType::RemoveHttpFrontend(_))
+                | Some(RequestType::AddHttpsFrontend(_))
+                | Some(RequestType::RemoveHttpsFrontend(_))
+                | Some(RequestType::AddTcpFrontend(_))
+                | Some(RequestType::RemoveTcpFrontend(_))
+                | Some(RequestType::AddCertificate(_))
+                | Some(RequestType::ReplaceCertificate(_))
+                | Some(RequestType::RemoveCertificate(_))
+                | Some(RequestType::PinCertificate(_))
+                | Some(RequestType::AddBackend(_))
+                | Some(RequestType::RemoveBackend(_))
+        )
+    }
+
     pub fn short_name(&self) -> &str {
         match &self.request_type {
             Some(request_type) => format_request_type(request_type),
@@ -173,10 +213,25 @@ impl RequestHttpFrontend {
                 }
             })?,
             tags: Some(self.tags),
+            matching: self.matching,
+            debug_trace: self.debug_trace,
         })
     }
 }
 
+impl FrontendFilters {
+    /// True if the filters select frontends by more than their kind: empty filters match
+    /// every frontend, pagination does not count
+    pub fn selects_frontends(&self) -> bool {
+        let is_set = |filter: &Option<String>| filter.as_deref().is_some_and(|f| !f.is_empty());
+        is_set(&self.domain)
+            || is_set(&self.cluster_id)
+            || self.address.is_some()
+            || is_set(&self.path)
+            || is_set(&self.tag_key)
+    }
+}
+
 impl Display for RequestHttpFrontend {
     /// Used to create a unique summary of the frontend, used as a key in maps
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -228,11 +283,64 @@ impl FromStr for LoadBalancingAlgorithms {
             "random" => Ok(LoadBalancingAlgorithms::Random),
             "power_of_two" => Ok(LoadBalancingAlgorithms::PowerOfTwo),
             "least_loaded" => Ok(LoadBalancingAlgorithms::LeastLoaded),
+            "least_response_time" => Ok(LoadBalancingAlgorithms::LeastResponseTime),
             _ => Err(ParseErrorLoadBalancing {}),
         }
     }
 }
 
+#[derive(Debug)]
+pub struct ParseErrorStickySessionFallback;
+
+impl fmt::Display for ParseErrorStickySessionFallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unknown sticky session fallback, expected 'rebalance' or 'fail'"
+        )
+    }
+}
+
+impl error::Error for ParseErrorStickySessionFallback {}
+
+impl FromStr for StickySessionFallback {
+    type Err = ParseErrorStickySessionFallback;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rebalance" => Ok(StickySessionFallback::Rebalance),
+            "fail" => Ok(StickySessionFallback::Fail),
+            _ => Err(ParseErrorStickySessionFallback {}),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseErrorFallbackCertificatePolicy;
+
+impl fmt::Display for ParseErrorFallbackCertificatePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unknown fallback certificate policy, expected 'default' or 'reject'"
+        )
+    }
+}
+
+impl error::Error for ParseErrorFallbackCertificatePolicy {}
+
+impl FromStr for FallbackCertificatePolicy {
+    type Err = ParseErrorFallbackCertificatePolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "default" | "default_certificate" => Ok(FallbackCertificatePolicy::DefaultCertificate),
+            "reject" => Ok(FallbackCertificatePolicy::Reject),
+            _ => Err(ParseErrorFallbackCertificatePolicy {}),
+        }
+    }
+}
+
 impl SocketAddress {
     pub fn new_v4(a: u8, b: u8, c: u8, d: u8, port: u16) -> Self {
         SocketAddr::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), port).into()
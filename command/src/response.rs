@@ -2,9 +2,9 @@ use std::{cmp::Ordering, collections::BTreeMap, fmt, net::SocketAddr};
 
 use crate::{
     proto::command::{
-        AddBackend, FilteredTimeSerie, LoadBalancingParams, PathRule, PathRuleKind,
-        RequestHttpFrontend, RequestTcpFrontend, Response, ResponseContent, ResponseStatus,
-        RulePosition, RunState, WorkerResponse,
+        response_content::ContentType, AddBackend, FilteredTimeSerie, LoadBalancingParams,
+        MatchingOptions, PathRule, PathRuleKind, RequestHttpFrontend, RequestTcpFrontend, Response,
+        ResponseContent, ResponseStatus, RulePosition, RunState, WorkerResponse,
     },
     state::ClusterId,
 };
@@ -21,6 +21,11 @@ impl Response {
             content,
         }
     }
+
+    /// the typed content of the response, if any
+    pub fn into_content(self) -> Option<ContentType> {
+        self.content.and_then(|content| content.content_type)
+    }
 }
 
 /// An HTTP or HTTPS frontend, as used *within* Sōzu
@@ -39,6 +44,13 @@ pub struct HttpFrontend {
     #[serde(default)]
     pub position: RulePosition,
     pub tags: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matching: Option<MatchingOptions>,
+    /// the responses carry the routing decision trace headers
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug_trace: Option<bool>,
 }
 
 impl From<HttpFrontend> for RequestHttpFrontend {
@@ -55,6 +67,8 @@ impl From<HttpFrontend> for RequestHttpFrontend {
             method: val.method,
             position: val.position.into(),
             tags,
+            matching: val.matching,
+            debug_trace: val.debug_trace,
         }
     }
 }
@@ -68,6 +82,12 @@ impl From<Backend> for AddBackend {
             sticky_id: val.sticky_id,
             load_balancing_parameters: val.load_balancing_parameters,
             backup: val.backup,
+            priority: val.priority,
+            alternate_addresses: val
+                .alternate_addresses
+                .into_iter()
+                .map(Into::into)
+                .collect(),
         }
     }
 }
@@ -126,6 +146,24 @@ impl PathRule {
     }
 }
 
+impl MatchingOptions {
+    /// returns None if no option is set, to keep the default matching
+    pub fn from_cli_options(
+        case_insensitive: bool,
+        ignore_trailing_slash: bool,
+        percent_decode: bool,
+    ) -> Option<Self> {
+        if !(case_insensitive || ignore_trailing_slash || percent_decode) {
+            return None;
+        }
+        Some(MatchingOptions {
+            case_insensitive: Some(case_insensitive),
+            ignore_trailing_slash: Some(ignore_trailing_slash),
+            percent_decode: Some(percent_decode),
+        })
+    }
+}
+
 pub fn is_default_path_rule(p: &PathRule) -> bool {
     PathRuleKind::try_from(p.kind) == Ok(PathRuleKind::Prefix) && p.value.is_empty()
 }
@@ -175,6 +213,12 @@ pub struct Backend {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub backup: Option<bool>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u32>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub alternate_addresses: Vec<SocketAddr>,
 }
 
 impl Ord for Backend {
@@ -188,6 +232,8 @@ impl Ord for Backend {
                     .cmp(&o.load_balancing_parameters),
             )
             .then(self.backup.cmp(&o.backup))
+            .then(self.priority.cmp(&o.priority))
+            .then(self.alternate_addresses.cmp(&o.alternate_addresses))
             .then(socketaddr_cmp(&self.address, &o.address))
     }
 }
@@ -207,6 +253,12 @@ impl Backend {
             backend_id: self.backend_id,
             load_balancing_parameters: self.load_balancing_parameters,
             backup: self.backup,
+            priority: self.priority,
+            alternate_addresses: self
+                .alternate_addresses
+                .into_iter()
+                .map(Into::into)
+                .collect(),
         }
     }
 }
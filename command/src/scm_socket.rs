@@ -1,6 +1,6 @@
 use std::{
     io::{IoSlice, IoSliceMut},
-    net::{AddrParseError, SocketAddr},
+    net::{AddrParseError, SocketAddr, TcpStream as StdTcpStream},
     os::unix::{
         io::{FromRawFd, IntoRawFd, RawFd},
         net::UnixStream as StdUnixStream,
@@ -11,10 +11,12 @@ use mio::net::TcpListener;
 use nix::{cmsg_space, sys::socket};
 use prost::{DecodeError, Message};
 
-use crate::proto::command::ListenersCount;
+use crate::proto::command::{HandedOffSession, HandedOffSessions, ListenersCount};
 
 pub const MAX_FDS_OUT: usize = 200;
 pub const MAX_BYTES_OUT: usize = 4096;
+/// beyond this size, a handed off session is sent without the details of its request
+pub const MAX_SESSION_BYTES_OUT: usize = 256;
 
 #[derive(thiserror::Error, Debug)]
 pub enum ScmSocketError {
@@ -38,6 +40,8 @@ pub enum ScmSocketError {
     },
     #[error("error decoding the protobuf format of the listeners: {0}")]
     DecodeError(DecodeError),
+    #[error("expected {expected} file descriptors with the sessions, received {received}")]
+    SessionSockets { expected: usize, received: usize },
 }
 
 /// A unix socket specialized for file descriptor passing
@@ -151,6 +155,88 @@ impl ScmSocket {
         Ok(Listeners { http, tls, tcp })
     }
 
+    /// Send established sessions and the file descriptors of their sockets via an scm socket,
+    /// in as many messages as needed
+    pub fn send_sessions(&self, sessions: &[SessionSockets]) -> Result<(), ScmSocketError> {
+        let mut remaining = sessions;
+        loop {
+            let mut message = HandedOffSessions {
+                sessions: Vec::new(),
+                last: false,
+            };
+            let mut file_descriptors: Vec<RawFd> = Vec::new();
+
+            while let Some((first, rest)) = remaining.split_first() {
+                let mut session = first.session.clone();
+                // the details of the upgraded request only go to the access logs
+                if session.encoded_len() > MAX_SESSION_BYTES_OUT {
+                    session.method = None;
+                    session.authority = None;
+                    session.path = None;
+                }
+                if !message.sessions.is_empty()
+                    && (message.encoded_len() + session.encoded_len() + 16 > MAX_BYTES_OUT
+                        || file_descriptors.len() + 2 > MAX_FDS_OUT)
+                {
+                    break;
+                }
+                message.sessions.push(session);
+                file_descriptors.push(first.frontend);
+                file_descriptors.push(first.backend);
+                remaining = rest;
+            }
+
+            message.last = remaining.is_empty();
+            self.send_msg_and_fds(&message.encode_length_delimited_to_vec(), &file_descriptors)?;
+            if message.last {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Receive established sessions and the file descriptors of their sockets via an scm socket
+    pub fn receive_sessions(&self) -> Result<Vec<SessionSockets>, ScmSocketError> {
+        let mut sessions = Vec::new();
+        loop {
+            let mut buf = vec![0; MAX_BYTES_OUT];
+            let mut received_fds: [RawFd; MAX_FDS_OUT] = [0; MAX_FDS_OUT];
+
+            let (size, file_descriptor_length) =
+                self.receive_msg_and_fds(&mut buf, &mut received_fds)?;
+            let received_fds = &received_fds[..file_descriptor_length];
+
+            let message = match HandedOffSessions::decode_length_delimited(&buf[..size]) {
+                Ok(message) if message.sessions.len() * 2 == file_descriptor_length => message,
+                Ok(message) => {
+                    close_file_descriptors(received_fds);
+                    return Err(ScmSocketError::SessionSockets {
+                        expected: message.sessions.len() * 2,
+                        received: file_descriptor_length,
+                    });
+                }
+                Err(error) => {
+                    close_file_descriptors(received_fds);
+                    return Err(ScmSocketError::DecodeError(error));
+                }
+            };
+
+            sessions.extend(
+                message
+                    .sessions
+                    .into_iter()
+                    .zip(received_fds.chunks(2))
+                    .map(|(session, fds)| SessionSockets {
+                        session,
+                        frontend: fds[0],
+                        backend: fds[1],
+                    }),
+            );
+            if message.last {
+                return Ok(sessions);
+            }
+        }
+    }
+
     /// Sends message and file descriptors separately. The file descriptors are summed up
     /// in a ControlMessage.
     fn send_msg_and_fds(&self, message: &[u8], fds: &[RawFd]) -> Result<(), ScmSocketError> {
@@ -265,6 +351,29 @@ impl Listeners {
     }
 }
 
+/// An established session passed between workers, with the file descriptors of its sockets
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionSockets {
+    pub session: HandedOffSession,
+    pub frontend: RawFd,
+    pub backend: RawFd,
+}
+
+impl SessionSockets {
+    /// Close the file descriptors of the sockets, without shutting the connections down
+    pub fn close(&self) {
+        close_file_descriptors(&[self.frontend, self.backend]);
+    }
+}
+
+fn close_file_descriptors(fds: &[RawFd]) {
+    for fd in fds {
+        unsafe {
+            let _ = StdTcpStream::from_raw_fd(*fd);
+        }
+    }
+}
+
 fn parse_addresses(addresses: &[String]) -> Result<Vec<SocketAddr>, ScmSocketError> {
     let mut parsed_addresses = Vec::new();
     for address in addresses {
@@ -399,4 +508,57 @@ mod tests {
 
         assert_eq!(listeners.http[0].0, received_listeners.http[0].0);
     }
+
+    #[test]
+    fn send_and_receive_sessions() {
+        let (stream_1, stream_2) =
+            MioUnixStream::pair().expect("Could not create a pair of mio unix streams");
+        let sending_scm_socket =
+            ScmSocket::new(stream_1.into_raw_fd()).expect("Could not create scm socket");
+        let receiving_scm_socket =
+            ScmSocket::new(stream_2.into_raw_fd()).expect("Could not create scm socket");
+
+        let (frontend, backend) =
+            MioUnixStream::pair().expect("Could not create a pair of mio unix streams");
+
+        // more sessions than file descriptors fit in one message
+        let sessions: Vec<SessionSockets> = (0..150)
+            .map(|index| SessionSockets {
+                session: HandedOffSession {
+                    listener_type: crate::proto::command::ListenerType::Tcp as i32,
+                    listener_address: socket_addr_from_str("127.0.0.1:8080").into(),
+                    cluster_id: Some("cluster_1".to_owned()),
+                    backend_id: Some(format!("backend_{index}")),
+                    request_id: format!("request_{index}"),
+                    method: None,
+                    authority: None,
+                    path: None,
+                    status: None,
+                },
+                frontend: frontend.as_raw_fd(),
+                backend: backend.as_raw_fd(),
+            })
+            .collect();
+
+        sending_scm_socket
+            .send_sessions(&sessions)
+            .expect("Could not send sessions");
+        let received_sessions = receiving_scm_socket
+            .receive_sessions()
+            .expect("Could not receive sessions");
+
+        assert_eq!(received_sessions.len(), sessions.len());
+        for (sent, received) in sessions.iter().zip(received_sessions.iter()) {
+            assert_eq!(sent.session, received.session);
+            received.close();
+        }
+
+        sending_scm_socket
+            .send_sessions(&[])
+            .expect("Could not send sessions");
+        assert!(receiving_scm_socket
+            .receive_sessions()
+            .expect("Could not receive sessions")
+            .is_empty());
+    }
 }
@@ -13,15 +13,15 @@ use std::{
 use prost::{Message, UnknownEnumValue};
 
 use crate::{
-    certificate::{calculate_fingerprint, CertificateError, Fingerprint},
+    certificate::{calculate_fingerprint, name_covers_domain, CertificateError, Fingerprint},
     proto::{
         command::{
             request::RequestType, ActivateListener, AddBackend, AddCertificate, CertificateAndKey,
             Cluster, ClusterInformation, DeactivateListener, FrontendFilters, HttpListenerConfig,
             HttpsListenerConfig, InitialState, ListedFrontends, ListenerType, ListenersList,
-            PathRule, QueryCertificatesFilters, RemoveBackend, RemoveCertificate, RemoveListener,
-            ReplaceCertificate, Request, RequestCounts, RequestHttpFrontend, RequestTcpFrontend,
-            SocketAddress, TcpListenerConfig, WorkerRequest,
+            PathRule, PinCertificate, QueryCertificatesFilters, RemoveBackend, RemoveCertificate,
+            RemoveListener, ReplaceCertificate, Request, RequestCounts, RequestHttpFrontend,
+            RequestTcpFrontend, SocketAddress, TcpListenerConfig, WorkerRequest,
         },
         display::format_request_type,
     },
@@ -52,6 +52,8 @@ pub enum StateError {
     RemoveCertificate(String),
     #[error("Could not replace certificate: {0}")]
     ReplaceCertificate(String),
+    #[error("Could not pin certificate: {0}")]
+    PinCertificate(String),
     #[error(
         "Could not convert the frontend to an insertable one. Frontend: {frontend} error: {error}"
     )]
@@ -85,6 +87,8 @@ pub struct ConfigState {
     pub https_fronts: BTreeMap<String, HttpFrontend>,
     pub tcp_fronts: HashMap<ClusterId, Vec<TcpFrontend>>,
     pub certificates: HashMap<SocketAddr, HashMap<Fingerprint, CertificateAndKey>>,
+    /// socket address -> domain -> fingerprint of the certificate forced for this domain
+    pub certificate_pins: BTreeMap<SocketAddr, BTreeMap<String, Fingerprint>>,
     /// A census of requests that were received. Name of the request -> number of occurences
     pub request_counts: BTreeMap<String, i32>,
 }
@@ -116,6 +120,7 @@ impl ConfigState {
             RequestType::AddCertificate(add) => self.add_certificate(add),
             RequestType::RemoveCertificate(remove) => self.remove_certificate(remove),
             RequestType::ReplaceCertificate(replace) => self.replace_certificate(replace),
+            RequestType::PinCertificate(pin) => self.pin_certificate(pin),
             RequestType::AddHttpsFrontend(front) => self.add_https_frontend(front),
             RequestType::RemoveHttpsFrontend(front) => self.remove_https_frontend(front),
             RequestType::AddTcpFrontend(front) => self.add_tcp_frontend(front),
@@ -134,7 +139,10 @@ impl ConfigState {
             | RequestType::QueryMetrics(_)
             | RequestType::QueryClustersHashes(_)
             | RequestType::ConfigureMetrics(_)
+            | RequestType::ConfigureFaultInjection(_)
             | RequestType::ReturnListenSockets(_)
+            | RequestType::ReturnSessions(_)
+            | RequestType::AdoptSessions(_)
             | RequestType::HardStop(_) => Ok(()),
 
             _other_request => Err(StateError::UndispatchableRequest),
@@ -401,9 +409,13 @@ impl ConfigState {
                 .map_err(|decode_error| StateError::RemoveCertificate(decode_error.to_string()))?,
         );
 
-        if let Some(index) = self.certificates.get_mut(&remove.address.clone().into()) {
+        let address = remove.address.into();
+        if let Some(index) = self.certificates.get_mut(&address) {
             index.remove(&fingerprint);
         }
+        if let Some(pins) = self.certificate_pins.get_mut(&address) {
+            pins.retain(|_, pinned| pinned != &fingerprint);
+        }
 
         Ok(())
     }
@@ -451,6 +463,71 @@ impl ConfigState {
                 replace.address
             )));
         }
+
+        // the pins follow the renewed certificate, if it still covers their domain
+        let new_names = replace
+            .new_certificate
+            .get_overriding_names()
+            .map_err(|error| StateError::ReplaceCertificate(error.to_string()))?;
+        if let Some(pins) = self.certificate_pins.get_mut(&replace_address) {
+            pins.retain(|domain, pinned| {
+                if pinned != &old_fingerprint {
+                    return true;
+                }
+                *pinned = new_fingerprint.clone();
+                new_names
+                    .iter()
+                    .any(|name| name_covers_domain(name, domain))
+            });
+        }
+        Ok(())
+    }
+
+    /// pins a certificate that covers the domain, or removes the pin without fingerprint
+    fn pin_certificate(&mut self, pin: &PinCertificate) -> Result<(), StateError> {
+        let address: SocketAddr = pin.address.into();
+
+        let fingerprint = match &pin.fingerprint {
+            Some(fingerprint) => Fingerprint(
+                hex::decode(fingerprint)
+                    .map_err(|decode_error| StateError::PinCertificate(decode_error.to_string()))?,
+            ),
+            None => {
+                return match self
+                    .certificate_pins
+                    .get_mut(&address)
+                    .and_then(|pins| pins.remove(&pin.domain))
+                {
+                    Some(_) => Ok(()),
+                    None => Err(StateError::NoChange),
+                };
+            }
+        };
+
+        let certificate = self
+            .certificates
+            .get(&address)
+            .and_then(|certificates| certificates.get(&fingerprint))
+            .ok_or(StateError::NotFound {
+                kind: ObjectKind::Certificate,
+                id: fingerprint.to_string(),
+            })?;
+
+        if !certificate
+            .names
+            .iter()
+            .any(|name| name_covers_domain(name, &pin.domain))
+        {
+            return Err(StateError::PinCertificate(format!(
+                "certificate {} does not cover the domain {}",
+                fingerprint, pin.domain
+            )));
+        }
+
+        self.certificate_pins
+            .entry(address)
+            .or_default()
+            .insert(pin.domain.to_owned(), fingerprint);
         Ok(())
     }
 
@@ -501,6 +578,13 @@ impl ConfigState {
             sticky_id: add_backend.sticky_id.clone(),
             load_balancing_parameters: add_backend.load_balancing_parameters.clone(),
             backup: add_backend.backup,
+            priority: add_backend.priority,
+            alternate_addresses: add_backend
+                .alternate_addresses
+                .iter()
+                .cloned()
+                .map(Into::into)
+                .collect(),
         };
         let backends = self.backends.entry(backend.cluster_id.clone()).or_default();
 
@@ -598,6 +682,8 @@ impl ConfigState {
             }
         }
 
+        v.extend(self.generate_pin_requests());
+
         for front in self.https_fronts.values() {
             v.push(RequestType::AddHttpsFrontend(front.clone().into()).into());
         }
@@ -617,6 +703,22 @@ impl ConfigState {
         v
     }
 
+    fn generate_pin_requests(&self) -> Vec<Request> {
+        self.certificate_pins
+            .iter()
+            .flat_map(|(address, pins)| {
+                pins.iter().map(|(domain, fingerprint)| {
+                    RequestType::PinCertificate(PinCertificate {
+                        address: SocketAddress::from(*address),
+                        domain: domain.to_owned(),
+                        fingerprint: Some(fingerprint.to_string()),
+                    })
+                    .into()
+                })
+            })
+            .collect()
+    }
+
     pub fn generate_activate_requests(&self) -> Vec<Request> {
         let mut v: Vec<Request> = Vec::new();
         for front in self
@@ -695,6 +797,7 @@ impl ConfigState {
                         address: SocketAddress::from(**address),
                         proxy: ListenerType::Tcp.into(),
                         to_scm: false,
+                        drain_timeout: None,
                     })
                     .into(),
                 );
@@ -731,6 +834,7 @@ impl ConfigState {
                         address: SocketAddress::from(**address),
                         proxy: ListenerType::Http.into(),
                         to_scm: false,
+                        drain_timeout: None,
                     })
                     .into(),
                 );
@@ -767,6 +871,7 @@ impl ConfigState {
                         address: SocketAddress::from(**address),
                         proxy: ListenerType::Https.into(),
                         to_scm: false,
+                        drain_timeout: None,
                     })
                     .into(),
                 );
@@ -820,6 +925,7 @@ impl ConfigState {
                         address: SocketAddress::from(**addr),
                         proxy: ListenerType::Tcp.into(),
                         to_scm: false,
+                        drain_timeout: None,
                     })
                     .into(),
                 );
@@ -861,6 +967,7 @@ impl ConfigState {
                         address: SocketAddress::from(**addr),
                         proxy: ListenerType::Http.into(),
                         to_scm: false,
+                        drain_timeout: None,
                     })
                     .into(),
                 );
@@ -902,6 +1009,7 @@ impl ConfigState {
                         address: SocketAddress::from(**addr),
                         proxy: ListenerType::Https.into(),
                         to_scm: false,
+                        drain_timeout: None,
                     })
                     .into(),
                 );
@@ -1097,6 +1205,17 @@ impl ConfigState {
             }
         }
 
+        for (address, domain, fingerprint) in diff_pins(self, other) {
+            v.push(
+                RequestType::PinCertificate(PinCertificate {
+                    address: SocketAddress::from(address),
+                    domain: domain.to_owned(),
+                    fingerprint: fingerprint.map(ToString::to_string),
+                })
+                .into(),
+            );
+        }
+
         for address in added_tcp_listeners {
             let listener = &other.tcp_listeners[*address];
             if listener.active {
@@ -1260,44 +1379,109 @@ impl ConfigState {
         // if no http / https / tcp filter is provided, list all of them
         let list_all = !filters.http && !filters.https && !filters.tcp;
 
+        let address: Option<SocketAddr> = filters.address.map(Into::into);
+        let matches_common =
+            |cluster_id: Option<&str>,
+             frontend_address: &SocketAddr,
+             tags: Option<&BTreeMap<String, String>>| {
+                if let Some(id) = &filters.cluster_id {
+                    if cluster_id != Some(id.as_str()) {
+                        return false;
+                    }
+                }
+                if let Some(address) = &address {
+                    if frontend_address != address {
+                        return false;
+                    }
+                }
+                if let Some(key) = &filters.tag_key {
+                    match tags.and_then(|tags| tags.get(key)) {
+                        None => return false,
+                        Some(value) => {
+                            if let Some(expected) = &filters.tag_value {
+                                if value != expected {
+                                    return false;
+                                }
+                            }
+                        }
+                    }
+                }
+                true
+            };
+        let matches_http = |frontend: &HttpFrontend| {
+            if let Some(domain) = &filters.domain {
+                if !frontend.hostname.contains(domain) {
+                    return false;
+                }
+            }
+            if let Some(path) = &filters.path {
+                if !frontend.path.value.contains(path) {
+                    return false;
+                }
+            }
+            matches_common(
+                frontend.cluster_id.as_deref(),
+                &frontend.address,
+                frontend.tags.as_ref(),
+            )
+        };
+
+        // matching frontends are counted across all kinds, only the requested page is returned
+        let offset = filters.offset.unwrap_or(0) as usize;
+        let limit = filters
+            .limit
+            .map(|limit| limit as usize)
+            .unwrap_or(usize::MAX);
+        let mut matched = 0usize;
+        let mut in_page = || {
+            let index = matched;
+            matched += 1;
+            index >= offset && index - offset < limit
+        };
+
         let mut listed_frontends = ListedFrontends::default();
 
         if filters.http || list_all {
-            for http_frontend in self.http_fronts.iter().filter(|f| {
-                if let Some(domain) = &filters.domain {
-                    f.1.hostname.contains(domain)
-                } else {
-                    true
+            for http_frontend in self.http_fronts.values().filter(|f| matches_http(f)) {
+                if in_page() {
+                    listed_frontends
+                        .http_frontends
+                        .push(http_frontend.to_owned().into());
                 }
-            }) {
-                listed_frontends
-                    .http_frontends
-                    .push(http_frontend.1.to_owned().into());
             }
         }
 
         if filters.https || list_all {
-            for https_frontend in self.https_fronts.iter().filter(|f| {
-                if let Some(domain) = &filters.domain {
-                    f.1.hostname.contains(domain)
-                } else {
-                    true
+            for https_frontend in self.https_fronts.values().filter(|f| matches_http(f)) {
+                if in_page() {
+                    listed_frontends
+                        .https_frontends
+                        .push(https_frontend.to_owned().into());
                 }
-            }) {
-                listed_frontends
-                    .https_frontends
-                    .push(https_frontend.1.to_owned().into());
             }
         }
 
-        if (filters.tcp || list_all) && filters.domain.is_none() {
-            for tcp_frontend in self.tcp_fronts.values().flat_map(|v| v.iter()) {
-                listed_frontends
-                    .tcp_frontends
-                    .push(tcp_frontend.to_owned().into())
+        if (filters.tcp || list_all) && filters.domain.is_none() && filters.path.is_none() {
+            // the TCP frontends are in a HashMap, sort them to get the same pages on each call
+            let mut tcp_frontends = self
+                .tcp_fronts
+                .values()
+                .flat_map(|v| v.iter())
+                .filter(|f| matches_common(Some(&f.cluster_id), &f.address, Some(&f.tags)))
+                .collect::<Vec<_>>();
+            tcp_frontends
+                .sort_by(|a, b| (&a.cluster_id, a.address).cmp(&(&b.cluster_id, b.address)));
+
+            for tcp_frontend in tcp_frontends {
+                if in_page() {
+                    listed_frontends
+                        .tcp_frontends
+                        .push(tcp_frontend.to_owned().into())
+                }
             }
         }
 
+        listed_frontends.total = Some(matched as u64);
         listed_frontends
     }
 
@@ -1380,6 +1564,34 @@ impl ConfigState {
     }
 }
 
+/// the pins to set (with a fingerprint) or remove (without one) to go from `my` to `their` state
+fn diff_pins<'a>(
+    my: &'a ConfigState,
+    their: &'a ConfigState,
+) -> Vec<(SocketAddr, &'a str, Option<&'a Fingerprint>)> {
+    let flatten = |state: &'a ConfigState| {
+        state.certificate_pins.iter().flat_map(|(address, pins)| {
+            pins.iter()
+                .map(|(domain, fingerprint)| ((*address, domain.as_str()), fingerprint))
+        })
+    };
+    let my_pins: BTreeMap<_, _> = flatten(my).collect();
+    let their_pins: BTreeMap<_, _> = flatten(their).collect();
+
+    let mut pins = Vec::new();
+    for (&(address, domain), fingerprint) in &their_pins {
+        if my_pins.get(&(address, domain)) != Some(fingerprint) {
+            pins.push((address, domain, Some(*fingerprint)));
+        }
+    }
+    for &(address, domain) in my_pins.keys() {
+        if !their_pins.contains_key(&(address, domain)) {
+            pins.push((address, domain, None));
+        }
+    }
+    pins
+}
+
 fn domain_check(
     front_hostname: &str,
     front_path_rule: &PathRule,
@@ -1859,6 +2071,139 @@ mod tests {
         );
     }
 
+    #[test]
+    fn list_frontends_with_filters() {
+        let mut state: ConfigState = Default::default();
+        for i in 0..10 {
+            let mut tags = BTreeMap::new();
+            tags.insert(String::from("tenant"), format!("tenant-{}", i % 2));
+            state
+                .dispatch(
+                    &RequestType::AddHttpFrontend(RequestHttpFrontend {
+                        cluster_id: Some(format!("cluster_{}", i % 3)),
+                        hostname: format!("host{i}.local"),
+                        path: PathRule::prefix(format!("/api/v{i}")),
+                        address: SocketAddress::new_v4(0, 0, 0, 0, 8080 + i % 2),
+                        tags,
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+                .expect("Could not execute request");
+        }
+        state
+            .dispatch(
+                &RequestType::AddTcpFrontend(RequestTcpFrontend {
+                    cluster_id: String::from("cluster_0"),
+                    address: SocketAddress::new_v4(0, 0, 0, 0, 5432),
+                    ..Default::default()
+                })
+                .into(),
+            )
+            .expect("Could not execute request");
+
+        // an empty filter matches every frontend, bulk removal refuses it
+        assert!(!FrontendFilters::default().selects_frontends());
+        assert!(!FrontendFilters {
+            tcp: true,
+            tag_key: Some(String::new()),
+            limit: Some(2),
+            ..Default::default()
+        }
+        .selects_frontends());
+
+        let filters = FrontendFilters {
+            cluster_id: Some(String::from("cluster_0")),
+            ..Default::default()
+        };
+        assert!(filters.selects_frontends());
+        let listed = state.list_frontends(filters);
+        assert_eq!(listed.http_frontends.len(), 4);
+        assert_eq!(listed.tcp_frontends.len(), 1);
+        assert_eq!(listed.total, Some(5));
+
+        let listed = state.list_frontends(FrontendFilters {
+            address: Some(SocketAddress::new_v4(0, 0, 0, 0, 8081)),
+            tag_key: Some(String::from("tenant")),
+            tag_value: Some(String::from("tenant-1")),
+            ..Default::default()
+        });
+        assert_eq!(listed.total, Some(5));
+
+        let listed = state.list_frontends(FrontendFilters {
+            tag_key: Some(String::from("tenant")),
+            tag_value: Some(String::from("tenant-1")),
+            address: Some(SocketAddress::new_v4(0, 0, 0, 0, 8080)),
+            ..Default::default()
+        });
+        assert_eq!(listed.total, Some(0));
+
+        let listed = state.list_frontends(FrontendFilters {
+            path: Some(String::from("/api/v3")),
+            ..Default::default()
+        });
+        assert_eq!(listed.http_frontends.len(), 1);
+        assert!(listed.tcp_frontends.is_empty());
+
+        let listed = state.list_frontends(FrontendFilters {
+            offset: Some(8),
+            limit: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(listed.http_frontends.len(), 2);
+        assert!(listed.tcp_frontends.is_empty());
+        assert_eq!(listed.total, Some(11));
+
+        let listed = state.list_frontends(FrontendFilters {
+            offset: Some(10),
+            limit: Some(5),
+            ..Default::default()
+        });
+        assert!(listed.http_frontends.is_empty());
+        assert_eq!(listed.tcp_frontends.len(), 1);
+    }
+
+    #[test]
+    fn list_tcp_frontends_in_stable_pages() {
+        let mut state: ConfigState = Default::default();
+        for i in (0..20).rev() {
+            state
+                .dispatch(
+                    &RequestType::AddTcpFrontend(RequestTcpFrontend {
+                        cluster_id: format!("cluster_{}", i % 7),
+                        address: SocketAddress::new_v4(0, 0, 0, 0, 5000 + i),
+                        ..Default::default()
+                    })
+                    .into(),
+                )
+                .expect("Could not execute request");
+        }
+
+        let mut listed = Vec::new();
+        for page in 0..4 {
+            let mut frontends = state
+                .list_frontends(FrontendFilters {
+                    tcp: true,
+                    offset: Some(page * 5),
+                    limit: Some(5),
+                    ..Default::default()
+                })
+                .tcp_frontends;
+            assert_eq!(frontends.len(), 5);
+            listed.append(&mut frontends);
+        }
+
+        let keys = listed
+            .iter()
+            .map(|frontend| (frontend.cluster_id.clone(), frontend.address.port))
+            .collect::<Vec<_>>();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(keys, sorted);
+        assert_eq!(keys.len(), 20);
+    }
+
     #[test]
     fn duplicate_backends() {
         let mut state: ConfigState = Default::default();
@@ -1882,6 +2227,8 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: Some("sticky".to_string()),
             backup: None,
+            priority: None,
+            alternate_addresses: Vec::new(),
         };
 
         state
@@ -2108,6 +2455,7 @@ mod tests {
                 address: SocketAddress::new_v4(0, 0, 0, 0, 1234),
                 proxy: ListenerType::Tcp.into(),
                 to_scm: false,
+                drain_timeout: None,
             })
             .into(),
             RequestType::RemoveListener(RemoveListener {
@@ -2160,6 +2508,7 @@ mod tests {
             certificate_chain: vec![],
             versions: vec![],
             names: vec!["lolcatho.st".to_string()],
+            cipher_list: vec![],
         };
         let add_certificate = AddCertificate {
             address: SocketAddress::new_v4(127, 0, 0, 1, 8080),
@@ -2198,4 +2547,57 @@ mod tests {
 
         assert!(!certificate_found_by_domain_name.is_empty());
     }
+
+    #[test]
+    fn certificate_pins() {
+        let address = SocketAddress::new_v4(127, 0, 0, 1, 8443);
+        let mut state: ConfigState = Default::default();
+        state
+            .dispatch(
+                &RequestType::AddCertificate(AddCertificate {
+                    address,
+                    certificate: CertificateAndKey {
+                        certificate: String::from(include_str!("../assets/certificate.pem")),
+                        key: String::from(include_str!("../assets/key.pem")),
+                        names: vec!["*.lolcatho.st".to_string()],
+                        ..Default::default()
+                    },
+                    expired_at: None,
+                })
+                .into(),
+            )
+            .expect("Could not add certificate");
+        let fingerprint =
+            "ab2618b674e15243fd02a5618c66509e4840ba60e7d64cebec84cdbfeceee0c5".to_string();
+
+        let pin = |domain: &str| {
+            RequestType::PinCertificate(PinCertificate {
+                address,
+                domain: domain.to_string(),
+                fingerprint: Some(fingerprint.clone()),
+            })
+            .into()
+        };
+
+        // the wildcard does not cover the apex domain
+        assert!(state.dispatch(&pin("lolcatho.st")).is_err());
+        state
+            .dispatch(&pin("www.lolcatho.st"))
+            .expect("Could not pin certificate");
+
+        let empty_state: ConfigState = Default::default();
+        assert!(empty_state.diff(&state).contains(&pin("www.lolcatho.st")));
+        assert!(state.generate_requests().contains(&pin("www.lolcatho.st")));
+
+        state
+            .dispatch(
+                &RequestType::RemoveCertificate(RemoveCertificate {
+                    address,
+                    fingerprint,
+                })
+                .into(),
+            )
+            .expect("Could not remove certificate");
+        assert!(state.certificate_pins[&address.into()].is_empty());
+    }
 }
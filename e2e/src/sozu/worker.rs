@@ -306,6 +306,8 @@ impl Worker {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id,
             backup: None,
+            priority: None,
+            alternate_addresses: Vec::new(),
         }
     }
 }
@@ -353,6 +353,81 @@ pub fn try_issue_810_panic(part2: bool) -> State {
     }
 }
 
+pub fn try_tcp_idle_timeout() -> State {
+    let front_address = create_local_address();
+    let back_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let mut worker = Worker::start_new_worker("TCP-IDLE-TIMEOUT", config, &listeners, state);
+
+    worker.send_proxy_request_type(RequestType::AddTcpListener(
+        ListenerBuilder::new_tcp(front_address.into())
+            .to_tcp(None)
+            .unwrap(),
+    ));
+    worker.send_proxy_request_type(RequestType::ActivateListener(ActivateListener {
+        address: front_address.into(),
+        proxy: ListenerType::Tcp.into(),
+        from_scm: false,
+    }));
+    worker.send_proxy_request_type(RequestType::AddCluster(Cluster {
+        idle_timeout: Some(2),
+        ..Worker::default_cluster("cluster_0")
+    }));
+    worker.send_proxy_request_type(RequestType::AddTcpFrontend(Worker::default_tcp_frontend(
+        "cluster_0",
+        front_address,
+    )));
+    worker.send_proxy_request_type(RequestType::AddBackend(Worker::default_backend(
+        "cluster_0",
+        "cluster_0-0",
+        back_address,
+        None,
+    )));
+    worker.read_to_last();
+
+    let mut backend = SyncBackend::new("backend", back_address, "pong");
+    let mut client = Client::new("client", front_address, "ping");
+
+    backend.connect();
+    client.connect();
+    client.send();
+    backend.accept(0);
+    backend.receive(0);
+    backend.send(0);
+    let response = client.receive();
+    println!("Response: {response:?}");
+
+    // traffic pushes the idle deadline back, past the 2 seconds since the first request
+    thread::sleep(Duration::from_millis(1200));
+    client.send();
+    backend.receive(0);
+    backend.send(0);
+    let response = client.receive();
+    println!("Response: {response:?}");
+    thread::sleep(Duration::from_millis(1200));
+    let still_connected = client.is_connected();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut closed_when_idle = false;
+    while Instant::now() < deadline {
+        if !client.is_connected() {
+            closed_when_idle = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    worker.hard_stop();
+    worker.wait_for_server_stop();
+
+    if response.is_some() && still_connected && closed_when_idle {
+        State::Success
+    } else {
+        State::Fail
+    }
+}
+
 pub fn try_tls_endpoint() -> State {
     let front_port = provide_port();
     let front_address = SocketAddress::new_v4(127, 0, 0, 1, front_port);
@@ -389,6 +464,7 @@ pub fn try_tls_endpoint() -> State {
         certificate_chain: vec![], // in config.toml the certificate chain would be the same as the certificate
         versions: vec![],
         names: vec![],
+        cipher_list: vec![],
     };
     let add_certificate = AddCertificate {
         address: front_address,
@@ -1412,6 +1488,59 @@ fn try_max_connections() -> State {
     State::Success
 }
 
+pub fn try_alternate_address() -> State {
+    let front_address = create_local_address();
+
+    let (config, listeners, state) = Worker::empty_config();
+    let (mut worker, _) = setup_sync_test(
+        "ALTERNATE",
+        config,
+        listeners,
+        state,
+        front_address,
+        0,
+        false,
+    );
+
+    // nothing listens on the main address of the backend
+    let unreachable_address = create_local_address();
+    let back_address = create_local_address();
+    let mut backend = SyncBackend::new("backend", back_address, http_ok_response("pong"));
+    backend.connect();
+
+    let mut add_backend =
+        Worker::default_backend("cluster_0", "cluster_0-0", unreachable_address, None);
+    add_backend.alternate_addresses = vec![back_address.into()];
+    worker.send_proxy_request_type(RequestType::AddBackend(add_backend));
+    worker.read_to_last();
+
+    let mut client = Client::new(
+        "client",
+        front_address,
+        http_request("GET", "/api", "ping", "localhost"),
+    );
+    client.connect();
+    client.send();
+
+    if !backend.accept(0) {
+        return State::Fail;
+    }
+    let request = backend.receive(0);
+    println!("request: {request:?}");
+    backend.send(0);
+
+    let response = client.receive();
+    println!("response: {response:?}");
+
+    worker.soft_stop();
+    let success = worker.wait_for_server_stop();
+
+    match response {
+        Some(response) if success && response.starts_with("HTTP/1.1 200 OK") => State::Success,
+        _ => State::Fail,
+    }
+}
+
 pub fn try_head() -> State {
     let front_address = create_local_address();
 
@@ -1798,6 +1927,18 @@ fn test_max_connections() {
     );
 }
 
+#[test]
+fn test_alternate_address() {
+    assert_eq!(
+        repeat_until_error_or(
+            2,
+            "Connect to the alternate address of a backend",
+            try_alternate_address
+        ),
+        State::Success
+    );
+}
+
 #[test]
 fn test_head() {
     assert_eq!(
@@ -1821,3 +1962,11 @@ fn test_status_header_split() {
         State::Success
     );
 }
+
+#[test]
+fn test_tcp_idle_timeout() {
+    assert_eq!(
+        repeat_until_error_or(2, "Close idle TCP connections", try_tcp_idle_timeout),
+        State::Success
+    );
+}
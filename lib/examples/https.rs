@@ -61,6 +61,8 @@ fn main() -> anyhow::Result<()> {
         address: SocketAddress::new_v4(127, 0, 0, 1, 1026),
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         backup: None,
+        priority: None,
+        alternate_addresses: Vec::new(),
     };
 
     command.write_message(&WorkerRequest {
@@ -101,6 +103,7 @@ fn main() -> anyhow::Result<()> {
         certificate_chain: vec![],
         versions: vec![],
         names: vec![],
+        cipher_list: vec![],
     };
     command2.write_message(&WorkerRequest {
         id: String::from("ID_IJKL1"),
@@ -131,6 +134,8 @@ fn main() -> anyhow::Result<()> {
         address: SocketAddress::new_v4(127, 0, 0, 1, 1026),
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         backup: None,
+        priority: None,
+        alternate_addresses: Vec::new(),
     };
 
     command2.write_message(&WorkerRequest {
@@ -147,6 +152,7 @@ fn main() -> anyhow::Result<()> {
         certificate_chain: vec![],
         versions: vec![],
         names: vec![],
+        cipher_list: vec![],
     };
 
     command2.write_message(&WorkerRequest {
@@ -179,6 +185,8 @@ fn main() -> anyhow::Result<()> {
         address: SocketAddress::new_v4(127, 0, 0, 1, 1026),
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         backup: None,
+        priority: None,
+        alternate_addresses: Vec::new(),
     };
 
     command2.write_message(&WorkerRequest {
@@ -48,6 +48,8 @@ fn main() -> anyhow::Result<()> {
         load_balancing_parameters: Some(LoadBalancingParams::default()),
         sticky_id: None,
         backup: None,
+        priority: None,
+        alternate_addresses: Vec::new(),
     };
 
     command.write_message(&WorkerRequest {
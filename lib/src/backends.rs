@@ -1,19 +1,44 @@
-use std::{cell::RefCell, collections::HashMap, net::SocketAddr, rc::Rc, time::Duration};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::SocketAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
-use mio::net::TcpStream;
+use mio::{net::TcpStream, Token};
 
 use sozu_command::{
-    proto::command::{Event, EventKind, LoadBalancingAlgorithms, LoadBalancingParams, LoadMetric},
+    proto::command::{
+        Event, EventKind, FaultInjection, LoadBalancingAlgorithms, LoadBalancingParams, LoadMetric,
+        StickySessionFallback,
+    },
+    ready::Ready,
     state::ClusterId,
 };
 
 use crate::{
-    load_balancing::{LeastLoaded, LoadBalancingAlgorithm, PowerOfTwo, Random, RoundRobin},
-    retry::{self, RetryPolicy},
+    load_balancing::{
+        LeastLoaded, LeastResponseTime, LoadBalancingAlgorithm, PowerOfTwo, Random, RoundRobin,
+    },
+    retry::{self, RetryBudget, RetryPolicy},
     server::{self, push_event},
     PeakEWMA,
 };
 
+/// response time recorded for a backend when a connection to it fails
+const CONNECTION_FAILURE_PENALTY: Duration = Duration::from_secs(1);
+
+/// how long a lower priority tier must stay available before traffic fails back to it
+const PRIORITY_FAILBACK_DELAY: Duration = Duration::from_secs(10);
+
+/// fraction of its weight a backend starts with in slow start
+const SLOW_START_MIN_RATIO: f64 = 0.1;
+
+/// delay before trying the next address of a backend, if the connection
+/// to the previous one is not established yet (RFC 8305)
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
 #[derive(thiserror::Error, Debug)]
 pub enum BackendError {
     #[error("No backend found for cluster {0}")]
@@ -31,6 +56,19 @@ pub enum BackendError {
         failures: usize,
         error: String,
     },
+    #[error("all backends of cluster {cluster_id} have reached their maximum connections")]
+    Saturated {
+        cluster_id: String,
+        /// how long the request may wait for a connection slot, if configured
+        queue_timeout: Option<Duration>,
+    },
+    #[error(
+        "the backend of sticky session {sticky_session} in cluster {cluster_id} is unavailable"
+    )]
+    StickyBackendUnavailable {
+        cluster_id: String,
+        sticky_session: String,
+    },
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -52,7 +90,17 @@ pub struct Backend {
     pub failures: usize,
     pub load_balancing_parameters: Option<LoadBalancingParams>,
     pub backup: bool,
+    /// priority tier, lower tiers are used first
+    pub priority: u32,
     pub connection_time: PeakEWMA,
+    /// moving average of response times, penalized by connection failures
+    pub response_time: PeakEWMA,
+    /// since when the weight of the backend is being ramped up
+    pub slow_start_since: Option<Instant>,
+    /// other addresses of the backend, like its IPv6 and IPv4 addresses
+    pub alternate_addresses: Vec<SocketAddr>,
+    /// the address connections are tried on first, the last one that worked
+    pub preferred_address: SocketAddr,
 }
 
 impl Backend {
@@ -62,9 +110,10 @@ impl Backend {
         sticky_id: Option<String>,
         load_balancing_parameters: Option<LoadBalancingParams>,
         backup: Option<bool>,
+        priority: Option<u32>,
     ) -> Backend {
         let desired_policy = retry::ExponentialBackoffPolicy::new(6);
-        Backend {
+        let mut backend = Backend {
             sticky_id,
             backend_id: backend_id.to_string(),
             address,
@@ -75,8 +124,15 @@ impl Backend {
             failures: 0,
             load_balancing_parameters,
             backup: backup.unwrap_or(false),
+            priority: priority.unwrap_or(0),
             connection_time: PeakEWMA::new(),
-        }
+            response_time: PeakEWMA::new(),
+            slow_start_since: None,
+            alternate_addresses: Vec::new(),
+            preferred_address: address,
+        };
+        backend.start_slow_start();
+        backend
     }
 
     pub fn set_closing(&mut self) {
@@ -128,35 +184,185 @@ impl Backend {
         }
     }
 
+    /// a connection to the backend is closed: the sessions waiting for a
+    /// connection slot in its cluster are woken up if it was saturated
+    pub fn release_connection(&mut self, cluster_id: &str) -> Option<usize> {
+        let was_saturated = self.is_saturated();
+        let active_connections = self.dec_connections();
+        if was_saturated {
+            server::backend_available(cluster_id);
+        }
+        active_connections
+    }
+
     pub fn set_connection_time(&mut self, dur: Duration) {
         self.connection_time.observe(dur.as_nanos() as f64);
     }
 
+    /// ramp up the weight of the backend, if slow start is configured
+    pub fn start_slow_start(&mut self) {
+        if self.slow_start().is_some() {
+            self.slow_start_since = Some(Instant::now());
+        }
+    }
+
+    pub fn slow_start(&self) -> Option<Duration> {
+        self.load_balancing_parameters
+            .as_ref()
+            .and_then(|params| params.slow_start)
+            .filter(|slow_start| *slow_start > 0)
+            .map(|slow_start| Duration::from_secs(slow_start as u64))
+    }
+
+    pub fn weight(&self) -> i32 {
+        self.load_balancing_parameters
+            .as_ref()
+            .map(|params| params.weight)
+            .unwrap_or(100)
+    }
+
+    /// the configured weight, reduced while the backend is in slow start
+    pub fn effective_weight(&mut self) -> i32 {
+        let weight = self.weight();
+        if weight <= 0 {
+            return weight;
+        }
+        ((weight as f64 * self.slow_start_ratio()).round() as i32).max(1)
+    }
+
+    /// the fraction of its weight the backend has, from `SLOW_START_MIN_RATIO`
+    /// at the start of the slow start to 1.0 at its end
+    pub fn slow_start_ratio(&mut self) -> f64 {
+        let (since, window) = match (self.slow_start_since, self.slow_start()) {
+            (Some(since), Some(window)) => (since, window),
+            _ => return 1.0,
+        };
+
+        let ratio = since.elapsed().as_secs_f64() / window.as_secs_f64();
+        if ratio >= 1.0 {
+            self.slow_start_since = None;
+            return 1.0;
+        }
+        ratio.max(SLOW_START_MIN_RATIO)
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.load_balancing_parameters
+            .as_ref()
+            .and_then(|params| params.max_connections)
+            .map(|max| max as usize)
+    }
+
+    pub fn queue_timeout(&self) -> Option<Duration> {
+        self.load_balancing_parameters
+            .as_ref()
+            .and_then(|params| params.queue_timeout)
+            .map(|timeout| Duration::from_millis(timeout as u64))
+    }
+
+    /// a backend is saturated when it has as many active connections as its
+    /// configured `max_connections`
+    pub fn is_saturated(&self) -> bool {
+        self.max_connections()
+            .map(|max| self.active_connections >= max)
+            .unwrap_or(false)
+    }
+
     pub fn peak_ewma_connection(&mut self) -> f64 {
         self.connection_time.get(self.active_connections)
     }
 
-    pub fn try_connect(&mut self) -> Result<mio::net::TcpStream, BackendError> {
+    pub fn set_response_time(&mut self, dur: Duration) {
+        self.response_time.observe(dur.as_nanos() as f64);
+    }
+
+    /// a failed connection counts as a very slow response, so that the
+    /// backend is avoided until its score decays
+    pub fn penalize_response_time(&mut self) {
+        self.response_time
+            .observe(CONNECTION_FAILURE_PENALTY.as_nanos() as f64);
+    }
+
+    pub fn peak_ewma_response(&mut self) -> f64 {
+        self.response_time.get(self.active_requests)
+    }
+
+    /// The addresses to connect to, in the order of RFC 8305: the preferred address
+    /// first, then alternating between the address families
+    pub fn connection_addresses(&self) -> Vec<SocketAddr> {
+        let preferred = self.preferred_address;
+        let (mut same_family, mut other_family): (VecDeque<_>, VecDeque<_>) =
+            std::iter::once(self.address)
+                .chain(self.alternate_addresses.iter().copied())
+                .filter(|address| *address != preferred)
+                .partition(|address| address.is_ipv6() == preferred.is_ipv6());
+
+        let mut addresses = vec![preferred];
+        loop {
+            match (other_family.pop_front(), same_family.pop_front()) {
+                (None, None) => break,
+                (other, same) => addresses.extend(other.into_iter().chain(same)),
+            }
+        }
+        addresses
+    }
+
+    pub fn set_alternate_addresses(&mut self, alternate_addresses: Vec<SocketAddr>) {
+        if self.preferred_address != self.address
+            && !alternate_addresses.contains(&self.preferred_address)
+        {
+            self.preferred_address = self.address;
+        }
+        self.alternate_addresses = alternate_addresses;
+    }
+
+    /// Connects to the first address that does not fail right away, it becomes
+    /// the preferred address if it was not already
+    pub fn try_connect(&mut self, cluster_id: &str) -> Result<mio::net::TcpStream, BackendError> {
         if self.status != BackendStatus::Normal {
             return Err(BackendError::Status(self.status.to_owned()));
         }
 
-        match mio::net::TcpStream::connect(self.address) {
-            Ok(tcp_stream) => {
-                //self.retry_policy.succeed();
-                self.inc_connections();
-                Ok(tcp_stream)
-            }
-            Err(io_error) => {
-                self.retry_policy.fail();
-                self.failures += 1;
-                // TODO: handle EINPROGRESS. It is difficult. It is discussed here:
-                // https://docs.rs/mio/latest/mio/net/struct.TcpStream.html#method.connect
-                // with an example code here:
-                // https://github.com/Thomasdezeeuw/heph/blob/0c4f1ab3eaf08bea1d65776528bfd6114c9f8374/src/net/tcp/stream.rs#L560-L622
-                Err(BackendError::MioConnection(io_error))
+        let mut last_error = None;
+        for address in self.connection_addresses() {
+            match mio::net::TcpStream::connect(address) {
+                Ok(tcp_stream) => {
+                    //self.retry_policy.succeed();
+                    self.preferred_address = address;
+                    self.inc_connections();
+                    if self.is_saturated() {
+                        incr!("backend.saturated", None, Some(self.backend_id.as_str()));
+                        server::push_event(Event {
+                            kind: EventKind::BackendSaturated as i32,
+                            backend_id: Some(self.backend_id.clone()),
+                            address: Some(self.address.into()),
+                            cluster_id: Some(cluster_id.to_owned()),
+                            crash_report: None,
+                            tags: BTreeMap::new(),
+                        });
+                    }
+                    return Ok(tcp_stream);
+                }
+                Err(io_error) => {
+                    debug!(
+                        "could not connect to backend {} at {}: {}",
+                        self.backend_id, address, io_error
+                    );
+                    last_error = Some(io_error);
+                }
             }
         }
+
+        self.retry_policy.fail();
+        self.failures += 1;
+        self.penalize_response_time();
+        // TODO: handle EINPROGRESS. It is difficult. It is discussed here:
+        // https://docs.rs/mio/latest/mio/net/struct.TcpStream.html#method.connect
+        // with an example code here:
+        // https://github.com/Thomasdezeeuw/heph/blob/0c4f1ab3eaf08bea1d65776528bfd6114c9f8374/src/net/tcp/stream.rs#L560-L622
+        Err(BackendError::MioConnection(last_error.unwrap_or_else(
+            || std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "no backend address"),
+        )))
     }
 }
 
@@ -170,10 +376,117 @@ impl std::ops::Drop for Backend {
             backend_id: Some(self.backend_id.clone()),
             address: Some(self.address.into()),
             cluster_id: None,
+            crash_report: None,
+            tags: BTreeMap::new(),
         });
     }
 }
 
+/// A connection attempt to another address of a backend
+pub struct ConnectionAttempt {
+    pub address: SocketAddr,
+    pub events: Ready,
+    pub socket: TcpStream,
+    pub token: Token,
+}
+
+/// Staggered connection attempts to the other addresses of a backend, racing
+/// the first connection attempt, as in RFC 8305 (happy eyeballs)
+///
+/// A new address is tried every `CONNECTION_ATTEMPT_DELAY` while no connection
+/// is established, or right away when an attempt fails. The first connected
+/// socket is used, the others are closed.
+pub struct ConnectionRace {
+    /// addresses not tried yet
+    addresses: VecDeque<SocketAddr>,
+    next_attempt: Instant,
+    /// set once the first connection attempt has failed
+    pub first_failed: bool,
+    pub attempts: Vec<ConnectionAttempt>,
+}
+
+impl ConnectionRace {
+    /// returns None if the backend has a single address
+    pub fn new(backend: &Backend) -> Option<ConnectionRace> {
+        let mut addresses = VecDeque::from(backend.connection_addresses());
+        // the preferred address is the one the first attempt connects to
+        addresses.pop_front();
+
+        if addresses.is_empty() {
+            return None;
+        }
+
+        Some(ConnectionRace {
+            addresses,
+            next_attempt: Instant::now() + CONNECTION_ATTEMPT_DELAY,
+            first_failed: false,
+            attempts: Vec::new(),
+        })
+    }
+
+    /// the next address to try, once the connection attempt delay has expired
+    pub fn next_address(&mut self) -> Option<SocketAddr> {
+        if Instant::now() < self.next_attempt {
+            return None;
+        }
+
+        let address = self.addresses.pop_front()?;
+        self.next_attempt = Instant::now() + CONNECTION_ATTEMPT_DELAY;
+        Some(address)
+    }
+
+    /// when the next address should be tried
+    pub fn next_attempt(&self) -> Instant {
+        self.next_attempt
+    }
+
+    /// an attempt has failed, the next address should be tried without waiting
+    pub fn hurry(&mut self) {
+        self.next_attempt = Instant::now();
+    }
+
+    pub fn has_addresses(&self) -> bool {
+        !self.addresses.is_empty()
+    }
+
+    /// true if every address was tried and every attempt failed
+    pub fn is_lost(&self) -> bool {
+        self.first_failed && self.addresses.is_empty() && self.attempts.is_empty()
+    }
+
+    pub fn update_readiness(&mut self, token: Token, events: Ready) {
+        if let Some(attempt) = self
+            .attempts
+            .iter_mut()
+            .find(|attempt| attempt.token == token)
+        {
+            attempt.events |= events;
+        }
+    }
+
+    /// removes the failed attempts
+    pub fn take_failed(&mut self) -> Vec<ConnectionAttempt> {
+        let (failed, attempts) = self
+            .attempts
+            .drain(..)
+            .partition(|attempt| attempt.events.is_hup() || attempt.events.is_error());
+        self.attempts = attempts;
+        if !failed.is_empty() {
+            self.hurry();
+        }
+        failed
+    }
+
+    /// removes the first attempt that established its connection
+    pub fn take_connected(&mut self) -> Option<ConnectionAttempt> {
+        let index = self
+            .attempts
+            .iter()
+            .position(|attempt| attempt.events.is_writable())?;
+        Some(self.attempts.remove(index))
+    }
+}
+
 #[derive(Debug)]
 pub struct BackendMap {
     pub backends: HashMap<ClusterId, BackendList>,
@@ -214,6 +527,7 @@ impl BackendMap {
             .entry(cluster_id.to_string())
             .or_default()
             .add_backend(backend);
+        server::backend_available(cluster_id);
     }
 
     // TODO: return <Result, BackendError>, log the error downstream
@@ -232,7 +546,7 @@ impl BackendMap {
     pub fn close_backend_connection(&mut self, cluster_id: &str, addr: &SocketAddr) {
         if let Some(cluster_backends) = self.backends.get_mut(cluster_id) {
             if let Some(ref mut backend) = cluster_backends.find_backend(addr) {
-                backend.borrow_mut().dec_connections();
+                backend.borrow_mut().release_connection(cluster_id);
             }
         }
     }
@@ -260,6 +574,12 @@ impl BackendMap {
 
         let next_backend = match cluster_backends.next_available_backend() {
             Some(nb) => nb,
+            None if cluster_backends.is_saturated() => {
+                return Err(BackendError::Saturated {
+                    cluster_id: cluster_id.to_owned(),
+                    queue_timeout: cluster_backends.queue_timeout(),
+                });
+            }
             None => {
                 if self.available {
                     self.available = false;
@@ -269,6 +589,8 @@ impl BackendMap {
                         cluster_id: Some(cluster_id.to_owned()),
                         backend_id: None,
                         address: None,
+                        crash_report: None,
+                        tags: BTreeMap::new(),
                     });
                 }
                 return Err(BackendError::NoBackendForCluster(cluster_id.to_owned()));
@@ -287,14 +609,14 @@ impl BackendMap {
             )
         );
 
-        let tcp_stream = borrowed_backend.try_connect().map_err(|backend_error| {
-            BackendError::ConnectionFailures {
+        let tcp_stream = borrowed_backend
+            .try_connect(cluster_id)
+            .map_err(|backend_error| BackendError::ConnectionFailures {
                 cluster_id: cluster_id.to_owned(),
                 backend_address: borrowed_backend.address,
                 failures: borrowed_backend.failures,
                 error: backend_error.to_string(),
-            }
-        })?;
+            })?;
         self.available = true;
 
         Ok((next_backend.clone(), tcp_stream))
@@ -311,7 +633,7 @@ impl BackendMap {
             .and_then(|cluster_backends| cluster_backends.find_sticky(sticky_session))
             .map(|backend| {
                 let mut borrowed = backend.borrow_mut();
-                let conn = borrowed.try_connect();
+                let conn = borrowed.try_connect(cluster_id);
 
                 conn.map(|tcp_stream| (backend.clone(), tcp_stream))
                     .map_err(|e| {
@@ -330,11 +652,71 @@ impl BackendMap {
                     "Couldn't find a backend corresponding to sticky_session {} for cluster {}",
                     sticky_session, cluster_id
                 );
+                self.sticky_session_fallback(cluster_id, sticky_session)?;
                 self.backend_from_cluster_id(cluster_id)
             }
         }
     }
 
+    /// With the FAIL fallback, a request whose sticky backend is still in the cluster
+    /// but down or saturated is not rebalanced. Unknown sticky ids are always rebalanced,
+    /// so that a removed backend does not lock its users out
+    fn sticky_session_fallback(
+        &mut self,
+        cluster_id: &str,
+        sticky_session: &str,
+    ) -> Result<(), BackendError> {
+        let cluster_backends = match self.backends.get(cluster_id) {
+            Some(cluster_backends) => cluster_backends,
+            None => return Ok(()),
+        };
+        let sticky_backend = match cluster_backends
+            .backends
+            .iter()
+            .find(|backend| backend.borrow().sticky_id.as_deref() == Some(sticky_session))
+        {
+            Some(backend) => backend.borrow(),
+            None => return Ok(()),
+        };
+
+        if cluster_backends.sticky_session_fallback == StickySessionFallback::Rebalance {
+            incr!("backend.sticky.rebalanced", Some(cluster_id), None);
+            return Ok(());
+        }
+
+        incr!(
+            "backend.sticky.unavailable",
+            Some(cluster_id),
+            Some(sticky_backend.backend_id.as_str())
+        );
+        if sticky_backend.can_open() && sticky_backend.is_saturated() {
+            return Err(BackendError::Saturated {
+                cluster_id: cluster_id.to_owned(),
+                queue_timeout: sticky_backend.queue_timeout(),
+            });
+        }
+        Err(BackendError::StickyBackendUnavailable {
+            cluster_id: cluster_id.to_owned(),
+            sticky_session: sticky_session.to_owned(),
+        })
+    }
+
+    /// connects to another backend than `excluded_backend_id`, to send it a hedged request
+    pub fn hedge_backend_from_cluster_id(
+        &mut self,
+        cluster_id: &str,
+        excluded_backend_id: &str,
+    ) -> Result<(Rc<RefCell<Backend>>, TcpStream), BackendError> {
+        let backend = self
+            .backends
+            .get_mut(cluster_id)
+            .and_then(|cluster_backends| cluster_backends.next_hedge_backend(excluded_backend_id))
+            .ok_or(BackendError::NoBackendForCluster(cluster_id.to_owned()))?;
+
+        let tcp_stream = backend.borrow_mut().try_connect(cluster_id)?;
+        Ok((backend, tcp_stream))
+    }
+
     pub fn set_load_balancing_policy_for_cluster(
         &mut self,
         cluster_id: &str,
@@ -347,6 +729,114 @@ impl BackendMap {
         cluster_backends.set_load_balancing_policy(lb_algo, metric);
     }
 
+    pub fn set_retry_budget_for_cluster(&mut self, cluster_id: &str, retry_budget: Option<u32>) {
+        let cluster_backends = self.get_or_create_backend_list_for_cluster(cluster_id);
+        match (retry_budget, cluster_backends.retry_budget.as_mut()) {
+            (None, _) => cluster_backends.retry_budget = None,
+            // keep the accounting of recent requests if the cluster is updated
+            (Some(percent), Some(budget)) => budget.set_percent(percent),
+            (Some(percent), None) => {
+                cluster_backends.retry_budget = Some(RetryBudget::new(percent))
+            }
+        }
+    }
+
+    pub fn set_sticky_session_fallback_for_cluster(
+        &mut self,
+        cluster_id: &str,
+        fallback: StickySessionFallback,
+    ) {
+        self.get_or_create_backend_list_for_cluster(cluster_id)
+            .sticky_session_fallback = fallback;
+    }
+
+    /// a percentage of 0 disables the fault injection on the cluster
+    pub fn set_fault_injection_for_cluster(&mut self, fault_injection: &FaultInjection) {
+        self.get_or_create_backend_list_for_cluster(&fault_injection.cluster_id)
+            .fault_injection = Some(fault_injection.clone()).filter(|fault| fault.percentage > 0);
+    }
+
+    pub fn fault_injection(&self, cluster_id: &str) -> Option<&FaultInjection> {
+        self.backends
+            .get(cluster_id)
+            .and_then(|cluster_backends| cluster_backends.fault_injection.as_ref())
+    }
+
+    /// The backend of a cluster an established connection goes to, found by its id
+    /// or its address, with the connection counted. For the sessions handed off by
+    /// another worker
+    pub fn backend_of_connection(
+        &mut self,
+        cluster_id: &str,
+        backend_id: Option<&str>,
+        address: Option<SocketAddr>,
+    ) -> Option<Rc<RefCell<Backend>>> {
+        let backends = &self.backends.get(cluster_id)?.backends;
+        let backend = backends
+            .iter()
+            .find(|backend| Some(backend.borrow().backend_id.as_str()) == backend_id)
+            .or_else(|| {
+                backends
+                    .iter()
+                    .find(|backend| Some(backend.borrow().address) == address)
+            })?
+            .clone();
+        backend.borrow_mut().inc_connections();
+        Some(backend)
+    }
+
+    /// account a new request in the retry budget of the cluster
+    pub fn record_request(&mut self, cluster_id: &str) {
+        if let Some(budget) = self
+            .backends
+            .get_mut(cluster_id)
+            .and_then(|cluster_backends| cluster_backends.retry_budget.as_mut())
+        {
+            budget.record_request();
+        }
+    }
+
+    /// returns false if the retry budget of the cluster forbids another connection retry
+    pub fn can_retry(&mut self, cluster_id: &str) -> bool {
+        let budget = match self
+            .backends
+            .get_mut(cluster_id)
+            .and_then(|cluster_backends| cluster_backends.retry_budget.as_mut())
+        {
+            Some(budget) => budget,
+            None => return true,
+        };
+
+        let was_exhausted = budget.exhausted;
+        let allowed = budget.try_retry();
+
+        gauge!(
+            "retry_budget.remaining",
+            budget.remaining() as usize,
+            Some(cluster_id),
+            None
+        );
+
+        if allowed {
+            incr!("retry_budget.retries", Some(cluster_id), None);
+        } else {
+            incr!("retry_budget.exhausted", Some(cluster_id), None);
+            if !was_exhausted {
+                warn!("retry budget of cluster {} is exhausted", cluster_id);
+                push_event(Event {
+                    kind: EventKind::RetryBudgetExhausted as i32,
+                    cluster_id: Some(cluster_id.to_owned()),
+                    backend_id: None,
+                    address: None,
+                    crash_report: None,
+                    tags: BTreeMap::new(),
+                });
+            }
+        }
+
+        allowed
+    }
+
     pub fn get_or_create_backend_list_for_cluster(&mut self, cluster_id: &str) -> &mut BackendList {
         self.backends.entry(cluster_id.to_string()).or_default()
     }
@@ -357,6 +847,16 @@ pub struct BackendList {
     pub backends: Vec<Rc<RefCell<Backend>>>,
     pub next_id: u32,
     pub load_balancing: Box<dyn LoadBalancingAlgorithm>,
+    /// priority tier currently receiving the traffic
+    pub active_priority: Option<u32>,
+    /// since when a lower priority tier than the active one is available
+    pub failback_since: Option<Instant>,
+    /// limits connection retries to a percentage of recent requests
+    pub retry_budget: Option<RetryBudget>,
+    /// what to do when the backend of a sticky session is unavailable
+    pub sticky_session_fallback: StickySessionFallback,
+    /// faults injected in the requests of the cluster, set at runtime
+    pub fault_injection: Option<FaultInjection>,
 }
 
 impl Default for BackendList {
@@ -371,6 +871,11 @@ impl BackendList {
             backends: Vec::new(),
             next_id: 0,
             load_balancing: Box::new(Random),
+            active_priority: None,
+            failback_since: None,
+            retry_budget: None,
+            sticky_session_fallback: StickySessionFallback::Rebalance,
+            fault_injection: None,
         }
     }
 
@@ -385,6 +890,7 @@ impl BackendList {
                 backend.sticky_id.clone(),
                 backend.load_balancing_parameters.clone(),
                 backend.backup,
+                backend.priority,
             );
             list.add_backend(backend);
         }
@@ -408,7 +914,12 @@ impl BackendList {
                 b.sticky_id.clone_from(&backend.sticky_id);
                 b.load_balancing_parameters
                     .clone_from(&backend.load_balancing_parameters);
+                if b.slow_start().is_none() {
+                    b.slow_start_since = None;
+                }
                 b.backup = backend.backup;
+                b.priority = backend.priority;
+                b.set_alternate_addresses(backend.alternate_addresses.clone());
             }
         }
     }
@@ -437,7 +948,14 @@ impl BackendList {
         self.backends
             .iter_mut()
             .find(|b| b.borrow().sticky_id.as_deref() == Some(sticky_session))
-            .and_then(|b| if b.borrow().can_open() { Some(b) } else { None })
+            .and_then(|b| {
+                let usable = b.borrow().can_open() && !b.borrow().is_saturated();
+                if usable {
+                    Some(b)
+                } else {
+                    None
+                }
+            })
     }
 
     pub fn available_backends(&mut self, backup: bool) -> Vec<Rc<RefCell<Backend>>> {
@@ -445,12 +963,33 @@ impl BackendList {
             .iter()
             .filter(|backend| {
                 let owned = backend.borrow();
-                owned.backup == backup && owned.can_open()
+                owned.backup == backup && owned.can_open() && !owned.is_saturated()
             })
             .map(Clone::clone)
             .collect()
     }
 
+    /// true if some backends could be used, but all of them have reached
+    /// their maximum number of connections
+    pub fn is_saturated(&self) -> bool {
+        let mut openable = self
+            .backends
+            .iter()
+            .filter(|backend| backend.borrow().can_open())
+            .peekable();
+
+        openable.peek().is_some() && openable.all(|backend| backend.borrow().is_saturated())
+    }
+
+    /// the longest queue timeout among saturated backends
+    pub fn queue_timeout(&self) -> Option<Duration> {
+        self.backends
+            .iter()
+            .filter(|backend| backend.borrow().is_saturated())
+            .filter_map(|backend| backend.borrow().queue_timeout())
+            .max()
+    }
+
     pub fn next_available_backend(&mut self) -> Option<Rc<RefCell<Backend>>> {
         let mut backends = self.available_backends(false);
 
@@ -462,9 +1001,96 @@ impl BackendList {
             return None;
         }
 
+        let priority = self.select_priority(&backends);
+        backends.retain(|backend| backend.borrow().priority == priority);
+
+        self.load_balancing.next_available_backend(&mut backends)
+    }
+
+    /// Chooses a backend to send a hedged request to, among the available backends
+    /// of the active priority tier, without switching tiers
+    pub fn next_hedge_backend(
+        &mut self,
+        excluded_backend_id: &str,
+    ) -> Option<Rc<RefCell<Backend>>> {
+        let mut backends = self.available_backends(false);
+
+        if backends.is_empty() {
+            backends = self.available_backends(true);
+        }
+
+        backends.retain(|backend| backend.borrow().backend_id != excluded_backend_id);
+
+        if let Some(active) = self.active_priority {
+            if backends
+                .iter()
+                .any(|backend| backend.borrow().priority == active)
+            {
+                backends.retain(|backend| backend.borrow().priority == active);
+            }
+        }
+
+        if backends.is_empty() {
+            return None;
+        }
+
         self.load_balancing.next_available_backend(&mut backends)
     }
 
+    /// Chooses the priority tier receiving the traffic among the available backends.
+    ///
+    /// If the active tier has no available backend left, traffic moves right away
+    /// to the best available tier. Traffic comes back to a better tier only once
+    /// it has been available for `PRIORITY_FAILBACK_DELAY`, to avoid flapping.
+    fn select_priority(&mut self, backends: &[Rc<RefCell<Backend>>]) -> u32 {
+        let best = backends
+            .iter()
+            .map(|backend| backend.borrow().priority)
+            .min()
+            .unwrap_or_default();
+
+        let active = match self.active_priority {
+            Some(active)
+                if backends
+                    .iter()
+                    .any(|backend| backend.borrow().priority == active) =>
+            {
+                active
+            }
+            _ => {
+                self.switch_priority(best);
+                return best;
+            }
+        };
+
+        if best >= active {
+            self.failback_since = None;
+            return active;
+        }
+
+        let failback_since = *self.failback_since.get_or_insert_with(Instant::now);
+        if failback_since.elapsed() >= PRIORITY_FAILBACK_DELAY {
+            self.switch_priority(best);
+            best
+        } else {
+            active
+        }
+    }
+
+    fn switch_priority(&mut self, priority: u32) {
+        if let Some(previous) = self.active_priority {
+            if previous != priority {
+                info!(
+                    "switching backend priority tier from {} to {}",
+                    previous, priority
+                );
+                incr!("backend.priority.switch");
+            }
+        }
+        self.active_priority = Some(priority);
+        self.failback_since = None;
+    }
+
     pub fn set_load_balancing_policy(
         &mut self,
         load_balancing_policy: LoadBalancingAlgorithms,
@@ -485,6 +1111,9 @@ impl BackendList {
                     metric: metric.unwrap_or(LoadMetric::Connections),
                 })
             }
+            LoadBalancingAlgorithms::LeastResponseTime => {
+                self.load_balancing = Box::new(LeastResponseTime)
+            }
         }
     }
 }
@@ -528,6 +1157,7 @@ mod backends_test {
                 None,
                 None,
                 None,
+                None,
             ),
         );
 
@@ -541,7 +1171,14 @@ mod backends_test {
         let cluster_not_recorded = "not";
         backend_map.add_backend(
             "foo",
-            Backend::new("foo-1", "127.0.0.1:9001".parse().unwrap(), None, None, None),
+            Backend::new(
+                "foo-1",
+                "127.0.0.1:9001".parse().unwrap(),
+                None,
+                None,
+                None,
+                None,
+            ),
         );
 
         assert!(backend_map
@@ -574,6 +1211,7 @@ mod backends_test {
                 Some("server-1".to_string()),
                 None,
                 None,
+                None,
             ),
         );
         backend_map.add_backend(
@@ -584,6 +1222,7 @@ mod backends_test {
                 Some("server-2".to_string()),
                 None,
                 None,
+                None,
             ),
         );
         // sticky backend
@@ -595,6 +1234,7 @@ mod backends_test {
                 Some("server-3".to_string()),
                 None,
                 None,
+                None,
             ),
         );
 
@@ -627,6 +1267,35 @@ mod backends_test {
             .is_err());
     }
 
+    #[test]
+    fn it_should_fail_fast_when_the_sticky_backend_is_down() {
+        let mut backend_map = BackendMap::new();
+        let cluster_id = "mycluster";
+
+        let mut sticky_backend = Backend::new(
+            &format!("{cluster_id}-1"),
+            "127.0.0.1:9002".parse().unwrap(),
+            Some("server-1".to_string()),
+            None,
+            None,
+            None,
+        );
+        sticky_backend.set_closing();
+        backend_map.add_backend(cluster_id, sticky_backend);
+        backend_map
+            .set_sticky_session_fallback_for_cluster(cluster_id, StickySessionFallback::Fail);
+
+        assert!(matches!(
+            backend_map.backend_from_sticky_session(cluster_id, "server-1"),
+            Err(BackendError::StickyBackendUnavailable { .. })
+        ));
+        // unknown sticky ids are balanced like requests without sticky session
+        assert!(matches!(
+            backend_map.backend_from_sticky_session(cluster_id, "server-2"),
+            Err(BackendError::NoBackendForCluster(_))
+        ));
+    }
+
     #[test]
     fn it_should_add_a_backend_when_he_doesnt_already_exist() {
         let backend_id = "myback";
@@ -637,6 +1306,7 @@ mod backends_test {
             None,
             None,
             None,
+            None,
         ));
 
         assert_eq!(1, backends_list.backends.len());
@@ -652,6 +1322,7 @@ mod backends_test {
             None,
             None,
             None,
+            None,
         ));
 
         //same backend id
@@ -661,8 +1332,266 @@ mod backends_test {
             None,
             None,
             None,
+            None,
         ));
 
         assert_eq!(1, backends_list.backends.len());
     }
+
+    #[test]
+    fn it_should_skip_saturated_backends() {
+        let mut backends_list = BackendList::new();
+        let load_balancing_parameters = Some(LoadBalancingParams {
+            weight: 100,
+            max_connections: Some(1),
+            queue_timeout: Some(500),
+            slow_start: None,
+        });
+        for (id, address) in [("back-1", "127.0.0.1:80"), ("back-2", "127.0.0.1:81")] {
+            backends_list.add_backend(Backend::new(
+                id,
+                address.parse().unwrap(),
+                None,
+                load_balancing_parameters,
+                None,
+                None,
+            ));
+        }
+
+        backends_list.backends[0].borrow_mut().active_connections = 1;
+        let next = backends_list.next_available_backend().unwrap();
+        assert_eq!(next.borrow().backend_id, "back-2");
+        assert!(!backends_list.is_saturated());
+
+        backends_list.backends[1].borrow_mut().active_connections = 1;
+        assert!(backends_list.next_available_backend().is_none());
+        assert!(backends_list.is_saturated());
+        assert_eq!(
+            backends_list.queue_timeout(),
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn it_should_wake_up_waiting_sessions_on_release() {
+        let mut backend = Backend::new(
+            "back-1",
+            "127.0.0.1:80".parse().unwrap(),
+            None,
+            Some(LoadBalancingParams {
+                weight: 100,
+                max_connections: Some(1),
+                queue_timeout: Some(500),
+                slow_start: None,
+            }),
+            None,
+            None,
+        );
+        backend.active_connections = 1;
+        server::queue_for_backend("cluster_1", Token(1));
+        server::queue_for_backend("cluster_1", Token(1));
+        server::queue_for_backend("cluster_2", Token(2));
+
+        backend.release_connection("cluster_1");
+        let woken_up = server::WOKEN_UP.with(|woken_up| woken_up.take());
+        assert_eq!(woken_up, vec![Token(1)]);
+
+        // the backend was not saturated anymore
+        backend.release_connection("cluster_2");
+        assert!(server::WOKEN_UP.with(|woken_up| woken_up.borrow().is_empty()));
+    }
+
+    #[test]
+    fn it_should_wake_up_waiting_sessions_on_new_backend() {
+        server::queue_for_backend("cluster_1", Token(1));
+        server::queue_for_backend("cluster_1", Token(2));
+        // the session closed before a connection was released
+        server::forget_session(Token(2));
+
+        let mut backend_map = BackendMap::new();
+        backend_map.add_backend(
+            "cluster_1",
+            Backend::new(
+                "back-1",
+                "127.0.0.1:80".parse().unwrap(),
+                None,
+                None,
+                None,
+                None,
+            ),
+        );
+        let woken_up = server::WOKEN_UP.with(|woken_up| woken_up.take());
+        assert_eq!(woken_up, vec![Token(1)]);
+    }
+
+    #[test]
+    fn it_should_forget_closed_sessions() {
+        let now = Instant::now();
+        server::queue_for_backend("cluster_1", Token(3));
+        server::wake_up_at(Token(3), now);
+        server::wake_up_at(Token(4), now);
+
+        server::forget_session(Token(3));
+        assert!(server::BACKEND_QUEUES.with(|waiting| waiting.borrow().is_empty()));
+        let wake_ups = server::WAKE_UPS.with(|wake_ups| wake_ups.take());
+        assert_eq!(
+            wake_ups.into_iter().collect::<Vec<_>>(),
+            vec![(now, Token(4))]
+        );
+    }
+
+    #[test]
+    fn it_should_hedge_to_another_backend() {
+        let mut backends_list = BackendList::new();
+        for (id, address) in [("back-1", "127.0.0.1:80"), ("back-2", "127.0.0.1:81")] {
+            backends_list.add_backend(Backend::new(
+                id,
+                address.parse().unwrap(),
+                None,
+                None,
+                None,
+                None,
+            ));
+        }
+
+        for _ in 0..10 {
+            let next = backends_list.next_hedge_backend("back-1").unwrap();
+            assert_eq!(next.borrow().backend_id, "back-2");
+        }
+
+        backends_list.backends[1].borrow_mut().status = BackendStatus::Closing;
+        assert!(backends_list.next_hedge_backend("back-1").is_none());
+    }
+
+    #[test]
+    fn it_should_interleave_address_families() {
+        let mut backend = Backend::new(
+            "back-1",
+            "[::1]:80".parse().unwrap(),
+            None,
+            None,
+            None,
+            None,
+        );
+        backend.set_alternate_addresses(vec![
+            "[::2]:80".parse().unwrap(),
+            "127.0.0.1:80".parse().unwrap(),
+            "127.0.0.2:80".parse().unwrap(),
+        ]);
+
+        let addresses: Vec<SocketAddr> = ["[::1]:80", "127.0.0.1:80", "[::2]:80", "127.0.0.2:80"]
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect();
+        assert_eq!(backend.connection_addresses(), addresses);
+
+        backend.preferred_address = "127.0.0.1:80".parse().unwrap();
+        let addresses: Vec<SocketAddr> = ["127.0.0.1:80", "[::1]:80", "127.0.0.2:80", "[::2]:80"]
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect();
+        assert_eq!(backend.connection_addresses(), addresses);
+
+        // the preferred address is forgotten once it is removed from the configuration
+        backend.set_alternate_addresses(vec!["[::2]:80".parse().unwrap()]);
+        assert_eq!(backend.preferred_address, backend.address);
+    }
+
+    #[test]
+    fn it_should_stagger_connection_attempts() {
+        let mut backend = Backend::new(
+            "back-1",
+            "[::1]:80".parse().unwrap(),
+            None,
+            None,
+            None,
+            None,
+        );
+        assert!(ConnectionRace::new(&backend).is_none());
+
+        backend.set_alternate_addresses(vec![
+            "127.0.0.1:80".parse().unwrap(),
+            "127.0.0.2:80".parse().unwrap(),
+        ]);
+        let mut connection_race = ConnectionRace::new(&backend).unwrap();
+
+        // the first address is tried by the first connection attempt
+        assert_eq!(connection_race.next_address(), None);
+        connection_race.hurry();
+        assert_eq!(
+            connection_race.next_address(),
+            Some("127.0.0.1:80".parse().unwrap())
+        );
+        assert_eq!(connection_race.next_address(), None);
+        assert!(connection_race.has_addresses());
+
+        connection_race.hurry();
+        assert_eq!(
+            connection_race.next_address(),
+            Some("127.0.0.2:80".parse().unwrap())
+        );
+        assert!(!connection_race.has_addresses());
+        assert!(!connection_race.is_lost());
+        connection_race.first_failed = true;
+        assert!(connection_race.is_lost());
+    }
+
+    #[test]
+    fn it_should_ramp_up_weight_during_slow_start() {
+        let mut backend = Backend::new(
+            "back-1",
+            "127.0.0.1:80".parse().unwrap(),
+            None,
+            Some(LoadBalancingParams {
+                weight: 100,
+                slow_start: Some(10),
+                ..Default::default()
+            }),
+            None,
+            None,
+        );
+        assert_eq!(backend.effective_weight(), 10);
+
+        backend.slow_start_since = Some(Instant::now() - Duration::from_secs(5));
+        assert_eq!(backend.effective_weight(), 50);
+
+        backend.slow_start_since = Some(Instant::now() - Duration::from_secs(10));
+        assert_eq!(backend.effective_weight(), 100);
+        assert!(backend.slow_start_since.is_none());
+    }
+
+    #[test]
+    fn it_should_fail_over_priority_tiers_with_hysteresis() {
+        let mut backends_list = BackendList::new();
+        for (id, address, priority) in [
+            ("primary", "127.0.0.1:80", None),
+            ("secondary", "127.0.0.1:81", Some(1)),
+        ] {
+            backends_list.add_backend(Backend::new(
+                id,
+                address.parse().unwrap(),
+                None,
+                None,
+                None,
+                priority,
+            ));
+        }
+
+        let next = backends_list.next_available_backend().unwrap();
+        assert_eq!(next.borrow().backend_id, "primary");
+
+        // the first tier goes down, fail over right away
+        backends_list.backends[0].borrow_mut().status = BackendStatus::Closing;
+        let next = backends_list.next_available_backend().unwrap();
+        assert_eq!(next.borrow().backend_id, "secondary");
+
+        // the first tier is back, but must be stable before failing back
+        backends_list.backends[0].borrow_mut().status = BackendStatus::Normal;
+        let next = backends_list.next_available_backend().unwrap();
+        assert_eq!(next.borrow().backend_id, "secondary");
+
+        backends_list.failback_since = Some(Instant::now() - PRIORITY_FAILBACK_DELAY);
+        let next = backends_list.next_available_backend().unwrap();
+        assert_eq!(next.borrow().backend_id, "primary");
+    }
 }
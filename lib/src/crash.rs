@@ -0,0 +1,121 @@
+//! What a worker was doing when it crashed
+//!
+//! The worker keeps track of the last request it applied and of its sessions,
+//! so that a panic hook or a fatal error can summarize them in a crash report.
+//! The report is collected from the thread of the event loop, without assuming
+//! that the session manager can still be borrowed.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashSet},
+    rc::{Rc, Weak},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sozu_command::proto::command::CrashReport;
+
+use crate::{server::SessionManager, Protocol};
+
+thread_local! {
+    static CONTEXT: RefCell<CrashContext> = RefCell::new(CrashContext::default());
+}
+
+#[derive(Default)]
+struct CrashContext {
+    last_request_id: Option<String>,
+    sessions: Weak<RefCell<SessionManager>>,
+}
+
+/// remembers the last request applied by the worker
+pub fn applied_request(request_id: &str) {
+    CONTEXT.with(|context| {
+        if let Ok(mut context) = context.try_borrow_mut() {
+            context.last_request_id = Some(request_id.to_owned());
+        }
+    });
+}
+
+/// the sessions of this session manager are summarized in crash reports
+pub(crate) fn watch_sessions(sessions: &Rc<RefCell<SessionManager>>) {
+    CONTEXT.with(|context| {
+        if let Ok(mut context) = context.try_borrow_mut() {
+            context.sessions = Rc::downgrade(sessions);
+        }
+    });
+}
+
+/// Collects a crash report of the current thread
+pub fn collect(worker_id: u32, reason: String, backtrace: Option<String>) -> CrashReport {
+    let crashed_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default();
+
+    let (last_request_id, sessions) = CONTEXT.with(|context| match context.try_borrow() {
+        Ok(context) => (
+            context.last_request_id.clone(),
+            context
+                .sessions
+                .upgrade()
+                .map(|sessions| summarize_sessions(&sessions))
+                .unwrap_or_default(),
+        ),
+        Err(_) => (None, BTreeMap::new()),
+    });
+
+    CrashReport {
+        worker_id,
+        pid: std::process::id() as i32,
+        crashed_at,
+        reason,
+        backtrace,
+        last_request_id,
+        sessions,
+        path: None,
+    }
+}
+
+/// counts the open sessions by protocol, listeners excluded. The session manager,
+/// or a session, may be borrowed by the code that panicked: those are skipped
+fn summarize_sessions(sessions: &Rc<RefCell<SessionManager>>) -> BTreeMap<String, u64> {
+    let mut summary = BTreeMap::new();
+    let Ok(sessions) = sessions.try_borrow() else {
+        return summary;
+    };
+
+    // a session has several entries, one per socket
+    let mut seen = HashSet::new();
+    for (_key, session) in &sessions.slab {
+        if !seen.insert(Rc::as_ptr(session) as *const ()) {
+            continue;
+        }
+        let protocol = match session.try_borrow() {
+            Ok(session) => session.protocol(),
+            Err(_) => {
+                *summary.entry("unavailable".to_owned()).or_insert(0) += 1;
+                continue;
+            }
+        };
+        if matches!(protocol, Protocol::HTTP | Protocol::HTTPS | Protocol::TCP) {
+            *summary.entry(format!("{protocol:?}")).or_insert(0) += 1;
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_crash_context() {
+        let report = collect(1, "no context yet".to_owned(), None);
+        assert!(report.last_request_id.is_none());
+        assert!(report.sessions.is_empty());
+
+        applied_request("ID-ADD-CLUSTER");
+        let report = collect(1, "panicked".to_owned(), None);
+        assert_eq!(report.last_request_id.as_deref(), Some("ID-ADD-CLUSTER"));
+        assert_eq!(report.pid, std::process::id() as i32);
+    }
+}
@@ -0,0 +1,460 @@
+//! Embed a proxy worker in another Rust application
+//!
+//! [`Server::builder()`] gathers the server configuration, the listeners and the
+//! routing configuration, then starts the worker in its own thread. The returned
+//! [`ServerHandle`] sends further orders to the worker and receives the events it
+//! emits (backend up or down, for instance) on a standard channel, without any
+//! configuration file or command socket involved.
+//!
+//! ```no_run
+//! use sozu_command_lib::{
+//!     config::ListenerBuilder,
+//!     proto::command::{
+//!         request::RequestType, AddBackend, Cluster, PathRule, RequestHttpFrontend,
+//!         SocketAddress,
+//!     },
+//! };
+//! use sozu_lib::server::Server;
+//!
+//! let address = SocketAddress::new_v4(127, 0, 0, 1, 8080);
+//! let listener = ListenerBuilder::new_http(address)
+//!     .to_http(None)
+//!     .expect("Could not create HTTP listener");
+//!
+//! let mut handle = Server::builder()
+//!     .with_max_connections(500)
+//!     .with_http_listener(listener)
+//!     .with_cluster(Cluster {
+//!         cluster_id: "my-cluster".to_string(),
+//!         ..Default::default()
+//!     })
+//!     .with_http_frontend(RequestHttpFrontend {
+//!         cluster_id: Some("my-cluster".to_string()),
+//!         address,
+//!         hostname: "example.com".to_string(),
+//!         path: PathRule::prefix(String::from("/")),
+//!         ..Default::default()
+//!     })
+//!     .with_backend(AddBackend {
+//!         cluster_id: "my-cluster".to_string(),
+//!         backend_id: "my-backend".to_string(),
+//!         address: SocketAddress::new_v4(127, 0, 0, 1, 8000),
+//!         ..Default::default()
+//!     })
+//!     .start()
+//!     .expect("could not start the worker");
+//!
+//! // the worker keeps running, orders can be sent at any time
+//! handle
+//!     .execute(RequestType::Status(Default::default()))
+//!     .expect("the worker should answer");
+//!
+//! while let Ok(event) = handle.events().recv() {
+//!     println!("worker event: {event:?}");
+//! }
+//! ```
+
+use std::{
+    io::Error as IoError,
+    os::unix::{io::IntoRawFd, net::UnixStream},
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+};
+
+use mio::net::UnixStream as MioUnixStream;
+use sozu_command::{
+    channel::{Channel, ChannelError},
+    config::{DEFAULT_COMMAND_BUFFER_SIZE, DEFAULT_MAX_COMMAND_BUFFER_SIZE},
+    proto::command::{
+        request::RequestType, response_content::ContentType, ActivateListener, AddBackend,
+        AddCertificate, Cluster, Event, HardStop, HttpListenerConfig, HttpsListenerConfig,
+        InitialState, ListenerType, RequestHttpFrontend, RequestTcpFrontend, ResponseStatus,
+        ServerConfig, SocketAddress, SoftStop, TcpListenerConfig, WorkerRequest, WorkerResponse,
+    },
+    scm_socket::{Listeners, ScmSocket, ScmSocketError},
+};
+
+use crate::server::{Server, ServerError};
+
+/// id of the responses the worker sends for its events, see `server::push_event`
+const EVENT_RESPONSE_ID: &str = "EVENT";
+
+#[derive(thiserror::Error, Debug)]
+pub enum EmbedError {
+    #[error("could not create the channel to the worker: {0}")]
+    CreateChannel(IoError),
+    #[error("channel error with the worker: {0}")]
+    Channel(ChannelError),
+    #[error("could not create the scm socket of the worker: {0}")]
+    ScmSocket(ScmSocketError),
+    #[error("could not spawn the worker thread: {0}")]
+    SpawnThread(IoError),
+    #[error("could not create the worker: {0}")]
+    CreateServer(ServerError),
+    #[error("the worker refused request {id}: {message}")]
+    Request { id: String, message: String },
+    #[error("the worker stopped")]
+    WorkerStopped,
+}
+
+/// Configures a worker before starting it, created with [`Server::builder()`]
+///
+/// The listeners are activated and the routing orders are applied in the order
+/// they were given, once the event loop runs.
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    server_config: ServerConfig,
+    requests: Vec<RequestType>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            server_config: ServerConfig {
+                command_buffer_size: DEFAULT_COMMAND_BUFFER_SIZE,
+                max_command_buffer_size: DEFAULT_MAX_COMMAND_BUFFER_SIZE,
+                ..Default::default()
+            },
+            requests: Vec::new(),
+        }
+    }
+}
+
+impl Server {
+    /// Start configuring a worker to embed in the current process
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+}
+
+impl ServerBuilder {
+    /// replaces the whole server configuration (timeouts, buffers, metrics...)
+    pub fn with_server_config(mut self, server_config: ServerConfig) -> Self {
+        self.server_config = server_config;
+        self
+    }
+
+    pub fn with_max_connections(mut self, max_connections: u64) -> Self {
+        self.server_config.max_connections = max_connections;
+        self
+    }
+
+    pub fn with_buffers(mut self, min_buffers: u64, max_buffers: u64, buffer_size: u64) -> Self {
+        self.server_config.min_buffers = min_buffers;
+        self.server_config.max_buffers = max_buffers;
+        self.server_config.buffer_size = buffer_size;
+        self
+    }
+
+    /// adds the listener and activates it
+    pub fn with_http_listener(mut self, listener: HttpListenerConfig) -> Self {
+        let activate = activate_listener(listener.address, ListenerType::Http);
+        self.requests.push(RequestType::AddHttpListener(listener));
+        self.requests.push(activate);
+        self
+    }
+
+    /// adds the listener and activates it
+    pub fn with_https_listener(mut self, listener: HttpsListenerConfig) -> Self {
+        let activate = activate_listener(listener.address, ListenerType::Https);
+        self.requests.push(RequestType::AddHttpsListener(listener));
+        self.requests.push(activate);
+        self
+    }
+
+    /// adds the listener and activates it
+    pub fn with_tcp_listener(mut self, listener: TcpListenerConfig) -> Self {
+        let activate = activate_listener(listener.address, ListenerType::Tcp);
+        self.requests.push(RequestType::AddTcpListener(listener));
+        self.requests.push(activate);
+        self
+    }
+
+    pub fn with_cluster(self, cluster: Cluster) -> Self {
+        self.with_request(RequestType::AddCluster(cluster))
+    }
+
+    pub fn with_http_frontend(self, frontend: RequestHttpFrontend) -> Self {
+        self.with_request(RequestType::AddHttpFrontend(frontend))
+    }
+
+    pub fn with_https_frontend(self, frontend: RequestHttpFrontend) -> Self {
+        self.with_request(RequestType::AddHttpsFrontend(frontend))
+    }
+
+    pub fn with_tcp_frontend(self, frontend: RequestTcpFrontend) -> Self {
+        self.with_request(RequestType::AddTcpFrontend(frontend))
+    }
+
+    pub fn with_backend(self, backend: AddBackend) -> Self {
+        self.with_request(RequestType::AddBackend(backend))
+    }
+
+    pub fn with_certificate(self, certificate: AddCertificate) -> Self {
+        self.with_request(RequestType::AddCertificate(certificate))
+    }
+
+    /// any other order to apply when the worker starts
+    pub fn with_request(mut self, request: RequestType) -> Self {
+        self.requests.push(request);
+        self
+    }
+
+    /// Creates the worker in a new thread, runs its event loop and applies the
+    /// configuration. Fails on the first order the worker refuses, after stopping it.
+    pub fn start(self) -> Result<ServerHandle, EmbedError> {
+        let (command, proxy) = UnixStream::pair().map_err(EmbedError::CreateChannel)?;
+        proxy
+            .set_nonblocking(true)
+            .map_err(EmbedError::CreateChannel)?;
+        // the handle writes orders and a dedicated thread reads the answers
+        let command_reader = command.try_clone().map_err(EmbedError::CreateChannel)?;
+
+        let buffer_size = self.server_config.command_buffer_size;
+        let max_buffer_size = self.server_config.max_command_buffer_size;
+        let proxy_channel =
+            Channel::new(MioUnixStream::from_std(proxy), buffer_size, max_buffer_size);
+        let mut writer: Channel<WorkerRequest, WorkerResponse> = Channel::new(
+            MioUnixStream::from_std(command),
+            buffer_size,
+            max_buffer_size,
+        );
+        writer.blocking().map_err(EmbedError::Channel)?;
+        let mut reader: Channel<WorkerRequest, WorkerResponse> = Channel::new(
+            MioUnixStream::from_std(command_reader),
+            buffer_size,
+            max_buffer_size,
+        );
+        reader.blocking().map_err(EmbedError::Channel)?;
+
+        let (scm_server, scm_client) = UnixStream::pair().map_err(EmbedError::CreateChannel)?;
+        let scm_client = ScmSocket::new(scm_client.into_raw_fd()).map_err(EmbedError::ScmSocket)?;
+        let scm_server = ScmSocket::new(scm_server.into_raw_fd()).map_err(EmbedError::ScmSocket)?;
+        // the worker waits for listen sockets, it will bind its own
+        scm_client
+            .send_listeners(&Listeners::default())
+            .map_err(EmbedError::ScmSocket)?;
+
+        let (created_tx, created_rx) = mpsc::channel();
+        let server_config = self.server_config;
+        let worker = thread::Builder::new()
+            .name("sozu-worker".to_string())
+            .spawn(move || {
+                let server = Server::try_new_from_config(
+                    proxy_channel,
+                    scm_server,
+                    server_config,
+                    InitialState::default(),
+                    false,
+                );
+                match server {
+                    Ok(mut server) => {
+                        let _ = created_tx.send(Ok(()));
+                        server.run();
+                    }
+                    Err(server_error) => {
+                        let _ = created_tx.send(Err(server_error));
+                    }
+                }
+            })
+            .map_err(EmbedError::SpawnThread)?;
+
+        match created_rx.recv() {
+            Ok(Ok(())) => {}
+            Ok(Err(server_error)) => {
+                let _ = worker.join();
+                return Err(EmbedError::CreateServer(server_error));
+            }
+            Err(_) => return Err(EmbedError::WorkerStopped),
+        }
+
+        let (responses_tx, responses) = mpsc::channel();
+        let (events_tx, events) = mpsc::channel();
+        let reader = thread::Builder::new()
+            .name("sozu-worker-reader".to_string())
+            .spawn(move || read_worker_messages(reader, responses_tx, events_tx))
+            .map_err(EmbedError::SpawnThread)?;
+
+        let mut handle = ServerHandle {
+            channel: writer,
+            responses,
+            events,
+            worker: Some(worker),
+            reader: Some(reader),
+            _scm: scm_client,
+            next_id: 0,
+        };
+
+        for request in self.requests {
+            if let Err(error) = handle.execute(request) {
+                let _ = handle.hard_stop();
+                return Err(error);
+            }
+        }
+
+        Ok(handle)
+    }
+}
+
+fn activate_listener(address: SocketAddress, proxy: ListenerType) -> RequestType {
+    RequestType::ActivateListener(ActivateListener {
+        address,
+        proxy: proxy.into(),
+        from_scm: false,
+    })
+}
+
+/// Sorts the messages of the worker between answers and events, until it stops
+fn read_worker_messages(
+    mut channel: Channel<WorkerRequest, WorkerResponse>,
+    responses: Sender<WorkerResponse>,
+    events: Sender<Event>,
+) {
+    while let Ok(response) = channel.read_message() {
+        if response.id == EVENT_RESPONSE_ID {
+            if let Some(ContentType::Event(event)) = response.content.and_then(|c| c.content_type) {
+                // nobody listens to the events, this is fine
+                let _ = events.send(event);
+            }
+            continue;
+        }
+        if responses.send(response).is_err() {
+            return;
+        }
+    }
+}
+
+/// Gives orders to an embedded worker and receives its events
+///
+/// The worker runs until [`ServerHandle::soft_stop`] or [`ServerHandle::hard_stop`].
+pub struct ServerHandle {
+    channel: Channel<WorkerRequest, WorkerResponse>,
+    responses: Receiver<WorkerResponse>,
+    events: Receiver<Event>,
+    worker: Option<JoinHandle<()>>,
+    reader: Option<JoinHandle<()>>,
+    /// the worker would return its listen sockets on it
+    _scm: ScmSocket,
+    next_id: usize,
+}
+
+impl ServerHandle {
+    /// Sends an order to the worker and waits for its final answer
+    pub fn execute(&mut self, request: RequestType) -> Result<WorkerResponse, EmbedError> {
+        let id = format!("EMBED-{}", self.next_id);
+        self.next_id += 1;
+
+        self.channel
+            .write_message(&WorkerRequest {
+                id: id.clone(),
+                content: request.into(),
+            })
+            .map_err(EmbedError::Channel)?;
+
+        loop {
+            let response = self
+                .responses
+                .recv()
+                .map_err(|_| EmbedError::WorkerStopped)?;
+
+            if response.id != id {
+                debug!(
+                    "ignoring worker answer to a previous request: {:?}",
+                    response
+                );
+                continue;
+            }
+            match ResponseStatus::try_from(response.status) {
+                Ok(ResponseStatus::Processing) => continue,
+                Ok(ResponseStatus::Ok) => return Ok(response),
+                _ => {
+                    return Err(EmbedError::Request {
+                        id,
+                        message: response.message,
+                    })
+                }
+            }
+        }
+    }
+
+    /// Events emitted by the worker: backends going up or down, for instance
+    pub fn events(&self) -> &Receiver<Event> {
+        &self.events
+    }
+
+    /// Lets the current sessions finish, then stops the worker and waits for its thread
+    pub fn soft_stop(mut self) -> Result<(), EmbedError> {
+        self.execute(RequestType::SoftStop(SoftStop {}))?;
+        self.join();
+        Ok(())
+    }
+
+    /// Stops the worker right away and waits for its thread
+    pub fn hard_stop(mut self) -> Result<(), EmbedError> {
+        self.execute(RequestType::HardStop(HardStop {}))?;
+        self.join();
+        Ok(())
+    }
+
+    fn join(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("the embedded worker panicked");
+            }
+        }
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sozu_command::{
+        config::ListenerBuilder,
+        proto::command::{PathRule, Status},
+    };
+
+    #[test]
+    fn embedded_worker() {
+        let address = SocketAddress::new_v4(127, 0, 0, 1, 1065);
+        let listener = ListenerBuilder::new_http(address)
+            .to_http(None)
+            .expect("could not create the listener");
+
+        let mut handle = Server::builder()
+            .with_max_connections(10)
+            .with_buffers(1, 10, 16384)
+            .with_http_listener(listener)
+            .with_cluster(Cluster {
+                cluster_id: "cluster_1".to_string(),
+                ..Default::default()
+            })
+            .with_http_frontend(RequestHttpFrontend {
+                cluster_id: Some("cluster_1".to_string()),
+                address,
+                hostname: "localhost".to_string(),
+                path: PathRule::prefix(String::from("/")),
+                ..Default::default()
+            })
+            .start()
+            .expect("the worker should start");
+
+        handle
+            .execute(RequestType::Status(Status {}))
+            .expect("the worker should answer");
+
+        let refused = handle.execute(RequestType::AddHttpFrontend(RequestHttpFrontend {
+            cluster_id: Some("cluster_1".to_string()),
+            address: SocketAddress::new_v4(127, 0, 0, 1, 1066),
+            hostname: "localhost".to_string(),
+            path: PathRule::prefix(String::from("/")),
+            ..Default::default()
+        }));
+        assert!(matches!(refused, Err(EmbedError::Request { .. })));
+
+        handle.hard_stop().expect("the worker should stop");
+    }
+}
@@ -0,0 +1,47 @@
+//! Established sessions surviving the upgrade of their worker
+//!
+//! When a worker is upgraded, the old worker hands the sessions that only relay
+//! bytes, TCP sessions and the WebSockets of HTTP listeners, off to the new worker.
+//! Their sockets go through the main process on the SCM sockets, along with the
+//! minimal state needed to recreate them. Sessions in the middle of a request,
+//! with buffered data, or encrypted with TLS stay in the old worker, which drains
+//! them as before.
+
+use std::str::FromStr;
+
+use mio::net::TcpStream;
+use rusty_ulid::Ulid;
+use sozu_command::proto::command::HandedOffSession;
+
+use crate::protocol::{http::parser::Method, pipe::WebSocketContext};
+
+/// Sessions handed off in one upgrade, so that their sockets fit in the buffers
+/// of the SCM sockets. The other sessions are drained by the old worker
+pub const MAX_HANDED_OFF_SESSIONS: usize = 256;
+
+/// A session taken out of its worker, with its sockets
+#[derive(Debug)]
+pub struct SessionHandoff {
+    pub session: HandedOffSession,
+    pub frontend: TcpStream,
+    pub backend: TcpStream,
+}
+
+/// the request id of a handed off session, or a new one if it can not be parsed
+pub fn request_id(session: &HandedOffSession) -> Ulid {
+    Ulid::from_str(&session.request_id).unwrap_or_else(|_| Ulid::generate())
+}
+
+/// the request that upgraded a handed off WebSocket, for its access logs
+pub fn websocket_context(session: &HandedOffSession) -> WebSocketContext {
+    WebSocketContext::Http {
+        method: session
+            .method
+            .as_ref()
+            .map(|method| Method::new(method.as_bytes())),
+        authority: session.authority.clone(),
+        path: session.path.clone(),
+        status: session.status.and_then(|status| u16::try_from(status).ok()),
+        reason: None,
+    }
+}
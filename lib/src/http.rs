@@ -1,6 +1,6 @@
 use std::{
     cell::RefCell,
-    collections::{hash_map::Entry, BTreeMap, HashMap},
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     io::ErrorKind,
     net::{Shutdown, SocketAddr},
     os::unix::io::AsRawFd,
@@ -29,6 +29,7 @@ use sozu_command::{
 
 use crate::{
     backends::BackendMap,
+    handoff::{self, SessionHandoff},
     pool::Pool,
     protocol::{
         http::{
@@ -40,8 +41,8 @@ use crate::{
         Http, Pipe, SessionState,
     },
     router::{Route, Router},
-    server::{ListenToken, SessionManager},
-    socket::server_bind,
+    server::{paced_accept, AcceptPacer, ListenToken, SessionManager},
+    socket::{canonical_address, server_bind},
     timer::TimeoutContainer,
     AcceptError, FrontendFromRequestError, L7ListenerHandler, L7Proxy, ListenerError,
     ListenerHandler, Protocol, ProxyConfiguration, ProxyError, ProxySession, SessionIsToBeClosed,
@@ -119,7 +120,7 @@ impl HttpSession {
             ))
         } else {
             gauge_add!("protocol.http", 1);
-            let session_address = sock.peer_addr().ok();
+            let session_address = sock.peer_addr().ok().map(canonical_address);
 
             HttpStateMachine::Http(Http::new(
                 answers.clone(),
@@ -338,6 +339,35 @@ impl ProxySession for HttpSession {
         state_result == StateResult::CloseSession
     }
 
+    fn hand_off(&mut self) -> Option<SessionHandoff> {
+        if self.has_been_closed {
+            return None;
+        }
+        let session = match &self.state {
+            HttpStateMachine::WebSocket(pipe) if pipe.can_hand_off() => {
+                pipe.handoff_state(ListenerType::Http)
+            }
+            _ => return None,
+        };
+
+        gauge_add!("protocol.ws", -1);
+        gauge_add!("websocket.active_requests", -1);
+        self.state.cancel_timeouts();
+        self.state.close(self.proxy.clone(), &mut self.metrics);
+        self.has_been_closed = true;
+
+        let HttpStateMachine::WebSocket(mut pipe) = self.state.take() else {
+            unreachable!()
+        };
+        pipe.release_backend_connection();
+        let (frontend, backend) = pipe.into_sockets()?;
+        Some(SessionHandoff {
+            session,
+            frontend,
+            backend,
+        })
+    }
+
     fn protocol(&self) -> Protocol {
         Protocol::HTTP
     }
@@ -389,6 +419,10 @@ impl ProxySession for HttpSession {
     fn frontend_token(&self) -> Token {
         self.frontend_token
     }
+
+    fn listener_address(&self) -> Option<SocketAddr> {
+        Some(*self.listener.borrow().get_addr())
+    }
 }
 
 pub type Hostname = String;
@@ -400,7 +434,12 @@ pub struct HttpListener {
     config: HttpListenerConfig,
     fronts: Router,
     listener: Option<MioTcpListener>,
+    pacer: Option<AcceptPacer>,
     tags: BTreeMap<String, CachedTags>,
+    /// tags of the listener, merged into those of its frontends
+    default_tags: Option<CachedTags>,
+    /// hostnames of the frontends whose responses carry the debug trace headers
+    traced_hostnames: HashSet<String>,
     token: Token,
 }
 
@@ -410,12 +449,18 @@ impl ListenerHandler for HttpListener {
     }
 
     fn get_tags(&self, key: &str) -> Option<&CachedTags> {
-        self.tags.get(key)
+        self.tags.get(key).or(self.default_tags.as_ref())
+    }
+
+    fn get_default_tags(&self) -> Option<&CachedTags> {
+        self.default_tags.as_ref()
     }
 
     fn set_tags(&mut self, key: String, tags: Option<BTreeMap<String, String>>) {
         match tags {
-            Some(tags) => self.tags.insert(key, CachedTags::new(tags)),
+            Some(tags) => self
+                .tags
+                .insert(key, CachedTags::with_defaults(&self.config.tags, tags)),
             None => self.tags.remove(&key),
         };
     }
@@ -430,6 +475,24 @@ impl L7ListenerHandler for HttpListener {
         self.config.connect_timeout
     }
 
+    fn get_strict_responses(&self) -> bool {
+        self.config.strict_responses.unwrap_or(false)
+    }
+
+    fn get_debug_trace_key(&self) -> Option<&str> {
+        self.config.debug_trace_key.as_deref()
+    }
+
+    fn has_debug_trace(&self, hostname: &str) -> bool {
+        self.traced_hostnames.contains(hostname)
+    }
+
+    fn describe_frontend(&self, hostname: &str, uri: &str, method: &Method) -> Option<String> {
+        self.fronts
+            .lookup_rule(hostname, uri, method)
+            .map(|rule| rule.to_string())
+    }
+
     // redundant, already called once in extract_route
     fn frontend_from_request(
         &self,
@@ -717,6 +780,133 @@ impl HttpProxy {
 
         Ok(())
     }
+
+    /// Recreates a WebSocket handed off by another worker, around its established sockets
+    pub fn adopt_session(
+        &mut self,
+        handoff: SessionHandoff,
+        proxy: Rc<RefCell<Self>>,
+    ) -> Result<(), AcceptError> {
+        let SessionHandoff {
+            session,
+            frontend: mut frontend_sock,
+            backend: backend_sock,
+        } = handoff;
+
+        let listener_address: SocketAddr = session.listener_address.into();
+        let listener = self
+            .listeners
+            .values()
+            .find(|listener| listener.borrow().address == listener_address)
+            .cloned()
+            .ok_or(AcceptError::WrongSocketAddress)?;
+
+        let (front_buffer, back_buffer) = {
+            let mut pool = self.pool.borrow_mut();
+            match (pool.checkout(), pool.checkout()) {
+                (Some(fb), Some(bb)) => (fb, bb),
+                _ => return Err(AcceptError::BufferCapacityReached),
+            }
+        };
+
+        let backend = session.cluster_id.as_ref().and_then(|cluster_id| {
+            self.backends.borrow_mut().backend_of_connection(
+                cluster_id,
+                session.backend_id.as_deref(),
+                backend_sock.peer_addr().ok(),
+            )
+        });
+        if let Some(backend) = &backend {
+            backend.borrow_mut().active_requests += 1;
+        }
+
+        let mut session_manager = self.sessions.borrow_mut();
+        let frontend_token = Token(session_manager.slab.vacant_key());
+        if let Err(register_error) = self.registry.register(
+            &mut frontend_sock,
+            frontend_token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            error!(
+                "error registering adopted front socket({:?}): {:?}",
+                frontend_sock, register_error
+            );
+            return Err(AcceptError::RegisterError);
+        }
+
+        let owned = listener.borrow();
+        let session_address = frontend_sock.peer_addr().ok().map(canonical_address);
+        let pipe = Pipe::new(
+            back_buffer,
+            session.backend_id.clone(),
+            Some(backend_sock),
+            backend,
+            Some(TimeoutContainer::new_empty(Duration::from_secs(
+                owned.config.back_timeout as u64,
+            ))),
+            Some(TimeoutContainer::new(
+                Duration::from_secs(owned.config.front_timeout as u64),
+                frontend_token,
+            )),
+            session.cluster_id.clone(),
+            front_buffer,
+            frontend_token,
+            frontend_sock,
+            listener.clone(),
+            Protocol::HTTP,
+            handoff::request_id(&session),
+            session_address,
+            handoff::websocket_context(&session),
+        );
+
+        let http_session = Rc::new(RefCell::new(HttpSession {
+            answers: owned.answers.clone(),
+            configured_backend_timeout: Duration::from_secs(owned.config.back_timeout as u64),
+            configured_connect_timeout: Duration::from_secs(owned.config.connect_timeout as u64),
+            configured_frontend_timeout: Duration::from_secs(owned.config.front_timeout as u64),
+            frontend_token,
+            has_been_closed: false,
+            last_event: Instant::now(),
+            listener: listener.clone(),
+            metrics: SessionMetrics::new(None),
+            pool: Rc::downgrade(&self.pool),
+            proxy,
+            state: HttpStateMachine::WebSocket(pipe),
+            sticky_name: owned.config.sticky_name.clone(),
+        }));
+        session_manager.slab.insert(http_session.clone());
+        let back_token = Token(session_manager.slab.insert(http_session.clone()));
+
+        if let HttpStateMachine::WebSocket(pipe) = &mut http_session.borrow_mut().state {
+            pipe.set_back_token(back_token);
+            if let Some(timeout) = pipe.container_backend_timeout.as_mut() {
+                timeout.set(back_token);
+            }
+            if let Some(backend_sock) = pipe.back_socket_mut() {
+                if let Err(register_error) = self.registry.register(
+                    backend_sock,
+                    back_token,
+                    Interest::READABLE | Interest::WRITABLE,
+                ) {
+                    error!(
+                        "error registering adopted back socket({:?}): {:?}",
+                        backend_sock, register_error
+                    );
+                }
+            }
+        }
+
+        gauge_add!("protocol.ws", 1);
+        gauge_add!("websocket.active_requests", 1);
+        gauge_add!("backend.connections", 1);
+        gauge_add!(
+            "connections_per_backend",
+            1,
+            session.cluster_id.as_deref(),
+            session.backend_id.as_deref()
+        );
+        Ok(())
+    }
 }
 
 impl HttpListener {
@@ -728,10 +918,13 @@ impl HttpListener {
                 HttpAnswers::new(&config.http_answers)
                     .map_err(|(status, error)| ListenerError::TemplateParse(status, error))?,
             )),
+            pacer: AcceptPacer::new(config.accept_rate, config.accept_burst),
+            default_tags: (!config.tags.is_empty()).then(|| CachedTags::new(config.tags.clone())),
             config,
             fronts: Router::new(),
             listener: None,
             tags: BTreeMap::new(),
+            traced_hostnames: HashSet::new(),
             token,
         })
     }
@@ -748,12 +941,12 @@ impl HttpListener {
 
         let mut listener = match tcp_listener {
             Some(tcp_listener) => tcp_listener,
-            None => {
-                server_bind(address).map_err(|server_bind_error| ListenerError::Activation {
+            None => server_bind(address, self.config.v6only).map_err(|server_bind_error| {
+                ListenerError::Activation {
                     address,
                     error: server_bind_error.to_string(),
-                })?
-            }
+                }
+            })?,
         };
 
         registry
@@ -768,27 +961,35 @@ impl HttpListener {
     pub fn add_http_front(&mut self, http_front: HttpFrontend) -> Result<(), ListenerError> {
         self.fronts
             .add_http_front(&http_front)
-            .map_err(ListenerError::AddFrontend)
+            .map_err(ListenerError::AddFrontend)?;
+        if http_front.debug_trace == Some(true) {
+            self.traced_hostnames.insert(http_front.hostname);
+        }
+        Ok(())
     }
 
     pub fn remove_http_front(&mut self, http_front: HttpFrontend) -> Result<(), ListenerError> {
         debug!("removing http_front {:?}", http_front);
         self.fronts
             .remove_http_front(&http_front)
-            .map_err(ListenerError::RemoveFrontend)
+            .map_err(ListenerError::RemoveFrontend)?;
+        self.traced_hostnames.remove(&http_front.hostname);
+        Ok(())
     }
 
     fn accept(&mut self) -> Result<TcpStream, AcceptError> {
         if let Some(ref sock) = self.listener {
-            sock.accept()
-                .map_err(|e| match e.kind() {
-                    ErrorKind::WouldBlock => AcceptError::WouldBlock,
-                    _ => {
-                        error!("accept() IO error: {:?}", e);
-                        AcceptError::IoError
-                    }
-                })
-                .map(|(sock, _)| sock)
+            paced_accept(&mut self.pacer, || {
+                sock.accept()
+                    .map_err(|e| match e.kind() {
+                        ErrorKind::WouldBlock => AcceptError::WouldBlock,
+                        _ => {
+                            error!("accept() IO error: {:?}", e);
+                            AcceptError::IoError
+                        }
+                    })
+                    .map(|(sock, _)| sock)
+            })
         } else {
             error!("cannot accept connections, no listening socket available");
             Err(AcceptError::IoError)
@@ -1054,11 +1255,16 @@ mod tests {
     use super::*;
     use sozu_command::proto::command::{CustomHttpAnswers, SocketAddress};
 
-    use crate::sozu_command::{
-        channel::Channel,
-        config::ListenerBuilder,
-        proto::command::{LoadBalancingParams, PathRule, RulePosition, WorkerRequest},
-        response::{Backend, HttpFrontend},
+    use crate::{
+        sozu_command::{
+            channel::Channel,
+            config::ListenerBuilder,
+            proto::command::{
+                HandedOffSession, LoadBalancingParams, PathRule, RulePosition, WorkerRequest,
+            },
+            response::{Backend, HttpFrontend},
+        },
+        testing::{prebuild_server, ServerParts},
     };
 
     use std::{
@@ -1121,6 +1327,8 @@ mod tests {
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
             backup: None,
+            priority: None,
+            alternate_addresses: Vec::new(),
         };
         command
             .write_message(&WorkerRequest {
@@ -1204,6 +1412,8 @@ mod tests {
             cluster_id: String::from("cluster_1"),
             load_balancing_parameters: Some(LoadBalancingParams::default()),
             sticky_id: None,
+            priority: None,
+            alternate_addresses: Vec::new(),
         };
         command
             .write_message(&WorkerRequest {
@@ -1326,6 +1536,8 @@ mod tests {
                 position: RulePosition::Tree,
                 cluster_id: Some(cluster_id1),
                 tags: None,
+                matching: None,
+                debug_trace: None,
             })
             .expect("Could not add http frontend");
         fronts
@@ -1337,6 +1549,8 @@ mod tests {
                 position: RulePosition::Tree,
                 cluster_id: Some(cluster_id2),
                 tags: None,
+                matching: None,
+                debug_trace: None,
             })
             .expect("Could not add http frontend");
         fronts
@@ -1348,6 +1562,8 @@ mod tests {
                 position: RulePosition::Tree,
                 cluster_id: Some(cluster_id3),
                 tags: None,
+                matching: None,
+                debug_trace: None,
             })
             .expect("Could not add http frontend");
         fronts
@@ -1359,6 +1575,8 @@ mod tests {
                 position: RulePosition::Tree,
                 cluster_id: Some("cluster_1".to_owned()),
                 tags: None,
+                matching: None,
+                debug_trace: None,
             })
             .expect("Could not add http frontend");
 
@@ -1376,9 +1594,12 @@ mod tests {
                 HttpAnswers::new(&Some(CustomHttpAnswers::default())).unwrap(),
             )),
             config: default_config,
+            pacer: None,
             token: Token(0),
             active: true,
             tags: BTreeMap::new(),
+            default_tags: None,
+            traced_hostnames: HashSet::new(),
         };
 
         let frontend1 = listener.frontend_from_request("lolcatho.st", "/", &Method::Get);
@@ -1404,4 +1625,128 @@ mod tests {
         );
         assert!(frontend5.is_err());
     }
+
+    #[test]
+    fn listener_default_tags() {
+        let config = ListenerBuilder::new_http(SocketAddress::new_v4(127, 0, 0, 1, 1031))
+            .with_tags(Some(BTreeMap::from([
+                ("datacenter".to_owned(), "par1".to_owned()),
+                ("owner".to_owned(), "edge".to_owned()),
+            ])))
+            .to_http(None)
+            .expect("Could not create HTTP listener config");
+        let mut listener = HttpListener::new(config, Token(0)).expect("Could not create listener");
+
+        listener.set_tags(
+            "lolcatho.st".to_owned(),
+            Some(BTreeMap::from([("owner".to_owned(), "bob".to_owned())])),
+        );
+        assert_eq!(
+            listener.get_concatenated_tags("lolcatho.st"),
+            Some("datacenter=par1, owner=bob")
+        );
+        assert_eq!(
+            listener.get_concatenated_tags("unknown.host"),
+            Some("datacenter=par1, owner=edge")
+        );
+        assert_eq!(
+            listener.get_event_tags(),
+            BTreeMap::from([
+                ("datacenter".to_owned(), "par1".to_owned()),
+                ("owner".to_owned(), "edge".to_owned()),
+            ])
+        );
+
+        listener.set_tags("lolcatho.st".to_owned(), None);
+        assert_eq!(
+            listener.get_concatenated_tags("lolcatho.st"),
+            Some("datacenter=par1, owner=edge")
+        );
+    }
+
+    /// a connected pair of sockets, the first one on the side that accepted
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("could not bind");
+        let client = TcpStream::connect(listener.local_addr().unwrap()).expect("could not connect");
+        let (server, _) = listener.accept().expect("could not accept");
+        (server, client)
+    }
+
+    #[test]
+    fn handed_off_websockets_release_their_backend_connection() {
+        let ServerParts {
+            event_loop,
+            registry,
+            sessions,
+            pool,
+            backends,
+            ..
+        } = prebuild_server(10, 16384, false).expect("could not build the server");
+        let listener_address: SocketAddr = "127.0.0.1:1260".parse().unwrap();
+        let proxy = Rc::new(RefCell::new(HttpProxy::new(
+            registry,
+            sessions.clone(),
+            pool,
+            backends.clone(),
+        )));
+        proxy
+            .borrow_mut()
+            .add_listener(
+                ListenerBuilder::new_http(listener_address.into())
+                    .to_http(None)
+                    .unwrap(),
+                Token(10),
+            )
+            .unwrap();
+        let (frontend, _client) = socket_pair();
+        let (_backend_server, backend_socket) = socket_pair();
+        let backend_address = backend_socket.peer_addr().unwrap();
+        backends.borrow_mut().add_backend(
+            "cluster_0",
+            crate::backends::Backend::new("cluster_0-0", backend_address, None, None, None, None),
+        );
+        let active_connections = || {
+            backends.borrow().backends["cluster_0"].backends[0]
+                .borrow()
+                .active_connections
+        };
+
+        let handoff = SessionHandoff {
+            session: HandedOffSession {
+                listener_type: ListenerType::Http.into(),
+                listener_address: listener_address.into(),
+                cluster_id: Some("cluster_0".to_owned()),
+                backend_id: Some("cluster_0-0".to_owned()),
+                request_id: Ulid::generate().to_string(),
+                method: Some("GET".to_owned()),
+                authority: Some("localhost".to_owned()),
+                path: Some("/".to_owned()),
+                status: Some(101),
+            },
+            frontend: mio::net::TcpStream::from_std(frontend),
+            backend: mio::net::TcpStream::from_std(backend_socket),
+        };
+        proxy
+            .borrow_mut()
+            .adopt_session(handoff, proxy.clone())
+            .expect("could not adopt the session");
+        assert_eq!(active_connections(), 1);
+
+        let session = sessions.borrow().slab.iter().last().unwrap().1.clone();
+        let mut handoff = session
+            .borrow_mut()
+            .hand_off()
+            .expect("the session should be handed off");
+        assert_eq!(active_connections(), 0);
+
+        // the sessions that could not be sent are adopted back by their worker
+        for socket in [&mut handoff.frontend, &mut handoff.backend] {
+            event_loop.registry().deregister(socket).unwrap();
+        }
+        proxy
+            .borrow_mut()
+            .adopt_session(handoff, proxy.clone())
+            .expect("could not adopt the session back");
+        assert_eq!(active_connections(), 1);
+    }
 }
@@ -1,11 +1,11 @@
 use std::{
     cell::RefCell,
-    collections::{hash_map::Entry, BTreeMap, HashMap},
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     io::ErrorKind,
     net::{Shutdown, SocketAddr as StdSocketAddr},
     os::unix::io::AsRawFd,
     rc::{Rc, Weak},
-    str::{from_utf8, from_utf8_unchecked},
+    str::{from_utf8, from_utf8_unchecked, FromStr},
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -38,10 +38,11 @@ use sozu_command::{
     certificate::Fingerprint,
     config::DEFAULT_CIPHER_SUITES,
     proto::command::{
-        request::RequestType, response_content::ContentType, AddCertificate, CertificateSummary,
-        CertificatesByAddress, Cluster, HttpsListenerConfig, ListOfCertificatesByAddress,
-        ListenerType, RemoveCertificate, RemoveListener, ReplaceCertificate, RequestHttpFrontend,
-        ResponseContent, TlsVersion, WorkerRequest, WorkerResponse,
+        request::RequestType, response_content::ContentType, AddCertificate, CertificateAndKey,
+        CertificateSummary, CertificatesByAddress, Cluster, HttpsListenerConfig,
+        ListOfCertificatesByAddress, ListenerType, PinCertificate, RemoveCertificate,
+        RemoveListener, ReplaceCertificate, RequestHttpFrontend, ResponseContent, TlsVersion,
+        WorkerRequest, WorkerResponse,
     },
     ready::Ready,
     response::HttpFrontend,
@@ -59,14 +60,14 @@ use crate::{
             ResponseStream,
         },
         proxy_protocol::expect::ExpectProxyProtocol,
-        rustls::TlsHandshake,
+        rustls::{CertificateAcceptor, TlsHandshake},
         Http, Pipe, SessionState,
     },
     router::{Route, Router},
-    server::{ListenToken, SessionManager},
-    socket::{server_bind, FrontRustls},
+    server::{paced_accept, AcceptPacer, ListenToken, SessionManager},
+    socket::{canonical_address, server_bind, FrontRustls},
     timer::TimeoutContainer,
-    tls::MutexCertificateResolver,
+    tls::{CertificateFallback, MutexCertificateResolver, TlsConfigs},
     util::UnwrapLog,
     AcceptError, CachedTags, FrontendFromRequestError, L7ListenerHandler, L7Proxy, ListenerError,
     ListenerHandler, Protocol, ProxyConfiguration, ProxyError, ProxySession, SessionIsToBeClosed,
@@ -84,7 +85,7 @@ StateMachineBuilder! {
     /// - HTTP or HTTP2
     /// - WebSocket (passthrough), only from HTTP
     enum HttpsStateMachine impl SessionState {
-        Expect(ExpectProxyProtocol<MioTcpStream>, ServerConnection),
+        Expect(ExpectProxyProtocol<MioTcpStream>, ServerConnection, Option<CertificateAcceptor>),
         Handshake(TlsHandshake),
         Http(Http<FrontRustls, HttpsListener>),
         WebSocket(Pipe<FrontRustls, HttpsListener>),
@@ -129,6 +130,7 @@ impl HttpsSession {
         proxy: Rc<RefCell<HttpsProxy>>,
         public_address: StdSocketAddr,
         rustls_details: ServerConnection,
+        certificate_acceptor: Option<CertificateAcceptor>,
         sock: MioTcpStream,
         sticky_name: String,
         token: Token,
@@ -138,7 +140,7 @@ impl HttpsSession {
             // Will be defined later once the expect proxy header has been received and parsed
             None
         } else {
-            sock.peer_addr().ok()
+            sock.peer_addr().ok().map(canonical_address)
         };
 
         let request_id = Ulid::generate();
@@ -150,12 +152,14 @@ impl HttpsSession {
             HttpsStateMachine::Expect(
                 ExpectProxyProtocol::new(container_frontend_timeout, sock, token, request_id),
                 rustls_details,
+                certificate_acceptor,
             )
         } else {
             gauge_add!("protocol.tls.handshake", 1);
             HttpsStateMachine::Handshake(TlsHandshake::new(
                 container_frontend_timeout,
                 rustls_details,
+                certificate_acceptor,
                 sock,
                 token,
                 request_id,
@@ -186,7 +190,9 @@ impl HttpsSession {
     pub fn upgrade(&mut self) -> SessionIsToBeClosed {
         debug!("HTTP::upgrade");
         let new_state = match self.state.take() {
-            HttpsStateMachine::Expect(expect, ssl) => self.upgrade_expect(expect, ssl),
+            HttpsStateMachine::Expect(expect, ssl, certificate_acceptor) => {
+                self.upgrade_expect(expect, ssl, certificate_acceptor)
+            }
             HttpsStateMachine::Handshake(handshake) => self.upgrade_handshake(handshake),
             HttpsStateMachine::Http(http) => self.upgrade_http(http),
             HttpsStateMachine::Http2(_) => self.upgrade_http2(),
@@ -208,6 +214,7 @@ impl HttpsSession {
         &mut self,
         mut expect: ExpectProxyProtocol<MioTcpStream>,
         ssl: ServerConnection,
+        certificate_acceptor: Option<CertificateAcceptor>,
     ) -> Option<HttpsStateMachine> {
         if let Some(ref addresses) = expect.addresses {
             if let (Some(public_address), Some(session_address)) =
@@ -227,6 +234,7 @@ impl HttpsSession {
                 let mut handshake = TlsHandshake::new(
                     container_frontend_timeout,
                     ssl,
+                    certificate_acceptor,
                     frontend,
                     self.frontend_token,
                     request_id,
@@ -519,6 +527,10 @@ impl ProxySession for HttpsSession {
     fn frontend_token(&self) -> Token {
         self.frontend_token
     }
+
+    fn listener_address(&self) -> Option<StdSocketAddr> {
+        Some(*self.listener.borrow().get_addr())
+    }
 }
 
 pub type HostName = String;
@@ -533,7 +545,14 @@ pub struct HttpsListener {
     listener: Option<MioTcpListener>,
     resolver: Arc<MutexCertificateResolver>,
     rustls_details: Arc<RustlsServerConfig>,
+    /// fingerprint -> rustls configuration, for certificates with their own TLS settings
+    certificate_configs: Arc<HashMap<Fingerprint, Arc<RustlsServerConfig>>>,
+    pacer: Option<AcceptPacer>,
     tags: BTreeMap<String, CachedTags>,
+    /// tags of the listener, merged into those of its frontends
+    default_tags: Option<CachedTags>,
+    /// hostnames of the frontends whose responses carry the debug trace headers
+    traced_hostnames: HashSet<String>,
     token: Token,
 }
 
@@ -543,12 +562,18 @@ impl ListenerHandler for HttpsListener {
     }
 
     fn get_tags(&self, key: &str) -> Option<&CachedTags> {
-        self.tags.get(key)
+        self.tags.get(key).or(self.default_tags.as_ref())
+    }
+
+    fn get_default_tags(&self) -> Option<&CachedTags> {
+        self.default_tags.as_ref()
     }
 
     fn set_tags(&mut self, key: String, tags: Option<BTreeMap<String, String>>) {
         match tags {
-            Some(tags) => self.tags.insert(key, CachedTags::new(tags)),
+            Some(tags) => self
+                .tags
+                .insert(key, CachedTags::with_defaults(&self.config.tags, tags)),
             None => self.tags.remove(&key),
         };
     }
@@ -563,6 +588,24 @@ impl L7ListenerHandler for HttpsListener {
         self.config.connect_timeout
     }
 
+    fn get_strict_responses(&self) -> bool {
+        self.config.strict_responses.unwrap_or(false)
+    }
+
+    fn get_debug_trace_key(&self) -> Option<&str> {
+        self.config.debug_trace_key.as_deref()
+    }
+
+    fn has_debug_trace(&self, hostname: &str) -> bool {
+        self.traced_hostnames.contains(hostname)
+    }
+
+    fn describe_frontend(&self, hostname: &str, uri: &str, method: &Method) -> Option<String> {
+        self.fronts
+            .lookup_rule(hostname, uri, method)
+            .map(|rule| rule.to_string())
+    }
+
     fn frontend_from_request(
         &self,
         host: &str,
@@ -612,7 +655,9 @@ impl HttpsListener {
         config: HttpsListenerConfig,
         token: Token,
     ) -> Result<HttpsListener, ListenerError> {
-        let resolver = Arc::new(MutexCertificateResolver::default());
+        let fallback =
+            CertificateFallback::try_from_config(&config).map_err(ListenerError::Resolver)?;
+        let resolver = Arc::new(MutexCertificateResolver::new(fallback));
 
         let server_config = Arc::new(Self::create_rustls_context(&config, resolver.to_owned())?);
 
@@ -621,15 +666,19 @@ impl HttpsListener {
             address: config.address.clone().into(),
             resolver,
             rustls_details: server_config,
+            certificate_configs: Arc::new(HashMap::new()),
             active: false,
             fronts: Router::new(),
             answers: Rc::new(RefCell::new(
                 HttpAnswers::new(&config.http_answers)
                     .map_err(|(status, error)| ListenerError::TemplateParse(status, error))?,
             )),
+            pacer: AcceptPacer::new(config.accept_rate, config.accept_burst),
+            default_tags: (!config.tags.is_empty()).then(|| CachedTags::new(config.tags.clone())),
             config,
             token,
             tags: BTreeMap::new(),
+            traced_hostnames: HashSet::new(),
         })
     }
 
@@ -645,12 +694,12 @@ impl HttpsListener {
 
         let mut listener = match tcp_listener {
             Some(tcp_listener) => tcp_listener,
-            None => {
-                server_bind(address).map_err(|server_bind_error| ListenerError::Activation {
+            None => server_bind(address, self.config.v6only).map_err(|server_bind_error| {
+                ListenerError::Activation {
                     address,
                     error: server_bind_error.to_string(),
-                })?
-            }
+                }
+            })?,
         };
 
         registry
@@ -734,30 +783,88 @@ impl HttpsListener {
         Ok(server_config)
     }
 
+    /// the rustls configuration of a certificate with its own TLS versions or cipher suites,
+    /// the other settings are those of the listener
+    fn certificate_config(
+        &self,
+        certificate: &CertificateAndKey,
+    ) -> Result<Option<Arc<RustlsServerConfig>>, ListenerError> {
+        if certificate.versions.is_empty() && certificate.cipher_list.is_empty() {
+            return Ok(None);
+        }
+
+        let mut config = self.config.clone();
+        if !certificate.versions.is_empty() {
+            config.versions = certificate.versions.clone();
+        }
+        if !certificate.cipher_list.is_empty() {
+            config.cipher_list = certificate.cipher_list.clone();
+        }
+        Self::create_rustls_context(&config, self.resolver.clone())
+            .map(|server_config| Some(Arc::new(server_config)))
+    }
+
+    fn set_certificate_config(
+        &mut self,
+        fingerprint: Fingerprint,
+        server_config: Option<Arc<RustlsServerConfig>>,
+    ) {
+        match server_config {
+            Some(server_config) => {
+                Arc::make_mut(&mut self.certificate_configs).insert(fingerprint, server_config);
+            }
+            None if self.certificate_configs.contains_key(&fingerprint) => {
+                Arc::make_mut(&mut self.certificate_configs).remove(&fingerprint);
+            }
+            None => {}
+        }
+    }
+
+    /// the ClientHello is read before the handshake only if some certificates
+    /// have their own TLS settings
+    fn certificate_acceptor(&self) -> Option<CertificateAcceptor> {
+        if self.certificate_configs.is_empty() {
+            return None;
+        }
+        Some(CertificateAcceptor::new(TlsConfigs {
+            resolver: self.resolver.clone(),
+            listener: self.rustls_details.clone(),
+            certificates: self.certificate_configs.clone(),
+        }))
+    }
+
     pub fn add_https_front(&mut self, tls_front: HttpFrontend) -> Result<(), ListenerError> {
         self.fronts
             .add_http_front(&tls_front)
-            .map_err(ListenerError::AddFrontend)
+            .map_err(ListenerError::AddFrontend)?;
+        if tls_front.debug_trace == Some(true) {
+            self.traced_hostnames.insert(tls_front.hostname);
+        }
+        Ok(())
     }
 
     pub fn remove_https_front(&mut self, tls_front: HttpFrontend) -> Result<(), ListenerError> {
         debug!("removing tls_front {:?}", tls_front);
         self.fronts
             .remove_http_front(&tls_front)
-            .map_err(ListenerError::RemoveFrontend)
+            .map_err(ListenerError::RemoveFrontend)?;
+        self.traced_hostnames.remove(&tls_front.hostname);
+        Ok(())
     }
 
     fn accept(&mut self) -> Result<MioTcpStream, AcceptError> {
         if let Some(ref sock) = self.listener {
-            sock.accept()
-                .map_err(|e| match e.kind() {
-                    ErrorKind::WouldBlock => AcceptError::WouldBlock,
-                    _ => {
-                        error!("accept() IO error: {:?}", e);
-                        AcceptError::IoError
-                    }
-                })
-                .map(|(sock, _)| sock)
+            paced_accept(&mut self.pacer, || {
+                sock.accept()
+                    .map_err(|e| match e.kind() {
+                        ErrorKind::WouldBlock => AcceptError::WouldBlock,
+                        _ => {
+                            error!("accept() IO error: {:?}", e);
+                            AcceptError::IoError
+                        }
+                    })
+                    .map(|(sock, _)| sock)
+            })
         } else {
             error!("cannot accept connections, no listening socket available");
             Err(AcceptError::IoError)
@@ -1084,23 +1191,27 @@ impl HttpsProxy {
     ) -> Result<Option<ResponseContent>, ProxyError> {
         let address = add_certificate.address.clone().into();
 
-        let listener = self
+        let mut listener = self
             .listeners
             .values()
             .find(|l| l.borrow().address == address)
             .ok_or(ProxyError::NoListenerFound(address))?
             .borrow_mut();
 
-        let mut resolver = listener
+        let certificate_config = listener
+            .certificate_config(&add_certificate.certificate)
+            .map_err(ProxyError::CertificateTlsSettings)?;
+
+        let fingerprint = listener
             .resolver
             .0
             .lock()
-            .map_err(|e| ProxyError::Lock(e.to_string()))?;
-
-        resolver
+            .map_err(|e| ProxyError::Lock(e.to_string()))?
             .add_certificate(&add_certificate)
             .map_err(ProxyError::AddCertificate)?;
 
+        listener.set_certificate_config(fingerprint, certificate_config);
+
         Ok(None)
     }
 
@@ -1116,23 +1227,23 @@ impl HttpsProxy {
                 .map_err(ProxyError::WrongCertificateFingerprint)?,
         );
 
-        let listener = self
+        let mut listener = self
             .listeners
             .values()
             .find(|l| l.borrow().address == address)
             .ok_or(ProxyError::NoListenerFound(address))?
             .borrow_mut();
 
-        let mut resolver = listener
+        listener
             .resolver
             .0
             .lock()
-            .map_err(|e| ProxyError::Lock(e.to_string()))?;
-
-        resolver
+            .map_err(|e| ProxyError::Lock(e.to_string()))?
             .remove_certificate(&fingerprint)
             .map_err(ProxyError::RemoveCertificate)?;
 
+        listener.set_certificate_config(fingerprint, None);
+
         Ok(None)
     }
 
@@ -1143,23 +1254,63 @@ impl HttpsProxy {
     ) -> Result<Option<ResponseContent>, ProxyError> {
         let address = replace_certificate.address.clone().into();
 
-        let listener = self
+        let mut listener = self
             .listeners
             .values()
             .find(|l| l.borrow().address == address)
             .ok_or(ProxyError::NoListenerFound(address))?
             .borrow_mut();
 
-        let mut resolver = listener
+        let certificate_config = listener
+            .certificate_config(&replace_certificate.new_certificate)
+            .map_err(ProxyError::CertificateTlsSettings)?;
+
+        let fingerprint = listener
             .resolver
             .0
             .lock()
-            .map_err(|e| ProxyError::Lock(e.to_string()))?;
-
-        resolver
+            .map_err(|e| ProxyError::Lock(e.to_string()))?
             .replace_certificate(&replace_certificate)
             .map_err(ProxyError::ReplaceCertificate)?;
 
+        if let Ok(old_fingerprint) = Fingerprint::from_str(&replace_certificate.old_fingerprint) {
+            listener.set_certificate_config(old_fingerprint, None);
+        }
+        listener.set_certificate_config(fingerprint, certificate_config);
+
+        Ok(None)
+    }
+
+    pub fn pin_certificate(
+        &mut self,
+        pin_certificate: PinCertificate,
+    ) -> Result<Option<ResponseContent>, ProxyError> {
+        let address = pin_certificate.address.into();
+
+        let fingerprint = pin_certificate
+            .fingerprint
+            .map(|fingerprint| {
+                hex::decode(fingerprint)
+                    .map(Fingerprint)
+                    .map_err(ProxyError::WrongCertificateFingerprint)
+            })
+            .transpose()?;
+
+        let listener = self
+            .listeners
+            .values()
+            .find(|l| l.borrow().address == address)
+            .ok_or(ProxyError::NoListenerFound(address))?
+            .borrow_mut();
+
+        listener
+            .resolver
+            .0
+            .lock()
+            .map_err(|e| ProxyError::Lock(e.to_string()))?
+            .pin_certificate(&pin_certificate.domain, fingerprint)
+            .map_err(ProxyError::PinCertificate)?;
+
         Ok(None)
     }
 }
@@ -1231,6 +1382,7 @@ impl ProxyConfiguration for HttpsProxy {
             proxy,
             public_address,
             rustls_details,
+            owned.certificate_acceptor(),
             frontend_sock,
             owned.config.sticky_name.clone(),
             session_token,
@@ -1284,6 +1436,10 @@ impl ProxyConfiguration for HttpsProxy {
                 );
                 self.replace_certificate(replace_certificate)
             }
+            RequestType::PinCertificate(pin_certificate) => {
+                debug!("{} pin certificate: {:?}", request_id, pin_certificate);
+                self.pin_certificate(pin_certificate)
+            }
             RequestType::RemoveListener(remove) => {
                 debug!("removing HTTPS listener at address {:?}", remove.address);
                 self.remove_listener(remove)
@@ -1508,7 +1664,9 @@ mod tests {
         proto::command::{CustomHttpAnswers, SocketAddress},
     };
 
-    use crate::router::{pattern_trie::TrieNode, MethodRule, PathRule, Route, Router};
+    use crate::router::{
+        pattern_trie::TrieNode, MatchOptions, MethodRule, PathRule, Route, Router,
+    };
 
     /*
     #[test]
@@ -1541,24 +1699,28 @@ mod tests {
             "lolcatho.st".as_bytes(),
             &PathRule::Prefix(uri1),
             &MethodRule::new(None),
+            &MatchOptions::default(),
             &Route::ClusterId(cluster_id1.clone())
         ));
         assert!(fronts.add_tree_rule(
             "lolcatho.st".as_bytes(),
             &PathRule::Prefix(uri2),
             &MethodRule::new(None),
+            &MatchOptions::default(),
             &Route::ClusterId(cluster_id2)
         ));
         assert!(fronts.add_tree_rule(
             "lolcatho.st".as_bytes(),
             &PathRule::Prefix(uri3),
             &MethodRule::new(None),
+            &MatchOptions::default(),
             &Route::ClusterId(cluster_id3)
         ));
         assert!(fronts.add_tree_rule(
             "other.domain".as_bytes(),
             &PathRule::Prefix("test".to_string()),
             &MethodRule::new(None),
+            &MatchOptions::default(),
             &Route::ClusterId(cluster_id1)
         ));
 
@@ -1586,14 +1748,18 @@ mod tests {
             address: address.into(),
             fronts,
             rustls_details,
+            certificate_configs: Arc::new(HashMap::new()),
             resolver,
             answers: Rc::new(RefCell::new(
                 HttpAnswers::new(&Some(CustomHttpAnswers::default())).unwrap(),
             )),
             config: default_config,
+            pacer: None,
             token: Token(0),
             active: true,
             tags: BTreeMap::new(),
+            default_tags: None,
+            traced_hostnames: HashSet::new(),
         };
 
         println!("TEST {}", line!());
@@ -1626,6 +1792,61 @@ mod tests {
         // assert!(false);
     }
 
+    #[test]
+    fn certificate_with_its_own_tls_settings() {
+        let address = SocketAddress::new_v4(127, 0, 0, 1, 1033);
+        let config = ListenerBuilder::new_https(address)
+            .to_tls(None)
+            .expect("Could not create default HTTPS listener config");
+        let mut listener =
+            HttpsListener::try_new(config, Token(0)).expect("could not create the listener");
+
+        let certificate = CertificateAndKey {
+            certificate: String::from(include_str!("../assets/certificate.pem")),
+            key: String::from(include_str!("../assets/key.pem")),
+            names: vec!["lolcatho.st".into()],
+            versions: vec![TlsVersion::TlsV13 as i32],
+            ..Default::default()
+        };
+        let certificate_config = listener
+            .certificate_config(&certificate)
+            .expect("could not build the certificate TLS settings");
+        let fingerprint = listener
+            .resolver
+            .0
+            .lock()
+            .unwrap()
+            .add_certificate(&AddCertificate {
+                address,
+                certificate,
+                expired_at: None,
+            })
+            .expect("could not add the certificate");
+        listener.set_certificate_config(fingerprint.clone(), certificate_config);
+
+        let configs = TlsConfigs {
+            resolver: listener.resolver.clone(),
+            listener: listener.rustls_details.clone(),
+            certificates: listener.certificate_configs.clone(),
+        };
+        assert!(!Arc::ptr_eq(
+            &configs.for_server_name(Some("lolcatho.st")),
+            &listener.rustls_details
+        ));
+        assert!(Arc::ptr_eq(
+            &configs.for_server_name(Some("other.domain")),
+            &listener.rustls_details
+        ));
+        assert!(Arc::ptr_eq(
+            &configs.for_server_name(None),
+            &listener.rustls_details
+        ));
+        assert!(listener.certificate_acceptor().is_some());
+
+        listener.set_certificate_config(fingerprint, None);
+        assert!(listener.certificate_acceptor().is_none());
+    }
+
     #[test]
     fn wildcard_certificate_names() {
         let mut trie = TrieNode::root();
@@ -23,6 +23,10 @@
 //!
 //! ## How to use this library directly
 //!
+//! The [`embed`] module provides a builder, `Server::builder()`, that starts a worker
+//! in its own thread from listeners and clusters defined in code, and returns a handle
+//! to give it further orders and receive its events.
+//!
 //! This documentation here explains how to write a binary that will start a single Sōzu
 //! worker and give it orders. The method has two steps:
 //!
@@ -316,9 +320,14 @@ pub mod util;
 pub mod metrics;
 
 pub mod backends;
+pub mod crash;
+pub mod embed;
 pub mod features;
+pub mod handoff;
 pub mod http;
 pub mod load_balancing;
+#[cfg(feature = "pkcs11")]
+pub mod pkcs11;
 pub mod pool;
 pub mod protocol;
 pub mod retry;
@@ -362,7 +371,7 @@ use sozu_command::{
     AsStr, ObjectKind,
 };
 
-use crate::{backends::BackendMap, router::Route};
+use crate::{backends::BackendMap, metrics::MetricValue, router::Route};
 
 /// Anything that can be registered in mio (subscribe to kernel events)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -409,6 +418,14 @@ pub trait ProxySession {
     /// if the session handles HTTP requests, it will not close until the response
     /// is completely sent back to the client
     fn shutting_down(&mut self) -> SessionIsToBeClosed;
+    /// address of the listener that accepted the session, None for listeners
+    fn listener_address(&self) -> Option<SocketAddr>;
+    /// take the session out of the worker, to hand it off to another worker during an
+    /// upgrade. The session gives its sockets away and is considered closed. Sessions
+    /// that can not be handed off return None and are left untouched
+    fn hand_off(&mut self) -> Option<handoff::SessionHandoff> {
+        None
+    }
 }
 
 #[macro_export]
@@ -524,8 +541,20 @@ macro_rules! StateMachineBuilder {
 pub trait ListenerHandler {
     fn get_addr(&self) -> &SocketAddr;
 
+    /// the tags of a frontend merged with those of the listener, or the tags of the
+    /// listener if the frontend has none
     fn get_tags(&self, key: &str) -> Option<&CachedTags>;
 
+    /// the tags of the listener, for the sessions that match no frontend
+    fn get_default_tags(&self) -> Option<&CachedTags>;
+
+    /// the tags of the listener, stamped on the events noticed by its sessions
+    fn get_event_tags(&self) -> BTreeMap<String, String> {
+        self.get_default_tags()
+            .map(|tags| tags.tags.clone())
+            .unwrap_or_default()
+    }
+
     fn get_concatenated_tags(&self, key: &str) -> Option<&str> {
         self.get_tags(key).map(|tags| tags.concatenated.as_str())
     }
@@ -548,6 +577,18 @@ pub trait L7ListenerHandler {
 
     fn get_connect_timeout(&self) -> u32;
 
+    /// reject the backend responses that break HTTP framing instead of correcting them
+    fn get_strict_responses(&self) -> bool;
+
+    /// key of the signatures of the header asking for the debug trace of a request
+    fn get_debug_trace_key(&self) -> Option<&str>;
+
+    /// true if the responses of the frontends of this hostname carry the debug trace
+    fn has_debug_trace(&self, hostname: &str) -> bool;
+
+    /// describes the frontend rule matching a request, for the debug trace
+    fn describe_frontend(&self, hostname: &str, uri: &str, method: &Method) -> Option<String>;
+
     /// retrieve a frontend by parsing a request's hostname, uri and method
     fn frontend_from_request(
         &self,
@@ -583,12 +624,16 @@ pub enum BackendConnectionError {
     NotFound(ObjectKind),
     #[error("Too many connections on cluster {0:?}")]
     MaxConnectionRetries(Option<String>),
+    #[error("the retry budget of cluster {0} is exhausted")]
+    RetryBudgetExhausted(String),
     #[error("the sessions slab has reached maximum capacity")]
     MaxSessionsMemory,
     #[error("error from the backend: {0}")]
     Backend(BackendError),
     #[error("failed to retrieve the cluster: {0}")]
     RetrieveClusterError(RetrieveClusterError),
+    #[error("fault injected in a request of cluster {0}")]
+    FaultInjected(String),
 }
 
 /// used in kawa_h1 module for the Http session state
@@ -602,6 +647,8 @@ pub enum RetrieveClusterError {
     NoPath,
     #[error("unauthorized route")]
     UnauthorizedRoute,
+    #[error("upgrade to {0} is not allowed")]
+    UpgradeNotAllowed(String),
     #[error("{0}")]
     RetrieveFrontend(FrontendFromRequestError),
 }
@@ -615,6 +662,8 @@ pub enum AcceptError {
     RegisterError,
     WrongSocketAddress,
     BufferCapacityReached,
+    /// the listener accepted as many connections as its pacing allows, until this date
+    Paced(Instant),
 }
 
 /// returned by the HTTP, HTTPS and TCP listeners
@@ -679,6 +728,10 @@ pub enum ProxyError {
     RemoveCertificate(CertificateResolverError),
     #[error("could not replace certificate: {0}")]
     ReplaceCertificate(CertificateResolverError),
+    #[error("could not pin certificate: {0}")]
+    PinCertificate(CertificateResolverError),
+    #[error("could not apply the TLS settings of the certificate: {0}")]
+    CertificateTlsSettings(ListenerError),
     #[error("wrong certificate fingerprint: {0}")]
     WrongCertificateFingerprint(FromHexError),
     #[error("this request is not supported by the proxy")]
@@ -948,6 +1001,12 @@ pub struct SessionMetrics {
     pub bin: usize,
     /// bytes sent by the frontend
     pub bout: usize,
+    /// bytes of the request line and headers received by the frontend
+    pub request_header_size: usize,
+    /// bytes of the status line and headers received from the backend
+    pub response_header_size: usize,
+    /// body length of the default answer sent in place of a backend response
+    pub default_answer_body_size: Option<usize>,
 
     /// date at which we started working on the request
     pub service_start: Option<Instant>,
@@ -969,6 +1028,9 @@ impl SessionMetrics {
             wait_time: wait_time.unwrap_or_else(|| Duration::from_secs(0)),
             bin: 0,
             bout: 0,
+            request_header_size: 0,
+            response_header_size: 0,
+            default_answer_body_size: None,
             service_start: None,
             wait_start: Instant::now(),
             backend_id: None,
@@ -986,6 +1048,9 @@ impl SessionMetrics {
         self.wait_time = Duration::from_secs(0);
         self.bin = 0;
         self.bout = 0;
+        self.request_header_size = 0;
+        self.response_header_size = 0;
+        self.default_answer_body_size = None;
         self.service_start = None;
         self.backend_start = None;
         self.backend_connected = None;
@@ -1061,13 +1126,42 @@ impl SessionMetrics {
         }
     }
 
-    pub fn register_end_of_session(&self, context: &LogContext) {
+    /// bytes received by the frontend, without the request line and headers
+    pub fn request_body_size(&self) -> usize {
+        self.bin.saturating_sub(self.request_header_size)
+    }
+
+    /// bytes of the response, without the status line and headers. Sozu edits
+    /// the headers it forwards, so a backend response is measured as received,
+    /// and a default answer by the length of its body
+    pub fn response_body_size(&self) -> usize {
+        match self.default_answer_body_size {
+            Some(size) => size,
+            None if self.response_header_size == 0 => self.bout,
+            None => self.backend_bin.saturating_sub(self.response_header_size),
+        }
+    }
+
+    pub fn register_end_of_session(&self, context: &LogContext, tags: Option<&CachedTags>) {
         let request_time = self.request_time();
         let service_time = self.service_time();
 
+        if let Some(tags) = tags {
+            self.register_tag_metrics(&tags.tags, request_time, service_time);
+        }
+
         if let Some(cluster_id) = context.cluster_id {
             time!("request_time", cluster_id, request_time.as_millis());
             time!("service_time", cluster_id, service_time.as_millis());
+
+            histogram!("request_size", cluster_id, self.request_body_size());
+            histogram!("response_size", cluster_id, self.response_body_size());
+            if let Some(throughput) = throughput(self.bin, request_time) {
+                histogram!("throughput_in", cluster_id, throughput);
+            }
+            if let Some(throughput) = throughput(self.bout, request_time) {
+                histogram!("throughput_out", cluster_id, throughput);
+            }
         }
         time!("request_time", request_time.as_millis());
         time!("service_time", service_time.as_millis());
@@ -1087,6 +1181,39 @@ impl SessionMetrics {
 
         incr!("access_logs.count", context.cluster_id, context.backend_id);
     }
+
+    /// metrics of the session under the metrics tag of its frontend, if set up
+    fn register_tag_metrics(
+        &self,
+        tags: &BTreeMap<String, String>,
+        request_time: Duration,
+        service_time: Duration,
+    ) {
+        metrics::METRICS.with(|metrics| {
+            let metrics = &mut *metrics.borrow_mut();
+            metrics.receive_tag_metric("requests", tags, MetricValue::Count(1));
+            metrics.receive_tag_metric("bytes_in", tags, MetricValue::Count(self.bin as i64));
+            metrics.receive_tag_metric("bytes_out", tags, MetricValue::Count(self.bout as i64));
+            metrics.receive_tag_metric(
+                "request_time",
+                tags,
+                MetricValue::Time(request_time.as_millis() as usize),
+            );
+            metrics.receive_tag_metric(
+                "service_time",
+                tags,
+                MetricValue::Time(service_time.as_millis() as usize),
+            );
+        });
+    }
+}
+
+/// bytes per second transferred over a duration, too short durations are not significant
+fn throughput(bytes: usize, duration: Duration) -> Option<usize> {
+    if bytes == 0 || duration < Duration::from_millis(1) {
+        return None;
+    }
+    Some((bytes as f64 / duration.as_secs_f64()) as usize)
 }
 
 /// exponentially weighted moving average with high sensibility to latency bursts
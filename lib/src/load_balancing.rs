@@ -20,17 +20,42 @@ pub struct RoundRobin {
     pub next_backend: u32,
 }
 
+/// the load of a backend as measured by the metric, raised while the backend
+/// is in slow start so that it gets a growing share of the traffic
+fn weighted_load(backend: &mut Backend, metric: LoadMetric) -> f64 {
+    let load = match metric {
+        // one more, so that idle backends in slow start are not picked first
+        LoadMetric::Connections => backend.active_connections as f64 + 1.0,
+        LoadMetric::Requests => backend.active_requests as f64 + 1.0,
+        LoadMetric::ConnectionTime => backend.peak_ewma_connection(),
+    };
+    load / backend.slow_start_ratio()
+}
+
 impl LoadBalancingAlgorithm for RoundRobin {
+    /// backends in slow start are skipped in proportion of the weight they do not have yet
     fn next_available_backend(
         &mut self,
         backends: &mut Vec<Rc<RefCell<Backend>>>,
     ) -> Option<Rc<RefCell<Backend>>> {
-        let res = backends
-            .get(self.next_backend as usize % backends.len())
-            .map(|backend| (*backend).clone());
+        if backends.is_empty() {
+            return None;
+        }
 
-        self.next_backend = (self.next_backend + 1) % backends.len() as u32;
-        res
+        let mut rng = thread_rng();
+        let first = self.next_backend as usize % backends.len();
+        for offset in 0..backends.len() {
+            let backend = &backends[(first + offset) % backends.len()];
+            let ratio = backend.borrow_mut().slow_start_ratio();
+            if ratio >= 1.0 || rng.gen_bool(ratio) {
+                self.next_backend = ((first + offset + 1) % backends.len()) as u32;
+                return Some(backend.clone());
+            }
+        }
+
+        // all backends are in slow start
+        self.next_backend = ((first + 1) % backends.len()) as u32;
+        backends.get(first).cloned()
     }
 }
 
@@ -57,13 +82,7 @@ impl LoadBalancingAlgorithm for Random {
         let mut rng = thread_rng();
         let weights: Vec<i32> = backends
             .iter()
-            .map(|b| {
-                b.borrow()
-                    .load_balancing_parameters
-                    .as_ref()
-                    .map(|p| p.weight)
-                    .unwrap_or(100)
-            })
+            .map(|b| b.borrow_mut().effective_weight())
             .collect();
 
         if let Ok(dist) = WeightedIndex::new(weights) {
@@ -87,34 +106,23 @@ impl LoadBalancingAlgorithm for LeastLoaded {
         &mut self,
         backends: &mut Vec<Rc<RefCell<Backend>>>,
     ) -> Option<Rc<RefCell<Backend>>> {
-        let opt_b = match self.metric {
-            LoadMetric::Connections => backends
-                .iter_mut()
-                .min_by_key(|backend| backend.borrow().active_connections),
-            LoadMetric::Requests => backends
-                .iter_mut()
-                .min_by_key(|backend| backend.borrow().active_requests),
-            LoadMetric::ConnectionTime => {
-                let mut b = None;
-                for backend in backends.iter_mut() {
-                    let cost2 = backend.borrow_mut().peak_ewma_connection();
-
-                    match b.take() {
-                        None => b = Some((cost2, backend)),
-                        Some((cost1, back1)) => {
-                            if cost1 <= cost2 {
-                                b = Some((cost1, back1));
-                            } else {
-                                b = Some((cost2, backend));
-                            }
-                        }
+        let mut b = None;
+        for backend in backends.iter_mut() {
+            let cost2 = weighted_load(&mut backend.borrow_mut(), self.metric);
+
+            match b.take() {
+                None => b = Some((cost2, backend)),
+                Some((cost1, back1)) => {
+                    if cost1 <= cost2 {
+                        b = Some((cost1, back1));
+                    } else {
+                        b = Some((cost2, backend));
                     }
                 }
-
-                b.map(|(_cost, backend)| backend)
             }
-        };
-        opt_b.map(|backend| (*backend).clone())
+        }
+
+        b.map(|(_cost, backend)| (*backend).clone())
     }
 }
 
@@ -132,11 +140,7 @@ impl LoadBalancingAlgorithm for PowerOfTwo {
         let mut second = None;
 
         for backend in backends.iter_mut() {
-            let measure = match self.metric {
-                LoadMetric::Connections => backend.borrow().active_connections as f64,
-                LoadMetric::Requests => backend.borrow().active_requests as f64,
-                LoadMetric::ConnectionTime => backend.borrow_mut().peak_ewma_connection(),
-            };
+            let measure = weighted_load(&mut backend.borrow_mut(), self.metric);
 
             if first.is_none() {
                 first = Some((measure, backend));
@@ -172,19 +176,53 @@ impl LoadBalancingAlgorithm for PowerOfTwo {
     }
 }
 
+/// Picks two backends at random and keeps the one with the lowest
+/// moving average of response times, weighted by its active requests
+#[derive(Debug)]
+pub struct LeastResponseTime;
+
+impl LoadBalancingAlgorithm for LeastResponseTime {
+    fn next_available_backend(
+        &mut self,
+        backends: &mut Vec<Rc<RefCell<Backend>>>,
+    ) -> Option<Rc<RefCell<Backend>>> {
+        let mut choices = backends.choose_multiple(&mut thread_rng(), 2);
+
+        let first = choices.next()?;
+        let second = match choices.next() {
+            Some(second) => second,
+            None => return Some(first.clone()),
+        };
+
+        let score = |backend: &Rc<RefCell<Backend>>| {
+            let mut backend = backend.borrow_mut();
+            backend.peak_ewma_response() / backend.slow_start_ratio()
+        };
+        let first_score = score(first);
+        let second_score = score(second);
+
+        if first_score <= second_score {
+            Some(first.clone())
+        } else {
+            Some(second.clone())
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::retry::{ExponentialBackoffPolicy, RetryPolicyWrapper};
-    use crate::sozu_command::proto::command::LoadMetric;
+    use crate::sozu_command::proto::command::{LoadBalancingParams, LoadMetric};
     use crate::{backends::BackendStatus, PeakEWMA};
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
     fn create_backend(id: String, connections: Option<usize>) -> Backend {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
         Backend {
             sticky_id: None,
             backend_id: id,
-            address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+            address,
             status: BackendStatus::Normal,
             retry_policy: RetryPolicyWrapper::ExponentialBackoff(ExponentialBackoffPolicy::new(1)),
             active_connections: connections.unwrap_or(0),
@@ -192,7 +230,12 @@ mod test {
             failures: 0,
             load_balancing_parameters: None,
             backup: false,
+            priority: 0,
             connection_time: PeakEWMA::new(),
+            response_time: PeakEWMA::new(),
+            slow_start_since: None,
+            alternate_addresses: Vec::new(),
+            preferred_address: address,
         }
     }
 
@@ -248,4 +291,65 @@ mod test {
         let backend2 = roundrobin.next_available_backend(&mut backends);
         assert_eq!(backend2.as_ref(), backends.first());
     }
+
+    #[test]
+    fn it_should_find_the_backend_with_least_response_time() {
+        let fast_backend = Rc::new(RefCell::new(create_backend("fast".to_string(), None)));
+        let slow_backend = Rc::new(RefCell::new(create_backend("slow".to_string(), None)));
+        let failing_backend = Rc::new(RefCell::new(create_backend("failing".to_string(), None)));
+
+        fast_backend
+            .borrow_mut()
+            .set_response_time(std::time::Duration::from_millis(5));
+        slow_backend
+            .borrow_mut()
+            .set_response_time(std::time::Duration::from_millis(500));
+        failing_backend.borrow_mut().penalize_response_time();
+
+        let mut least_response_time = LeastResponseTime;
+
+        let mut backends = vec![slow_backend.clone(), fast_backend.clone()];
+        let backend = least_response_time
+            .next_available_backend(&mut backends)
+            .unwrap();
+        assert_eq!(backend.borrow().backend_id, "fast");
+
+        let mut backends = vec![failing_backend, slow_backend];
+        let backend = least_response_time
+            .next_available_backend(&mut backends)
+            .unwrap();
+        assert_eq!(backend.borrow().backend_id, "slow");
+    }
+
+    #[test]
+    fn it_should_ramp_up_backends_in_slow_start() {
+        let mut starting = create_backend("starting".to_string(), Some(0));
+        starting.load_balancing_parameters = Some(LoadBalancingParams {
+            weight: 100,
+            slow_start: Some(60),
+            ..Default::default()
+        });
+        starting.start_slow_start();
+        let starting = Rc::new(RefCell::new(starting));
+        let loaded = Rc::new(RefCell::new(create_backend("loaded".to_string(), Some(5))));
+        let mut backends = vec![starting.clone(), loaded];
+
+        let mut least_loaded = LeastLoaded {
+            metric: LoadMetric::Connections,
+        };
+        let backend = least_loaded.next_available_backend(&mut backends).unwrap();
+        assert_eq!(backend.borrow().backend_id, "loaded");
+
+        let mut roundrobin = RoundRobin::new();
+        let picked = (0..1000)
+            .filter(|_| {
+                let backend = roundrobin.next_available_backend(&mut backends).unwrap();
+                Rc::ptr_eq(&backend, &starting)
+            })
+            .count();
+        assert!(
+            picked < 300,
+            "the backend in slow start was picked {picked} times"
+        );
+    }
 }
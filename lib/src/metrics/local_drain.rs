@@ -17,6 +17,9 @@
 //!                 map: BTreeMap<metric_name, AggregatedMetric>
 //!             },
 //!         }>
+//!     }>,
+//!     tag_metrics: BTreeMap<tag_value, MetricsMap {
+//!         map: BTreeMap<metric_name, AggregatedMetric>
 //!     }>
 //! }
 //! ```
@@ -33,7 +36,7 @@ use hdrhistogram::Histogram;
 use sozu_command::proto::command::{
     filtered_metrics, response_content::ContentType, AvailableMetrics, BackendMetrics, Bucket,
     ClusterMetrics, FilteredHistogram, FilteredMetrics, MetricsConfiguration, Percentiles,
-    QueryMetricsOptions, ResponseContent, WorkerMetrics,
+    QueryMetricsOptions, ResponseContent, TagMetrics, WorkerMetrics,
 };
 
 use crate::metrics::{MetricError, MetricValue, Subscriber};
@@ -44,6 +47,8 @@ pub enum AggregatedMetric {
     Gauge(usize),
     Count(i64),
     Time(Histogram<u32>),
+    /// values that are not durations, like sizes
+    Histogram(Histogram<u32>),
 }
 
 impl AggregatedMetric {
@@ -52,7 +57,7 @@ impl AggregatedMetric {
             MetricValue::Gauge(value) => Ok(AggregatedMetric::Gauge(value)),
             MetricValue::GaugeAdd(value) => Ok(AggregatedMetric::Gauge(value as usize)),
             MetricValue::Count(value) => Ok(AggregatedMetric::Count(value)),
-            MetricValue::Time(value) => {
+            MetricValue::Time(value) | MetricValue::Histogram(value) => {
                 let mut histogram = ::hdrhistogram::Histogram::new(3).map_err(|error| {
                     MetricError::HistogramCreation {
                         time_metric: metric.clone(),
@@ -67,7 +72,10 @@ impl AggregatedMetric {
                     }
                 })?;
 
-                Ok(AggregatedMetric::Time(histogram))
+                match metric {
+                    MetricValue::Time(_) => Ok(AggregatedMetric::Time(histogram)),
+                    _ => Ok(AggregatedMetric::Histogram(histogram)),
+                }
             }
         }
     }
@@ -83,9 +91,10 @@ impl AggregatedMetric {
             (&mut AggregatedMetric::Count(ref mut v1), MetricValue::Count(v2)) => {
                 *v1 += v2;
             }
-            (&mut AggregatedMetric::Time(ref mut v1), MetricValue::Time(v2)) => {
+            (&mut AggregatedMetric::Time(ref mut v1), MetricValue::Time(v2))
+            | (&mut AggregatedMetric::Histogram(ref mut v1), MetricValue::Histogram(v2)) => {
                 if let Err(e) = (*v1).record(v2 as u64) {
-                    error!("could not record time metric: {:?}", e.to_string());
+                    error!("could not record metric {}: {:?}", key, e.to_string());
                 }
             }
             (s, m) => panic!(
@@ -102,11 +111,13 @@ impl AggregatedMetric {
             AggregatedMetric::Count(i) => FilteredMetrics {
                 inner: Some(filtered_metrics::Inner::Count(i)),
             },
-            AggregatedMetric::Time(ref hist) => FilteredMetrics {
-                inner: Some(filtered_metrics::Inner::Percentiles(
-                    histogram_to_percentiles(hist),
-                )),
-            },
+            AggregatedMetric::Time(ref hist) | AggregatedMetric::Histogram(ref hist) => {
+                FilteredMetrics {
+                    inner: Some(filtered_metrics::Inner::Percentiles(
+                        histogram_to_percentiles(hist),
+                    )),
+                }
+            }
         }
     }
 }
@@ -183,7 +194,9 @@ impl MetricsMap {
                 let mut filtered = vec![(name.to_owned(), metric.to_filtered())];
 
                 // convert time metrics to a histogram format, on top of percentiles
-                if let AggregatedMetric::Time(ref hist) = metric {
+                if let AggregatedMetric::Time(ref hist) | AggregatedMetric::Histogram(ref hist) =
+                    metric
+                {
                     filtered.push((format!("{}_histogram", name), filter_histogram(hist)));
                 }
                 filtered.into_iter()
@@ -318,6 +331,10 @@ pub struct LocalDrain {
     pub proxy_metrics: MetricsMap,
     /// cluster_id -> cluster_metrics
     cluster_metrics: BTreeMap<String, LocalClusterMetrics>,
+    /// frontend tag whose values partition the metrics, for instance "tenant"
+    metrics_tag: Option<String>,
+    /// tag_value -> metrics of the frontends having this value for the metrics tag
+    tag_metrics: BTreeMap<String, MetricsMap>,
     use_tagged_metrics: bool,
     origin: String,
     disable_cluster_metrics: bool,
@@ -330,6 +347,8 @@ impl LocalDrain {
             created: Instant::now(),
             proxy_metrics: MetricsMap::new(),
             cluster_metrics: BTreeMap::new(),
+            metrics_tag: None,
+            tag_metrics: BTreeMap::new(),
             use_tagged_metrics: false,
             origin: String::from("x"),
             disable_cluster_metrics: false,
@@ -341,11 +360,68 @@ impl LocalDrain {
             MetricsConfiguration::Enabled => self.disable_cluster_metrics = false,
             MetricsConfiguration::Disabled => self.disable_cluster_metrics = true,
             MetricsConfiguration::Clear => self.clear(),
+            MetricsConfiguration::DisableBackendMetrics => self.clear_backend_metrics(),
+            MetricsConfiguration::DisableTimeMetrics => self.clear_time_metrics(),
+            // the aggregator records them again
+            MetricsConfiguration::EnableBackendMetrics
+            | MetricsConfiguration::EnableTimeMetrics => {}
+        }
+    }
+
+    pub fn cluster_metrics_enabled(&self) -> bool {
+        !self.disable_cluster_metrics
+    }
+
+    fn clear_backend_metrics(&mut self) {
+        for cluster in self.cluster_metrics.values_mut() {
+            cluster.backends.clear();
         }
     }
 
+    fn clear_time_metrics(&mut self) {
+        let maps = std::iter::once(&mut self.proxy_metrics)
+            .chain(self.tag_metrics.values_mut())
+            .chain(self.cluster_metrics.values_mut().flat_map(|cluster| {
+                std::iter::once(&mut cluster.cluster).chain(
+                    cluster
+                        .backends
+                        .iter_mut()
+                        .map(|backend| &mut backend.metrics),
+                )
+            }));
+        for metrics in maps {
+            metrics
+                .map
+                .retain(|_, metric| !matches!(metric, AggregatedMetric::Time(_)));
+        }
+    }
+
+    /// the number of metric series at the proxy, cluster and backend levels
+    pub fn series(&self) -> (usize, usize, usize) {
+        let cluster_series = self
+            .cluster_metrics
+            .values()
+            .map(|cluster| cluster.cluster.map.len())
+            .sum();
+        let backend_series = self
+            .cluster_metrics
+            .values()
+            .flat_map(|cluster| cluster.backends.iter())
+            .map(|backend| backend.metrics.map.len())
+            .sum();
+        (self.proxy_metrics.map.len(), cluster_series, backend_series)
+    }
+
     pub fn clear(&mut self) {
         self.cluster_metrics.clear();
+        self.tag_metrics.clear();
+    }
+
+    pub fn set_metrics_tag(&mut self, metrics_tag: Option<String>) {
+        if self.metrics_tag != metrics_tag {
+            self.tag_metrics.clear();
+        }
+        self.metrics_tag = metrics_tag;
     }
 
     pub fn query(&mut self, options: &QueryMetricsOptions) -> Result<ResponseContent, MetricError> {
@@ -361,17 +437,25 @@ impl LocalDrain {
             list,
             no_clusters,
             workers: _workers,
+            tag_key,
+            tag_value,
         } = options;
 
         if *list {
             return self.list_all_metric_names();
         }
 
+        if let Some(tag_key) = tag_key {
+            let worker_metrics = self.query_tags(tag_key, tag_value.as_deref(), metric_names)?;
+            return Ok(ContentType::WorkerMetrics(worker_metrics).into());
+        }
+
         if *no_clusters {
             let proxy_metrics = self.dump_proxy_metrics(metric_names);
             return Ok(ContentType::WorkerMetrics(WorkerMetrics {
                 proxy: proxy_metrics,
                 clusters: BTreeMap::new(),
+                tags: BTreeMap::new(),
             })
             .into());
         }
@@ -418,6 +502,7 @@ impl LocalDrain {
         Ok(WorkerMetrics {
             proxy: self.dump_proxy_metrics(metric_names),
             clusters: self.dump_cluster_metrics(metric_names)?,
+            tags: BTreeMap::new(),
         })
     }
 
@@ -498,6 +583,7 @@ impl LocalDrain {
         Ok(WorkerMetrics {
             proxy: BTreeMap::new(),
             clusters,
+            tags: BTreeMap::new(),
         })
     }
 
@@ -537,9 +623,67 @@ impl LocalDrain {
         Ok(WorkerMetrics {
             proxy: BTreeMap::new(),
             clusters,
+            tags: BTreeMap::new(),
         })
     }
 
+    /// metrics of all the values of the metrics tag, or of only one value
+    fn query_tags(
+        &self,
+        tag_key: &str,
+        tag_value: Option<&str>,
+        metric_names: &[String],
+    ) -> Result<WorkerMetrics, MetricError> {
+        if self.metrics_tag.as_deref() != Some(tag_key) {
+            return Err(MetricError::NotATagMetric(tag_key.to_owned()));
+        }
+
+        let tags = self
+            .tag_metrics
+            .iter()
+            .filter(|(value, _)| tag_value.map_or(true, |tag_value| tag_value == *value))
+            .map(|(value, metrics)| {
+                (
+                    format!("{tag_key}={value}"),
+                    TagMetrics {
+                        metrics: metrics.to_filtered_metrics(metric_names),
+                    },
+                )
+            })
+            .collect();
+
+        Ok(WorkerMetrics {
+            proxy: BTreeMap::new(),
+            clusters: BTreeMap::new(),
+            tags,
+        })
+    }
+
+    /// records a metric under the value of the metrics tag, among the tags of a frontend
+    pub fn receive_tag_metric(
+        &mut self,
+        metric_name: &str,
+        tags: &BTreeMap<String, String>,
+        metric: MetricValue,
+    ) {
+        if self.disable_cluster_metrics {
+            return;
+        }
+        let tag_value = match self.metrics_tag.as_ref().and_then(|key| tags.get(key)) {
+            Some(tag_value) => tag_value,
+            None => return,
+        };
+
+        if let Err(e) = self
+            .tag_metrics
+            .entry(tag_value.to_owned())
+            .or_default()
+            .receive_metric(metric_name, metric)
+        {
+            error!("Could not receive tag metric: {}", e);
+        }
+    }
+
     fn receive_cluster_metric(
         &mut self,
         metric_name: &str,
@@ -630,6 +774,7 @@ mod tests {
     use sozu_command::proto::command::{filtered_metrics::Inner, FilteredMetrics};
 
     use super::*;
+    use crate::metrics::Aggregator;
 
     #[test]
     fn receive_and_yield_backend_metrics() {
@@ -706,4 +851,137 @@ mod tests {
 
         assert_eq!(expected_cluster_metrics, returned_cluster_metrics);
     }
+
+    #[test]
+    fn receive_and_yield_tag_metrics() {
+        let mut local_drain = LocalDrain::new("prefix".to_string());
+        local_drain.set_metrics_tag(Some("tenant".to_string()));
+
+        let acme = BTreeMap::from([
+            ("owner".to_string(), "bob".to_string()),
+            ("tenant".to_string(), "acme".to_string()),
+        ]);
+        let globex = BTreeMap::from([("tenant".to_string(), "globex".to_string())]);
+        let untagged = BTreeMap::from([("owner".to_string(), "bob".to_string())]);
+        local_drain.receive_tag_metric("requests", &acme, MetricValue::Count(1));
+        local_drain.receive_tag_metric("requests", &acme, MetricValue::Count(1));
+        local_drain.receive_tag_metric("requests", &globex, MetricValue::Count(1));
+        local_drain.receive_tag_metric("requests", &untagged, MetricValue::Count(1));
+
+        let expected_tag_metrics = TagMetrics {
+            metrics: BTreeMap::from([(
+                "requests".to_string(),
+                FilteredMetrics {
+                    inner: Some(Inner::Count(2)),
+                },
+            )]),
+        };
+        let returned = local_drain
+            .query_tags("tenant", Some("acme"), &[])
+            .expect("could not query metrics for this tag");
+        assert_eq!(
+            returned.tags,
+            BTreeMap::from([("tenant=acme".to_string(), expected_tag_metrics)])
+        );
+
+        let returned = local_drain
+            .query_tags("tenant", None, &[])
+            .expect("could not query metrics for this tag");
+        assert_eq!(
+            returned.tags.keys().collect::<Vec<_>>(),
+            vec!["tenant=acme", "tenant=globex"]
+        );
+
+        assert!(local_drain.query_tags("owner", None, &[]).is_err());
+    }
+
+    #[test]
+    fn receive_and_yield_histogram_metrics() {
+        let mut local_drain = LocalDrain::new("prefix".to_string());
+        for size in [100, 200, 300] {
+            local_drain.receive_metric(
+                "request_size",
+                Some("test-cluster"),
+                None,
+                MetricValue::Histogram(size),
+            );
+        }
+
+        let returned_cluster_metrics = local_drain
+            .metrics_of_one_cluster("test-cluster", ["request_size".to_string()].as_ref())
+            .expect("could not query metrics for this cluster");
+
+        match &returned_cluster_metrics.cluster["request_size"].inner {
+            Some(Inner::Percentiles(percentiles)) => {
+                assert_eq!(percentiles.samples, 3);
+                assert_eq!(percentiles.p_50, 200);
+                assert_eq!(percentiles.p_100, 300);
+            }
+            other => panic!("expected percentiles, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn toggle_backend_and_time_metrics() {
+        let mut aggregator = Aggregator::new("prefix".to_string());
+        let receive_all = |aggregator: &mut Aggregator| {
+            aggregator.receive_metric("requests", None, None, MetricValue::Count(1));
+            aggregator.receive_metric("requests", Some("cluster"), None, MetricValue::Count(1));
+            aggregator.receive_metric(
+                "bytes_in",
+                Some("cluster"),
+                Some("backend"),
+                MetricValue::Count(1),
+            );
+            aggregator.receive_metric(
+                "backend_response_time",
+                Some("cluster"),
+                Some("backend"),
+                MetricValue::Time(10),
+            );
+            aggregator.receive_metric(
+                "response_time",
+                Some("cluster"),
+                None,
+                MetricValue::Time(10),
+            );
+            aggregator.receive_metric(
+                "request_size",
+                Some("cluster"),
+                None,
+                MetricValue::Histogram(1024),
+            );
+        };
+
+        receive_all(&mut aggregator);
+        let status = aggregator.status();
+        assert!(status.backend_metrics && status.time_metrics);
+        assert_eq!(
+            (
+                status.proxy_series,
+                status.cluster_series,
+                status.backend_series
+            ),
+            (1, 3, 2)
+        );
+
+        aggregator.configure(&MetricsConfiguration::DisableBackendMetrics);
+        aggregator.configure(&MetricsConfiguration::DisableTimeMetrics);
+        receive_all(&mut aggregator);
+        let status = aggregator.status();
+        assert!(!status.backend_metrics && !status.time_metrics);
+        assert!(status.cluster_metrics);
+        assert_eq!(
+            (
+                status.proxy_series,
+                status.cluster_series,
+                status.backend_series
+            ),
+            (1, 2, 0)
+        );
+
+        aggregator.configure(&MetricsConfiguration::EnableBackendMetrics);
+        receive_all(&mut aggregator);
+        assert_eq!(aggregator.status().backend_series, 1);
+    }
 }
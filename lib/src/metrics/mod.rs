@@ -14,7 +14,7 @@ use std::{
 use mio::net::UdpSocket;
 
 use sozu_command::proto::command::{
-    FilteredMetrics, MetricsConfiguration, QueryMetricsOptions, ResponseContent,
+    FilteredMetrics, MetricsConfiguration, MetricsStatus, QueryMetricsOptions, ResponseContent,
 };
 
 use crate::metrics::{local_drain::LocalDrain, network_drain::NetworkDrain};
@@ -31,6 +31,8 @@ pub enum MetricError {
     UdpBind { address: String, error: String },
     #[error("No metrics found for object with id {0}")]
     NoMetrics(String),
+    #[error("The metrics are not partitioned by the tag {0}")]
+    NotATagMetric(String),
     #[error("Could not create histogram for time metric {time_metric:?}: {error}")]
     HistogramCreation {
         time_metric: MetricValue,
@@ -49,6 +51,8 @@ pub enum MetricValue {
     GaugeAdd(i64),
     Count(i64),
     Time(usize),
+    /// a value that is not a duration, like a size
+    Histogram(usize),
 }
 
 impl MetricValue {
@@ -167,6 +171,12 @@ pub struct Aggregator {
     network: Option<NetworkDrain>,
     /// gather metrics locally, queried by the CLI
     local: LocalDrain,
+    /// record the metrics of each backend, on top of those of their cluster
+    backend_metrics: bool,
+    /// record time metrics, in histograms
+    time_metrics: bool,
+    /// the frontend tag partitioning the metrics of the sessions
+    metrics_tag: Option<String>,
 }
 
 impl Aggregator {
@@ -175,6 +185,9 @@ impl Aggregator {
             prefix: prefix.clone(),
             network: None,
             local: LocalDrain::new(prefix),
+            backend_metrics: true,
+            time_metrics: true,
+            metrics_tag: None,
         }
     }
 
@@ -192,6 +205,35 @@ impl Aggregator {
         }
     }
 
+    /// the values of this frontend tag, for instance "tenant", partition the metrics
+    /// of the sessions, that can be queried by tag value
+    pub fn set_up_metrics_tag(&mut self, metrics_tag: Option<String>) {
+        self.local.set_metrics_tag(metrics_tag.clone());
+        self.metrics_tag = metrics_tag;
+    }
+
+    /// records a metric of a session under the value of the metrics tag of its frontend
+    pub fn receive_tag_metric(
+        &mut self,
+        key: &'static str,
+        tags: &BTreeMap<String, String>,
+        metric: MetricValue,
+    ) {
+        if metric.is_time() && !self.time_metrics {
+            return;
+        }
+        if let Some(network) = self.network.as_mut() {
+            if let Some((tag_key, tag_value)) = self
+                .metrics_tag
+                .as_ref()
+                .and_then(|tag_key| tags.get_key_value(tag_key))
+            {
+                network.receive_tag_metric(key, tag_key, tag_value, metric.clone());
+            }
+        }
+        self.local.receive_tag_metric(key, tags, metric);
+    }
+
     pub fn set_up_tagged_metrics(&mut self, tagged: bool) {
         if let Some(n) = self.network.as_mut() {
             n.use_tagged_metrics = tagged;
@@ -244,9 +286,48 @@ impl Aggregator {
         self.local.clear();
     }
 
+    /// the toggles of backend and time metrics apply to all drains, to spare the
+    /// statsd server, the cluster metrics toggle only to the local drain
     pub fn configure(&mut self, config: &MetricsConfiguration) {
+        match config {
+            MetricsConfiguration::DisableBackendMetrics => {
+                self.backend_metrics = false;
+                if let Some(network) = self.network.as_mut() {
+                    network.clear_backend_metrics();
+                }
+            }
+            MetricsConfiguration::EnableBackendMetrics => self.backend_metrics = true,
+            MetricsConfiguration::DisableTimeMetrics => self.time_metrics = false,
+            MetricsConfiguration::EnableTimeMetrics => self.time_metrics = true,
+            _ => {}
+        }
         self.local.configure(config);
     }
+
+    /// the current configuration, and the number of metric series recorded by level.
+    /// A series is counted once if both drains record it
+    pub fn status(&self) -> MetricsStatus {
+        let (mut proxy_series, mut cluster_series, mut backend_series) = self.local.series();
+        if let Some(network) = self.network.as_ref() {
+            let (proxy, cluster, backend) = network.series();
+            proxy_series = proxy_series.max(proxy);
+            cluster_series = cluster_series.max(cluster);
+            backend_series = backend_series.max(backend);
+        }
+
+        MetricsStatus {
+            cluster_metrics: self.local.cluster_metrics_enabled(),
+            backend_metrics: self.backend_metrics,
+            time_metrics: self.time_metrics,
+            address: self
+                .network
+                .as_ref()
+                .map(|network| network.remote.get_ref().addr.to_string()),
+            proxy_series: proxy_series as u64,
+            cluster_series: cluster_series as u64,
+            backend_series: backend_series as u64,
+        }
+    }
 }
 
 impl Subscriber for Aggregator {
@@ -257,6 +338,11 @@ impl Subscriber for Aggregator {
         backend_id: Option<&str>,
         metric: MetricValue,
     ) {
+        if (backend_id.is_some() && !self.backend_metrics)
+            || (metric.is_time() && !self.time_metrics)
+        {
+            return;
+        }
         if let Some(ref mut net) = self.network.as_mut() {
             net.receive_metric(label, cluster_id, backend_id, metric.to_owned());
         }
@@ -335,7 +421,17 @@ macro_rules! gauge (
     $crate::metrics::METRICS.with(|metrics| {
       (*metrics.borrow_mut()).set_gauge($key, v);
     });
-  })
+  });
+  ($key:expr, $value:expr, $cluster_id:expr, $backend_id:expr) => {
+    {
+        use $crate::metrics::Subscriber;
+        let v = $value;
+
+        $crate::metrics::METRICS.with(|metrics| {
+          (*metrics.borrow_mut()).receive_metric($key, $cluster_id, $backend_id, $crate::metrics::MetricValue::Gauge(v));
+        });
+    }
+  }
 );
 
 #[macro_export]
@@ -381,6 +477,30 @@ macro_rules! time (
   })
 );
 
+/// records a value that is not a duration, like a size, in a histogram
+#[macro_export]
+macro_rules! histogram (
+  ($key:expr, $value: expr) => ({
+    use $crate::metrics::{MetricValue,Subscriber};
+    let v = $value;
+    $crate::metrics::METRICS.with(|metrics| {
+      let m = &mut *metrics.borrow_mut();
+
+      m.receive_metric($key, None, None, MetricValue::Histogram(v as usize));
+    });
+  });
+  ($key:expr, $cluster_id:expr, $value: expr) => ({
+    use $crate::metrics::{MetricValue,Subscriber};
+    let v = $value;
+    $crate::metrics::METRICS.with(|metrics| {
+      let m = &mut *metrics.borrow_mut();
+      let cluster: &str = $cluster_id;
+
+      m.receive_metric($key, Some(cluster), None, MetricValue::Histogram(v as usize));
+    });
+  })
+);
+
 #[macro_export]
 macro_rules! record_backend_metrics (
   ($cluster_id:expr, $backend_id:expr, $response_time: expr, $backend_connection_time: expr, $bin: expr, $bout: expr) => {
@@ -20,8 +20,12 @@ pub struct MetricLine {
     label: &'static str,
     cluster_id: Option<String>,
     backend_id: Option<String>,
-    /// in milliseconds
-    duration: usize,
+    /// (tag key, tag value) of a tag metric
+    tag: Option<(String, String)>,
+    /// in milliseconds for times
+    value: usize,
+    /// statsd type: `ms` for times, `h` for histograms
+    metric_type: &'static str,
 }
 
 /// gathers metrics and send them on a UDP socket
@@ -37,6 +41,8 @@ pub struct NetworkDrain {
     cluster_metrics: HashMap<(String, String), StoredMetricValue>,
     /// (cluster_id, backend_id, key) -> metric
     backend_metrics: HashMap<(String, String, String), StoredMetricValue>,
+    /// (tag_key, tag_value, key) -> metric
+    tag_metrics: HashMap<(String, String, String), StoredMetricValue>,
     pub use_tagged_metrics: bool,
     pub origin: String,
     created: Instant,
@@ -54,6 +60,7 @@ impl NetworkDrain {
             proxy_metrics: HashMap::new(),
             cluster_metrics: HashMap::new(),
             backend_metrics: HashMap::new(),
+            tag_metrics: HashMap::new(),
             use_tagged_metrics: false,
             origin: String::from("x"),
             created: Instant::now(),
@@ -64,6 +71,20 @@ impl NetworkDrain {
         self.is_writable = true;
     }
 
+    pub fn clear_backend_metrics(&mut self) {
+        self.backend_metrics.clear();
+    }
+
+    /// the number of metric series at the proxy, cluster and backend levels,
+    /// time metrics excluded since they are sent as they come
+    pub fn series(&self) -> (usize, usize, usize) {
+        (
+            self.proxy_metrics.len(),
+            self.cluster_metrics.len(),
+            self.backend_metrics.len(),
+        )
+    }
+
     pub fn send_metrics(&mut self) {
         let now = Instant::now();
         let secs = Duration::new(1, 0);
@@ -76,6 +97,9 @@ impl NetworkDrain {
         self.backend_metrics.retain(|_, ref value| {
             value.updated || now.duration_since(value.last_sent) < Duration::new(600, 00)
         });
+        self.tag_metrics.retain(|_, ref value| {
+            value.updated || now.duration_since(value.last_sent) < Duration::new(600, 00)
+        });
 
         if !self.is_writable {
             return;
@@ -332,55 +356,168 @@ impl NetworkDrain {
           error!("error flushing metrics socket: {:?}", e);
         }*/
 
+        if self.is_writable {
+            for (key, stored_metric) in self
+                .tag_metrics
+                .iter_mut()
+                .filter(|(_, value)| value.updated && now.duration_since(value.last_sent) > secs)
+            {
+                let res = match stored_metric.data {
+                    MetricValue::Gauge(value) => {
+                        if self.use_tagged_metrics {
+                            self.remote.write_fmt(format_args!(
+                                "{}.tag.{},origin={},version={},{}={}:{}|g\n",
+                                self.prefix, key.2, self.origin, VERSION, key.0, key.1, value
+                            ))
+                        } else {
+                            self.remote.write_fmt(format_args!(
+                                "{}.{}.tag.{}.{}.{}:{}|g\n",
+                                self.prefix, self.origin, key.0, key.1, key.2, value
+                            ))
+                        }
+                    }
+                    MetricValue::Count(value) => {
+                        if value == 0 {
+                            stored_metric.last_sent = now;
+                        }
+
+                        let res = if self.use_tagged_metrics {
+                            self.remote.write_fmt(format_args!(
+                                "{}.tag.{},origin={},version={},{}={}:{}|c\n",
+                                self.prefix, key.2, self.origin, VERSION, key.0, key.1, value
+                            ))
+                        } else {
+                            self.remote.write_fmt(format_args!(
+                                "{}.{}.tag.{}.{}.{}:{}|c\n",
+                                self.prefix, self.origin, key.0, key.1, key.2, value
+                            ))
+                        };
+
+                        if res.is_ok() {
+                            stored_metric.data = MetricValue::Count(0);
+                        }
+
+                        res
+                    }
+                    _ => Ok(()),
+                };
+
+                match res {
+                    Ok(()) => {
+                        stored_metric.last_sent = now;
+                        stored_metric.updated = false;
+                    }
+                    Err(e) => match e.kind() {
+                        ErrorKind::WriteZero => {
+                            if let Err(e) = self.remote.flush() {
+                                error!("error flushing metrics socket: {:?}", e);
+                            }
+                        }
+                        ErrorKind::WouldBlock => {
+                            error!("WouldBlock while writing tag metrics to socket");
+                            self.is_writable = false;
+                            break;
+                        }
+                        e => {
+                            error!("metrics socket write error={:?}", e);
+                            break;
+                        }
+                    },
+                }
+            }
+        }
+
         if self.is_writable {
             for metric in self.queue.drain(..) {
-                let res = match (metric.cluster_id, metric.backend_id) {
-                    (Some(cluster_id), Some(backend_id)) => {
+                let res = match (metric.tag, metric.cluster_id, metric.backend_id) {
+                    (Some((tag_key, tag_value)), _, _) => {
                         if self.use_tagged_metrics {
                             self.remote.write_fmt(format_args!(
-                                "{}.backend.{},origin={},version={},cluster_id={},backend_id={}:{}|ms\n",
-                                self.prefix, metric.label, self.origin, VERSION, cluster_id, backend_id, metric.duration
+                                "{}.tag.{},origin={},version={},{}={}:{}|{}\n",
+                                self.prefix,
+                                metric.label,
+                                self.origin,
+                                VERSION,
+                                tag_key,
+                                tag_value,
+                                metric.value,
+                                metric.metric_type
+                            ))
+                        } else {
+                            self.remote.write_fmt(format_args!(
+                                "{}.{}.tag.{}.{}.{}:{}|{}\n",
+                                self.prefix,
+                                self.origin,
+                                tag_key,
+                                tag_value,
+                                metric.label,
+                                metric.value,
+                                metric.metric_type
+                            ))
+                        }
+                    }
+                    (None, Some(cluster_id), Some(backend_id)) => {
+                        if self.use_tagged_metrics {
+                            self.remote.write_fmt(format_args!(
+                                "{}.backend.{},origin={},version={},cluster_id={},backend_id={}:{}|{}\n",
+                                self.prefix, metric.label, self.origin, VERSION, cluster_id, backend_id, metric.value, metric.metric_type
                             ))
                         } else {
                             self.remote.write_fmt(format_args!(
-                                "{}.{}.cluster.{}.backend.{}.{}:{}|ms\n",
+                                "{}.{}.cluster.{}.backend.{}.{}:{}|{}\n",
                                 self.prefix,
                                 self.origin,
                                 cluster_id,
                                 backend_id,
                                 metric.label,
-                                metric.duration
+                                metric.value,
+                                metric.metric_type
                             ))
                         }
                     }
-                    (Some(cluster_id), None) => {
+                    (None, Some(cluster_id), None) => {
                         if self.use_tagged_metrics {
                             self.remote.write_fmt(format_args!(
-                                "{}.cluster.{},origin={},version={},cluster_id={}:{}|ms\n",
+                                "{}.cluster.{},origin={},version={},cluster_id={}:{}|{}\n",
                                 self.prefix,
                                 metric.label,
                                 self.origin,
                                 VERSION,
                                 cluster_id,
-                                metric.duration
+                                metric.value,
+                                metric.metric_type
                             ))
                         } else {
                             self.remote.write_fmt(format_args!(
-                                "{}.{}.cluster.{}.{}:{}|ms\n",
-                                self.prefix, self.origin, cluster_id, metric.label, metric.duration
+                                "{}.{}.cluster.{}.{}:{}|{}\n",
+                                self.prefix,
+                                self.origin,
+                                cluster_id,
+                                metric.label,
+                                metric.value,
+                                metric.metric_type
                             ))
                         }
                     }
-                    (None, None) => {
+                    (None, None, None) => {
                         if self.use_tagged_metrics {
                             self.remote.write_fmt(format_args!(
-                                "{}.{},origin={},version={}:{}|ms\n",
-                                self.prefix, metric.label, self.origin, VERSION, metric.duration
+                                "{}.{},origin={},version={}:{}|{}\n",
+                                self.prefix,
+                                metric.label,
+                                self.origin,
+                                VERSION,
+                                metric.value,
+                                metric.metric_type
                             ))
                         } else {
                             self.remote.write_fmt(format_args!(
-                                "{}.{}.{}:{}|ms\n",
-                                self.prefix, self.origin, metric.label, metric.duration
+                                "{}.{}.{}:{}|{}\n",
+                                self.prefix,
+                                self.origin,
+                                metric.label,
+                                metric.value,
+                                metric.metric_type
                             ))
                         }
                     }
@@ -423,6 +560,43 @@ impl NetworkDrain {
     }
 }
 
+impl NetworkDrain {
+    /// records a metric under the value of the metrics tag of a frontend, sent as
+    /// `tag.<metric>` with the tag key and value, like the cluster metrics
+    pub fn receive_tag_metric(
+        &mut self,
+        key: &'static str,
+        tag_key: &str,
+        tag_value: &str,
+        metric: MetricValue,
+    ) {
+        let sampled = match metric {
+            MetricValue::Time(millis) => Some((millis, "ms")),
+            MetricValue::Histogram(value) => Some((value, "h")),
+            _ => None,
+        };
+        if let Some((value, metric_type)) = sampled {
+            self.queue.push_back(MetricLine {
+                label: key,
+                cluster_id: None,
+                backend_id: None,
+                tag: Some((tag_key.to_owned(), tag_value.to_owned())),
+                value,
+                metric_type,
+            });
+            return;
+        }
+
+        let k = (tag_key.to_owned(), tag_value.to_owned(), String::from(key));
+        match self.tag_metrics.entry(k) {
+            Entry::Vacant(e) => {
+                e.insert(StoredMetricValue::new(self.created, metric));
+            }
+            Entry::Occupied(mut e) => e.get_mut().update(key, metric),
+        }
+    }
+}
+
 impl Subscriber for NetworkDrain {
     fn receive_metric(
         &mut self,
@@ -431,15 +605,21 @@ impl Subscriber for NetworkDrain {
         backend_id: Option<&str>,
         metric: MetricValue,
     ) {
-        if metric.is_time() {
-            if let MetricValue::Time(millis) = metric {
-                self.queue.push_back(MetricLine {
-                    label: key,
-                    cluster_id: cluster_id.map(|s| s.to_string()),
-                    backend_id: backend_id.map(|s| s.to_string()),
-                    duration: millis,
-                });
-            }
+        // times and histograms are sent as they come, not aggregated
+        let sampled = match metric {
+            MetricValue::Time(millis) => Some((millis, "ms")),
+            MetricValue::Histogram(value) => Some((value, "h")),
+            _ => None,
+        };
+        if let Some((value, metric_type)) = sampled {
+            self.queue.push_back(MetricLine {
+                label: key,
+                cluster_id: cluster_id.map(|s| s.to_string()),
+                backend_id: backend_id.map(|s| s.to_string()),
+                tag: None,
+                value,
+                metric_type,
+            });
             return;
         }
 
@@ -501,3 +681,48 @@ impl Subscriber for NetworkDrain {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_metrics_are_sent() {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut drain =
+            NetworkDrain::new(String::from("sozu"), socket, receiver.local_addr().unwrap());
+        drain.origin = String::from("worker");
+        drain.created = Instant::now() - Duration::from_secs(2);
+
+        // the writer sends full packets only, give it enough lines for one
+        let tenants: Vec<String> = (0..30).map(|i| format!("tenant-{i}")).collect();
+        for tenant in &tenants {
+            drain.receive_tag_metric("requests", "tenant", tenant, MetricValue::Count(1));
+            drain.receive_tag_metric("requests", "tenant", tenant, MetricValue::Count(1));
+            drain.receive_tag_metric("request_time", "tenant", tenant, MetricValue::Time(12));
+        }
+        drain.send_metrics();
+        drain.remote.flush().unwrap();
+
+        let expected: Vec<String> = tenants
+            .iter()
+            .flat_map(|tenant| {
+                [
+                    format!("sozu.worker.tag.tenant.{tenant}.requests:2|c"),
+                    format!("sozu.worker.tag.tenant.{tenant}.request_time:12|ms"),
+                ]
+            })
+            .collect();
+        let mut buf = [0; 1024];
+        let size = receiver.recv(&mut buf).expect("the metrics should be sent");
+        let lines: Vec<&str> = str::from_utf8(&buf[..size]).unwrap().lines().collect();
+        assert!(!lines.is_empty());
+        for line in lines {
+            assert!(expected.iter().any(|e| e == line), "unexpected line {line}");
+        }
+    }
+}
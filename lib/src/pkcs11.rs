@@ -0,0 +1,1214 @@
+//! Private keys held by a PKCS#11 token, like an HSM or SoftHSM
+//!
+//! A certificate references its key with a PKCS#11 URI (RFC 7512) instead of a PEM key:
+//!
+//! ```text
+//! pkcs11:token=sozu;object=example-org?module-path=/usr/lib/softhsm/libsofthsm2.so&pin-source=/etc/sozu/pin
+//! ```
+//!
+//! The module is loaded once per process, and each key gets its own session on the
+//! token, on which rustls signs the handshakes. The key never leaves the token.
+//!
+//! Supported attributes:
+//! - `token`, `slot-id`: the token, the first one with a private key matching the
+//!   object otherwise
+//! - `object`, `id`: the label and the id of the private key, at least one of them
+//! - `module-path`: the PKCS#11 library of the token, required
+//! - `pin-source` or `pin-value`: the user PIN, from a file or inline.
+//!   Prefer a file, the URI is stored in the state of sozu
+//!
+//! RSA keys sign with RSA-PSS and PKCS#1 v1.5, EC keys on P-256 and P-384 with ECDSA,
+//! with the mechanisms that hash on the token (`CKM_SHA256_RSA_PKCS_PSS`, `CKM_ECDSA_SHA256`...).
+
+use std::{
+    collections::HashMap,
+    ffi::{c_void, CStr, CString},
+    fmt,
+    os::raw::c_ulong,
+    ptr,
+    sync::{Arc, Mutex},
+};
+
+use rustls::{
+    sign::{Signer, SigningKey},
+    Error as RustlsError, SignatureAlgorithm, SignatureScheme,
+};
+
+use crate::tls::{register_key_provider, CertificateResolverError, KeyProvider};
+
+type CkUlong = c_ulong;
+type CkRv = CkUlong;
+type SlotId = CkUlong;
+type SessionHandle = CkUlong;
+type ObjectHandle = CkUlong;
+
+const CKR_OK: CkRv = 0;
+const CKR_USER_ALREADY_LOGGED_IN: CkRv = 0x100;
+const CKR_BUFFER_TOO_SMALL: CkRv = 0x150;
+const CKR_CRYPTOKI_ALREADY_INITIALIZED: CkRv = 0x191;
+
+const CKF_OS_LOCKING_OK: CkUlong = 0x2;
+const CKF_SERIAL_SESSION: CkUlong = 0x4;
+const CKU_USER: CkUlong = 1;
+
+const CKA_CLASS: CkUlong = 0x0;
+const CKA_LABEL: CkUlong = 0x3;
+const CKA_KEY_TYPE: CkUlong = 0x100;
+const CKA_ID: CkUlong = 0x102;
+const CKA_EC_PARAMS: CkUlong = 0x180;
+
+const CKO_PRIVATE_KEY: CkUlong = 3;
+const CKK_RSA: CkUlong = 0;
+const CKK_EC: CkUlong = 3;
+
+const CKM_SHA256_RSA_PKCS: CkUlong = 0x40;
+const CKM_SHA384_RSA_PKCS: CkUlong = 0x41;
+const CKM_SHA512_RSA_PKCS: CkUlong = 0x42;
+const CKM_SHA256_RSA_PKCS_PSS: CkUlong = 0x43;
+const CKM_SHA384_RSA_PKCS_PSS: CkUlong = 0x44;
+const CKM_SHA512_RSA_PKCS_PSS: CkUlong = 0x45;
+const CKM_SHA256: CkUlong = 0x250;
+const CKM_SHA384: CkUlong = 0x260;
+const CKM_SHA512: CkUlong = 0x270;
+const CKM_ECDSA_SHA256: CkUlong = 0x1044;
+const CKM_ECDSA_SHA384: CkUlong = 0x1045;
+
+const CKG_MGF1_SHA256: CkUlong = 0x2;
+const CKG_MGF1_SHA384: CkUlong = 0x3;
+const CKG_MGF1_SHA512: CkUlong = 0x4;
+
+/// DER encoded OIDs of the curves, as found in CKA_EC_PARAMS
+const P256_OID: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const P384_OID: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+
+/// large enough for the signatures of RSA keys up to 8192 bits
+const SIGNATURE_BUFFER_SIZE: usize = 1024;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Version {
+    pub major: u8,
+    pub minor: u8,
+}
+
+#[repr(C)]
+pub(crate) struct Attribute {
+    pub typ: CkUlong,
+    pub value: *mut c_void,
+    pub value_len: CkUlong,
+}
+
+#[repr(C)]
+pub(crate) struct Mechanism {
+    pub mechanism: CkUlong,
+    pub parameter: *const c_void,
+    pub parameter_len: CkUlong,
+}
+
+#[repr(C)]
+struct RsaPssParams {
+    hash_alg: CkUlong,
+    mgf: CkUlong,
+    salt_len: CkUlong,
+}
+
+#[repr(C)]
+struct InitializeArgs {
+    create_mutex: *const c_void,
+    destroy_mutex: *const c_void,
+    lock_mutex: *const c_void,
+    unlock_mutex: *const c_void,
+    flags: CkUlong,
+    reserved: *mut c_void,
+}
+
+#[repr(C)]
+pub(crate) struct TokenInfo {
+    pub label: [u8; 32],
+    pub manufacturer_id: [u8; 32],
+    pub model: [u8; 16],
+    pub serial_number: [u8; 16],
+    pub flags: CkUlong,
+    pub counters: [CkUlong; 10],
+    pub hardware_version: Version,
+    pub firmware_version: Version,
+    pub utc_time: [u8; 16],
+}
+
+type Unused = Option<unsafe extern "C" fn()>;
+
+/// The start of CK_FUNCTION_LIST, up to C_Sign. The functions keep the order of
+/// the specification, those sozu does not call are left untyped
+#[repr(C)]
+pub(crate) struct FunctionList {
+    pub version: Version,
+    pub initialize: unsafe extern "C" fn(*mut c_void) -> CkRv,
+    pub finalize: Unused,
+    pub get_info: Unused,
+    pub get_function_list: Unused,
+    pub get_slot_list: unsafe extern "C" fn(u8, *mut SlotId, *mut CkUlong) -> CkRv,
+    pub get_slot_info: Unused,
+    pub get_token_info: unsafe extern "C" fn(SlotId, *mut TokenInfo) -> CkRv,
+    pub get_mechanism_list: Unused,
+    pub get_mechanism_info: Unused,
+    pub init_token: Unused,
+    pub init_pin: Unused,
+    pub set_pin: Unused,
+    pub open_session: unsafe extern "C" fn(
+        SlotId,
+        CkUlong,
+        *mut c_void,
+        *const c_void,
+        *mut SessionHandle,
+    ) -> CkRv,
+    pub close_session: unsafe extern "C" fn(SessionHandle) -> CkRv,
+    pub close_all_sessions: Unused,
+    pub get_session_info: Unused,
+    pub get_operation_state: Unused,
+    pub set_operation_state: Unused,
+    pub login: unsafe extern "C" fn(SessionHandle, CkUlong, *const u8, CkUlong) -> CkRv,
+    pub logout: Unused,
+    pub create_object: Unused,
+    pub copy_object: Unused,
+    pub destroy_object: Unused,
+    pub get_object_size: Unused,
+    pub get_attribute_value:
+        unsafe extern "C" fn(SessionHandle, ObjectHandle, *mut Attribute, CkUlong) -> CkRv,
+    pub set_attribute_value: Unused,
+    pub find_objects_init: unsafe extern "C" fn(SessionHandle, *const Attribute, CkUlong) -> CkRv,
+    pub find_objects:
+        unsafe extern "C" fn(SessionHandle, *mut ObjectHandle, CkUlong, *mut CkUlong) -> CkRv,
+    pub find_objects_final: unsafe extern "C" fn(SessionHandle) -> CkRv,
+    pub encrypt_init: Unused,
+    pub encrypt: Unused,
+    pub encrypt_update: Unused,
+    pub encrypt_final: Unused,
+    pub decrypt_init: Unused,
+    pub decrypt: Unused,
+    pub decrypt_update: Unused,
+    pub decrypt_final: Unused,
+    pub digest_init: Unused,
+    pub digest: Unused,
+    pub digest_update: Unused,
+    pub digest_key: Unused,
+    pub digest_final: Unused,
+    pub sign_init: unsafe extern "C" fn(SessionHandle, *const Mechanism, ObjectHandle) -> CkRv,
+    pub sign:
+        unsafe extern "C" fn(SessionHandle, *const u8, CkUlong, *mut u8, *mut CkUlong) -> CkRv,
+}
+
+/// the error of a PKCS#11 function, with its return value
+fn check(function: &str, rv: CkRv) -> Result<(), String> {
+    if rv == CKR_OK {
+        Ok(())
+    } else {
+        Err(format!("{function} failed with error {rv:#x}"))
+    }
+}
+
+/// The parts of a PKCS#11 URI that designate a private key
+#[derive(Debug, Default, PartialEq, Eq)]
+struct KeyUri {
+    token: Option<String>,
+    slot_id: Option<SlotId>,
+    object: Option<String>,
+    id: Option<Vec<u8>>,
+    module_path: String,
+    pin: Option<String>,
+}
+
+impl KeyUri {
+    fn parse(uri: &str) -> Result<Self, String> {
+        let rest = uri
+            .strip_prefix("pkcs11:")
+            .ok_or_else(|| "the key URI does not start with pkcs11:".to_owned())?;
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let mut key_uri = KeyUri::default();
+        let mut module_path = None;
+        let mut pin_source = None;
+        let attributes = path
+            .split(';')
+            .chain(query.split('&'))
+            .filter(|attribute| !attribute.is_empty());
+        for attribute in attributes {
+            let (name, value) = attribute
+                .split_once('=')
+                .ok_or_else(|| format!("the attribute {name} has no value", name = attribute))?;
+            let value = percent_decode(value)?;
+            let text = || {
+                String::from_utf8(value.clone())
+                    .map_err(|_| format!("the attribute {name} is not UTF-8"))
+            };
+            match name {
+                "token" => key_uri.token = Some(text()?),
+                "slot-id" => {
+                    key_uri.slot_id = Some(
+                        text()?
+                            .parse()
+                            .map_err(|_| "the slot-id is not a number".to_owned())?,
+                    )
+                }
+                "object" => key_uri.object = Some(text()?),
+                "id" => key_uri.id = Some(value),
+                "type" if value != b"private" => {
+                    return Err("the key URI does not designate a private key".to_owned())
+                }
+                "module-path" => module_path = Some(text()?),
+                "pin-value" => key_uri.pin = Some(text()?),
+                "pin-source" => pin_source = Some(text()?),
+                // the other attributes narrow the token and are not needed
+                _ => {}
+            }
+        }
+
+        key_uri.module_path =
+            module_path.ok_or_else(|| "the key URI has no module-path".to_owned())?;
+        if key_uri.object.is_none() && key_uri.id.is_none() {
+            return Err("the key URI has neither an object nor an id".to_owned());
+        }
+        if let Some(pin_source) = pin_source {
+            let path = pin_source.strip_prefix("file:").unwrap_or(&pin_source);
+            let pin = std::fs::read_to_string(path)
+                .map_err(|e| format!("could not read the pin-source {path}: {e}"))?;
+            key_uri.pin = Some(pin.trim_end_matches(['\r', '\n']).to_owned());
+        }
+        Ok(key_uri)
+    }
+}
+
+fn percent_decode(value: &str) -> Result<Vec<u8>, String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("invalid percent encoding in {value}"))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(decoded)
+}
+
+/// A loaded and initialized PKCS#11 library, never unloaded
+struct Module {
+    functions: &'static FunctionList,
+}
+
+// the library is initialized with CKF_OS_LOCKING_OK, it can be called from any thread
+unsafe impl Send for Module {}
+unsafe impl Sync for Module {}
+
+impl Module {
+    fn open(path: &str) -> Result<Self, String> {
+        let c_path = CString::new(path).map_err(|_| "invalid module-path".to_owned())?;
+        unsafe {
+            let handle = libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+            if handle.is_null() {
+                return Err(format!("could not load {path}: {}", dl_error()));
+            }
+            let symbol = libc::dlsym(handle, c"C_GetFunctionList".as_ptr());
+            if symbol.is_null() {
+                return Err(format!("{path} is not a PKCS#11 module: {}", dl_error()));
+            }
+            let get_function_list: unsafe extern "C" fn(*mut *const FunctionList) -> CkRv =
+                std::mem::transmute(symbol);
+            let mut functions = ptr::null();
+            check("C_GetFunctionList", get_function_list(&mut functions))?;
+            if functions.is_null() {
+                return Err(format!("{path} gave no function list"));
+            }
+            Self::initialize(&*functions)
+        }
+    }
+
+    fn initialize(functions: &'static FunctionList) -> Result<Self, String> {
+        let mut args = InitializeArgs {
+            create_mutex: ptr::null(),
+            destroy_mutex: ptr::null(),
+            lock_mutex: ptr::null(),
+            unlock_mutex: ptr::null(),
+            flags: CKF_OS_LOCKING_OK,
+            reserved: ptr::null_mut(),
+        };
+        let rv = unsafe { (functions.initialize)(&mut args as *mut InitializeArgs as *mut c_void) };
+        if rv != CKR_CRYPTOKI_ALREADY_INITIALIZED {
+            check("C_Initialize", rv)?;
+        }
+        Ok(Self { functions })
+    }
+
+    fn slots(&self) -> Result<Vec<SlotId>, String> {
+        let mut count = 0;
+        unsafe {
+            check(
+                "C_GetSlotList",
+                (self.functions.get_slot_list)(1, ptr::null_mut(), &mut count),
+            )?;
+            let mut slots = vec![0; count as usize];
+            check(
+                "C_GetSlotList",
+                (self.functions.get_slot_list)(1, slots.as_mut_ptr(), &mut count),
+            )?;
+            slots.truncate(count as usize);
+            Ok(slots)
+        }
+    }
+
+    fn token_label(&self, slot: SlotId) -> Result<String, String> {
+        let mut info: TokenInfo = unsafe { std::mem::zeroed() };
+        check("C_GetTokenInfo", unsafe {
+            (self.functions.get_token_info)(slot, &mut info)
+        })?;
+        // blank padded, not null terminated
+        Ok(String::from_utf8_lossy(&info.label)
+            .trim_end_matches([' ', '\0'])
+            .to_owned())
+    }
+}
+
+fn dl_error() -> String {
+    let error = unsafe { libc::dlerror() };
+    if error.is_null() {
+        "unknown error".to_owned()
+    } else {
+        unsafe { CStr::from_ptr(error) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// A session on a token, logged in, used to sign with one key
+struct Session {
+    module: Arc<Module>,
+    handle: SessionHandle,
+}
+
+impl Session {
+    fn open(module: Arc<Module>, slot: SlotId, pin: Option<&str>) -> Result<Self, String> {
+        let mut handle = 0;
+        check("C_OpenSession", unsafe {
+            (module.functions.open_session)(
+                slot,
+                CKF_SERIAL_SESSION,
+                ptr::null_mut(),
+                ptr::null(),
+                &mut handle,
+            )
+        })?;
+        let session = Session { module, handle };
+        if let Some(pin) = pin {
+            let rv = unsafe {
+                (session.module.functions.login)(
+                    handle,
+                    CKU_USER,
+                    pin.as_ptr(),
+                    pin.len() as CkUlong,
+                )
+            };
+            // the login is shared by the sessions of the process
+            if rv != CKR_USER_ALREADY_LOGGED_IN {
+                check("C_Login", rv)?;
+            }
+        }
+        Ok(session)
+    }
+
+    /// the private keys matching the label and id, if given
+    fn find_private_key(
+        &self,
+        label: Option<&str>,
+        id: Option<&[u8]>,
+    ) -> Result<Option<ObjectHandle>, String> {
+        let mut class = CKO_PRIVATE_KEY;
+        let mut template = vec![Attribute {
+            typ: CKA_CLASS,
+            value: &mut class as *mut CkUlong as *mut c_void,
+            value_len: std::mem::size_of::<CkUlong>() as CkUlong,
+        }];
+        if let Some(label) = label {
+            template.push(Attribute {
+                typ: CKA_LABEL,
+                value: label.as_ptr() as *mut c_void,
+                value_len: label.len() as CkUlong,
+            });
+        }
+        if let Some(id) = id {
+            template.push(Attribute {
+                typ: CKA_ID,
+                value: id.as_ptr() as *mut c_void,
+                value_len: id.len() as CkUlong,
+            });
+        }
+
+        let functions = self.module.functions;
+        unsafe {
+            check(
+                "C_FindObjectsInit",
+                (functions.find_objects_init)(
+                    self.handle,
+                    template.as_ptr(),
+                    template.len() as CkUlong,
+                ),
+            )?;
+            let mut object = 0;
+            let mut count = 0;
+            let found = check(
+                "C_FindObjects",
+                (functions.find_objects)(self.handle, &mut object, 1, &mut count),
+            );
+            check(
+                "C_FindObjectsFinal",
+                (functions.find_objects_final)(self.handle),
+            )?;
+            found?;
+            Ok((count > 0).then_some(object))
+        }
+    }
+
+    fn attribute(&self, object: ObjectHandle, typ: CkUlong) -> Result<Vec<u8>, String> {
+        let functions = self.module.functions;
+        let mut attribute = Attribute {
+            typ,
+            value: ptr::null_mut(),
+            value_len: 0,
+        };
+        unsafe {
+            check(
+                "C_GetAttributeValue",
+                (functions.get_attribute_value)(self.handle, object, &mut attribute, 1),
+            )?;
+            let mut value = vec![0u8; attribute.value_len as usize];
+            attribute.value = value.as_mut_ptr() as *mut c_void;
+            check(
+                "C_GetAttributeValue",
+                (functions.get_attribute_value)(self.handle, object, &mut attribute, 1),
+            )?;
+            value.truncate(attribute.value_len as usize);
+            Ok(value)
+        }
+    }
+
+    fn sign(
+        &self,
+        object: ObjectHandle,
+        mechanism: &Mechanism,
+        data: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let functions = self.module.functions;
+        let mut signature = vec![0u8; SIGNATURE_BUFFER_SIZE];
+        let mut length = signature.len() as CkUlong;
+        unsafe {
+            check(
+                "C_SignInit",
+                (functions.sign_init)(self.handle, mechanism, object),
+            )?;
+            let mut rv = (functions.sign)(
+                self.handle,
+                data.as_ptr(),
+                data.len() as CkUlong,
+                signature.as_mut_ptr(),
+                &mut length,
+            );
+            if rv == CKR_BUFFER_TOO_SMALL {
+                // the operation is still active, the length is the one needed
+                signature.resize(length as usize, 0);
+                rv = (functions.sign)(
+                    self.handle,
+                    data.as_ptr(),
+                    data.len() as CkUlong,
+                    signature.as_mut_ptr(),
+                    &mut length,
+                );
+            }
+            check("C_Sign", rv)?;
+        }
+        signature.truncate(length as usize);
+        Ok(signature)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        unsafe {
+            (self.module.functions.close_session)(self.handle);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KeyKind {
+    Rsa,
+    EcdsaP256,
+    EcdsaP384,
+}
+
+impl KeyKind {
+    /// the schemes of the key, preferred first
+    fn schemes(self) -> &'static [SignatureScheme] {
+        match self {
+            KeyKind::Rsa => &[
+                SignatureScheme::RSA_PSS_SHA512,
+                SignatureScheme::RSA_PSS_SHA384,
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::RSA_PKCS1_SHA512,
+                SignatureScheme::RSA_PKCS1_SHA384,
+                SignatureScheme::RSA_PKCS1_SHA256,
+            ],
+            KeyKind::EcdsaP256 => &[SignatureScheme::ECDSA_NISTP256_SHA256],
+            KeyKind::EcdsaP384 => &[SignatureScheme::ECDSA_NISTP384_SHA384],
+        }
+    }
+}
+
+/// A private key on a token, signing through its own session
+struct Pkcs11Key {
+    session: Mutex<Session>,
+    object: ObjectHandle,
+    kind: KeyKind,
+}
+
+// the session is only used behind its mutex
+unsafe impl Send for Pkcs11Key {}
+unsafe impl Sync for Pkcs11Key {}
+
+impl fmt::Debug for Pkcs11Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pkcs11Key")
+            .field("object", &self.object)
+            .field("kind", &self.kind)
+            .finish()
+    }
+}
+
+impl Pkcs11Key {
+    fn load(module: Arc<Module>, key_uri: &KeyUri) -> Result<Self, String> {
+        let slots = match key_uri.slot_id {
+            Some(slot) => vec![slot],
+            None => module.slots()?,
+        };
+
+        for slot in slots {
+            if let Some(token) = &key_uri.token {
+                if module.token_label(slot)? != *token {
+                    continue;
+                }
+            }
+            let session = Session::open(module.clone(), slot, key_uri.pin.as_deref())?;
+            let Some(object) =
+                session.find_private_key(key_uri.object.as_deref(), key_uri.id.as_deref())?
+            else {
+                continue;
+            };
+
+            let key_type = session.attribute(object, CKA_KEY_TYPE)?;
+            let kind = match ulong_from_bytes(&key_type) {
+                Some(CKK_RSA) => KeyKind::Rsa,
+                Some(CKK_EC) => match session.attribute(object, CKA_EC_PARAMS)?.as_slice() {
+                    P256_OID => KeyKind::EcdsaP256,
+                    P384_OID => KeyKind::EcdsaP384,
+                    _ => return Err("the curve of the key is not supported".to_owned()),
+                },
+                _ => return Err("the type of the key is not supported".to_owned()),
+            };
+
+            return Ok(Pkcs11Key {
+                session: Mutex::new(session),
+                object,
+                kind,
+            });
+        }
+        Err("no private key matches the URI".to_owned())
+    }
+
+    fn sign(&self, scheme: SignatureScheme, message: &[u8]) -> Result<Vec<u8>, String> {
+        let pss = |hash_alg, mgf, salt_len| RsaPssParams {
+            hash_alg,
+            mgf,
+            salt_len,
+        };
+        let (mechanism, params) = match scheme {
+            SignatureScheme::RSA_PSS_SHA256 => (
+                CKM_SHA256_RSA_PKCS_PSS,
+                Some(pss(CKM_SHA256, CKG_MGF1_SHA256, 32)),
+            ),
+            SignatureScheme::RSA_PSS_SHA384 => (
+                CKM_SHA384_RSA_PKCS_PSS,
+                Some(pss(CKM_SHA384, CKG_MGF1_SHA384, 48)),
+            ),
+            SignatureScheme::RSA_PSS_SHA512 => (
+                CKM_SHA512_RSA_PKCS_PSS,
+                Some(pss(CKM_SHA512, CKG_MGF1_SHA512, 64)),
+            ),
+            SignatureScheme::RSA_PKCS1_SHA256 => (CKM_SHA256_RSA_PKCS, None),
+            SignatureScheme::RSA_PKCS1_SHA384 => (CKM_SHA384_RSA_PKCS, None),
+            SignatureScheme::RSA_PKCS1_SHA512 => (CKM_SHA512_RSA_PKCS, None),
+            SignatureScheme::ECDSA_NISTP256_SHA256 => (CKM_ECDSA_SHA256, None),
+            SignatureScheme::ECDSA_NISTP384_SHA384 => (CKM_ECDSA_SHA384, None),
+            _ => return Err(format!("unsupported signature scheme {scheme:?}")),
+        };
+        let mechanism = Mechanism {
+            mechanism,
+            parameter: params.as_ref().map_or(ptr::null(), |params| {
+                params as *const RsaPssParams as *const c_void
+            }),
+            parameter_len: params
+                .as_ref()
+                .map_or(0, |_| std::mem::size_of::<RsaPssParams>() as CkUlong),
+        };
+
+        let session = self
+            .session
+            .lock()
+            .map_err(|_| "the session of the key is poisoned".to_owned())?;
+        let signature = session.sign(self.object, &mechanism, message)?;
+        match self.kind {
+            // the token gives r and s, TLS expects an ECDSA-Sig-Value
+            KeyKind::EcdsaP256 | KeyKind::EcdsaP384 => ecdsa_signature_to_der(&signature),
+            KeyKind::Rsa => Ok(signature),
+        }
+    }
+}
+
+fn ulong_from_bytes(bytes: &[u8]) -> Option<CkUlong> {
+    Some(CkUlong::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+/// DER encodes the concatenation of r and s returned by the token
+fn ecdsa_signature_to_der(signature: &[u8]) -> Result<Vec<u8>, String> {
+    if signature.is_empty() || signature.len() % 2 != 0 {
+        return Err("invalid ECDSA signature length".to_owned());
+    }
+    let (r, s) = signature.split_at(signature.len() / 2);
+    let mut integers = Vec::with_capacity(signature.len() + 6);
+    for integer in [r, s] {
+        let start = integer
+            .iter()
+            .position(|b| *b != 0)
+            .unwrap_or(integer.len() - 1);
+        let integer = &integer[start..];
+        let padding = usize::from(integer[0] & 0x80 != 0);
+        integers.push(0x02);
+        integers.push((integer.len() + padding) as u8);
+        if padding == 1 {
+            integers.push(0);
+        }
+        integers.extend_from_slice(integer);
+    }
+    // P-384 signatures stay under 128 bytes, the length fits in one byte
+    let mut der = vec![0x30, integers.len() as u8];
+    der.extend(integers);
+    Ok(der)
+}
+
+#[derive(Debug)]
+struct Pkcs11SigningKey(Arc<Pkcs11Key>);
+
+impl SigningKey for Pkcs11SigningKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        self.0
+            .kind
+            .schemes()
+            .iter()
+            .find(|scheme| offered.contains(scheme))
+            .map(|scheme| {
+                Box::new(Pkcs11Signer {
+                    key: self.0.clone(),
+                    scheme: *scheme,
+                }) as Box<dyn Signer>
+            })
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        match self.0.kind {
+            KeyKind::Rsa => SignatureAlgorithm::RSA,
+            KeyKind::EcdsaP256 | KeyKind::EcdsaP384 => SignatureAlgorithm::ECDSA,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Pkcs11Signer {
+    key: Arc<Pkcs11Key>,
+    scheme: SignatureScheme,
+}
+
+impl Signer for Pkcs11Signer {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, RustlsError> {
+        self.key.sign(self.scheme, message).map_err(|error| {
+            error!("could not sign with a PKCS#11 key: {}", error);
+            RustlsError::General(error)
+        })
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
+
+/// Loads the keys referenced by `pkcs11:` URIs, see the module documentation
+#[derive(Default)]
+pub struct Pkcs11Provider {
+    /// module-path -> initialized module
+    modules: Mutex<HashMap<String, Arc<Module>>>,
+}
+
+impl Pkcs11Provider {
+    fn module(&self, path: &str) -> Result<Arc<Module>, String> {
+        let mut modules = self
+            .modules
+            .lock()
+            .map_err(|_| "the PKCS#11 modules are poisoned".to_owned())?;
+        if let Some(module) = modules.get(path) {
+            return Ok(module.clone());
+        }
+        let module = Arc::new(Module::open(path)?);
+        modules.insert(path.to_owned(), module.clone());
+        Ok(module)
+    }
+
+    /// a module that is not loaded from a library, for tests
+    #[cfg(test)]
+    pub(crate) fn with_module(path: &str, functions: &'static FunctionList) -> Self {
+        let provider = Self::default();
+        let module = Module::initialize(functions).expect("could not initialize the module");
+        provider
+            .modules
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), Arc::new(module));
+        provider
+    }
+}
+
+impl KeyProvider for Pkcs11Provider {
+    fn load_signing_key(&self, uri: &str) -> Result<Arc<dyn SigningKey>, CertificateResolverError> {
+        let load = || {
+            let key_uri = KeyUri::parse(uri)?;
+            let module = self.module(&key_uri.module_path)?;
+            Pkcs11Key::load(module, &key_uri)
+        };
+        // the error never holds the URI, that may contain a PIN
+        let key = load().map_err(CertificateResolverError::KeyProvider)?;
+        Ok(Arc::new(Pkcs11SigningKey(Arc::new(key))))
+    }
+}
+
+/// makes the keys of `pkcs11:` URIs available to the certificates of this process
+pub fn register() {
+    register_key_provider("pkcs11", Arc::new(Pkcs11Provider::default()));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        ffi::c_void,
+        slice,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            LazyLock, Mutex,
+        },
+    };
+
+    use ring::{
+        rand::SystemRandom,
+        signature::{self, EcdsaKeyPair, KeyPair, RsaKeyPair, UnparsedPublicKey},
+    };
+    use rustls::{sign::SigningKey, SignatureScheme};
+
+    use super::*;
+    use crate::tls::{CertificateResolverError, KeyProvider};
+
+    const CKR_PIN_INCORRECT: CkRv = 0xa0;
+    const CKR_ARGUMENTS_BAD: CkRv = 0x7;
+
+    const MOCK_SLOT: SlotId = 1;
+    const MOCK_PIN: &str = "1234";
+    const RSA_KEY: ObjectHandle = 10;
+    const EC_KEY: ObjectHandle = 11;
+
+    /// the objects found in a session, and the mechanism and key of its signature
+    type MockSession = (Vec<ObjectHandle>, Option<(CkUlong, ObjectHandle)>);
+
+    /// a token holding the RSA test key and an EC key, labelled `rsa-key` (id 01)
+    /// and `ec-key` (id 02)
+    struct MockToken {
+        rsa: RsaKeyPair,
+        ec: EcdsaKeyPair,
+        sessions: Mutex<HashMap<SessionHandle, MockSession>>,
+    }
+
+    static TOKEN: LazyLock<MockToken> = LazyLock::new(|| {
+        let rng = SystemRandom::new();
+        let (rsa_der, _) = rustls_pemfile::read_one_from_slice(include_bytes!("../assets/key.pem"))
+            .unwrap()
+            .unwrap();
+        let rsa_der = match rsa_der {
+            rustls_pemfile::Item::Pkcs8Key(key) => key.secret_pkcs8_der().to_vec(),
+            _ => panic!("the test key should be PKCS#8"),
+        };
+        let ec_der =
+            EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .unwrap();
+        MockToken {
+            rsa: RsaKeyPair::from_pkcs8(&rsa_der).unwrap(),
+            ec: EcdsaKeyPair::from_pkcs8(
+                &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                ec_der.as_ref(),
+                &rng,
+            )
+            .unwrap(),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    });
+
+    static NEXT_SESSION: AtomicU64 = AtomicU64::new(1);
+
+    unsafe extern "C" fn initialize(_: *mut c_void) -> CkRv {
+        CKR_OK
+    }
+
+    unsafe extern "C" fn get_slot_list(_: u8, slots: *mut SlotId, count: *mut CkUlong) -> CkRv {
+        if !slots.is_null() {
+            *slots = MOCK_SLOT;
+        }
+        *count = 1;
+        CKR_OK
+    }
+
+    unsafe extern "C" fn get_token_info(slot: SlotId, info: *mut TokenInfo) -> CkRv {
+        if slot != MOCK_SLOT {
+            return CKR_ARGUMENTS_BAD;
+        }
+        let mut label = [b' '; 32];
+        label[..4].copy_from_slice(b"sozu");
+        (*info).label = label;
+        CKR_OK
+    }
+
+    unsafe extern "C" fn open_session(
+        _: SlotId,
+        _: CkUlong,
+        _: *mut c_void,
+        _: *const c_void,
+        session: *mut SessionHandle,
+    ) -> CkRv {
+        *session = NEXT_SESSION.fetch_add(1, Ordering::Relaxed) as SessionHandle;
+        TOKEN
+            .sessions
+            .lock()
+            .unwrap()
+            .insert(*session, (Vec::new(), None));
+        CKR_OK
+    }
+
+    unsafe extern "C" fn close_session(session: SessionHandle) -> CkRv {
+        TOKEN.sessions.lock().unwrap().remove(&session);
+        CKR_OK
+    }
+
+    unsafe extern "C" fn login(_: SessionHandle, _: CkUlong, pin: *const u8, len: CkUlong) -> CkRv {
+        if slice::from_raw_parts(pin, len as usize) == MOCK_PIN.as_bytes() {
+            CKR_OK
+        } else {
+            CKR_PIN_INCORRECT
+        }
+    }
+
+    unsafe extern "C" fn find_objects_init(
+        session: SessionHandle,
+        template: *const Attribute,
+        count: CkUlong,
+    ) -> CkRv {
+        let mut found = vec![RSA_KEY, EC_KEY];
+        for attribute in slice::from_raw_parts(template, count as usize) {
+            let value =
+                slice::from_raw_parts(attribute.value as *const u8, attribute.value_len as usize);
+            found.retain(|object| match attribute.typ {
+                CKA_CLASS => ulong_from_bytes(value) == Some(CKO_PRIVATE_KEY),
+                CKA_LABEL => {
+                    value
+                        == if *object == RSA_KEY {
+                            &b"rsa-key"[..]
+                        } else {
+                            b"ec-key"
+                        }
+                }
+                CKA_ID => value == if *object == RSA_KEY { [1] } else { [2] },
+                _ => false,
+            });
+        }
+        TOKEN.sessions.lock().unwrap().get_mut(&session).unwrap().0 = found;
+        CKR_OK
+    }
+
+    unsafe extern "C" fn find_objects(
+        session: SessionHandle,
+        objects: *mut ObjectHandle,
+        _: CkUlong,
+        count: *mut CkUlong,
+    ) -> CkRv {
+        let sessions = TOKEN.sessions.lock().unwrap();
+        let found = &sessions.get(&session).unwrap().0;
+        *count = found.len().min(1) as CkUlong;
+        if let Some(object) = found.first() {
+            *objects = *object;
+        }
+        CKR_OK
+    }
+
+    unsafe extern "C" fn find_objects_final(_: SessionHandle) -> CkRv {
+        CKR_OK
+    }
+
+    unsafe extern "C" fn get_attribute_value(
+        _: SessionHandle,
+        object: ObjectHandle,
+        attribute: *mut Attribute,
+        _: CkUlong,
+    ) -> CkRv {
+        let value = match ((*attribute).typ, object) {
+            (CKA_KEY_TYPE, RSA_KEY) => CKK_RSA.to_ne_bytes().to_vec(),
+            (CKA_KEY_TYPE, EC_KEY) => CKK_EC.to_ne_bytes().to_vec(),
+            (CKA_EC_PARAMS, EC_KEY) => P256_OID.to_vec(),
+            _ => return CKR_ARGUMENTS_BAD,
+        };
+        if !(*attribute).value.is_null() {
+            ptr::copy_nonoverlapping(value.as_ptr(), (*attribute).value as *mut u8, value.len());
+        }
+        (*attribute).value_len = value.len() as CkUlong;
+        CKR_OK
+    }
+
+    unsafe extern "C" fn sign_init(
+        session: SessionHandle,
+        mechanism: *const Mechanism,
+        object: ObjectHandle,
+    ) -> CkRv {
+        TOKEN.sessions.lock().unwrap().get_mut(&session).unwrap().1 =
+            Some(((*mechanism).mechanism, object));
+        CKR_OK
+    }
+
+    unsafe extern "C" fn sign(
+        session: SessionHandle,
+        data: *const u8,
+        data_len: CkUlong,
+        signature: *mut u8,
+        signature_len: *mut CkUlong,
+    ) -> CkRv {
+        let Some((mechanism, object)) = TOKEN
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(&session)
+            .unwrap()
+            .1
+            .take()
+        else {
+            return CKR_ARGUMENTS_BAD;
+        };
+        let data = slice::from_raw_parts(data, data_len as usize);
+        let rng = SystemRandom::new();
+        let result = match (mechanism, object) {
+            (CKM_ECDSA_SHA256, EC_KEY) => TOKEN.ec.sign(&rng, data).unwrap().as_ref().to_vec(),
+            (CKM_SHA256_RSA_PKCS_PSS | CKM_SHA256_RSA_PKCS, RSA_KEY) => {
+                let padding: &'static dyn signature::RsaEncoding =
+                    if mechanism == CKM_SHA256_RSA_PKCS {
+                        &signature::RSA_PKCS1_SHA256
+                    } else {
+                        &signature::RSA_PSS_SHA256
+                    };
+                let mut result = vec![0; TOKEN.rsa.public().modulus_len()];
+                TOKEN.rsa.sign(padding, &rng, data, &mut result).unwrap();
+                result
+            }
+            _ => return CKR_ARGUMENTS_BAD,
+        };
+        if (*signature_len as usize) < result.len() {
+            *signature_len = result.len() as CkUlong;
+            return CKR_BUFFER_TOO_SMALL;
+        }
+        ptr::copy_nonoverlapping(result.as_ptr(), signature, result.len());
+        *signature_len = result.len() as CkUlong;
+        CKR_OK
+    }
+
+    static FUNCTIONS: FunctionList = FunctionList {
+        version: Version {
+            major: 2,
+            minor: 40,
+        },
+        initialize,
+        finalize: None,
+        get_info: None,
+        get_function_list: None,
+        get_slot_list,
+        get_slot_info: None,
+        get_token_info,
+        get_mechanism_list: None,
+        get_mechanism_info: None,
+        init_token: None,
+        init_pin: None,
+        set_pin: None,
+        open_session,
+        close_session,
+        get_session_info: None,
+        close_all_sessions: None,
+        get_operation_state: None,
+        set_operation_state: None,
+        login,
+        logout: None,
+        create_object: None,
+        copy_object: None,
+        destroy_object: None,
+        get_object_size: None,
+        get_attribute_value,
+        set_attribute_value: None,
+        find_objects_init,
+        find_objects,
+        find_objects_final,
+        encrypt_init: None,
+        encrypt: None,
+        encrypt_update: None,
+        encrypt_final: None,
+        decrypt_init: None,
+        decrypt: None,
+        decrypt_update: None,
+        decrypt_final: None,
+        digest_init: None,
+        digest: None,
+        digest_update: None,
+        digest_key: None,
+        digest_final: None,
+        sign_init,
+        sign,
+    };
+
+    fn provider() -> Pkcs11Provider {
+        Pkcs11Provider::with_module("/mock/libpkcs11.so", &FUNCTIONS)
+    }
+
+    fn sign_with(key: &dyn SigningKey, offered: &[SignatureScheme]) -> (SignatureScheme, Vec<u8>) {
+        let signer = key.choose_scheme(offered).expect("a scheme should match");
+        let signature = signer.sign(b"handshake").expect("the token should sign");
+        (signer.scheme(), signature)
+    }
+
+    #[test]
+    fn parse_key_uri() {
+        assert_eq!(
+            KeyUri::parse(
+                "pkcs11:token=my%20token;object=example-org;id=%01%ab;type=private\
+                ?module-path=/usr/lib/softhsm/libsofthsm2.so&pin-value=1234"
+            ),
+            Ok(KeyUri {
+                token: Some("my token".to_owned()),
+                slot_id: None,
+                object: Some("example-org".to_owned()),
+                id: Some(vec![0x01, 0xab]),
+                module_path: "/usr/lib/softhsm/libsofthsm2.so".to_owned(),
+                pin: Some("1234".to_owned()),
+            })
+        );
+        assert!(KeyUri::parse("pkcs11:object=example-org").is_err());
+        assert!(KeyUri::parse("pkcs11:token=sozu?module-path=/lib.so").is_err());
+        assert!(KeyUri::parse("pkcs11:object=a;type=public?module-path=/lib.so").is_err());
+        assert!(KeyUri::parse("pkcs11:object=a%2?module-path=/lib.so").is_err());
+    }
+
+    #[test]
+    fn sign_with_ec_key() {
+        let key = provider()
+            .load_signing_key(
+                "pkcs11:token=sozu;object=ec-key?module-path=/mock/libpkcs11.so&pin-value=1234",
+            )
+            .expect("the key should load");
+
+        let (scheme, der) = sign_with(
+            key.as_ref(),
+            &[
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+            ],
+        );
+        assert_eq!(scheme, SignatureScheme::ECDSA_NISTP256_SHA256);
+        UnparsedPublicKey::new(
+            &signature::ECDSA_P256_SHA256_ASN1,
+            TOKEN.ec.public_key().as_ref(),
+        )
+        .verify(b"handshake", &der)
+        .expect("the signature should be a valid ECDSA-Sig-Value");
+        assert!(key
+            .choose_scheme(&[SignatureScheme::ECDSA_NISTP384_SHA384])
+            .is_none());
+    }
+
+    #[test]
+    fn sign_with_rsa_key() {
+        let key = provider()
+            .load_signing_key("pkcs11:id=%01?module-path=/mock/libpkcs11.so&pin-value=1234")
+            .expect("the key should load");
+        let public_key = TOKEN.rsa.public().as_ref();
+
+        let (scheme, signature) = sign_with(
+            key.as_ref(),
+            &[
+                SignatureScheme::RSA_PKCS1_SHA256,
+                SignatureScheme::RSA_PSS_SHA256,
+            ],
+        );
+        assert_eq!(scheme, SignatureScheme::RSA_PSS_SHA256);
+        UnparsedPublicKey::new(&signature::RSA_PSS_2048_8192_SHA256, public_key)
+            .verify(b"handshake", &signature)
+            .expect("the PSS signature should be valid");
+
+        let (_, signature) = sign_with(key.as_ref(), &[SignatureScheme::RSA_PKCS1_SHA256]);
+        UnparsedPublicKey::new(&signature::RSA_PKCS1_2048_8192_SHA256, public_key)
+            .verify(b"handshake", &signature)
+            .expect("the PKCS#1 signature should be valid");
+    }
+
+    #[test]
+    fn key_errors_hide_the_pin() {
+        let provider = provider();
+        for uri in [
+            "pkcs11:object=ec-key?module-path=/mock/libpkcs11.so&pin-value=4321",
+            "pkcs11:token=other;object=ec-key?module-path=/mock/libpkcs11.so&pin-value=1234",
+            "pkcs11:object=unknown?module-path=/mock/libpkcs11.so&pin-value=1234",
+        ] {
+            match provider.load_signing_key(uri) {
+                Err(CertificateResolverError::KeyProvider(message)) => {
+                    assert!(!message.contains("4321") && !message.contains("1234"))
+                }
+                other => panic!("{uri} should not load a key: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn ecdsa_signature_encoding() {
+        let mut raw = vec![0u8; 64];
+        raw[0] = 0x80;
+        raw[31] = 0x01;
+        raw[63] = 0x7f;
+        assert_eq!(
+            ecdsa_signature_to_der(&raw).unwrap()[..6],
+            [0x30, 0x26, 0x02, 0x21, 0x00, 0x80]
+        );
+        assert_eq!(
+            ecdsa_signature_to_der(&raw).unwrap()[37..],
+            [0x02, 0x01, 0x7f]
+        );
+        assert!(ecdsa_signature_to_der(&raw[..63]).is_err());
+    }
+}
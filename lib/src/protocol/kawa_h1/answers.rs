@@ -301,6 +301,8 @@ pub struct ListenerAnswers {
     pub answer_400: Template,
     /// Unauthorized
     pub answer_401: Template,
+    /// Forbidden
+    pub answer_403: Template,
     /// NotFound
     pub answer_404: Template,
     /// RequestTimeout
@@ -399,6 +401,29 @@ Sozu-Id: %REQUEST_ID\r
     )
 }
 
+fn default_403() -> String {
+    String::from(
+        "\
+HTTP/1.1 403 Forbidden\r
+Cache-Control: no-cache\r
+Connection: close\r
+%Content-Length: %CONTENT_LENGTH\r
+Sozu-Id: %REQUEST_ID\r
+\r
+<style>pre{background:#EEE;padding:10px;border:1px solid #AAA;border-radius: 5px;}</style>
+<h1>403 Forbidden</h1>
+<pre>
+{
+    \"route\": \"%ROUTE\",
+    \"request_id\": \"%REQUEST_ID\",
+    \"cluster_id\": \"%CLUSTER_ID\",
+}
+</pre>
+<p>Diagnostic: %MESSAGE</p>
+<footer>This is an automatic answer by Sozu.</footer>",
+    )
+}
+
 fn default_404() -> String {
     String::from(
         "\
@@ -664,6 +689,11 @@ impl HttpAnswers {
                 answer,
                 &[length, route, request_id]
             ),
+            403 => Template::new(
+                403,
+                answer,
+                &[length, route, request_id, cluster_id, message],
+            ),
             404 => Template::new(
                 404,
                 answer,
@@ -725,6 +755,12 @@ impl HttpAnswers {
                         .and_then(|c| c.answer_401.clone())
                         .unwrap_or(default_401()),
                 )?,
+                answer_403: Self::template(
+                    403,
+                    conf.as_ref()
+                        .and_then(|c| c.answer_403.clone())
+                        .unwrap_or(default_403()),
+                )?,
                 answer_404: Self::template(
                     404,
                     conf.as_ref()
@@ -817,6 +853,15 @@ impl HttpAnswers {
                 variables_once = vec![];
                 &self.listener_answers.answer_401
             }
+            DefaultAnswer::Answer403 { message } => {
+                variables = vec![
+                    route.into(),
+                    request_id.into(),
+                    cluster_id.unwrap_or_default().into(),
+                ];
+                variables_once = vec![message.into()];
+                &self.listener_answers.answer_403
+            }
             DefaultAnswer::Answer404 {} => {
                 variables = vec![route.into(), request_id.into()];
                 variables_once = vec![];
@@ -902,3 +947,33 @@ impl HttpAnswers {
         template.fill(&variables, &mut variables_once)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_answer_body_size() {
+        let answers = HttpAnswers::new(&None).expect("the default answers should be valid");
+        let mut kawa = answers.get(
+            DefaultAnswer::Answer404 {},
+            "request".to_owned(),
+            None,
+            None,
+            "/".to_owned(),
+        );
+        kawa.prepare(&mut kawa::h1::BlockConverter);
+        let raw: Vec<u8> = kawa
+            .as_io_slice()
+            .iter()
+            .flat_map(|slice| slice.iter().copied())
+            .collect();
+        let header_end = raw
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("the answer should have headers")
+            + 4;
+
+        assert_eq!(kawa.body_size, BodySize::Length(raw.len() - header_end));
+    }
+}
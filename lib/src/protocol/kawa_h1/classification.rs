@@ -0,0 +1,161 @@
+//! Response-based outlier detection
+//!
+//! If a cluster has a response classification, each response of its backends is
+//! classified as healthy or failed. Failed responses count like connection errors
+//! in the retry policy of the backend, which is ejected from the load balancing
+//! after enough failures in a row.
+
+use std::{cell::RefCell, rc::Rc};
+
+use sozu_command::proto::command::{Event, EventKind, ResponseClassification};
+
+use crate::{
+    protocol::http::{parser::compare_no_case, GenericHttpStream},
+    retry::RetryPolicy,
+    server::push_event,
+    socket::SocketHandler,
+    L7ListenerHandler, L7Proxy, ListenerHandler, SessionMetrics,
+};
+
+use super::{Http, ResponseStream};
+
+/// returns true if a response with this status and these headers counts as a failure of the backend
+pub fn is_failure<'a>(
+    classification: &ResponseClassification,
+    status: u16,
+    mut headers: impl Iterator<Item = (&'a [u8], &'a [u8])>,
+) -> bool {
+    let status = status as u32;
+    if classification.failure_statuses.contains(&status) {
+        return true;
+    }
+    if status >= 500 && !classification.healthy_statuses.contains(&status) {
+        return true;
+    }
+
+    headers.any(|(key, val)| {
+        classification.failure_headers.iter().any(|(name, value)| {
+            compare_no_case(key, name.as_bytes()) && compare_no_case(val, value.as_bytes())
+        })
+    })
+}
+
+fn response_headers(response: &GenericHttpStream) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let buf = response.storage.buffer();
+    response.blocks.iter().filter_map(move |block| match block {
+        kawa::Block::Header(header) if !header.is_elided() => {
+            Some((header.key.data(buf), header.val.data(buf)))
+        }
+        _ => None,
+    })
+}
+
+impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L> {
+    /// Responses are classified only on clusters with a response classification
+    pub(super) fn prepare_response_classification(
+        &mut self,
+        cluster_id: &str,
+        proxy: &Rc<RefCell<dyn L7Proxy>>,
+    ) {
+        self.response_classification = proxy
+            .borrow()
+            .clusters()
+            .get(cluster_id)
+            .and_then(|cluster| cluster.response_classification.clone());
+    }
+
+    /// Updates the retry policy of the backend once the response headers are parsed
+    pub(super) fn classify_response(&mut self, metrics: &SessionMetrics) {
+        let (classification, status) = match (&self.response_classification, self.context.status) {
+            (Some(classification), Some(status)) => (classification, status),
+            _ => return,
+        };
+        let response_stream = match &self.response_stream {
+            ResponseStream::BackendAnswer(response_stream) => response_stream,
+            _ => return,
+        };
+
+        if is_failure(classification, status, response_headers(response_stream)) {
+            incr!(
+                "backend.response.failure",
+                self.context.cluster_id.as_deref(),
+                metrics.backend_id.as_deref()
+            );
+            self.record_backend_failure();
+        } else if let Some(backend) = &self.backend {
+            let mut backend = backend.borrow_mut();
+            if !backend.retry_policy.is_down() {
+                backend.failures = 0;
+                backend.retry_policy.succeed();
+            }
+        }
+    }
+
+    /// counts a failure in the retry policy of the backend, and signals if it goes down
+    pub(super) fn record_backend_failure(&mut self) {
+        let backend = match &self.backend {
+            Some(backend) => backend,
+            None => return,
+        };
+        let mut backend = backend.borrow_mut();
+        backend.failures += 1;
+        backend.penalize_response_time();
+
+        let already_unavailable = backend.retry_policy.is_down();
+        backend.retry_policy.fail();
+
+        if !already_unavailable && backend.retry_policy.is_down() {
+            error!(
+                "{} backend server {} at {} is down",
+                self.context.log_context(),
+                backend.backend_id,
+                backend.address
+            );
+
+            incr!(
+                "backend.down",
+                self.context.cluster_id.as_deref(),
+                Some(backend.backend_id.as_str())
+            );
+
+            push_event(Event {
+                kind: EventKind::BackendDown as i32,
+                backend_id: Some(backend.backend_id.to_owned()),
+                address: Some(backend.address.into()),
+                cluster_id: None,
+                crash_report: None,
+                tags: self.listener.borrow().get_event_tags(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_responses() {
+        let classification = ResponseClassification {
+            failure_statuses: vec![429],
+            healthy_statuses: vec![503],
+            failure_headers: [("X-Backend-Overloaded".to_owned(), "true".to_owned())].into(),
+        };
+        let no_headers = std::iter::empty;
+
+        assert!(!is_failure(&classification, 200, no_headers()));
+        assert!(!is_failure(&classification, 404, no_headers()));
+        assert!(is_failure(&classification, 429, no_headers()));
+        assert!(is_failure(&classification, 500, no_headers()));
+        assert!(is_failure(&classification, 502, no_headers()));
+        assert!(!is_failure(&classification, 503, no_headers()));
+
+        let headers: &[(&[u8], &[u8])] = &[
+            (b"Content-Length", b"0"),
+            (b"x-backend-overloaded", b"TRUE"),
+        ];
+        assert!(is_failure(&classification, 200, headers.iter().copied()));
+        let headers: &[(&[u8], &[u8])] = &[(b"X-Backend-Overloaded", b"false")];
+        assert!(!is_failure(&classification, 200, headers.iter().copied()));
+    }
+}
@@ -7,7 +7,11 @@ use rusty_ulid::Ulid;
 
 use crate::{
     pool::Checkout,
-    protocol::http::{parser::compare_no_case, GenericHttpStream, Method},
+    protocol::http::{
+        parser::compare_no_case,
+        trace::{DebugTrace, DEBUG_TRACE_HEADER},
+        GenericHttpStream, Method,
+    },
     Protocol,
 };
 
@@ -36,6 +40,12 @@ pub struct HttpContext {
     pub reason: Option<String>,
     // ---------- Additional optional data
     pub user_agent: Option<String>,
+    /// the values of the Upgrade headers in the request, comma separated
+    pub upgrade: Option<String>,
+    /// the size of the request line and headers, as received
+    pub request_header_size: usize,
+    /// the size of the status line and headers, as received
+    pub response_header_size: usize,
 
     // ========== Read only
     /// signals wether Kawa should write a "Connection" header with a "close" value (request and response)
@@ -55,13 +65,26 @@ pub struct HttpContext {
     /// the sticky session that should be used
     /// used to create a "Set-Cookie" header in the response in case it differs from sticky_session_found
     pub sticky_session: Option<String>,
+    /// reject the responses that break HTTP framing instead of correcting them
+    pub strict_responses: bool,
+    /// set if the response should carry the routing decision trace headers
+    pub debug_trace: Option<DebugTrace>,
+    /// the value of the Sozu-Debug-Trace header in the request, not forwarded to the backend
+    pub debug_trace_token: Option<String>,
 }
 
 impl kawa::h1::ParserCallbacks<Checkout> for HttpContext {
     fn on_headers(&mut self, stream: &mut GenericHttpStream) {
+        let header_size = stream.storage.head - stream.storage.start;
         match stream.kind {
-            kawa::Kind::Request => self.on_request_headers(stream),
-            kawa::Kind::Response => self.on_response_headers(stream),
+            kawa::Kind::Request => {
+                self.request_header_size = header_size;
+                self.on_request_headers(stream)
+            }
+            kawa::Kind::Response => {
+                self.response_header_size = header_size;
+                self.on_response_headers(stream)
+            }
         }
     }
 }
@@ -77,6 +100,7 @@ impl HttpContext {
     ///   - front keep-alive
     ///   - sticky cookie
     ///   - user-agent
+    ///   - upgrade
     fn on_request_headers(&mut self, request: &mut GenericHttpStream) {
         let buf = &mut request.storage.mut_buffer();
 
@@ -182,6 +206,25 @@ impl HttpContext {
                             .data_opt(buf)
                             .and_then(|data| from_utf8(data).ok())
                             .map(ToOwned::to_owned);
+                    } else if compare_no_case(key, b"Upgrade") {
+                        // repeated Upgrade headers are one comma separated list
+                        if let Some(data) = header.val.data_opt(buf) {
+                            let protocols = String::from_utf8_lossy(data);
+                            match &mut self.upgrade {
+                                Some(upgrade) => {
+                                    upgrade.push_str(", ");
+                                    upgrade.push_str(&protocols);
+                                }
+                                None => self.upgrade = Some(protocols.into_owned()),
+                            }
+                        }
+                    } else if compare_no_case(key, DEBUG_TRACE_HEADER) {
+                        self.debug_trace_token = header
+                            .val
+                            .data_opt(buf)
+                            .and_then(|data| from_utf8(data).ok())
+                            .map(ToOwned::to_owned);
+                        header.elide();
                     }
                 }
                 _ => {}
@@ -290,6 +333,11 @@ impl HttpContext {
     ///   - reason
     ///   - back keep-alive
     fn on_response_headers(&mut self, response: &mut GenericHttpStream) {
+        self.enforce_response_framing(response);
+        if response.is_error() {
+            return;
+        }
+
         let buf = &mut response.storage.mut_buffer();
 
         // Captures the response line
@@ -344,6 +392,92 @@ impl HttpContext {
             key: kawa::Store::Static(b"Sozu-Id"),
             val: kawa::Store::from_string(self.id.to_string()),
         }));
+
+        // Describe the routing decision if the request is traced
+        if let Some(debug_trace) = &self.debug_trace {
+            for (key, val) in
+                debug_trace.headers(self.cluster_id.as_deref(), self.backend_id.as_deref())
+            {
+                response.push_block(kawa::Block::Header(kawa::Pair {
+                    key: kawa::Store::Static(key),
+                    val: kawa::Store::from_string(val),
+                }));
+            }
+        }
+    }
+
+    /// Corrects the parts of a response that break HTTP framing, or rejects the
+    /// response if the listener is strict. Each violation is counted
+    fn enforce_response_framing(&mut self, response: &mut GenericHttpStream) {
+        let status = match response.detached.status_line {
+            kawa::StatusLine::Response { code, .. } => code,
+            _ => return,
+        };
+        let informational = (100..200).contains(&status);
+        let no_length = informational || status == 204;
+        let no_body = no_length || status == 304 || self.method == Some(Method::Head);
+
+        // bytes received with the headers of a response without body are a body,
+        // the headers of an informational response are followed by the final one
+        if no_body && !informational && response.storage.end > response.storage.head {
+            incr!("http.backend_compliance.unexpected_body");
+            if self.strict_responses {
+                return reject_response(response, "Body in a response that cannot have one");
+            }
+            response.storage.end = response.storage.head;
+            // the rest of the body may still be on the way
+            self.keep_alive_backend = false;
+        }
+
+        let buf = response.storage.buffer();
+        let mut folded_cookies = false;
+        let mut violation = None;
+        for block in &mut response.blocks {
+            let kawa::Block::Header(header) = block else {
+                continue;
+            };
+            if header.is_elided() {
+                continue;
+            }
+            let key = header.key.data(buf);
+            if no_length
+                && (compare_no_case(key, b"content-length")
+                    || compare_no_case(key, b"transfer-encoding"))
+            {
+                incr!("http.backend_compliance.unexpected_length");
+                violation = Some("Length header in a response without body");
+                header.elide();
+            } else if compare_no_case(key, b"set-cookie")
+                && split_folded_cookies(header.val.data(buf)).len() > 1
+            {
+                incr!("http.backend_compliance.folded_set_cookie");
+                violation = Some("Several cookies in a Set-Cookie header");
+                folded_cookies = true;
+            }
+        }
+        if let (Some(message), true) = (violation, self.strict_responses) {
+            return reject_response(response, message);
+        }
+
+        if folded_cookies {
+            for block in std::mem::take(&mut response.blocks) {
+                match block {
+                    kawa::Block::Header(header)
+                        if !header.is_elided()
+                            && matches!(header.val, kawa::Store::Slice(_))
+                            && compare_no_case(header.key.data(buf), b"set-cookie") =>
+                    {
+                        for cookie in split_folded_cookies(header.val.data(buf)) {
+                            response.blocks.push_back(kawa::Block::Header(kawa::Pair {
+                                key: kawa::Store::Static(b"Set-Cookie"),
+                                val: kawa::Store::new_slice(buf, cookie),
+                            }));
+                        }
+                    }
+                    block => response.blocks.push_back(block),
+                }
+            }
+        }
     }
 
     pub fn reset(&mut self) {
@@ -356,6 +490,11 @@ impl HttpContext {
         self.status = None;
         self.reason = None;
         self.user_agent = None;
+        self.upgrade = None;
+        self.request_header_size = 0;
+        self.response_header_size = 0;
+        self.debug_trace = None;
+        self.debug_trace_token = None;
     }
 
     pub fn log_context(&self) -> LogContext {
@@ -366,3 +505,146 @@ impl HttpContext {
         }
     }
 }
+
+/// answers a 502 to a response that breaks HTTP framing
+fn reject_response(response: &mut GenericHttpStream, message: &'static str) {
+    incr!("http.backend_compliance.rejected");
+    response.parsing_phase.error(message.into());
+}
+
+/// Splits a Set-Cookie value holding several cookies folded with commas. A comma
+/// starts a new cookie only if a cookie name and "=" follow it, which is never
+/// the case of the comma in an Expires date
+fn split_folded_cookies(value: &[u8]) -> Vec<&[u8]> {
+    let mut cookies = Vec::new();
+    let mut start = 0;
+    for (index, byte) in value.iter().enumerate() {
+        if *byte == b',' && starts_with_cookie_name(&value[index + 1..]) {
+            cookies.push(value[start..index].trim_ascii());
+            start = index + 1;
+        }
+    }
+    cookies.push(value[start..].trim_ascii());
+    cookies
+}
+
+fn starts_with_cookie_name(value: &[u8]) -> bool {
+    let value = value.trim_ascii_start();
+    match value.iter().position(|byte| *byte == b'=') {
+        None | Some(0) => false,
+        Some(end) => value[..end]
+            .iter()
+            .all(|byte| byte.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(byte)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::Pool;
+
+    fn context() -> HttpContext {
+        HttpContext {
+            keep_alive_backend: true,
+            keep_alive_frontend: true,
+            sticky_session_found: None,
+            method: None,
+            authority: None,
+            path: None,
+            status: None,
+            reason: None,
+            user_agent: None,
+            upgrade: None,
+            request_header_size: 0,
+            response_header_size: 0,
+            closing: false,
+            id: Ulid::generate(),
+            backend_id: None,
+            cluster_id: None,
+            protocol: Protocol::HTTP,
+            public_address: "127.0.0.1:8080".parse().unwrap(),
+            session_address: None,
+            sticky_name: "SOZUBALANCEID".to_owned(),
+            sticky_session: None,
+            strict_responses: false,
+            debug_trace: None,
+            debug_trace_token: None,
+        }
+    }
+
+    fn parse(pool: &mut Pool, kind: kawa::Kind, raw: &[u8], context: &mut HttpContext) {
+        let buffer = pool.checkout().expect("the pool should have a buffer");
+        let mut stream = GenericHttpStream::new(kind, kawa::Buffer::new(buffer));
+        stream.storage.space()[..raw.len()].copy_from_slice(raw);
+        stream.storage.fill(raw.len());
+        kawa::h1::parse(&mut stream, context);
+        assert!(stream.is_main_phase(), "{raw:?} should be parsed");
+    }
+
+    #[test]
+    fn header_sizes() {
+        let mut pool = Pool::with_capacity(1, 2, 1024);
+        let mut context = context();
+
+        let head = b"POST /upload HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\n";
+        parse(
+            &mut pool,
+            kawa::Kind::Request,
+            &[&head[..], b"hello"].concat(),
+            &mut context,
+        );
+        assert_eq!(context.request_header_size, head.len());
+
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n";
+        parse(
+            &mut pool,
+            kawa::Kind::Response,
+            &[&head[..], b"abc"].concat(),
+            &mut context,
+        );
+        assert_eq!(context.response_header_size, head.len());
+
+        context.reset();
+        assert_eq!(
+            (context.request_header_size, context.response_header_size),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn repeated_upgrade_headers() {
+        let mut pool = Pool::with_capacity(1, 1, 1024);
+        let mut context = context();
+
+        parse(
+            &mut pool,
+            kawa::Kind::Request,
+            b"GET /chat HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: h2c\r\nUpgrade: websocket\r\n\r\n",
+            &mut context,
+        );
+        assert_eq!(context.upgrade.as_deref(), Some("h2c, websocket"));
+    }
+
+    #[test]
+    fn folded_cookies() {
+        assert_eq!(
+            split_folded_cookies(b"a=1; Path=/"),
+            vec![&b"a=1; Path=/"[..]]
+        );
+        assert_eq!(
+            split_folded_cookies(b"a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Secure"),
+            vec![&b"a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Secure"[..]]
+        );
+        assert_eq!(
+            split_folded_cookies(b"a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT, b=2; Path=/"),
+            vec![
+                &b"a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT"[..],
+                &b"b=2; Path=/"[..]
+            ]
+        );
+        assert_eq!(
+            split_folded_cookies(b"a=1,b=2"),
+            vec![&b"a=1"[..], &b"b=2"[..]]
+        );
+    }
+}
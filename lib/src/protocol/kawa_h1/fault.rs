@@ -0,0 +1,147 @@
+//! Fault injection, for chaos experiments at the proxy layer
+//!
+//! A cluster can be configured at runtime to delay a percentage of its requests
+//! before connecting to a backend, and to answer them with an error instead of
+//! forwarding them. The fault is drawn once per request, on its first connection
+//! attempt. A delayed session is woken up once its deadline is reached.
+
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use sozu_command::{proto::command::FaultInjection, ready::Ready};
+
+use crate::{
+    server, socket::SocketHandler, BackendConnectionError, L7ListenerHandler, L7Proxy,
+    ListenerHandler,
+};
+
+use super::{DefaultAnswer, Http};
+
+/// The fault injected in the current request
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Fault {
+    /// the request is held until then before connecting to the backend
+    deadline: Option<Instant>,
+    /// answered once the delay expired, instead of forwarding the request
+    error_status: Option<u32>,
+}
+
+impl Fault {
+    /// Decides if a request receives a fault, and which one
+    pub fn draw<R: Rng>(config: &FaultInjection, rng: &mut R) -> Option<Fault> {
+        if config.percentage == 0 || rng.gen_range(0..100) >= config.percentage {
+            return None;
+        }
+
+        let jitter = match config.jitter {
+            Some(jitter) if jitter > 0 => rng.gen_range(0..=jitter),
+            _ => 0,
+        };
+        let delay = config.latency.unwrap_or(0) + jitter;
+
+        Some(Fault {
+            deadline: (delay > 0).then(|| Instant::now() + Duration::from_millis(delay as u64)),
+            error_status: config.error_status,
+        })
+    }
+}
+
+impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L> {
+    /// Holds the request while its injected latency runs, then answers the injected
+    /// error if there is one. Returns an error as long as the request must not be
+    /// forwarded to a backend.
+    pub(super) fn inject_fault(
+        &mut self,
+        cluster_id: &str,
+        proxy: &Rc<RefCell<dyn L7Proxy>>,
+    ) -> Result<(), BackendConnectionError> {
+        if self.fault.is_none() {
+            // retries and requests waiting for a saturated backend already had their chance
+            if self.connection_attempts > 0 || self.queued_since.is_some() {
+                return Ok(());
+            }
+            let config = proxy
+                .borrow()
+                .backends()
+                .borrow()
+                .fault_injection(cluster_id)
+                .cloned();
+            self.fault = config.and_then(|config| Fault::draw(&config, &mut rand::thread_rng()));
+            if self.fault.is_none() {
+                return Ok(());
+            }
+            incr!("http.fault_injection.injected", Some(cluster_id), None);
+        }
+
+        if let Some(deadline) = self.fault.as_ref().and_then(|fault| fault.deadline) {
+            if Instant::now() < deadline {
+                // a connection to the backend kept alive must not receive the request yet
+                self.backend_readiness.interest.remove(Ready::WRITABLE);
+                server::wake_up_at(self.frontend_token, deadline);
+                return Err(BackendConnectionError::FaultInjected(cluster_id.to_owned()));
+            }
+            self.backend_readiness.interest.insert(Ready::WRITABLE);
+        }
+
+        let error_status = self.fault.take().and_then(|fault| fault.error_status);
+        let answer = match error_status {
+            Some(502) => DefaultAnswer::Answer502 {
+                message: "Fault injected by the proxy".into(),
+                phase: self.request_stream.parsing_phase.marker(),
+                details: format!("fault injection is enabled on cluster {cluster_id}"),
+            },
+            Some(504) => DefaultAnswer::Answer504 {
+                duration: self.container_backend_timeout.to_string(),
+            },
+            Some(_) => DefaultAnswer::Answer503 {
+                message: format!("Fault injected by the proxy on cluster {cluster_id}"),
+            },
+            None => return Ok(()),
+        };
+        incr!("http.fault_injection.errors", Some(cluster_id), None);
+        self.set_answer(answer);
+        Err(BackendConnectionError::FaultInjected(cluster_id.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(percentage: u32) -> FaultInjection {
+        FaultInjection {
+            cluster_id: "cluster_1".to_owned(),
+            percentage,
+            latency: Some(100),
+            jitter: Some(50),
+            error_status: Some(503),
+        }
+    }
+
+    #[test]
+    fn draw_faults() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            assert!(Fault::draw(&config(0), &mut rng).is_none());
+
+            let before = Instant::now();
+            let fault = Fault::draw(&config(100), &mut rng).expect("every request gets a fault");
+            let deadline = fault.deadline.expect("the fault has a latency");
+            assert!(deadline >= before + Duration::from_millis(100));
+            assert!(deadline <= Instant::now() + Duration::from_millis(150));
+            assert_eq!(fault.error_status, Some(503));
+        }
+
+        let error_only = FaultInjection {
+            latency: None,
+            jitter: None,
+            ..config(100)
+        };
+        let fault = Fault::draw(&error_only, &mut rng).expect("every request gets a fault");
+        assert!(fault.deadline.is_none());
+    }
+}
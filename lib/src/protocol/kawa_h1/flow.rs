@@ -0,0 +1,138 @@
+//! Flow control of the bodies streamed between a client and a backend
+//!
+//! Each direction has a valve on the buffer it fills: when the data waiting to be
+//! written reaches the high watermark of the cluster, Sōzu stops reading from the
+//! sending side, and resumes once the receiving side drained the buffer under the
+//! low watermark. Watermarks are percentages of the buffer capacity.
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{socket::SocketHandler, L7ListenerHandler, L7Proxy, ListenerHandler};
+
+use super::{GenericHttpStream, Http};
+
+/// if the cluster only sets a high watermark, the low watermark is the high one divided by this
+const DEFAULT_LOW_WATERMARK_RATIO: u32 = 2;
+
+/// Pauses the reads filling a buffer while it holds too much data not yet written
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Valve {
+    /// in percent of the buffer capacity, reads never pause if unset
+    high: Option<u32>,
+    /// in percent of the buffer capacity
+    low: u32,
+    paused: bool,
+}
+
+impl Valve {
+    pub fn new(high: Option<u32>, low: Option<u32>) -> Self {
+        let high = high.filter(|high| *high < 100);
+        let low = match (high, low) {
+            (Some(high), Some(low)) => low.min(high),
+            (Some(high), None) => high / DEFAULT_LOW_WATERMARK_RATIO,
+            (None, _) => 0,
+        };
+        Self {
+            high,
+            low,
+            paused: false,
+        }
+    }
+
+    /// Updates the valve with the data waiting in the stream buffer,
+    /// returns true if reads can go on
+    pub fn update(&mut self, stream: &GenericHttpStream) -> bool {
+        let Some(high) = self.high else {
+            return true;
+        };
+        let capacity = stream.storage.capacity().max(1);
+        let pending = (stream.storage.available_data() * 100 / capacity) as u32;
+
+        if self.paused && pending <= self.low {
+            self.paused = false;
+            incr!("http.flow.resumed");
+        } else if !self.paused && pending >= high {
+            self.paused = true;
+            incr!("http.flow.paused");
+        }
+        !self.paused
+    }
+}
+
+/// The valves of both directions of the current request
+#[derive(Debug, Default)]
+pub struct FlowControl {
+    /// on the request buffer, pauses reads from the client
+    pub request: Valve,
+    /// on the response buffer, pauses reads from the backend
+    pub response: Valve,
+}
+
+/// Moves the data waiting in a full buffer to its start, to make room for reads instead
+/// of waiting for the buffer to drain completely. Returns false if there is nothing to
+/// move, or if parsed blocks still reference the buffer.
+pub fn compact(stream: &mut GenericHttpStream) -> bool {
+    if stream.storage.start == 0 || !stream.blocks.is_empty() || !stream.detached.jar.is_empty() {
+        return false;
+    }
+    let amount = stream.storage.shift() as u32;
+    stream.push_left(amount);
+    incr!("http.flow.compacted");
+    true
+}
+
+impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L> {
+    /// Sets the watermarks of the cluster handling the request
+    pub(super) fn prepare_flow_control(
+        &mut self,
+        cluster_id: &str,
+        proxy: &Rc<RefCell<dyn L7Proxy>>,
+    ) {
+        let (high, low) = proxy
+            .borrow()
+            .clusters()
+            .get(cluster_id)
+            .map(|cluster| (cluster.stream_high_watermark, cluster.stream_low_watermark))
+            .unwrap_or_default();
+
+        self.flow_control = FlowControl {
+            request: Valve::new(high, low),
+            response: Valve::new(high, low),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::Pool;
+
+    #[test]
+    fn valve_hysteresis() {
+        let mut valve = Valve::new(Some(75), Some(25));
+        let mut pool = Pool::with_capacity(1, 1, 100);
+        let buffer = pool.checkout().expect("the pool should have a buffer");
+        let mut stream = GenericHttpStream::new(kawa::Kind::Response, kawa::Buffer::new(buffer));
+
+        stream.storage.fill(50);
+        assert!(valve.update(&stream));
+        stream.storage.fill(30);
+        assert!(!valve.update(&stream));
+
+        // still above the low watermark
+        stream.storage.consume(40);
+        assert!(!valve.update(&stream));
+        stream.storage.consume(20);
+        assert!(valve.update(&stream));
+    }
+
+    #[test]
+    fn valve_without_watermark() {
+        let valve = Valve::new(None, Some(25));
+        assert!(valve.high.is_none());
+        let valve = Valve::new(Some(60), None);
+        assert_eq!(valve.low, 30);
+        let valve = Valve::new(Some(60), Some(80));
+        assert_eq!(valve.low, 60);
+    }
+}
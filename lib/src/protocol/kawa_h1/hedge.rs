@@ -0,0 +1,350 @@
+//! Hedging of idempotent requests
+//!
+//! If a cluster has a hedge delay and a GET or HEAD request did not get a response
+//! within that delay, the same request is sent to another backend of the cluster.
+//! The first backend to answer serves the response, the other connection is closed.
+
+use std::{
+    cell::RefCell,
+    io::{ErrorKind, IoSlice},
+    net::Shutdown,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use mio::{net::TcpStream, Interest, Token};
+use sozu_command::ready::Ready;
+
+use crate::{
+    backends::Backend,
+    protocol::http::parser::Method,
+    server,
+    socket::{SocketHandler, SocketResult},
+    BackendConnectionStatus, L7ListenerHandler, L7Proxy, ListenerHandler, ProxySession, Readiness,
+    SessionMetrics,
+};
+
+use super::{Http, TimeoutStatus};
+
+/// The hedging state of the current request
+#[derive(Default)]
+pub struct Hedging {
+    /// delay after which the request is hedged, only set if the request can be hedged
+    delay: Option<Duration>,
+    /// copy of the request as it was written to the backend
+    request: Vec<u8>,
+    /// when the hedged request should be sent
+    deadline: Option<Instant>,
+    /// connection to the backend receiving the hedged request
+    hedge: Option<HedgedBackend>,
+}
+
+impl Hedging {
+    /// keeps a copy of the `size` bytes just written to the backend
+    pub fn capture(&mut self, bufs: &[IoSlice], size: usize) {
+        if self.delay.is_none() {
+            return;
+        }
+
+        let mut remaining = size;
+        for buf in bufs {
+            if remaining == 0 {
+                break;
+            }
+            let len = buf.len().min(remaining);
+            self.request.extend_from_slice(&buf[..len]);
+            remaining -= len;
+        }
+    }
+
+    /// starts the countdown once the whole request was written to the backend
+    pub fn arm(&mut self, token: Token) {
+        if let Some(delay) = self.delay {
+            if self.hedge.is_none() {
+                let deadline = Instant::now() + delay;
+                self.deadline = Some(deadline);
+                server::wake_up_at(token, deadline);
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = None;
+        self.request.clear();
+        self.deadline = None;
+    }
+
+    pub fn has_hedge(&self) -> bool {
+        self.hedge.is_some()
+    }
+
+    pub fn update_readiness(&mut self, token: Token, events: Ready) {
+        if let Some(hedge) = &mut self.hedge {
+            if hedge.token == token {
+                hedge.readiness.event |= events;
+            }
+        }
+    }
+}
+
+pub struct HedgedBackend {
+    backend: Rc<RefCell<Backend>>,
+    readiness: Readiness,
+    socket: TcpStream,
+    started: Instant,
+    token: Token,
+    /// how much of the request was written to the hedged backend
+    written: usize,
+}
+
+impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L> {
+    /// Hedging applies only to GET and HEAD requests without body, on clusters
+    /// with a hedge delay
+    pub(super) fn prepare_hedging(&mut self, cluster_id: &str, proxy: &Rc<RefCell<dyn L7Proxy>>) {
+        self.hedging.reset();
+
+        let idempotent = matches!(self.context.method, Some(Method::Get) | Some(Method::Head));
+        let without_body = matches!(
+            self.request_stream.body_size,
+            kawa::BodySize::Empty | kawa::BodySize::Length(0)
+        );
+        if !idempotent || !without_body {
+            return;
+        }
+
+        self.hedging.delay = proxy
+            .borrow()
+            .clusters()
+            .get(cluster_id)
+            .and_then(|cluster| cluster.hedge_delay)
+            .map(|delay| Duration::from_millis(delay as u64));
+    }
+
+    /// Sends the hedged request once the delay has expired, and checks which
+    /// backend answers first
+    pub(super) fn hedge_ready(
+        &mut self,
+        session: Rc<RefCell<dyn ProxySession>>,
+        proxy: Rc<RefCell<dyn L7Proxy>>,
+        metrics: &mut SessionMetrics,
+    ) {
+        if self.timeout_status() != TimeoutStatus::WaitingForResponse {
+            self.hedging.deadline = None;
+            return;
+        }
+
+        if let Some(deadline) = self.hedging.deadline {
+            // the session is woken up at the deadline
+            if Instant::now() < deadline {
+                return;
+            }
+            self.hedging.deadline = None;
+            self.send_hedge(session, proxy.clone());
+        }
+
+        let hedge = match &mut self.hedging.hedge {
+            Some(hedge) => hedge,
+            None => return,
+        };
+
+        if hedge.readiness.event.is_writable() && hedge.written < self.hedging.request.len() {
+            let (size, socket_state) = hedge
+                .socket
+                .socket_write(&self.hedging.request[hedge.written..]);
+            hedge.written += size;
+            count!("back_bytes_out", size as i64);
+            metrics.backend_bout += size;
+
+            match socket_state {
+                SocketResult::Error | SocketResult::Closed => {
+                    return self.fail_hedge(proxy);
+                }
+                SocketResult::WouldBlock => hedge.readiness.event.remove(Ready::WRITABLE),
+                SocketResult::Continue => {}
+            }
+        }
+
+        if hedge.readiness.event.is_readable() {
+            let mut tmp = [0u8; 1];
+            match hedge.socket.peek(&mut tmp[..]) {
+                Ok(0) => self.fail_hedge(proxy),
+                Ok(_) => self.switch_to_hedge(proxy, metrics),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    hedge.readiness.event.remove(Ready::READABLE)
+                }
+                Err(_) => self.fail_hedge(proxy),
+            }
+        } else if hedge.readiness.event.is_hup() || hedge.readiness.event.is_error() {
+            self.fail_hedge(proxy);
+        }
+    }
+
+    fn send_hedge(
+        &mut self,
+        session: Rc<RefCell<dyn ProxySession>>,
+        proxy: Rc<RefCell<dyn L7Proxy>>,
+    ) {
+        let (cluster_id, backend_id) = match (&self.context.cluster_id, &self.context.backend_id) {
+            (Some(cluster_id), Some(backend_id)) => (cluster_id.clone(), backend_id.clone()),
+            _ => return,
+        };
+
+        // hedged requests are extra load on the cluster, like retries
+        if !proxy
+            .borrow()
+            .backends()
+            .borrow_mut()
+            .can_retry(&cluster_id)
+        {
+            return;
+        }
+
+        let hedge_result = proxy
+            .borrow()
+            .backends()
+            .borrow_mut()
+            .hedge_backend_from_cluster_id(&cluster_id, &backend_id);
+        let (backend, mut socket) = match hedge_result {
+            Ok(backend_and_socket) => backend_and_socket,
+            Err(backend_error) => {
+                debug!(
+                    "{} Could not hedge the request: {}",
+                    self.context.log_context(),
+                    backend_error
+                );
+                return;
+            }
+        };
+
+        if let Err(e) = socket.set_nodelay(true) {
+            error!(
+                "{} Error setting nodelay on hedged backend socket({:?}): {:?}",
+                self.context.log_context(),
+                socket,
+                e
+            );
+        }
+
+        let token = proxy.borrow().add_session(session);
+        if let Err(e) = proxy.borrow().register_socket(
+            &mut socket,
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            error!(
+                "{} Error registering hedged backend socket({:?}): {:?}",
+                self.context.log_context(),
+                socket,
+                e
+            );
+        }
+
+        incr!(
+            "http.hedge.sent",
+            Some(cluster_id.as_str()),
+            Some(backend.borrow().backend_id.as_str())
+        );
+        backend.borrow_mut().active_requests += 1;
+
+        self.hedging.hedge = Some(HedgedBackend {
+            backend,
+            readiness: Readiness {
+                interest: Ready::READABLE | Ready::WRITABLE | Ready::HUP | Ready::ERROR,
+                event: Ready::EMPTY,
+            },
+            socket,
+            started: Instant::now(),
+            token,
+            written: 0,
+        });
+    }
+
+    /// The hedged backend answered first, it replaces the original backend
+    fn switch_to_hedge(&mut self, proxy: Rc<RefCell<dyn L7Proxy>>, metrics: &mut SessionMetrics) {
+        let hedge = match self.hedging.hedge.take() {
+            Some(hedge) => hedge,
+            None => return,
+        };
+
+        if let Some(backend) = &self.backend {
+            let mut backend = backend.borrow_mut();
+            backend.active_requests = backend.active_requests.saturating_sub(1);
+        }
+        self.close_backend(proxy, metrics);
+
+        let (backend_id, sticky_id) = {
+            let backend = hedge.backend.borrow();
+            (backend.backend_id.clone(), backend.sticky_id.clone())
+        };
+        incr!(
+            "http.hedge.won",
+            self.context.cluster_id.as_deref(),
+            Some(backend_id.as_str())
+        );
+
+        if self.context.sticky_session.is_some() {
+            self.context.sticky_session = Some(sticky_id.unwrap_or_else(|| backend_id.clone()));
+        }
+        metrics.backend_id = Some(backend_id.clone());
+        self.set_backend_id(backend_id);
+
+        // the request is counted again once the backend is marked as connected
+        {
+            let mut backend = hedge.backend.borrow_mut();
+            backend.active_requests = backend.active_requests.saturating_sub(1);
+        }
+
+        self.set_backend_token(hedge.token);
+        self.set_backend_socket(hedge.socket, Some(hedge.backend));
+        self.backend_readiness = hedge.readiness;
+        self.backend_readiness.interest = Ready::READABLE | Ready::HUP | Ready::ERROR;
+        self.backend_connection_status = BackendConnectionStatus::Connecting(hedge.started);
+        self.set_backend_connected(BackendConnectionStatus::Connected, metrics);
+    }
+
+    fn fail_hedge(&mut self, proxy: Rc<RefCell<dyn L7Proxy>>) {
+        if let Some(hedge) = &self.hedging.hedge {
+            let mut backend = hedge.backend.borrow_mut();
+            backend.penalize_response_time();
+            incr!(
+                "http.hedge.failed",
+                self.context.cluster_id.as_deref(),
+                Some(backend.backend_id.as_str())
+            );
+        }
+        self.close_hedge(proxy);
+    }
+
+    /// closes the connection to the backend receiving the hedged request
+    pub(super) fn close_hedge(&mut self, proxy: Rc<RefCell<dyn L7Proxy>>) {
+        let mut hedge = match self.hedging.hedge.take() {
+            Some(hedge) => hedge,
+            None => return,
+        };
+
+        let proxy = proxy.borrow();
+        if let Err(e) = proxy.deregister_socket(&mut hedge.socket) {
+            error!(
+                "{} Error deregistering hedged backend socket({:?}): {:?}",
+                self.context.log_context(),
+                hedge.socket,
+                e
+            );
+        }
+        if let Err(e) = hedge.socket.shutdown(Shutdown::Both) {
+            if e.kind() != ErrorKind::NotConnected {
+                error!(
+                    "{} Error shutting down hedged backend socket({:?}): {:?}",
+                    self.context.log_context(),
+                    hedge.socket,
+                    e
+                );
+            }
+        }
+        proxy.remove_session(hedge.token);
+        let cluster_id = self.context.cluster_id.as_deref().unwrap_or_default();
+        let mut backend = hedge.backend.borrow_mut();
+        backend.active_requests = backend.active_requests.saturating_sub(1);
+        backend.release_connection(cluster_id);
+    }
+}
@@ -1,7 +1,12 @@
 pub mod answers;
+pub mod classification;
 pub mod diagnostics;
 pub mod editor;
+pub mod fault;
+pub mod flow;
+pub mod hedge;
 pub mod parser;
+pub mod trace;
 
 use std::{
     cell::RefCell,
@@ -16,12 +21,12 @@ use rusty_ulid::Ulid;
 use sozu_command::{
     config::MAX_LOOP_ITERATIONS,
     logging::EndpointRecord,
-    proto::command::{Event, EventKind, ListenerType},
+    proto::command::{Event, EventKind, ListenerType, ResponseClassification},
 };
 // use time::{Duration, Instant};
 
 use crate::{
-    backends::{Backend, BackendError},
+    backends::{Backend, BackendError, ConnectionAttempt, ConnectionRace},
     pool::{Checkout, Pool},
     protocol::{
         http::{
@@ -35,8 +40,10 @@ use crate::{
     },
     retry::RetryPolicy,
     router::Route,
-    server::{push_event, CONN_RETRIES},
-    socket::{stats::socket_rtt, SocketHandler, SocketResult, TransportProtocol},
+    server::{self, push_event, CONN_RETRIES},
+    socket::{
+        canonical_address, stats::socket_rtt, SocketHandler, SocketResult, TransportProtocol,
+    },
     sozu_command::{logging::LogContext, ready::Ready},
     timer::TimeoutContainer,
     AcceptError, BackendConnectAction, BackendConnectionError, BackendConnectionStatus,
@@ -84,6 +91,9 @@ pub enum DefaultAnswer {
         details: String,
     },
     Answer401 {},
+    Answer403 {
+        message: String,
+    },
     Answer404 {},
     Answer408 {
         duration: String,
@@ -117,6 +127,7 @@ impl From<&DefaultAnswer> for u16 {
             DefaultAnswer::Answer301 { .. } => 301,
             DefaultAnswer::Answer400 { .. } => 400,
             DefaultAnswer::Answer401 { .. } => 401,
+            DefaultAnswer::Answer403 { .. } => 403,
             DefaultAnswer::Answer404 { .. } => 404,
             DefaultAnswer::Answer408 { .. } => 408,
             DefaultAnswer::Answer413 { .. } => 413,
@@ -157,11 +168,22 @@ pub struct Http<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> {
     configured_frontend_timeout: Duration,
     /// attempts to connect to the backends during the session
     connection_attempts: u8,
+    /// connection attempts to the other addresses of the backend
+    connection_race: Option<ConnectionRace>,
     pub frontend_readiness: Readiness,
     pub frontend_socket: Front,
     frontend_token: Token,
+    /// fault injected in the current request, until it is served
+    fault: Option<fault::Fault>,
+    /// pauses the reads of a body while the other side is slower
+    flow_control: flow::FlowControl,
+    hedging: hedge::Hedging,
+    /// which responses count as failures of the backend, from the cluster
+    response_classification: Option<ResponseClassification>,
     keepalive_count: usize,
     listener: Rc<RefCell<L>>,
+    /// set while the request waits for a saturated backend to free a connection
+    queued_since: Option<Instant>,
     pub request_stream: GenericHttpStream,
     pub response_stream: ResponseStream,
     /// The HTTP context was separated from the State for borrowing reasons.
@@ -205,6 +227,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             }
             None => return Err(AcceptError::BufferCapacityReached),
         };
+        let strict_responses = listener.borrow().get_strict_responses();
         Ok(Http {
             answers,
             backend_connection_status: BackendConnectionStatus::NotConnected,
@@ -217,6 +240,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             configured_connect_timeout,
             configured_frontend_timeout,
             connection_attempts: 0,
+            connection_race: None,
             container_backend_timeout: TimeoutContainer::new_empty(configured_connect_timeout),
             container_frontend_timeout,
             frontend_readiness: Readiness {
@@ -225,8 +249,13 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             },
             frontend_socket,
             frontend_token,
+            fault: None,
+            flow_control: flow::FlowControl::default(),
+            hedging: hedge::Hedging::default(),
+            response_classification: None,
             keepalive_count: 0,
             listener,
+            queued_since: None,
             request_stream: GenericHttpStream::new(
                 kawa::Kind::Request,
                 kawa::Buffer::new(front_buffer),
@@ -249,6 +278,12 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 sticky_name,
                 sticky_session: None,
                 sticky_session_found: None,
+                strict_responses,
+                debug_trace: None,
+                debug_trace_token: None,
+                upgrade: None,
+                request_header_size: 0,
+                response_header_size: 0,
 
                 method: None,
                 authority: None,
@@ -273,6 +308,8 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
         self.request_stream.clear();
         response_stream.clear();
+        self.flow_control = flow::FlowControl::default();
+        self.hedging.reset();
         self.keepalive_count += 1;
         gauge_add!("http.active_requests", -1);
 
@@ -340,7 +377,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             }
         };
 
-        if self.request_stream.storage.is_full() {
+        if self.request_stream.storage.is_full() && !flow::compact(&mut self.request_stream) {
             self.frontend_readiness.interest.remove(Ready::READABLE);
             if self.request_stream.is_main_phase() {
                 self.backend_readiness.interest.insert(Ready::WRITABLE);
@@ -407,6 +444,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
         kawa::h1::parse(&mut self.request_stream, &mut self.context);
         // kawa::debug_kawa(&self.request_stream);
+        metrics.request_header_size = self.context.request_header_size;
 
         if was_initial && !self.request_stream.is_initial() {
             // if it was the first request, the front timeout duration
@@ -457,6 +495,9 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
         if self.request_stream.is_main_phase() {
             self.backend_readiness.interest.insert(Ready::WRITABLE);
+            if !self.flow_control.request.update(&self.request_stream) {
+                self.frontend_readiness.interest.remove(Ready::READABLE);
+            }
             if was_not_proxying {
                 // Sozu tries to connect only once all the headers were gathered and edited
                 // this could be improved
@@ -494,7 +535,9 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             response_stream.consume(size);
             count!("bytes_out", size as i64);
             metrics.bout += size;
-            self.backend_readiness.interest.insert(Ready::READABLE);
+            if self.flow_control.response.update(response_stream) {
+                self.backend_readiness.interest.insert(Ready::READABLE);
+            }
         }
 
         match socket_state {
@@ -538,9 +581,15 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                     self.log_request_success(metrics);
                     return StateResult::Continue;
                 }
-                kawa::StatusLine::Response { code: 103, .. } => {
+                // 103 Early Hints, 102 Processing and the other interim responses
+                kawa::StatusLine::Response {
+                    code: 102..=199, ..
+                } => {
                     self.backend_readiness.event.insert(Ready::READABLE);
-                    trace!("{} ============== HANDLE EARLY HINT!", log_context!(self));
+                    trace!(
+                        "{} ============== HANDLE INTERIM RESPONSE!",
+                        log_context!(self)
+                    );
                     response_stream.clear();
                     self.log_request_success(metrics);
                     return StateResult::Continue;
@@ -610,6 +659,9 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             ResponseStream::DefaultAnswer(_, response_stream) => response_stream,
             _ => return StateResult::CloseSession,
         };
+        if let kawa::BodySize::Length(size) = response_stream.body_size {
+            metrics.default_answer_body_size = Some(size);
+        }
         let bufs = response_stream.as_io_slice();
         let (size, socket_state) = self.frontend_socket.socket_write_vectored(&bufs);
 
@@ -672,10 +724,13 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         debug!("{} Wrote {} bytes", log_context!(self), size);
 
         if size > 0 {
+            self.hedging.capture(&bufs, size);
             self.request_stream.consume(size);
             count!("back_bytes_out", size as i64);
             metrics.backend_bout += size;
-            self.frontend_readiness.interest.insert(Ready::READABLE);
+            if self.flow_control.request.update(&self.request_stream) {
+                self.frontend_readiness.interest.insert(Ready::READABLE);
+            }
             self.backend_readiness.interest.insert(Ready::READABLE);
         } else {
             self.backend_readiness.event.remove(Ready::WRITABLE);
@@ -708,6 +763,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             // cancel the front timeout while we are waiting for the server to answer
             self.container_frontend_timeout.cancel();
             self.container_backend_timeout.reset();
+            self.hedging.arm(self.frontend_token);
         }
         SessionResult::Continue
     }
@@ -745,7 +801,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             return SessionResult::Close;
         };
 
-        if response_stream.storage.is_full() {
+        if response_stream.storage.is_full() && !flow::compact(response_stream) {
             self.backend_readiness.interest.remove(Ready::READABLE);
             if response_stream.is_main_phase() {
                 self.frontend_readiness.interest.insert(Ready::WRITABLE);
@@ -809,8 +865,10 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             "{} ============== backend_readable_parse",
             log_context!(self)
         );
+        let was_main_phase = response_stream.is_main_phase();
         kawa::h1::parse(response_stream, &mut self.context);
         // kawa::debug_kawa(&self.response_stream);
+        metrics.response_header_size = self.context.response_header_size;
 
         if let kawa::ParsingPhase::Error { marker, kind } = response_stream.parsing_phase {
             incr!("http.backend_parse_errors");
@@ -847,14 +905,26 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             }
         }
 
+        let headers_parsed = !was_main_phase && response_stream.is_main_phase();
         if response_stream.is_main_phase() {
             self.frontend_readiness.interest.insert(Ready::WRITABLE);
+            if !self.flow_control.response.update(response_stream) {
+                self.backend_readiness.interest.remove(Ready::READABLE);
+            }
         }
         if response_stream.is_terminated() {
             metrics.backend_stop();
             self.backend_stop = Some(Instant::now());
+            if let (Some(backend), Some(response_time)) =
+                (&self.backend, metrics.backend_response_time())
+            {
+                backend.borrow_mut().set_response_time(response_time);
+            }
             self.backend_readiness.interest.remove(Ready::READABLE);
         }
+        if headers_parsed {
+            self.classify_response(metrics);
+        }
         SessionResult::Continue
     }
 }
@@ -874,6 +944,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         self.context
             .session_address
             .or_else(|| self.frontend_socket.socket_ref().peer_addr().ok())
+            .map(canonical_address)
     }
 
     pub fn get_backend_address(&self) -> Option<SocketAddr> {
@@ -917,16 +988,19 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
     pub fn log_request(&self, metrics: &SessionMetrics, error: bool, message: Option<&str>) {
         let listener = self.listener.borrow();
-        let tags = self.context.authority.as_ref().and_then(|host| {
-            let hostname = match host.split_once(':') {
-                None => host,
-                Some((hostname, _)) => hostname,
-            };
-            listener.get_tags(hostname)
-        });
+        let tags = match self.context.authority.as_ref() {
+            Some(host) => {
+                let hostname = match host.split_once(':') {
+                    None => host,
+                    Some((hostname, _)) => hostname,
+                };
+                listener.get_tags(hostname)
+            }
+            None => listener.get_default_tags(),
+        };
 
         let context = self.context.log_context();
-        metrics.register_end_of_session(&context);
+        metrics.register_end_of_session(&context, tags);
 
         log_access! {
             error,
@@ -988,6 +1062,11 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                     self.context.cluster_id.as_deref(),
                     self.context.backend_id.as_deref()
                 ),
+                DefaultAnswer::Answer403 { .. } => incr!(
+                    "http.403.errors",
+                    self.context.cluster_id.as_deref(),
+                    self.context.backend_id.as_deref()
+                ),
                 DefaultAnswer::Answer404 { .. } => incr!("http.404.errors"),
                 DefaultAnswer::Answer408 { .. } => incr!(
                     "http.408.errors",
@@ -1029,6 +1108,15 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             self.context.backend_id.as_deref(),
             self.get_route(),
         );
+        if let Some(debug_trace) = &self.context.debug_trace {
+            trace::add_headers(
+                &mut kawa,
+                debug_trace.headers(
+                    self.context.cluster_id.as_deref(),
+                    self.context.backend_id.as_deref(),
+                ),
+            );
+        }
         kawa.prepare(&mut kawa::h1::BlockConverter);
         self.context.status = Some(status);
         self.context.reason = None;
@@ -1108,6 +1196,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
     /// I don't think this is a good idea, but it is a quick fix
     fn close_backend(&mut self, proxy: Rc<RefCell<dyn L7Proxy>>, metrics: &mut SessionMetrics) {
         self.container_backend_timeout.cancel();
+        self.close_connection_race(&proxy);
         debug!(
             "{}\tPROXY [{}->{}] CLOSED BACKEND",
             log_context!(self),
@@ -1159,7 +1248,8 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             self.set_backend_connected(BackendConnectionStatus::NotConnected, metrics);
 
             if let Some(backend) = self.backend.take() {
-                backend.borrow_mut().dec_connections();
+                let cluster_id = self.context.cluster_id.as_deref().unwrap_or_default();
+                backend.borrow_mut().release_connection(cluster_id);
             }
         }
     }
@@ -1277,7 +1367,10 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 .borrow()
                 .clusters()
                 .get(&cluster_id)
-                .map(|cluster| cluster.https_redirect)
+                .map(|cluster| {
+                    cluster.https_redirect
+                        && !is_exempt_from_https_redirect(&cluster.https_redirect_exemptions, uri)
+                })
                 .unwrap_or(false);
 
         if frontend_should_redirect_https {
@@ -1287,6 +1380,24 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             return Err(RetrieveClusterError::UnauthorizedRoute);
         }
 
+        if let Some(upgrade) = self.context.upgrade.as_deref() {
+            let denied = proxy
+                .borrow()
+                .clusters()
+                .get(&cluster_id)
+                .and_then(|cluster| denied_upgrade(&cluster.allowed_upgrades, upgrade))
+                .map(ToOwned::to_owned);
+            if let Some(protocol) = denied {
+                incr!("http.upgrade.denied", Some(&cluster_id), None);
+                self.set_answer(DefaultAnswer::Answer403 {
+                    message: format!(
+                        "upgrade to {protocol} is not allowed on cluster {cluster_id}"
+                    ),
+                });
+                return Err(RetrieveClusterError::UpgradeNotAllowed(protocol));
+            }
+        }
+
         Ok(cluster_id)
     }
 
@@ -1305,6 +1416,30 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 proxy,
             )
             .map_err(|backend_error| {
+                if let BackendError::Saturated {
+                    queue_timeout: Some(queue_timeout),
+                    ..
+                } = backend_error
+                {
+                    let queued_since = match self.queued_since {
+                        Some(queued_since) => queued_since,
+                        None => {
+                            incr!("backend.queue.waiting", Some(cluster_id), None);
+                            let queued_since = *self.queued_since.insert(Instant::now());
+                            // to time out if no connection is released
+                            server::wake_up_at(self.frontend_token, queued_since + queue_timeout);
+                            queued_since
+                        }
+                    };
+
+                    if queued_since.elapsed() < queue_timeout {
+                        server::queue_for_backend(cluster_id, self.frontend_token);
+                        return BackendConnectionError::Backend(backend_error);
+                    }
+                    incr!("backend.queue.timeout", Some(cluster_id), None);
+                }
+                self.queued_since = None;
+
                 // some backend errors are actually retryable
                 // TODO: maybe retry or return a different default answer
                 self.set_answer(DefaultAnswer::Answer503 {
@@ -1312,6 +1447,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 });
                 BackendConnectionError::Backend(backend_error)
             })?;
+        self.queued_since = None;
 
         if frontend_should_stick {
             // update sticky name in case it changed I guess?
@@ -1364,12 +1500,19 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
         let old_cluster_id = self.context.cluster_id.clone();
         let old_backend_token = self.backend_token;
 
+        self.prepare_debug_trace();
         self.check_circuit_breaker()?;
 
         let cluster_id = self
             .cluster_id_from_request(proxy.clone())
             .map_err(BackendConnectionError::RetrieveClusterError)?;
 
+        self.inject_fault(&cluster_id, &proxy)?;
+
+        self.prepare_hedging(&cluster_id, &proxy);
+        self.prepare_flow_control(&cluster_id, &proxy);
+        self.prepare_response_classification(&cluster_id, &proxy);
+
         trace!(
             "{} Connect_to_backend: {:?} {:?} {:?}",
             log_context!(self),
@@ -1418,6 +1561,26 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
             .map(|cluster| cluster.sticky_session)
             .unwrap_or(false);
 
+        if self.connection_attempts == 0 {
+            if self.queued_since.is_none() {
+                proxy
+                    .borrow()
+                    .backends()
+                    .borrow_mut()
+                    .record_request(&cluster_id);
+            }
+        } else if !proxy
+            .borrow()
+            .backends()
+            .borrow_mut()
+            .can_retry(&cluster_id)
+        {
+            self.set_answer(DefaultAnswer::Answer503 {
+                message: format!("The retry budget of cluster {cluster_id} is exhausted"),
+            });
+            return Err(BackendConnectionError::RetryBudgetExhausted(cluster_id));
+        }
+
         let mut socket =
             self.backend_from_request(&cluster_id, frontend_should_stick, proxy.clone(), metrics)?;
         if let Err(e) = socket.set_nodelay(true) {
@@ -1450,6 +1613,7 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
 
                 self.set_backend_socket(socket, self.backend.clone());
                 self.set_backend_timeout(self.configured_connect_timeout);
+                self.start_connection_race();
 
                 Ok(BackendConnectAction::Replace)
             }
@@ -1472,12 +1636,159 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 self.set_backend_socket(socket, self.backend.clone());
                 self.set_backend_token(backend_token);
                 self.set_backend_timeout(self.configured_connect_timeout);
+                self.start_connection_race();
 
                 Ok(BackendConnectAction::New)
             }
         }
     }
 
+    /// if the backend has other addresses, they will be tried if this connection
+    /// takes too long to be established
+    fn start_connection_race(&mut self) {
+        self.connection_race = self
+            .backend
+            .as_ref()
+            .and_then(|backend| ConnectionRace::new(&backend.borrow()));
+
+        if let Some(connection_race) = &self.connection_race {
+            server::wake_up_at(self.frontend_token, connection_race.next_attempt());
+        }
+    }
+
+    /// Staggered connection attempts to the other addresses of the backend,
+    /// the first socket to connect becomes the backend socket
+    fn race_backend_connection(
+        &mut self,
+        session: Rc<RefCell<dyn ProxySession>>,
+        proxy: Rc<RefCell<dyn L7Proxy>>,
+    ) {
+        if !self.backend_connection_status.is_connecting() {
+            return self.close_connection_race(&proxy);
+        }
+
+        let first_failed = self.backend_readiness.event.is_hup() && !self.test_backend_socket();
+        if !first_failed && !self.backend_readiness.event.is_empty() {
+            // the first connection attempt won
+            return self.close_connection_race(&proxy);
+        }
+
+        let connection_race = match &mut self.connection_race {
+            Some(connection_race) => connection_race,
+            None => return,
+        };
+
+        for attempt in connection_race.take_failed() {
+            close_racing_socket(&proxy, attempt.socket, attempt.token);
+        }
+
+        if let Some(attempt) = connection_race.take_connected() {
+            return self.switch_to_connection_attempt(attempt, &proxy);
+        }
+
+        if first_failed {
+            connection_race.first_failed = true;
+            connection_race.hurry();
+            // the other attempts may still connect, do not retry yet
+            self.backend_readiness.event = Ready::EMPTY;
+        }
+
+        while let Some(address) = connection_race.next_address() {
+            let mut socket = match TcpStream::connect(address) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    debug!(
+                        "{} Could not connect to backend address {}: {}",
+                        log_context!(self),
+                        address,
+                        e
+                    );
+                    connection_race.hurry();
+                    continue;
+                }
+            };
+            if let Err(e) = socket.set_nodelay(true) {
+                error!(
+                    "{} Error setting nodelay on back socket({:?}): {:?}",
+                    log_context!(self),
+                    socket,
+                    e
+                );
+            }
+
+            let token = proxy.borrow().add_session(session.clone());
+            if let Err(e) = proxy.borrow().register_socket(
+                &mut socket,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            ) {
+                error!(
+                    "{} Error registering back socket({:?}): {:?}",
+                    log_context!(self),
+                    socket,
+                    e
+                );
+            }
+
+            incr!(
+                "backend.connections.staggered",
+                self.context.cluster_id.as_deref(),
+                self.context.backend_id.as_deref()
+            );
+            connection_race.attempts.push(ConnectionAttempt {
+                address,
+                events: Ready::EMPTY,
+                socket,
+                token,
+            });
+        }
+
+        if connection_race.is_lost() {
+            // let the usual retry mechanism handle the failure of the first attempt
+            self.connection_race = None;
+            self.backend_readiness.event.insert(Ready::HUP);
+        } else if connection_race.has_addresses() {
+            server::wake_up_at(self.frontend_token, connection_race.next_attempt());
+        }
+    }
+
+    /// replaces the backend socket with a connection attempt that succeeded
+    fn switch_to_connection_attempt(
+        &mut self,
+        attempt: ConnectionAttempt,
+        proxy: &Rc<RefCell<dyn L7Proxy>>,
+    ) {
+        // the backend connection slot is kept for the new socket
+        if let (Some(token), Some(socket)) = (self.backend_token, self.backend_socket.take()) {
+            close_racing_socket(proxy, socket, token);
+        }
+
+        if let Some(backend) = &self.backend {
+            let mut backend = backend.borrow_mut();
+            info!(
+                "{} connected to backend {} on its address {}",
+                log_context!(self),
+                backend.backend_id,
+                attempt.address
+            );
+            backend.preferred_address = attempt.address;
+        }
+
+        self.close_connection_race(proxy);
+        self.backend_socket = Some(attempt.socket);
+        self.set_backend_token(attempt.token);
+        self.backend_readiness.event = attempt.events;
+        self.set_backend_timeout(self.configured_connect_timeout);
+    }
+
+    fn close_connection_race(&mut self, proxy: &Rc<RefCell<dyn L7Proxy>>) {
+        if let Some(connection_race) = self.connection_race.take() {
+            for attempt in connection_race.attempts {
+                close_racing_socket(proxy, attempt.socket, attempt.token);
+            }
+        }
+    }
+
     fn set_backend_connected(
         &mut self,
         connected: BackendConnectionStatus,
@@ -1519,16 +1830,27 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                         backend.backend_id, backend.address
                     );
 
+                    backend.start_slow_start();
+                    if let Some(cluster_id) = self.context.cluster_id.as_deref() {
+                        server::backend_available(cluster_id);
+                    }
+
                     push_event(Event {
                         kind: EventKind::BackendUp as i32,
                         backend_id: Some(backend.backend_id.to_owned()),
                         address: Some(backend.address.into()),
                         cluster_id: None,
+                        crash_report: None,
+                        tags: self.listener.borrow().get_event_tags(),
                     });
                 }
 
                 if let BackendConnectionStatus::Connecting(start) = last {
                     backend.set_connection_time(Instant::now() - start);
+                    if let Some(debug_trace) = &mut self.context.debug_trace {
+                        debug_trace.connect_time = Some(start.elapsed());
+                        debug_trace.connected = Some(Instant::now());
+                    }
                 }
 
                 //successful connection, reset failure counter
@@ -1540,39 +1862,13 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
     }
 
     fn fail_backend_connection(&mut self, metrics: &SessionMetrics) {
-        if let Some(backend) = &self.backend {
-            let mut backend = backend.borrow_mut();
-            backend.failures += 1;
-
-            let already_unavailable = backend.retry_policy.is_down();
-            backend.retry_policy.fail();
+        if self.backend.is_some() {
             incr!(
                 "backend.connections.error",
                 self.context.cluster_id.as_deref(),
                 metrics.backend_id.as_deref()
             );
-
-            if !already_unavailable && backend.retry_policy.is_down() {
-                error!(
-                    "{} backend server {} at {} is down",
-                    log_context!(self),
-                    backend.backend_id,
-                    backend.address
-                );
-
-                incr!(
-                    "backend.down",
-                    self.context.cluster_id.as_deref(),
-                    metrics.backend_id.as_deref()
-                );
-
-                push_event(Event {
-                    kind: EventKind::BackendDown as i32,
-                    backend_id: Some(backend.backend_id.to_owned()),
-                    address: Some(backend.address.into()),
-                    cluster_id: None,
-                });
-            }
+            self.record_backend_failure();
         }
     }
 
@@ -1663,6 +1959,34 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
     ) -> SessionResult {
         let mut counter = 0;
 
+        // the request is waiting for a saturated backend, try to connect again
+        if self.queued_since.is_some() && self.backend_token.is_none() {
+            let connection_result =
+                self.connect_to_backend(session.clone(), proxy.clone(), metrics);
+
+            if let Some(session_result) = handle_connection_result(connection_result) {
+                return session_result;
+            }
+        }
+
+        // the request is held back by an injected latency
+        if self.fault.is_some() {
+            let connection_result =
+                self.connect_to_backend(session.clone(), proxy.clone(), metrics);
+
+            if let Some(session_result) = handle_connection_result(connection_result) {
+                return session_result;
+            }
+        }
+
+        if self.hedging.has_hedge() || self.timeout_status() == TimeoutStatus::WaitingForResponse {
+            self.hedge_ready(session.clone(), proxy.clone(), metrics);
+        }
+
+        if self.connection_race.is_some() {
+            self.race_backend_connection(session.clone(), proxy.clone());
+        }
+
         if self.backend_connection_status.is_connecting()
             && !self.backend_readiness.event.is_empty()
         {
@@ -1675,6 +1999,9 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L
                 );
 
                 self.connection_attempts += 1;
+                if let Some(debug_trace) = &mut self.context.debug_trace {
+                    debug_trace.retries += 1;
+                }
                 self.fail_backend_connection(metrics);
 
                 self.backend_connection_status =
@@ -1868,7 +2195,16 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> SessionState
         proxy: Rc<RefCell<dyn L7Proxy>>,
         metrics: &mut SessionMetrics,
     ) -> SessionResult {
-        let session_result = self.ready_inner(session, proxy, metrics);
+        let session_result = self.ready_inner(session, proxy.clone(), metrics);
+
+        // the original backend answered first, or the session is over
+        if self.hedging.has_hedge()
+            && (session_result != SessionResult::Continue
+                || self.timeout_status() != TimeoutStatus::WaitingForResponse)
+        {
+            self.close_hedge(proxy);
+        }
+
         if session_result == SessionResult::Upgrade {
             let response_storage = match &mut self.response_stream {
                 ResponseStream::BackendAnswer(response_stream) => &mut response_stream.storage,
@@ -1893,10 +2229,16 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> SessionState
             self.frontend_readiness.event |= events;
         } else if self.backend_token == Some(token) {
             self.backend_readiness.event |= events;
+        } else {
+            if let Some(connection_race) = &mut self.connection_race {
+                connection_race.update_readiness(token, events);
+            }
+            self.hedging.update_readiness(token, events);
         }
     }
 
     fn close(&mut self, proxy: Rc<RefCell<dyn L7Proxy>>, metrics: &mut SessionMetrics) {
+        self.close_hedge(proxy.clone());
         self.close_backend(proxy, metrics);
 
         //if the state was initial, the connection was already reset
@@ -2010,6 +2352,20 @@ impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> SessionState
     }
 }
 
+/// closes a socket that lost the race to connect to a backend
+fn close_racing_socket(proxy: &Rc<RefCell<dyn L7Proxy>>, mut socket: TcpStream, token: Token) {
+    let proxy = proxy.borrow();
+    if let Err(e) = proxy.deregister_socket(&mut socket) {
+        error!("Error deregistering back socket({:?}): {:?}", socket, e);
+    }
+    if let Err(e) = socket.shutdown(Shutdown::Both) {
+        if e.kind() != ErrorKind::NotConnected {
+            error!("Error shutting down back socket({:?}): {:?}", socket, e);
+        }
+    }
+    proxy.remove_session(token);
+}
+
 fn handle_connection_result(
     connection_result: Result<BackendConnectAction, BackendConnectionError>,
 ) -> Option<SessionResult> {
@@ -2024,9 +2380,11 @@ fn handle_connection_result(
             // All BackendConnectionError already set a default answer
             // the session must continue to serve it
             // - NotFound: not used for http (only tcp)
-            // - RetrieveClusterError: 301/400/401/404,
+            // - RetrieveClusterError: 301/400/401/403/404,
             // - MaxConnectionRetries: 503,
-            // - Backend: 503,
+            // - RetryBudgetExhausted: 503,
+            // - Backend: 503, unless waiting for a saturated backend
+            // - FaultInjected: 502/503/504, unless an injected latency is running
             // - MaxSessionsMemory: not checked in connect_to_backend (TODO: check it?)
             None
         }
@@ -2059,3 +2417,76 @@ fn save_http_status_metric(status: Option<u16>, context: LogContext) {
         }
     }
 }
+
+/// True if the path of the request matches one of the exemptions of the HTTPS redirection.
+/// An exemption ending with `*` matches any path starting with the rest of it,
+/// other exemptions must be equal to the path. The query string is ignored.
+fn is_exempt_from_https_redirect(exemptions: &[String], uri: &str) -> bool {
+    let path = uri.split_once('?').map_or(uri, |(path, _)| path);
+    exemptions
+        .iter()
+        .any(|exemption| match exemption.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == exemption,
+        })
+}
+
+/// The first protocol of an Upgrade header missing from the allowed upgrades of a cluster.
+/// Protocols are compared without case, and without their version if the allowed
+/// protocol has none. All upgrades are allowed if the list is empty.
+fn denied_upgrade<'a>(allowed_upgrades: &[String], upgrade: &'a str) -> Option<&'a str> {
+    if allowed_upgrades.is_empty() {
+        return None;
+    }
+    upgrade
+        .split(',')
+        .map(str::trim)
+        .filter(|protocol| !protocol.is_empty())
+        .find(|protocol| {
+            let name = protocol.split_once('/').map_or(*protocol, |(name, _)| name);
+            !allowed_upgrades.iter().any(|allowed| {
+                allowed.eq_ignore_ascii_case(protocol) || allowed.eq_ignore_ascii_case(name)
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn https_redirect_exemptions() {
+        let exemptions = vec![
+            "/.well-known/acme-challenge/*".to_owned(),
+            "/healthz".to_owned(),
+        ];
+
+        assert!(is_exempt_from_https_redirect(
+            &exemptions,
+            "/.well-known/acme-challenge/token"
+        ));
+        assert!(is_exempt_from_https_redirect(
+            &exemptions,
+            "/healthz?full=1"
+        ));
+        assert!(!is_exempt_from_https_redirect(&exemptions, "/healthz/more"));
+        assert!(!is_exempt_from_https_redirect(
+            &exemptions,
+            "/.well-known/other"
+        ));
+        assert!(!is_exempt_from_https_redirect(&[], "/healthz"));
+    }
+
+    #[test]
+    fn allowed_upgrades() {
+        let allowed = vec!["websocket".to_owned(), "foo/2".to_owned()];
+
+        assert_eq!(denied_upgrade(&allowed, "websocket"), None);
+        assert_eq!(denied_upgrade(&allowed, "WebSocket"), None);
+        assert_eq!(denied_upgrade(&allowed, "foo/2"), None);
+        assert_eq!(denied_upgrade(&allowed, "h2c"), Some("h2c"));
+        assert_eq!(denied_upgrade(&allowed, "foo/3"), Some("foo/3"));
+        assert_eq!(denied_upgrade(&allowed, "websocket, h2c"), Some("h2c"));
+        assert_eq!(denied_upgrade(&[], "h2c"), None);
+    }
+}
@@ -0,0 +1,237 @@
+//! Routing decision trace, for debugging
+//!
+//! A frontend can be configured to trace all its requests, or a request can ask for
+//! its trace with a `Sozu-Debug-Trace` header signed with the key of the listener.
+//! The response then carries headers describing the matched frontend rule, the
+//! cluster and backend, the connection retries, and a timing breakdown in a
+//! `Server-Timing` header, so that application teams can diagnose routing issues
+//! without access to the logs of the proxy.
+//!
+//! The value of the request header is `{expiration}:{signature}`, where expiration
+//! is a unix timestamp in seconds and signature the hex encoded HMAC-SHA256 of
+//! `{expiration}:{hostname}` with the key of the listener.
+
+use std::{
+    str::from_utf8,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ring::hmac;
+
+use crate::{socket::SocketHandler, L7ListenerHandler, ListenerHandler};
+
+use super::{answers::DefaultAnswerStream, Http};
+
+pub const DEBUG_TRACE_HEADER: &[u8] = b"Sozu-Debug-Trace";
+
+/// What happened to the request so far, written in the response headers
+#[derive(Debug, Clone)]
+pub struct DebugTrace {
+    /// description of the frontend rule matching the request
+    pub rule: Option<String>,
+    /// failed connection attempts to backends
+    pub retries: u32,
+    /// when the request was routed
+    pub started: Instant,
+    /// time spent establishing the backend connection, unset if it was reused
+    pub connect_time: Option<Duration>,
+    /// when the backend connection was established
+    pub connected: Option<Instant>,
+}
+
+impl DebugTrace {
+    pub fn new(rule: Option<String>) -> Self {
+        Self {
+            rule,
+            retries: 0,
+            started: Instant::now(),
+            connect_time: None,
+            connected: None,
+        }
+    }
+
+    /// the headers describing the routing decision, computed when the response starts
+    pub fn headers(
+        &self,
+        cluster_id: Option<&str>,
+        backend_id: Option<&str>,
+    ) -> Vec<(&'static [u8], String)> {
+        let mut headers = vec![(
+            &b"Sozu-Trace-Rule"[..],
+            self.rule.clone().unwrap_or_else(|| "none".to_owned()),
+        )];
+        if let Some(cluster_id) = cluster_id {
+            headers.push((b"Sozu-Trace-Cluster", cluster_id.to_owned()));
+        }
+        if let Some(backend_id) = backend_id {
+            headers.push((b"Sozu-Trace-Backend", backend_id.to_owned()));
+        }
+        headers.push((b"Sozu-Trace-Retries", self.retries.to_string()));
+
+        let mut timings = Vec::new();
+        if let Some(connect_time) = self.connect_time {
+            timings.push(format!("sozu-connect;dur={}", millis(connect_time)));
+        }
+        if let Some(connected) = self.connected {
+            timings.push(format!("sozu-backend;dur={}", millis(connected.elapsed())));
+        }
+        timings.push(format!("sozu-total;dur={}", millis(self.started.elapsed())));
+        headers.push((b"Server-Timing", timings.join(", ")));
+
+        headers
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
+
+/// Checks the value of a `Sozu-Debug-Trace` header: the signature of the
+/// hostname must be valid, and the expiration in the future
+pub fn verify_signature(key: &str, hostname: &str, value: &[u8]) -> bool {
+    let Some((expiration, signature)) = from_utf8(value)
+        .ok()
+        .and_then(|value| value.trim().split_once(':'))
+    else {
+        return false;
+    };
+    let Ok(expires_at) = expiration.parse::<u64>() else {
+        return false;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    if expires_at < now {
+        return false;
+    }
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+    hmac::verify(
+        &key,
+        format!("{expiration}:{hostname}").as_bytes(),
+        &signature,
+    )
+    .is_ok()
+}
+
+/// Adds the trace headers at the end of the headers of a default answer
+pub fn add_headers(answer: &mut DefaultAnswerStream, headers: Vec<(&'static [u8], String)>) {
+    let end_of_headers = answer
+        .blocks
+        .iter()
+        .position(|block| matches!(block, kawa::Block::Flags(flags) if flags.end_header))
+        .unwrap_or(answer.blocks.len());
+    for (index, (key, val)) in headers.into_iter().enumerate() {
+        answer.blocks.insert(
+            end_of_headers + index,
+            kawa::Block::Header(kawa::Pair {
+                key: kawa::Store::Static(key),
+                val: kawa::Store::from_string(val),
+            }),
+        );
+    }
+}
+
+impl<Front: SocketHandler, L: ListenerHandler + L7ListenerHandler> Http<Front, L> {
+    /// Starts the trace of the request if its frontend is traced, or if it carries
+    /// a valid signed header
+    pub(super) fn prepare_debug_trace(&mut self) {
+        if self.context.debug_trace.is_some() {
+            return;
+        }
+        let (Some(authority), Some(path), Some(method)) = (
+            self.context.authority.as_deref(),
+            self.context.path.as_deref(),
+            self.context.method.as_ref(),
+        ) else {
+            return;
+        };
+        let hostname = match authority.split_once(':') {
+            None => authority,
+            Some((hostname, _)) => hostname,
+        };
+
+        let listener = self.listener.borrow();
+        let signed = match (
+            self.context.debug_trace_token.as_deref(),
+            listener.get_debug_trace_key(),
+        ) {
+            (Some(token), Some(key)) => {
+                let valid = verify_signature(key, hostname, token.as_bytes());
+                if !valid {
+                    incr!("http.debug_trace.invalid_signature");
+                }
+                valid
+            }
+            _ => false,
+        };
+        if !signed && !listener.has_debug_trace(hostname) {
+            return;
+        }
+
+        incr!("http.debug_trace");
+        let rule = listener.describe_frontend(hostname, path, method);
+        drop(listener);
+        self.context.debug_trace = Some(DebugTrace::new(rule));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(key: &str, payload: &str) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+        hex::encode(hmac::sign(&key, payload.as_bytes()).as_ref())
+    }
+
+    #[test]
+    fn signed_header() {
+        let expiration = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let signature = sign("secret", &format!("{expiration}:example.com"));
+        let value = format!("{expiration}:{signature}");
+
+        assert!(verify_signature("secret", "example.com", value.as_bytes()));
+        assert!(!verify_signature("other", "example.com", value.as_bytes()));
+        assert!(!verify_signature("secret", "example.org", value.as_bytes()));
+        assert!(!verify_signature("secret", "example.com", b"garbage"));
+
+        let expired = expiration - 120;
+        let signature = sign("secret", &format!("{expired}:example.com"));
+        let value = format!("{expired}:{signature}");
+        assert!(!verify_signature("secret", "example.com", value.as_bytes()));
+    }
+
+    #[test]
+    fn trace_headers() {
+        let mut trace = DebugTrace::new(Some("tree example.com prefix / any method -> app".into()));
+        trace.retries = 2;
+        trace.connect_time = Some(Duration::from_micros(1500));
+        trace.connected = Some(Instant::now());
+
+        let headers = trace.headers(Some("app"), Some("app-0"));
+        let names: Vec<&[u8]> = headers.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            vec![
+                &b"Sozu-Trace-Rule"[..],
+                b"Sozu-Trace-Cluster",
+                b"Sozu-Trace-Backend",
+                b"Sozu-Trace-Retries",
+                b"Server-Timing"
+            ]
+        );
+        assert_eq!(headers[3].1, "2");
+        assert!(headers[4]
+            .1
+            .starts_with("sozu-connect;dur=1.500, sozu-backend;dur="));
+    }
+}
@@ -1,17 +1,25 @@
-use std::{cell::RefCell, net::SocketAddr, rc::Rc};
+use std::{
+    cell::RefCell,
+    net::SocketAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 use mio::{net::TcpStream, Token};
 use rusty_ulid::Ulid;
 use sozu_command::{
     config::MAX_LOOP_ITERATIONS,
     logging::{EndpointRecord, LogContext},
+    proto::command::{HandedOffSession, ListenerType},
 };
 
 use crate::{
     backends::Backend,
     pool::Checkout,
     protocol::{http::parser::Method, SessionState},
-    socket::{stats::socket_rtt, SocketHandler, SocketResult, TransportProtocol},
+    socket::{
+        canonical_address, stats::socket_rtt, SocketHandler, SocketResult, TransportProtocol,
+    },
     sozu_command::ready::Ready,
     timer::TimeoutContainer,
     L7Proxy, ListenerHandler, Protocol, Readiness, SessionMetrics, SessionResult, StateResult,
@@ -47,6 +55,31 @@ enum ConnectionStatus {
     Closed,
 }
 
+/// Limits on how long a pipe stays open, so that dead connections do not linger
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// the pipe is closed if no data went through it for that long
+    pub idle_timeout: Option<Duration>,
+    /// the pipe is closed once it has been open for that long, whatever its activity
+    pub max_lifetime: Option<Duration>,
+}
+
+/// Why a pipe was closed by its connection limits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionLimit {
+    IdleTimeout,
+    MaxLifetime,
+}
+
+impl ConnectionLimit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionLimit::IdleTimeout => "idle timeout",
+            ConnectionLimit::MaxLifetime => "max connection lifetime reached",
+        }
+    }
+}
+
 /// matches sozu_command_lib::logging::access_logs::EndpointRecords
 pub enum WebSocketContext {
     Http {
@@ -68,17 +101,22 @@ pub struct Pipe<Front: SocketHandler, L: ListenerHandler> {
     backend_token: Option<Token>,
     pub backend: Option<Rc<RefCell<Backend>>>,
     cluster_id: Option<String>,
+    connection_limits: ConnectionLimits,
     pub container_backend_timeout: Option<TimeoutContainer>,
     pub container_frontend_timeout: Option<TimeoutContainer>,
+    /// fires at the next deadline of the connection limits
+    container_limits_timeout: Option<TimeoutContainer>,
     frontend_buffer: Checkout,
     pub frontend_readiness: Readiness,
     frontend_status: ConnectionStatus,
     frontend_token: Token,
     frontend: Front,
+    last_activity: Instant,
     listener: Rc<RefCell<L>>,
     protocol: Protocol,
     request_id: Ulid,
     session_address: Option<SocketAddr>,
+    started: Instant,
     websocket_context: WebSocketContext,
 }
 
@@ -126,8 +164,10 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
             backend_token: None,
             backend,
             cluster_id,
+            connection_limits: ConnectionLimits::default(),
             container_backend_timeout,
             container_frontend_timeout,
+            container_limits_timeout: None,
             frontend_buffer,
             frontend_readiness: Readiness {
                 interest: Ready::READABLE | Ready::WRITABLE | Ready::HUP | Ready::ERROR,
@@ -136,10 +176,12 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
             frontend_status,
             frontend_token,
             frontend,
+            last_activity: Instant::now(),
             listener,
             protocol,
             request_id,
             session_address,
+            started: Instant::now(),
             websocket_context,
         };
 
@@ -173,6 +215,8 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
     }
 
     fn reset_timeouts(&mut self) {
+        self.last_activity = Instant::now();
+
         if let Some(t) = self.container_frontend_timeout.as_mut() {
             if !t.reset() {
                 error!(
@@ -189,6 +233,79 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         }
     }
 
+    /// Starts enforcing the idle timeout and max lifetime of the connection
+    pub fn set_connection_limits(&mut self, connection_limits: ConnectionLimits) {
+        self.connection_limits = connection_limits;
+        self.arm_limits_timeout();
+    }
+
+    /// Returns the limit the connection went over, otherwise waits for the next deadline
+    pub fn check_connection_limits(&mut self) -> Option<ConnectionLimit> {
+        if let Some(max_lifetime) = self.connection_limits.max_lifetime {
+            if self.started.elapsed() >= max_lifetime {
+                return Some(ConnectionLimit::MaxLifetime);
+            }
+        }
+        if let Some(idle_timeout) = self.connection_limits.idle_timeout {
+            if self.last_activity.elapsed() >= idle_timeout {
+                return Some(ConnectionLimit::IdleTimeout);
+            }
+        }
+        self.arm_limits_timeout();
+        None
+    }
+
+    /// records why the connection is closed, in the metrics and access logs
+    pub fn log_connection_limit(&self, metrics: &SessionMetrics, limit: ConnectionLimit) {
+        match limit {
+            ConnectionLimit::IdleTimeout => incr!(
+                "tcp.close.idle_timeout",
+                self.cluster_id.as_deref(),
+                self.backend_id.as_deref()
+            ),
+            ConnectionLimit::MaxLifetime => incr!(
+                "tcp.close.max_lifetime",
+                self.cluster_id.as_deref(),
+                self.backend_id.as_deref()
+            ),
+        }
+        info!(
+            "{} Closing the connection: {}",
+            log_context!(self),
+            limit.as_str()
+        );
+        self.log_request(metrics, false, Some(limit.as_str()));
+    }
+
+    /// the idle deadline moves with the traffic, it is checked lazily when the timeout fires
+    fn arm_limits_timeout(&mut self) {
+        let lifetime_left = self
+            .connection_limits
+            .max_lifetime
+            .map(|max_lifetime| max_lifetime.saturating_sub(self.started.elapsed()));
+        let idle_left = self
+            .connection_limits
+            .idle_timeout
+            .map(|idle_timeout| idle_timeout.saturating_sub(self.last_activity.elapsed()));
+
+        let next_deadline = match (lifetime_left, idle_left) {
+            (Some(lifetime_left), Some(idle_left)) => lifetime_left.min(idle_left),
+            (Some(left), None) | (None, Some(left)) => left,
+            (None, None) => return,
+        };
+
+        match self.container_limits_timeout.as_mut() {
+            Some(timeout) => {
+                timeout.set_duration(next_deadline);
+                timeout.set(self.frontend_token);
+            }
+            None => {
+                self.container_limits_timeout =
+                    Some(TimeoutContainer::new(next_deadline, self.frontend_token))
+            }
+        }
+    }
+
     pub fn set_cluster_id(&mut self, cluster_id: Option<String>) {
         self.cluster_id = cluster_id;
     }
@@ -201,9 +318,14 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         self.backend_token = Some(token);
     }
 
+    pub fn set_request_id(&mut self, request_id: Ulid) {
+        self.request_id = request_id;
+    }
+
     pub fn get_session_address(&self) -> Option<SocketAddr> {
         self.session_address
             .or_else(|| self.frontend.socket_ref().peer_addr().ok())
+            .map(canonical_address)
     }
 
     pub fn get_backend_address(&self) -> Option<SocketAddr> {
@@ -212,6 +334,69 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
             .and_then(|backend| backend.peer_addr().ok())
     }
 
+    /// A pipe can be handed off to another worker if it only relays bytes:
+    /// both sides are open and nothing waits in its buffers
+    pub fn can_hand_off(&self) -> bool {
+        self.backend_socket.is_some()
+            && matches!(self.frontend_status, ConnectionStatus::Normal)
+            && matches!(self.backend_status, ConnectionStatus::Normal)
+            && self.frontend_buffer.available_data() == 0
+            && self.backend_buffer.available_data() == 0
+    }
+
+    /// The minimal state needed to recreate the pipe in another worker
+    pub fn handoff_state(&self, listener_type: ListenerType) -> HandedOffSession {
+        let (method, authority, path, status) = match &self.websocket_context {
+            WebSocketContext::Http {
+                method,
+                authority,
+                path,
+                status,
+                ..
+            } => (
+                method.as_ref().map(ToString::to_string),
+                authority.clone(),
+                path.clone(),
+                status.map(u32::from),
+            ),
+            WebSocketContext::Tcp => (None, None, None, None),
+        };
+
+        HandedOffSession {
+            listener_type: listener_type as i32,
+            listener_address: (*self.listener.borrow().get_addr()).into(),
+            cluster_id: self.cluster_id.clone(),
+            backend_id: self.backend_id.clone(),
+            request_id: self.request_id.to_string(),
+            method,
+            authority,
+            path,
+            status,
+        }
+    }
+
+    /// The backend connection leaves this worker with the pipe: it no longer
+    /// counts in the connections of the backend and in the gauges
+    pub fn release_backend_connection(&mut self) {
+        gauge_add!("backend.connections", -1);
+        gauge_add!(
+            "connections_per_backend",
+            -1,
+            self.cluster_id.as_deref(),
+            self.backend_id.as_deref()
+        );
+        if let Some(backend) = self.backend.take() {
+            let cluster_id = self.cluster_id.as_deref().unwrap_or_default();
+            backend.borrow_mut().release_connection(cluster_id);
+        }
+    }
+
+    /// Gives the sockets of the pipe away, None if it has no backend socket
+    pub fn into_sockets(self) -> Option<(Front, TcpStream)> {
+        let backend_socket = self.backend_socket?;
+        Some((self.frontend, backend_socket))
+    }
+
     fn protocol_string(&self) -> &'static str {
         match self.protocol {
             Protocol::TCP => "TCP",
@@ -233,7 +418,8 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
         let listener = self.listener.borrow();
         let context = self.log_context();
         let endpoint = self.log_endpoint();
-        metrics.register_end_of_session(&context);
+        let tags = listener.get_tags(&listener.get_addr().to_string());
+        metrics.register_end_of_session(&context, tags);
         log_access!(
             error,
             on_failure: { incr!("unsent-access-logs") },
@@ -243,7 +429,7 @@ impl<Front: SocketHandler, L: ListenerHandler> Pipe<Front, L> {
             backend_address: self.get_backend_address(),
             protocol: self.protocol_string(),
             endpoint,
-            tags: listener.get_tags(&listener.get_addr().to_string()),
+            tags,
             client_rtt: socket_rtt(self.front_socket()),
             server_rtt: self.backend_socket.as_ref().and_then(socket_rtt),
             service_time: metrics.service_time(),
@@ -795,6 +981,7 @@ impl<Front: SocketHandler, L: ListenerHandler> SessionState for Pipe<Front, L> {
     fn cancel_timeouts(&mut self) {
         self.container_frontend_timeout.as_mut().map(|t| t.cancel());
         self.container_backend_timeout.as_mut().map(|t| t.cancel());
+        self.container_limits_timeout.as_mut().map(|t| t.cancel());
     }
 
     fn close(&mut self, _proxy: Rc<RefCell<dyn L7Proxy>>, _metrics: &mut SessionMetrics) {
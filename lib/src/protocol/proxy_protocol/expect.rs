@@ -11,7 +11,7 @@ use crate::{
         pipe::{Pipe, WebSocketContext},
         SessionResult, SessionState,
     },
-    socket::{SocketHandler, SocketResult},
+    socket::{canonical_address, SocketHandler, SocketResult},
     sozu_command::ready::Ready,
     tcp::TcpListener,
     timer::TimeoutContainer,
@@ -168,7 +168,7 @@ impl<Front: SocketHandler> ExpectProxyProtocol<Front> {
         backend_token: Option<Token>,
         listener: Rc<RefCell<TcpListener>>,
     ) -> Pipe<Front, TcpListener> {
-        let addr = self.front_socket().peer_addr().ok();
+        let addr = self.front_socket().peer_addr().ok().map(canonical_address);
 
         let mut pipe = Pipe::new(
             back_buf,
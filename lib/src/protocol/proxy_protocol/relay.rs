@@ -10,7 +10,7 @@ use crate::{
         pipe::{Pipe, WebSocketContext},
         proxy_protocol::parser::parse_v2_header,
     },
-    socket::{SocketHandler, SocketResult},
+    socket::{canonical_address, SocketHandler, SocketResult},
     sozu_command::ready::Ready,
     tcp::TcpListener,
     Protocol, Readiness, SessionMetrics, SessionResult,
@@ -178,7 +178,7 @@ impl<Front: SocketHandler> RelayProxyProtocol<Front> {
         listener: Rc<RefCell<TcpListener>>,
     ) -> Pipe<Front, TcpListener> {
         let backend_socket = self.backend.take().unwrap();
-        let addr = self.front_socket().peer_addr().ok();
+        let addr = self.front_socket().peer_addr().ok().map(canonical_address);
 
         let mut pipe = Pipe::new(
             back_buf,
@@ -13,7 +13,7 @@ use crate::{
         pipe::{Pipe, WebSocketContext},
         proxy_protocol::header::{Command, HeaderV2, ProxyProtocolHeader},
     },
-    socket::SocketHandler,
+    socket::{canonical_address, SocketHandler},
     sozu_command::ready::Ready,
     tcp::TcpListener,
     BackendConnectionStatus, Protocol, Readiness, SessionMetrics, SessionResult,
@@ -159,7 +159,7 @@ impl<Front: SocketHandler> SendProxyProtocol<Front> {
         listener: Rc<RefCell<TcpListener>>,
     ) -> Pipe<Front, TcpListener> {
         let backend_socket = self.backend.take().unwrap();
-        let addr = self.front_socket().peer_addr().ok();
+        let addr = self.front_socket().peer_addr().ok().map(canonical_address);
 
         let mut pipe = Pipe::new(
             back_buf,
@@ -1,13 +1,13 @@
 use std::{cell::RefCell, io::ErrorKind, net::SocketAddr, rc::Rc};
 
 use mio::{net::TcpStream, Token};
-use rustls::ServerConnection;
+use rustls::{server::Acceptor, ServerConnection};
 use rusty_ulid::Ulid;
 use sozu_command::{config::MAX_LOOP_ITERATIONS, logging::LogContext};
 
 use crate::{
-    protocol::SessionState, timer::TimeoutContainer, Readiness, Ready, SessionMetrics,
-    SessionResult, StateResult,
+    protocol::SessionState, timer::TimeoutContainer, tls::TlsConfigs, Readiness, Ready,
+    SessionMetrics, SessionResult, StateResult,
 };
 
 /// This macro is defined uniquely in this module to help the tracking of tls
@@ -39,7 +39,26 @@ pub enum TlsState {
     Error,
 }
 
+/// Reads the ClientHello before the handshake, to start it with the TLS versions
+/// and cipher suites of the certificate requested by SNI
+pub struct CertificateAcceptor {
+    acceptor: Acceptor,
+    configs: TlsConfigs,
+}
+
+impl CertificateAcceptor {
+    pub fn new(configs: TlsConfigs) -> Self {
+        Self {
+            acceptor: Acceptor::default(),
+            configs,
+        }
+    }
+}
+
 pub struct TlsHandshake {
+    /// set if some certificates of the listener have their own TLS settings,
+    /// the session is then replaced once the ClientHello is read
+    acceptor: Option<CertificateAcceptor>,
     pub container_frontend_timeout: TimeoutContainer,
     pub frontend_readiness: Readiness,
     frontend_token: Token,
@@ -57,12 +76,14 @@ impl TlsHandshake {
     pub fn new(
         container_frontend_timeout: TimeoutContainer,
         session: ServerConnection,
+        acceptor: Option<CertificateAcceptor>,
         stream: TcpStream,
         frontend_token: Token,
         request_id: Ulid,
         peer_address: Option<SocketAddr>,
     ) -> TlsHandshake {
         TlsHandshake {
+            acceptor,
             container_frontend_timeout,
             frontend_readiness: Readiness {
                 interest: Ready::READABLE | Ready::HUP | Ready::ERROR,
@@ -76,7 +97,80 @@ impl TlsHandshake {
         }
     }
 
+    /// reads the ClientHello, then starts the handshake with the configuration
+    /// of the requested certificate
+    fn accept(&mut self) -> SessionResult {
+        let mut certificate_acceptor = match self.acceptor.take() {
+            Some(certificate_acceptor) => certificate_acceptor,
+            None => return SessionResult::Continue,
+        };
+
+        let accepted = loop {
+            match certificate_acceptor.acceptor.read_tls(&mut self.stream) {
+                Ok(0) => {
+                    error!("{} Connection closed during handshake", log_context!(self));
+                    return SessionResult::Close;
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.frontend_readiness.event.remove(Ready::READABLE);
+                    self.acceptor = Some(certificate_acceptor);
+                    return SessionResult::Continue;
+                }
+                Err(e) => {
+                    error!(
+                        "{} Could not perform handshake: {:?}",
+                        log_context!(self),
+                        e
+                    );
+                    return SessionResult::Close;
+                }
+            }
+
+            match certificate_acceptor.acceptor.accept() {
+                Ok(Some(accepted)) => break accepted,
+                Ok(None) => {}
+                Err((e, mut alert)) => {
+                    error!(
+                        "{} Could not read the ClientHello: {:?}",
+                        log_context!(self),
+                        e
+                    );
+                    let _ = alert.write_all(&mut self.stream);
+                    return SessionResult::Close;
+                }
+            }
+        };
+
+        let config = certificate_acceptor
+            .configs
+            .for_server_name(accepted.client_hello().server_name());
+        match accepted.into_connection(config) {
+            Ok(session) => {
+                self.session = session;
+                self.frontend_readiness.interest.insert(Ready::WRITABLE);
+                SessionResult::Continue
+            }
+            Err((e, mut alert)) => {
+                error!(
+                    "{} Could not perform handshake: {:?}",
+                    log_context!(self),
+                    e
+                );
+                let _ = alert.write_all(&mut self.stream);
+                SessionResult::Close
+            }
+        }
+    }
+
     pub fn readable(&mut self) -> SessionResult {
+        if self.acceptor.is_some() {
+            let accept_result = self.accept();
+            if accept_result != SessionResult::Continue || self.acceptor.is_some() {
+                return accept_result;
+            }
+        }
+
         let mut can_read = true;
 
         loop {
@@ -1,4 +1,8 @@
-use std::{cmp, fmt::Debug, time};
+use std::{
+    cmp,
+    fmt::Debug,
+    time::{self, Duration, Instant},
+};
 
 use rand::Rng;
 
@@ -149,9 +153,80 @@ impl RetryPolicy for RetryPolicyWrapper {
     }
 }
 
+/// period over which requests and retries are accounted in a retry budget
+const RETRY_BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// retries always allowed in a window, so that clusters with little traffic can retry
+const RETRY_BUDGET_MIN_RETRIES: f64 = 10.0;
+
+/// Limits the connection retries of a cluster to a percentage of its recent requests,
+/// so that retry storms cannot amplify the load on backends during a brownout.
+///
+/// Requests and retries are counted with an exponential decay over `RETRY_BUDGET_WINDOW`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RetryBudget {
+    /// maximum ratio of retries over requests
+    ratio: f64,
+    requests: f64,
+    retries: f64,
+    last_decay: Instant,
+    /// set when a retry was refused, until one is allowed again
+    pub exhausted: bool,
+}
+
+impl RetryBudget {
+    /// `percent` is the maximum percentage of retries over requests
+    pub fn new(percent: u32) -> Self {
+        RetryBudget {
+            ratio: percent as f64 / 100.0,
+            requests: 0.0,
+            retries: 0.0,
+            last_decay: Instant::now(),
+            exhausted: false,
+        }
+    }
+
+    pub fn set_percent(&mut self, percent: u32) {
+        self.ratio = percent as f64 / 100.0;
+    }
+
+    fn decay(&mut self) {
+        let now = Instant::now();
+        let elapsed = now - self.last_decay;
+        let weight = (-elapsed.as_secs_f64() / RETRY_BUDGET_WINDOW.as_secs_f64()).exp();
+
+        self.requests *= weight;
+        self.retries *= weight;
+        self.last_decay = now;
+    }
+
+    pub fn record_request(&mut self) {
+        self.decay();
+        self.requests += 1.0;
+    }
+
+    /// number of retries still allowed
+    pub fn remaining(&mut self) -> f64 {
+        self.decay();
+        (RETRY_BUDGET_MIN_RETRIES + self.requests * self.ratio - self.retries).max(0.0)
+    }
+
+    /// consumes a retry from the budget, returns false if it is exhausted
+    pub fn try_retry(&mut self) -> bool {
+        if self.remaining() >= 1.0 {
+            self.retries += 1.0;
+            self.exhausted = false;
+            true
+        } else {
+            self.exhausted = true;
+            false
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{ExponentialBackoffPolicy, RetryAction, RetryPolicy};
+    use super::{ExponentialBackoffPolicy, RetryAction, RetryBudget, RetryPolicy};
     use serial_test::serial;
 
     const MAX_FAILS: usize = 10;
@@ -209,4 +284,24 @@ mod tests {
 
         assert_eq!(Some(RetryAction::WAIT), can_try)
     }
+
+    #[test]
+    fn retry_budget() {
+        let mut budget = RetryBudget::new(20);
+
+        for _ in 0..100 {
+            budget.record_request();
+        }
+
+        // 10 retries are always allowed, plus 20% of the requests
+        let allowed = (0..40).filter(|_| budget.try_retry()).count();
+        assert!((29..=30).contains(&allowed));
+        assert!(budget.exhausted);
+
+        for _ in 0..10 {
+            budget.record_request();
+        }
+        assert!(budget.try_retry());
+        assert!(!budget.exhausted);
+    }
 }
@@ -1,11 +1,11 @@
 pub mod pattern_trie;
 
-use std::{str::from_utf8, time::Instant};
+use std::{borrow::Cow, str::from_utf8, time::Instant};
 
-use regex::bytes::Regex;
+use regex::bytes::{Regex, RegexBuilder};
 
 use sozu_command::{
-    proto::command::{PathRule as CommandPathRule, PathRuleKind, RulePosition},
+    proto::command::{MatchingOptions, PathRule as CommandPathRule, PathRuleKind, RulePosition},
     response::HttpFrontend,
     state::ClusterId,
 };
@@ -31,9 +31,9 @@ pub enum RouterError {
 }
 
 pub struct Router {
-    pre: Vec<(DomainRule, PathRule, MethodRule, Route)>,
-    pub tree: TrieNode<Vec<(PathRule, MethodRule, Route)>>,
-    post: Vec<(DomainRule, PathRule, MethodRule, Route)>,
+    pre: Vec<(DomainRule, PathRule, MethodRule, MatchOptions, Route)>,
+    pub tree: TrieNode<Vec<(PathRule, MethodRule, MatchOptions, Route)>>,
+    post: Vec<(DomainRule, PathRule, MethodRule, MatchOptions, Route)>,
 }
 
 impl Default for Router {
@@ -57,79 +57,139 @@ impl Router {
         path: &str,
         method: &Method,
     ) -> Result<Route, RouterError> {
+        self.lookup_rule(hostname, path, method)
+            .map(|rule| rule.route.clone())
+            .ok_or_else(|| RouterError::RouteNotFound {
+                host: hostname.to_owned(),
+                path: path.to_owned(),
+                method: method.to_owned(),
+            })
+    }
+
+    /// finds the frontend rule matching a request
+    pub fn lookup_rule(
+        &self,
+        hostname: &str,
+        path: &str,
+        method: &Method,
+    ) -> Option<MatchedRule<'_>> {
         let hostname_b = hostname.as_bytes();
         let path_b = path.as_bytes();
-        for (domain_rule, path_rule, method_rule, cluster_id) in &self.pre {
-            if domain_rule.matches(hostname_b)
-                && path_rule.matches(path_b) != PathRuleResult::None
+        for (domain_rule, path_rule, method_rule, options, route) in &self.pre {
+            if domain_rule.matches(&options.normalize_hostname(hostname_b))
+                && options.matches(path_rule, path_b) != PathRuleResult::None
                 && method_rule.matches(method) != MethodRuleResult::None
             {
-                return Ok(cluster_id.clone());
+                return Some(MatchedRule {
+                    position: RulePosition::Pre,
+                    domain: RuleDomain::Rule(domain_rule),
+                    path: path_rule,
+                    method: method_rule,
+                    route,
+                });
             }
         }
 
-        if let Some((_, path_rules)) = self.tree.lookup(hostname_b, true) {
-            let mut prefix_length = 0;
-            let mut route = None;
+        if let Some(rule) = self.lookup_tree(hostname_b, path_b, method, false) {
+            return Some(rule);
+        }
+        // the tree only holds lowercase hostnames
+        if hostname_b.iter().any(u8::is_ascii_uppercase) {
+            let lowercase_hostname = hostname_b.to_ascii_lowercase();
+            if let Some(rule) = self.lookup_tree(&lowercase_hostname, path_b, method, true) {
+                return Some(rule);
+            }
+        }
 
-            for (rule, method_rule, cluster_id) in path_rules {
-                match rule.matches(path_b) {
-                    PathRuleResult::Regex | PathRuleResult::Equals => {
+        for (domain_rule, path_rule, method_rule, options, route) in self.post.iter() {
+            if domain_rule.matches(&options.normalize_hostname(hostname_b))
+                && options.matches(path_rule, path_b) != PathRuleResult::None
+                && method_rule.matches(method) != MethodRuleResult::None
+            {
+                return Some(MatchedRule {
+                    position: RulePosition::Post,
+                    domain: RuleDomain::Rule(domain_rule),
+                    path: path_rule,
+                    method: method_rule,
+                    route,
+                });
+            }
+        }
+
+        None
+    }
+
+    /// finds the best path rule of the hostname in the tree. If `case_insensitive_only`
+    /// is set, the hostname was lowercased and only case insensitive rules apply
+    fn lookup_tree(
+        &self,
+        hostname: &[u8],
+        path: &[u8],
+        method: &Method,
+        case_insensitive_only: bool,
+    ) -> Option<MatchedRule<'_>> {
+        let (domain, path_rules) = self.tree.lookup(hostname, true)?;
+        let mut prefix_length = 0;
+        let mut matched = None;
+
+        for (rule, method_rule, options, cluster_id) in path_rules {
+            if case_insensitive_only && !options.case_insensitive {
+                continue;
+            }
+            let candidate = (rule, method_rule, cluster_id);
+            match options.matches(rule, path) {
+                PathRuleResult::Regex | PathRuleResult::Equals => {
+                    match method_rule.matches(method) {
+                        MethodRuleResult::Equals => {
+                            matched = Some(candidate);
+                            break;
+                        }
+                        MethodRuleResult::All => {
+                            prefix_length = path.len();
+                            matched = Some(candidate);
+                        }
+                        MethodRuleResult::None => {}
+                    }
+                }
+                PathRuleResult::Prefix(size) => {
+                    if size >= prefix_length {
                         match method_rule.matches(method) {
-                            MethodRuleResult::Equals => return Ok(cluster_id.clone()),
+                            // FIXME: the rule order will be important here
+                            MethodRuleResult::Equals => {
+                                prefix_length = size;
+                                matched = Some(candidate);
+                            }
                             MethodRuleResult::All => {
-                                prefix_length = path_b.len();
-                                route = Some(cluster_id);
+                                prefix_length = size;
+                                matched = Some(candidate);
                             }
                             MethodRuleResult::None => {}
                         }
                     }
-                    PathRuleResult::Prefix(size) => {
-                        if size >= prefix_length {
-                            match method_rule.matches(method) {
-                                // FIXME: the rule order will be important here
-                                MethodRuleResult::Equals => {
-                                    prefix_length = size;
-                                    route = Some(cluster_id);
-                                }
-                                MethodRuleResult::All => {
-                                    prefix_length = size;
-                                    route = Some(cluster_id);
-                                }
-                                MethodRuleResult::None => {}
-                            }
-                        }
-                    }
-                    PathRuleResult::None => {}
                 }
-            }
-
-            if let Some(cluster_id) = route {
-                return Ok(cluster_id.clone());
+                PathRuleResult::None => {}
             }
         }
 
-        for (domain_rule, path_rule, method_rule, cluster_id) in self.post.iter() {
-            if domain_rule.matches(hostname_b)
-                && path_rule.matches(path_b) != PathRuleResult::None
-                && method_rule.matches(method) != MethodRuleResult::None
-            {
-                return Ok(cluster_id.clone());
-            }
-        }
-
-        Err(RouterError::RouteNotFound {
-            host: hostname.to_owned(),
-            path: path.to_owned(),
-            method: method.to_owned(),
+        matched.map(|(path, method, route)| MatchedRule {
+            position: RulePosition::Tree,
+            domain: RuleDomain::Hostname(domain),
+            path,
+            method,
+            route,
         })
     }
 
     pub fn add_http_front(&mut self, front: &HttpFrontend) -> Result<(), RouterError> {
+        let options = MatchOptions::from_config(front.matching.as_ref());
         let path_rule = PathRule::from_config(front.path.clone())
+            .and_then(|rule| options.normalize_rule(rule))
             .ok_or(RouterError::InvalidPathRule(front.path.to_string()))?;
 
         let method_rule = MethodRule::new(front.method.clone());
+        let hostname =
+            String::from_utf8_lossy(&options.normalize_hostname(front.hostname.as_bytes()))
+                .into_owned();
 
         let route = match &front.cluster_id {
             Some(cluster_id) => Route::ClusterId(cluster_id.clone()),
@@ -138,26 +198,32 @@ impl Router {
 
         let success = match front.position {
             RulePosition::Pre => {
-                let domain = front.hostname.parse::<DomainRule>().map_err(|_| {
-                    RouterError::InvalidDomain {
-                        hostname: front.hostname.clone(),
-                    }
-                })?;
-
-                self.add_pre_rule(&domain, &path_rule, &method_rule, &route)
+                let domain =
+                    hostname
+                        .parse::<DomainRule>()
+                        .map_err(|_| RouterError::InvalidDomain {
+                            hostname: front.hostname.clone(),
+                        })?;
+
+                self.add_pre_rule(&domain, &path_rule, &method_rule, &options, &route)
             }
             RulePosition::Post => {
-                let domain = front.hostname.parse::<DomainRule>().map_err(|_| {
-                    RouterError::InvalidDomain {
-                        hostname: front.hostname.clone(),
-                    }
-                })?;
-
-                self.add_post_rule(&domain, &path_rule, &method_rule, &route)
-            }
-            RulePosition::Tree => {
-                self.add_tree_rule(front.hostname.as_bytes(), &path_rule, &method_rule, &route)
+                let domain =
+                    hostname
+                        .parse::<DomainRule>()
+                        .map_err(|_| RouterError::InvalidDomain {
+                            hostname: front.hostname.clone(),
+                        })?;
+
+                self.add_post_rule(&domain, &path_rule, &method_rule, &options, &route)
             }
+            RulePosition::Tree => self.add_tree_rule(
+                hostname.as_bytes(),
+                &path_rule,
+                &method_rule,
+                &options,
+                &route,
+            ),
         };
         if !success {
             return Err(RouterError::AddRoute(format!("{:?}", front)));
@@ -166,32 +232,39 @@ impl Router {
     }
 
     pub fn remove_http_front(&mut self, front: &HttpFrontend) -> Result<(), RouterError> {
+        let options = MatchOptions::from_config(front.matching.as_ref());
         let path_rule = PathRule::from_config(front.path.clone())
+            .and_then(|rule| options.normalize_rule(rule))
             .ok_or(RouterError::InvalidPathRule(front.path.to_string()))?;
 
         let method_rule = MethodRule::new(front.method.clone());
+        let hostname =
+            String::from_utf8_lossy(&options.normalize_hostname(front.hostname.as_bytes()))
+                .into_owned();
 
         let remove_success = match front.position {
             RulePosition::Pre => {
-                let domain = front.hostname.parse::<DomainRule>().map_err(|_| {
-                    RouterError::InvalidDomain {
-                        hostname: front.hostname.clone(),
-                    }
-                })?;
+                let domain =
+                    hostname
+                        .parse::<DomainRule>()
+                        .map_err(|_| RouterError::InvalidDomain {
+                            hostname: front.hostname.clone(),
+                        })?;
 
                 self.remove_pre_rule(&domain, &path_rule, &method_rule)
             }
             RulePosition::Post => {
-                let domain = front.hostname.parse::<DomainRule>().map_err(|_| {
-                    RouterError::InvalidDomain {
-                        hostname: front.hostname.clone(),
-                    }
-                })?;
+                let domain =
+                    hostname
+                        .parse::<DomainRule>()
+                        .map_err(|_| RouterError::InvalidDomain {
+                            hostname: front.hostname.clone(),
+                        })?;
 
                 self.remove_post_rule(&domain, &path_rule, &method_rule)
             }
             RulePosition::Tree => {
-                self.remove_tree_rule(front.hostname.as_bytes(), &path_rule, &method_rule)
+                self.remove_tree_rule(hostname.as_bytes(), &path_rule, &method_rule)
             }
         };
         if !remove_success {
@@ -205,9 +278,12 @@ impl Router {
         hostname: &[u8],
         path: &PathRule,
         method: &MethodRule,
+        options: &MatchOptions,
         cluster: &Route,
     ) -> bool {
-        let hostname = match from_utf8(hostname) {
+        // requests are looked up again with their lowercase hostname for these rules
+        let hostname = options.normalize_hostname(hostname);
+        let hostname = match from_utf8(&hostname) {
             Err(_) => return false,
             Ok(h) => h,
         };
@@ -220,8 +296,13 @@ impl Router {
                     self.tree.domain_lookup_mut(hostname.as_bytes(), false)
                 {
                     empty = false;
-                    if !paths.iter().any(|(p, m, _)| p == path && m == method) {
-                        paths.push((path.to_owned(), method.to_owned(), cluster.to_owned()));
+                    if !paths.iter().any(|(p, m, _, _)| p == path && m == method) {
+                        paths.push((
+                            path.to_owned(),
+                            method.to_owned(),
+                            options.to_owned(),
+                            cluster.to_owned(),
+                        ));
                         return true;
                     }
                 }
@@ -229,7 +310,12 @@ impl Router {
                 if empty {
                     self.tree.domain_insert(
                         hostname.into_bytes(),
-                        vec![(path.to_owned(), method.to_owned(), cluster.to_owned())],
+                        vec![(
+                            path.to_owned(),
+                            method.to_owned(),
+                            options.to_owned(),
+                            cluster.to_owned(),
+                        )],
                     );
                     return true;
                 }
@@ -258,7 +344,7 @@ impl Router {
                     let paths_opt = self.tree.domain_lookup_mut(hostname.as_bytes(), false);
 
                     if let Some((_, paths)) = paths_opt {
-                        paths.retain(|(p, m, _)| p != path || m != method);
+                        paths.retain(|(p, m, _, _)| p != path || m != method);
                     }
 
                     paths_opt
@@ -282,17 +368,19 @@ impl Router {
         domain: &DomainRule,
         path: &PathRule,
         method: &MethodRule,
+        options: &MatchOptions,
         cluster_id: &Route,
     ) -> bool {
         if !self
             .pre
             .iter()
-            .any(|(d, p, m, _)| d == domain && p == path && m == method)
+            .any(|(d, p, m, _, _)| d == domain && p == path && m == method)
         {
             self.pre.push((
                 domain.to_owned(),
                 path.to_owned(),
                 method.to_owned(),
+                options.to_owned(),
                 cluster_id.to_owned(),
             ));
             true
@@ -306,17 +394,19 @@ impl Router {
         domain: &DomainRule,
         path: &PathRule,
         method: &MethodRule,
+        options: &MatchOptions,
         cluster_id: &Route,
     ) -> bool {
         if !self
             .post
             .iter()
-            .any(|(d, p, m, _)| d == domain && p == path && m == method)
+            .any(|(d, p, m, _, _)| d == domain && p == path && m == method)
         {
             self.post.push((
                 domain.to_owned(),
                 path.to_owned(),
                 method.to_owned(),
+                options.to_owned(),
                 cluster_id.to_owned(),
             ));
             true
@@ -334,7 +424,7 @@ impl Router {
         match self
             .pre
             .iter()
-            .position(|(d, p, m, _)| d == domain && p == path && m == method)
+            .position(|(d, p, m, _, _)| d == domain && p == path && m == method)
         {
             None => false,
             Some(index) => {
@@ -353,7 +443,7 @@ impl Router {
         match self
             .post
             .iter()
-            .position(|(d, p, m, _)| d == domain && p == path && m == method)
+            .position(|(d, p, m, _, _)| d == domain && p == path && m == method)
         {
             None => false,
             Some(index) => {
@@ -557,6 +647,128 @@ impl std::cmp::PartialEq for PathRule {
     }
 }
 
+/// Normalizations applied to the hostname and path of requests before matching them
+/// with a rule, so that differently encoded requests reach the same frontend
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MatchOptions {
+    pub case_insensitive: bool,
+    pub ignore_trailing_slash: bool,
+    pub percent_decode: bool,
+}
+
+impl MatchOptions {
+    pub fn from_config(options: Option<&MatchingOptions>) -> Self {
+        match options {
+            Some(options) => MatchOptions {
+                case_insensitive: options.case_insensitive.unwrap_or(false),
+                ignore_trailing_slash: options.ignore_trailing_slash.unwrap_or(false),
+                percent_decode: options.percent_decode.unwrap_or(false),
+            },
+            None => MatchOptions::default(),
+        }
+    }
+
+    /// normalizes a rule like the paths it will be compared to. Regexes are
+    /// not rewritten, but made case insensitive if needed
+    pub fn normalize_rule(&self, rule: PathRule) -> Option<PathRule> {
+        Some(match rule {
+            PathRule::Prefix(prefix) => PathRule::Prefix(self.normalize_pattern(prefix)?),
+            PathRule::Equals(pattern) => PathRule::Equals(self.normalize_pattern(pattern)?),
+            PathRule::Regex(regex) if self.case_insensitive => PathRule::Regex(
+                RegexBuilder::new(regex.as_str())
+                    .case_insensitive(true)
+                    .build()
+                    .ok()?,
+            ),
+            PathRule::Regex(regex) => PathRule::Regex(regex),
+        })
+    }
+
+    fn normalize_pattern(&self, pattern: String) -> Option<String> {
+        let mut pattern = pattern.into_bytes();
+        if self.percent_decode {
+            pattern = percent_decode(&pattern).into_owned();
+        }
+        if self.case_insensitive {
+            pattern.make_ascii_lowercase();
+        }
+        String::from_utf8(pattern).ok()
+    }
+
+    pub fn normalize_hostname<'a>(&self, hostname: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.case_insensitive && hostname.iter().any(u8::is_ascii_uppercase) {
+            Cow::Owned(hostname.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(hostname)
+        }
+    }
+
+    /// matches the normalized path against a rule normalized with [`Self::normalize_rule`]
+    pub fn matches(&self, rule: &PathRule, path: &[u8]) -> PathRuleResult {
+        if *self == MatchOptions::default() {
+            return rule.matches(path);
+        }
+
+        let mut path = Cow::Borrowed(path);
+        if self.percent_decode {
+            path = Cow::Owned(percent_decode(&path).into_owned());
+        }
+        // case insensitive regexes do not need a lowercase path
+        if self.case_insensitive && !matches!(rule, PathRule::Regex(_)) {
+            path.to_mut().make_ascii_lowercase();
+        }
+
+        let result = rule.matches(&path);
+        if result != PathRuleResult::None || !self.ignore_trailing_slash {
+            return result;
+        }
+
+        // try again with the trailing slash added or removed, keeping the query.
+        // Decoding does not add a `?`, it stays encoded
+        let query_start = path.iter().position(|c| *c == b'?').unwrap_or(path.len());
+        let (path, query) = path.split_at(query_start);
+        let toggled = match path.strip_suffix(b"/") {
+            Some(b"") => return result,
+            Some(stripped) => [stripped, query].concat(),
+            None => [path, b"/", query].concat(),
+        };
+        rule.matches(&toggled)
+    }
+}
+
+/// decodes the `%XX` sequences of a path, except the encoded `/`, `?` and `%`
+/// that would change its segments, its query or its other sequences. These
+/// are kept with uppercase digits
+fn percent_decode(path: &[u8]) -> Cow<'_, [u8]> {
+    if !path.contains(&b'%') {
+        return Cow::Borrowed(path);
+    }
+
+    let hex_value = |c: u8| (c as char).to_digit(16).map(|digit| digit as u8);
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut index = 0;
+    while index < path.len() {
+        if path[index] == b'%' && index + 2 < path.len() {
+            if let (Some(high), Some(low)) =
+                (hex_value(path[index + 1]), hex_value(path[index + 2]))
+            {
+                let c = high << 4 | low;
+                if matches!(c, b'/' | b'?' | b'%') {
+                    decoded.push(b'%');
+                    decoded.extend(path[index + 1..index + 3].to_ascii_uppercase());
+                } else {
+                    decoded.push(c);
+                }
+                index += 3;
+                continue;
+            }
+        }
+        decoded.push(path[index]);
+        index += 1;
+    }
+    Cow::Owned(decoded)
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MethodRule {
     pub inner: Option<Method>,
@@ -590,6 +802,54 @@ impl MethodRule {
     }
 }
 
+/// The domain part of a frontend rule
+pub enum RuleDomain<'a> {
+    /// rule of the pre or post routing rules
+    Rule(&'a DomainRule),
+    /// hostname of the tree, possibly a wildcard
+    Hostname(&'a [u8]),
+}
+
+/// A frontend rule matched by a request, to describe routing decisions
+pub struct MatchedRule<'a> {
+    pub position: RulePosition,
+    pub domain: RuleDomain<'a>,
+    pub path: &'a PathRule,
+    pub method: &'a MethodRule,
+    pub route: &'a Route,
+}
+
+impl std::fmt::Display for MatchedRule<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let position = match self.position {
+            RulePosition::Pre => "pre",
+            RulePosition::Tree => "tree",
+            RulePosition::Post => "post",
+        };
+        write!(f, "{position} ")?;
+        match &self.domain {
+            RuleDomain::Rule(DomainRule::Any) => write!(f, "*")?,
+            RuleDomain::Rule(DomainRule::Exact(hostname))
+            | RuleDomain::Rule(DomainRule::Wildcard(hostname)) => write!(f, "{hostname}")?,
+            RuleDomain::Rule(DomainRule::Regex(regex)) => write!(f, "/{}/", regex.as_str())?,
+            RuleDomain::Hostname(hostname) => write!(f, "{}", String::from_utf8_lossy(hostname))?,
+        }
+        match self.path {
+            PathRule::Prefix(prefix) => write!(f, " prefix {prefix}")?,
+            PathRule::Equals(pattern) => write!(f, " equals {pattern}")?,
+            PathRule::Regex(regex) => write!(f, " regex {}", regex.as_str())?,
+        }
+        match &self.method.inner {
+            Some(method) => write!(f, " {method}")?,
+            None => write!(f, " any method")?,
+        }
+        match self.route {
+            Route::Deny => write!(f, " -> deny"),
+            Route::ClusterId(cluster_id) => write!(f, " -> {cluster_id}"),
+        }
+    }
+}
+
 /// The cluster to which the traffic will be redirected
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum Route {
@@ -715,6 +975,7 @@ mod tests {
             b"*.sozu.io",
             &PathRule::Prefix("".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &MatchOptions::default(),
             &Route::ClusterId("base".to_string())
         ));
         println!("{:#?}", router.tree);
@@ -726,6 +987,7 @@ mod tests {
             b"*.sozu.io",
             &PathRule::Prefix("/api".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &MatchOptions::default(),
             &Route::ClusterId("api".to_string())
         ));
         println!("{:#?}", router.tree);
@@ -754,6 +1016,7 @@ mod tests {
             b"*.sozu.io",
             &PathRule::Prefix("".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &MatchOptions::default(),
             &Route::ClusterId("base".to_string())
         ));
         println!("{:#?}", router.tree);
@@ -765,6 +1028,7 @@ mod tests {
             b"api.sozu.io",
             &PathRule::Prefix("".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &MatchOptions::default(),
             &Route::ClusterId("api".to_string())
         ));
         println!("{:#?}", router.tree);
@@ -786,6 +1050,7 @@ mod tests {
             b"www./.*/.io",
             &PathRule::Prefix("".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &MatchOptions::default(),
             &Route::ClusterId("base".to_string())
         ));
         println!("{:#?}", router.tree);
@@ -793,6 +1058,7 @@ mod tests {
             b"www.doc./.*/.io",
             &PathRule::Prefix("".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &MatchOptions::default(),
             &Route::ClusterId("doc".to_string())
         ));
         println!("{:#?}", router.tree);
@@ -825,24 +1091,28 @@ mod tests {
             &"*".parse::<DomainRule>().unwrap(),
             &PathRule::Prefix("/.well-known/acme-challenge".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &MatchOptions::default(),
             &Route::ClusterId("acme".to_string())
         ));
         assert!(router.add_tree_rule(
             "www.example.com".as_bytes(),
             &PathRule::Prefix("/".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &MatchOptions::default(),
             &Route::ClusterId("example".to_string())
         ));
         assert!(router.add_tree_rule(
             "*.test.example.com".as_bytes(),
             &PathRule::Regex(Regex::new("/hello[A-Z]+/").unwrap()),
             &MethodRule::new(Some("GET".to_string())),
+            &MatchOptions::default(),
             &Route::ClusterId("examplewildcard".to_string())
         ));
         assert!(router.add_tree_rule(
             "/test[0-9]/.example.com".as_bytes(),
             &PathRule::Prefix("/".to_string()),
             &MethodRule::new(Some("GET".to_string())),
+            &MatchOptions::default(),
             &Route::ClusterId("exampleregex".to_string())
         ));
 
@@ -874,4 +1144,139 @@ mod tests {
             Ok(Route::ClusterId("exampleregex".to_string()))
         );
     }
+
+    #[test]
+    fn matching_options() {
+        let mut router = Router::new();
+        let options = MatchOptions {
+            case_insensitive: true,
+            ignore_trailing_slash: true,
+            percent_decode: true,
+        };
+
+        assert!(router.add_tree_rule(
+            b"www.example.com",
+            &options
+                .normalize_rule(PathRule::Equals("/API/Users".to_string()))
+                .unwrap(),
+            &MethodRule::new(None),
+            &options,
+            &Route::ClusterId("api".to_string())
+        ));
+        assert!(router.add_tree_rule(
+            b"www.example.com",
+            &PathRule::Prefix("/static/".to_string()),
+            &MethodRule::new(None),
+            &MatchOptions::default(),
+            &Route::ClusterId("static".to_string())
+        ));
+
+        for path in ["/api/users", "/Api/Users/", "/api/%55sers", "/API/users/"] {
+            assert_eq!(
+                router.lookup("WWW.Example.com", path, &Method::Get),
+                Ok(Route::ClusterId("api".to_string())),
+                "path {path} should match"
+            );
+        }
+        assert!(router
+            .lookup("www.example.com", "/api%2Fusers", &Method::Get)
+            .is_err());
+
+        // rules without options keep the exact matching
+        assert_eq!(
+            router.lookup("www.example.com", "/static/style.css", &Method::Get),
+            Ok(Route::ClusterId("static".to_string()))
+        );
+        assert!(router
+            .lookup("www.example.com", "/Static/style.css", &Method::Get)
+            .is_err());
+        assert!(router
+            .lookup("WWW.example.com", "/static/style.css", &Method::Get)
+            .is_err());
+    }
+
+    #[test]
+    fn matching_options_keep_the_path_structure() {
+        let mut router = Router::new();
+        let options = MatchOptions {
+            case_insensitive: false,
+            ignore_trailing_slash: true,
+            percent_decode: true,
+        };
+
+        assert!(router.add_tree_rule(
+            b"www.example.com",
+            &options
+                .normalize_rule(PathRule::Equals("/files/what%3fx".to_string()))
+                .unwrap(),
+            &MethodRule::new(None),
+            &options,
+            &Route::ClusterId("files".to_string())
+        ));
+
+        // the encoded `?` is not the start of the query
+        for path in ["/files/what%3Fx", "/files/what%3fx/"] {
+            assert_eq!(
+                router.lookup("www.example.com", path, &Method::Get),
+                Ok(Route::ClusterId("files".to_string())),
+                "path {path} should match"
+            );
+        }
+        assert!(router
+            .lookup("www.example.com", "/files/what?x", &Method::Get)
+            .is_err());
+    }
+
+    #[test]
+    fn case_insensitive_hostnames() {
+        let mut router = Router::new();
+        let options = MatchOptions {
+            case_insensitive: true,
+            ..Default::default()
+        };
+
+        assert!(router.add_tree_rule(
+            b"API.Example.com",
+            &PathRule::Prefix("/".to_string()),
+            &MethodRule::new(None),
+            &options,
+            &Route::ClusterId("api".to_string())
+        ));
+        for hostname in ["api.example.com", "API.EXAMPLE.COM", "Api.Example.Com"] {
+            assert_eq!(
+                router.lookup(hostname, "/", &Method::Get),
+                Ok(Route::ClusterId("api".to_string())),
+                "hostname {hostname} should match"
+            );
+        }
+
+        let front = HttpFrontend {
+            cluster_id: Some("api".to_string()),
+            address: "0.0.0.0:80".parse().unwrap(),
+            hostname: "WWW.Example.com".to_string(),
+            path: CommandPathRule::prefix("/".to_string()),
+            method: None,
+            position: RulePosition::Pre,
+            tags: None,
+            matching: Some(MatchingOptions {
+                case_insensitive: Some(true),
+                ..Default::default()
+            }),
+            debug_trace: None,
+        };
+        router.add_http_front(&front).unwrap();
+        assert_eq!(
+            router.lookup("www.EXAMPLE.com", "/", &Method::Get),
+            Ok(Route::ClusterId("api".to_string()))
+        );
+        router.remove_http_front(&front).unwrap();
+        assert!(router.lookup("www.example.com", "/", &Method::Get).is_err());
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(&percent_decode(b"/a%20b%2fc%zz%4")[..], b"/a b%2Fc%zz%4");
+        assert_eq!(&percent_decode(b"/a%3fb%3F%2541")[..], b"/a%3Fb%3F%2541");
+        assert_eq!(&percent_decode(b"/plain")[..], b"/plain");
+    }
 }
@@ -277,34 +277,36 @@ impl<V: Debug + Clone> TrieNode<V> {
         };
         //println!("lookup: prefix|suffix: {} | {}", std::str::from_utf8(prefix).unwrap(), std::str::from_utf8(suffix).unwrap());
 
-        match self.children.get(suffix) {
-            Some(child) => child.lookup(prefix, accept_wildcard),
-            None => {
-                //println!("no child found, testing wildcard and regexps");
-
-                if prefix.is_empty() && self.wildcard.is_some() && accept_wildcard {
-                    //println!("no dot, wildcard applies");
-                    self.wildcard.as_ref()
-                } else {
-                    //println!("there's still a subdomain, wildcard does not apply");
+        // the most specific match wins: an exact name, then a wildcard, then a regexp.
+        // A child may exist only for longer names, the less specific matches apply then
+        if let Some(child) = self.children.get(suffix) {
+            if let Some(key_value) = child.lookup(prefix, accept_wildcard) {
+                return Some(key_value);
+            }
+        }
+        //println!("no child found, testing wildcard and regexps");
 
-                    for (ref regexp, ref child) in self.regexps.iter() {
-                        let suffix = if suffix[0] == b'.' {
-                            &suffix[1..]
-                        } else {
-                            suffix
-                        };
-                        //println!("testing regexp: {} on suffix {}", r.as_str(), str::from_utf8(s).unwrap());
+        if prefix.is_empty() && self.wildcard.is_some() && accept_wildcard {
+            //println!("no dot, wildcard applies");
+            self.wildcard.as_ref()
+        } else {
+            //println!("there's still a subdomain, wildcard does not apply");
 
-                        if regexp.is_match(suffix) {
-                            //println!("matched");
-                            return child.lookup(prefix, accept_wildcard);
-                        }
-                    }
+            for (ref regexp, ref child) in self.regexps.iter() {
+                let suffix = if suffix[0] == b'.' {
+                    &suffix[1..]
+                } else {
+                    suffix
+                };
+                //println!("testing regexp: {} on suffix {}", r.as_str(), str::from_utf8(s).unwrap());
 
-                    None
+                if regexp.is_match(suffix) {
+                    //println!("matched");
+                    return child.lookup(prefix, accept_wildcard);
                 }
             }
+
+            None
         }
     }
 
@@ -758,6 +760,17 @@ mod tests {
             root.domain_lookup(b"pgstudio.services.clever-cloud.com", true),
             Some(&("*.services.clever-cloud.com".as_bytes().to_vec(), 1u8))
         );
+
+        // the node of "api" only leads to a longer name, the wildcard still applies
+        root.domain_insert("v1.api.clever-cloud.com".as_bytes().to_vec(), 3u8);
+        assert_eq!(
+            root.domain_lookup(b"api.clever-cloud.com", true),
+            Some(&("*.clever-cloud.com".as_bytes().to_vec(), 2u8))
+        );
+        assert_eq!(
+            root.domain_lookup(b"services.clever-cloud.com", true),
+            Some(&("services.clever-cloud.com".as_bytes().to_vec(), 0u8))
+        );
     }
 
     fn hm_insert(h: std::collections::HashMap<String, u32>) -> bool {
@@ -1,8 +1,9 @@
 //! event loop management
 use std::{
     cell::RefCell,
-    collections::{HashSet, VecDeque},
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
     io::Error as IoError,
+    net::SocketAddr,
     os::unix::io::{AsRawFd, FromRawFd},
     rc::Rc,
     time::{Duration, Instant},
@@ -22,17 +23,19 @@ use sozu_command::{
         CertificatesWithFingerprints, Cluster, ClusterHashes, ClusterInformations,
         DeactivateListener, Event, HttpListenerConfig, HttpsListenerConfig, InitialState,
         ListenerType, LoadBalancingAlgorithms, LoadMetric, MetricsConfiguration, RemoveBackend,
-        Request, ResponseStatus, ServerConfig, TcpListenerConfig as CommandTcpListener,
-        WorkerRequest, WorkerResponse,
+        Request, ResponseStatus, ServerConfig, StickySessionFallback,
+        TcpListenerConfig as CommandTcpListener, WorkerRequest, WorkerResponse,
     },
     ready::Ready,
-    scm_socket::{Listeners, ScmSocket, ScmSocketError},
-    state::ConfigState,
+    scm_socket::{Listeners, ScmSocket, ScmSocketError, SessionSockets},
+    state::{ClusterId, ConfigState},
 };
 
 use crate::{
     backends::{Backend, BackendMap},
+    crash,
     features::FEATURES,
+    handoff::{SessionHandoff, MAX_HANDED_OFF_SESSIONS},
     http, https,
     metrics::METRICS,
     pool::Pool,
@@ -44,6 +47,9 @@ use crate::{
 // Number of retries to perform on a server after a connection failure
 pub const CONN_RETRIES: u8 = 3;
 
+// Interval at which a draining listener reports how many sessions are left
+pub const DRAIN_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
 pub type ProxyChannel = Channel<WorkerResponse, WorkerRequest>;
 
 thread_local! {
@@ -54,6 +60,64 @@ thread_local! {
   pub static TIMER: RefCell<Timer<Token>> = RefCell::new(Timer::default());
 }
 
+thread_local! {
+  /// sessions waiting for a saturated backend of a cluster to free a connection
+  pub static BACKEND_QUEUES: RefCell<HashMap<ClusterId, Vec<Token>>> = RefCell::new(HashMap::new());
+}
+
+thread_local! {
+  /// sessions to wake up once a deadline is reached
+  pub static WAKE_UPS: RefCell<BTreeSet<(Instant, Token)>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+thread_local! {
+  /// sessions to wake up on the next event loop iteration
+  pub static WOKEN_UP: RefCell<Vec<Token>> = const { RefCell::new(Vec::new()) };
+}
+
+/// the session will be woken up when a connection to a backend of the cluster is released
+pub fn queue_for_backend(cluster_id: &str, token: Token) {
+    BACKEND_QUEUES.with(|waiting| {
+        let mut waiting = waiting.borrow_mut();
+        let tokens = waiting.entry(cluster_id.to_owned()).or_default();
+        if !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    });
+}
+
+/// wakes up the sessions waiting for a connection slot in the cluster: a connection
+/// was released, or a backend was added or came back up
+pub fn backend_available(cluster_id: &str) {
+    if let Some(tokens) = BACKEND_QUEUES.with(|waiting| waiting.borrow_mut().remove(cluster_id)) {
+        WOKEN_UP.with(|woken_up| woken_up.borrow_mut().extend(tokens));
+    }
+}
+
+/// the session will be woken up once the deadline is reached, to resume what it was
+/// waiting for. The timer wheel ticks every 100ms, too coarse for millisecond delays
+pub fn wake_up_at(token: Token, deadline: Instant) {
+    WAKE_UPS.with(|wake_ups| {
+        wake_ups.borrow_mut().insert((deadline, token));
+    });
+}
+
+/// forgets the wake-ups of a closed session, its token may be reused by another session
+pub fn forget_session(token: Token) {
+    BACKEND_QUEUES.with(|waiting| {
+        waiting.borrow_mut().retain(|_, tokens| {
+            tokens.retain(|waiting| *waiting != token);
+            !tokens.is_empty()
+        })
+    });
+    WAKE_UPS.with(|wake_ups| {
+        wake_ups
+            .borrow_mut()
+            .retain(|(_, waking_up)| *waking_up != token)
+    });
+    WOKEN_UP.with(|woken_up| woken_up.borrow_mut().retain(|woken_up| *woken_up != token));
+}
+
 pub fn push_queue(message: WorkerResponse) {
     QUEUE.with(|queue| {
         (*queue.borrow_mut()).push_back(message);
@@ -179,6 +243,74 @@ impl SessionManager {
     }
 }
 
+/// Limits the rate of new connections accepted on a listener with a token bucket,
+/// so that a reconnection storm stays in the kernel backlog instead of flooding the worker
+#[derive(Debug)]
+pub struct AcceptPacer {
+    /// connections per second
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl AcceptPacer {
+    /// returns None if the listener has no rate
+    pub fn new(rate: Option<u32>, burst: Option<u32>) -> Option<Self> {
+        let rate = rate.filter(|rate| *rate > 0)?;
+        let burst = burst.unwrap_or(rate).max(1) as f64;
+        Some(AcceptPacer {
+            rate: rate as f64,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+        })
+    }
+
+    /// takes a token for a new connection, returns false if the listener must wait
+    pub fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = (now - self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// when the bucket will hold a token again
+    pub fn next_token_at(&self) -> Instant {
+        let missing = (1.0 - self.tokens).max(0.0);
+        self.last_refill + Duration::from_secs_f64(missing / self.rate)
+    }
+
+    /// gives back the token of a connection that could not be accepted
+    pub fn release(&mut self) {
+        self.tokens = (self.tokens + 1.0).min(self.burst);
+    }
+}
+
+/// accepts a connection with the pacer of the listener, if it has one
+pub fn paced_accept<T>(
+    pacer: &mut Option<AcceptPacer>,
+    accept: impl FnOnce() -> Result<T, AcceptError>,
+) -> Result<T, AcceptError> {
+    let Some(pacer) = pacer else {
+        return accept();
+    };
+    if !pacer.try_acquire() {
+        return Err(AcceptError::Paced(pacer.next_token_at()));
+    }
+    let result = accept();
+    if result.is_err() {
+        pacer.release();
+    }
+    result
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum ServerError {
     #[error("could not create event loop with MIO poll: {0}")]
@@ -214,11 +346,16 @@ pub struct Server {
     accept_queue_timeout: Duration,
     accept_queue: VecDeque<(TcpStream, ListenToken, Protocol, Instant)>,
     accept_ready: HashSet<ListenToken>,
+    /// listeners that reached their accept rate, with connections left in the backlog,
+    /// and when their pacer allows a new connection
+    accept_paced: HashMap<ListenToken, Instant>,
     backends: Rc<RefCell<BackendMap>>,
     base_sessions_count: usize,
     channel: ProxyChannel,
     config_state: ConfigState,
     current_poll_errors: i32,
+    /// deactivated listeners waiting for their sessions to finish
+    draining: Vec<Drain>,
     http: Rc<RefCell<http::HttpProxy>>,
     https: Rc<RefCell<https::HttpsProxy>>,
     last_sessions_len: usize,
@@ -246,6 +383,9 @@ impl Server {
         expects_initial_status: bool,
     ) -> Result<Self, ServerError> {
         let event_loop = Poll::new().map_err(ServerError::CreatePoll)?;
+        METRICS.with(|metrics| {
+            (*metrics.borrow_mut()).set_up_metrics_tag(config.metrics_tag.clone());
+        });
         let pool = Rc::new(RefCell::new(Pool::with_capacity(
             config.min_buffers as usize,
             config.max_buffers as usize,
@@ -336,6 +476,7 @@ impl Server {
         });
 
         let base_sessions_count = sessions.borrow().slab.len();
+        crash::watch_sessions(&sessions);
 
         let http = Rc::new(RefCell::new(match http {
             Some(http) => http,
@@ -379,11 +520,13 @@ impl Server {
             )),
             accept_queue: VecDeque::new(),
             accept_ready: HashSet::new(),
+            accept_paced: HashMap::new(),
             backends,
             base_sessions_count,
             channel,
             config_state: ConfigState::new(),
             current_poll_errors: 0,
+            draining: Vec::new(),
             http,
             https,
             last_sessions_len: 0, // to be reset on server run
@@ -566,11 +709,13 @@ impl Server {
                 }
             }
             self.handle_remaining_readiness();
+            self.wake_up_sessions();
             self.create_sessions();
 
             self.should_poll_at = TIMER.with(|timer| timer.borrow().next_poll_date());
 
             self.zombie_check();
+            self.drain_listeners();
 
             let now = time::OffsetDateTime::now_utc();
             // clear the local metrics drain every plain hour (01:00, 02:00, etc.) to prevent memory overuse
@@ -610,14 +755,34 @@ impl Server {
         let now = Instant::now();
         time!("event_loop_time", (now - self.loop_start).as_millis());
 
+        let next_wake_up = WAKE_UPS.with(|wake_ups| {
+            wake_ups
+                .borrow()
+                .first()
+                .map(|(deadline, _)| deadline.saturating_duration_since(now))
+        });
+        let poll_timeout = match (self.poll_timeout, next_wake_up) {
+            (Some(timeout), Some(wake_up)) => Some(timeout.min(wake_up)),
+            (timeout, wake_up) => timeout.or(wake_up),
+        };
+        let next_accept = self
+            .accept_paced
+            .values()
+            .min()
+            .map(|retry_at| retry_at.saturating_duration_since(now));
+        let poll_timeout = match (poll_timeout, next_accept) {
+            (Some(timeout), Some(next_accept)) => Some(timeout.min(next_accept)),
+            (timeout, next_accept) => timeout.or(next_accept),
+        };
+
         let timeout = match self.should_poll_at.as_ref() {
-            None => self.poll_timeout,
+            None => poll_timeout,
             Some(i) => {
                 if *i <= now {
-                    self.poll_timeout
+                    poll_timeout
                 } else {
                     let dur = *i - now;
-                    match self.poll_timeout {
+                    match poll_timeout {
                         None => Some(dur),
                         Some(t) => {
                             if t < dur {
@@ -676,6 +841,34 @@ impl Server {
                             )),
                         }
                     }
+                    Some(RequestType::ReturnSessions(_)) => {
+                        info!("received ReturnSessions order");
+                        match self.return_sessions() {
+                            Ok(count) => {
+                                let mut response = WorkerResponse::ok(request.id);
+                                response.message = format!("handed off {count} sessions");
+                                push_queue(response);
+                            }
+                            Err(error) => push_queue(worker_response_error(
+                                request.id,
+                                format!("Could not send sessions on scm socket: {error:?}"),
+                            )),
+                        }
+                    }
+                    Some(RequestType::AdoptSessions(_)) => {
+                        info!("received AdoptSessions order");
+                        match self.adopt_sessions() {
+                            Ok(count) => {
+                                let mut response = WorkerResponse::ok(request.id);
+                                response.message = format!("adopted {count} sessions");
+                                push_queue(response);
+                            }
+                            Err(error) => push_queue(worker_response_error(
+                                request.id,
+                                format!("Could not receive sessions on scm socket: {error:?}"),
+                            )),
+                        }
+                    }
                     _ => self.notify(request),
                 },
                 // Not an error per se, occurs when there is nothing to read
@@ -730,6 +923,81 @@ impl Server {
         );
     }
 
+    /// Waits for the sessions of a deactivated listener to finish before answering
+    fn start_drain(&mut self, request_id: String, address: SocketAddr, drain_timeout: u32) {
+        info!(
+            "{} draining the sessions of listener {} for at most {} seconds",
+            request_id, address, drain_timeout
+        );
+        let now = Instant::now();
+        self.draining.push(Drain {
+            request_id,
+            address,
+            deadline: now + Duration::from_secs(u64::from(drain_timeout)),
+            last_report: now,
+        });
+    }
+
+    /// Asks the sessions of the draining listeners to finish, answers the
+    /// deactivation once they are closed, or closes them once the deadline passed
+    fn drain_listeners(&mut self) {
+        if self.draining.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+
+        for mut drain in std::mem::take(&mut self.draining) {
+            let expired = now >= drain.deadline;
+            let mut to_close = HashSet::new();
+            let mut remaining = HashSet::new();
+
+            for (_key, session) in &self.sessions.borrow().slab {
+                if session.borrow().listener_address() != Some(drain.address) {
+                    continue;
+                }
+                // a session has several entries, one per socket
+                let token = session.borrow().frontend_token();
+                if to_close.contains(&token) || remaining.contains(&token) {
+                    continue;
+                }
+                if expired || session.borrow_mut().shutting_down() {
+                    to_close.insert(token);
+                } else {
+                    remaining.insert(token);
+                }
+            }
+
+            let closed = to_close.len();
+            let _ = self.shut_down_sessions_by_frontend_tokens(to_close);
+
+            if remaining.is_empty() {
+                let mut response = WorkerResponse::ok(&drain.request_id);
+                if expired && closed > 0 {
+                    count!("drain.closed_sessions", closed as i64);
+                    response.message = format!(
+                        "closed {closed} sessions still open at the end of the drain of listener {}",
+                        drain.address
+                    );
+                }
+                info!("{} drained listener {}", drain.request_id, drain.address);
+                push_queue(response);
+                continue;
+            }
+
+            if now - drain.last_report >= DRAIN_REPORT_INTERVAL {
+                let mut response = WorkerResponse::processing(&drain.request_id);
+                response.message = format!(
+                    "{} sessions left on listener {}",
+                    remaining.len(),
+                    drain.address
+                );
+                push_queue(response);
+                drain.last_report = now;
+            }
+            self.draining.push(drain);
+        }
+    }
+
     /// Calls close on targeted sessions, yields the number of entries in the slab
     /// that were not properly removed
     fn shut_down_sessions_by_frontend_tokens(&self, tokens: HashSet<Token>) -> usize {
@@ -739,6 +1007,7 @@ impl Server {
 
         // close the sessions associated with the tokens
         for token in &tokens {
+            forget_session(*token);
             if self.sessions.borrow().slab.contains(token.0) {
                 let session = { self.sessions.borrow_mut().slab.remove(token.0) };
                 session.borrow_mut().close();
@@ -848,7 +1117,7 @@ impl Server {
                         }
                     }
 
-                    if self.channel.back_buf.available_data() > 0 {
+                    if self.channel.has_pending_writes() {
                         if let Err(e) = self.channel.writable() {
                             error!("error writing to channel: {:?}", e);
                         }
@@ -858,7 +1127,7 @@ impl Server {
                         break;
                     }
 
-                    if self.channel.back_buf.available_data() == 0 && queue.len() == 0 {
+                    if !self.channel.has_pending_writes() && queue.len() == 0 {
                         break;
                     }
                 }
@@ -883,6 +1152,25 @@ impl Server {
                 }
                 return;
             }
+            Some(RequestType::ConfigureFaultInjection(fault_injection)) => {
+                info!(
+                    "{} configuring fault injection on cluster {}: {}% of the requests",
+                    message.id, fault_injection.cluster_id, fault_injection.percentage
+                );
+                self.backends
+                    .borrow_mut()
+                    .set_fault_injection_for_cluster(fault_injection);
+                push_queue(WorkerResponse::ok(message.id));
+                return;
+            }
+            Some(RequestType::QueryMetricsConfiguration(_)) => {
+                let status = METRICS.with(|metrics| (*metrics.borrow()).status());
+                push_queue(WorkerResponse::ok_with_content(
+                    message.id,
+                    ContentType::MetricsStatus(status).into(),
+                ));
+                return;
+            }
             Some(RequestType::QueryMetrics(query_metrics_options)) => {
                 METRICS.with(|metrics| {
                     match (*metrics.borrow_mut()).query(query_metrics_options) {
@@ -974,6 +1262,7 @@ impl Server {
     }
 
     pub fn notify_proxys(&mut self, request: WorkerRequest) {
+        crash::applied_request(&request.id);
         if let Err(e) = self.config_state.dispatch(&request.content) {
             error!("Could not execute order on config state: {}", e);
         }
@@ -1043,7 +1332,13 @@ impl Server {
                 push_queue(self.notify_activate_listener(&req_id, activate));
             }
             Some(RequestType::DeactivateListener(ref deactivate)) => {
-                push_queue(self.notify_deactivate_listener(&req_id, deactivate));
+                let response = self.notify_deactivate_listener(&req_id, deactivate);
+                match deactivate.drain_timeout {
+                    Some(drain_timeout) if !response.is_failure() => {
+                        self.start_drain(req_id, deactivate.address.into(), drain_timeout)
+                    }
+                    _ => push_queue(response),
+                }
             }
             _other_request => {}
         };
@@ -1059,15 +1354,36 @@ impl Server {
                     .load_metric
                     .and_then(|n| LoadMetric::try_from(n).ok()),
             );
+        self.backends
+            .borrow_mut()
+            .set_retry_budget_for_cluster(&cluster.cluster_id, cluster.retry_budget);
+        self.backends
+            .borrow_mut()
+            .set_sticky_session_fallback_for_cluster(
+                &cluster.cluster_id,
+                cluster
+                    .sticky_session_fallback
+                    .and_then(|n| StickySessionFallback::try_from(n).ok())
+                    .unwrap_or_default(),
+            );
     }
 
     fn add_backend(&mut self, req_id: &str, add_backend: &AddBackend) -> WorkerResponse {
-        let new_backend = Backend::new(
+        let mut new_backend = Backend::new(
             &add_backend.backend_id,
             add_backend.address.clone().into(),
             add_backend.sticky_id.clone(),
             add_backend.load_balancing_parameters.clone(),
             add_backend.backup,
+            add_backend.priority,
+        );
+        new_backend.set_alternate_addresses(
+            add_backend
+                .alternate_addresses
+                .iter()
+                .cloned()
+                .map(Into::into)
+                .collect(),
         );
         self.backends
             .borrow_mut()
@@ -1446,6 +1762,140 @@ impl Server {
         res
     }
 
+    /// Takes the sessions that can survive an upgrade out of the worker, and sends
+    /// them with their sockets via the scm socket. Returns how many were handed off.
+    /// If they can not be sent, the sessions are recreated and stay in this worker
+    pub fn return_sessions(&mut self) -> Result<usize, ScmSocketError> {
+        // a session has several entries, one per socket
+        let mut seen = HashSet::new();
+        let sessions: Vec<Rc<RefCell<dyn ProxySession>>> = self
+            .sessions
+            .borrow()
+            .slab
+            .iter()
+            .filter(|(_, session)| seen.insert(Rc::as_ptr(session) as *const ()))
+            .map(|(_, session)| session.clone())
+            .collect();
+
+        let mut handoffs = Vec::new();
+        let mut tokens = HashSet::new();
+        for session in sessions {
+            if handoffs.len() >= MAX_HANDED_OFF_SESSIONS {
+                break;
+            }
+            let mut session = session.borrow_mut();
+            let Some(mut handoff) = session.hand_off() else {
+                continue;
+            };
+            for socket in [&mut handoff.frontend, &mut handoff.backend] {
+                if let Err(e) = self.poll.registry().deregister(socket) {
+                    error!(
+                        "error deregistering handed off socket({:?}): {:?}",
+                        socket, e
+                    );
+                }
+            }
+            forget_session(session.frontend_token());
+            tokens.insert(session.frontend_token());
+            handoffs.push(handoff);
+        }
+
+        let session_sockets: Vec<SessionSockets> = handoffs
+            .iter()
+            .map(|handoff| SessionSockets {
+                session: handoff.session.clone(),
+                frontend: handoff.frontend.as_raw_fd(),
+                backend: handoff.backend.as_raw_fd(),
+            })
+            .collect();
+
+        self.unblock_scm_socket();
+        let res = self.scm.send_sessions(&session_sockets);
+        self.block_scm_socket();
+
+        // the sessions that were handed off are empty shells for this worker
+        {
+            let mut sessions = self.sessions.borrow_mut();
+            sessions
+                .slab
+                .retain(|_, session| !tokens.contains(&session.borrow().frontend_token()));
+            for _ in &handoffs {
+                sessions.decr();
+            }
+        }
+
+        if let Err(error) = res {
+            // the sessions are recreated from their sockets, and drained by this worker
+            error!(
+                "could not hand off {} sessions, keeping them: {:?}",
+                handoffs.len(),
+                error
+            );
+            let mut kept = 0;
+            for handoff in handoffs {
+                match self.adopt_handoff(handoff) {
+                    Ok(()) => kept += 1,
+                    Err(error) => error!("could not keep a session: {:?}", error),
+                }
+            }
+            count!("upgrade.sessions.kept", kept as i64);
+            return Err(error);
+        }
+
+        // the sockets are closed here, the connections stay open in the new worker
+        count!("upgrade.sessions.handed_off", handoffs.len() as i64);
+        info!("handed off {} sessions", handoffs.len());
+        Ok(handoffs.len())
+    }
+
+    /// Receives the sessions handed off by another worker via the scm socket,
+    /// and recreates them. Returns how many were adopted
+    pub fn adopt_sessions(&mut self) -> Result<usize, ScmSocketError> {
+        let session_sockets = self.scm.receive_sessions()?;
+
+        let mut adopted = 0;
+        for sockets in session_sockets {
+            let handoff = SessionHandoff {
+                frontend: unsafe { TcpStream::from_raw_fd(sockets.frontend) },
+                backend: unsafe { TcpStream::from_raw_fd(sockets.backend) },
+                session: sockets.session,
+            };
+
+            match self.adopt_handoff(handoff) {
+                Ok(()) => adopted += 1,
+                Err(error) => error!("could not adopt a handed off session: {:?}", error),
+            }
+        }
+
+        count!("upgrade.sessions.adopted", adopted as i64);
+        Ok(adopted)
+    }
+
+    /// Recreates a handed off session from its sockets, in the listener it came from
+    fn adopt_handoff(&mut self, handoff: SessionHandoff) -> Result<(), AcceptError> {
+        let at_capacity = {
+            let sessions = self.sessions.borrow();
+            sessions.nb_connections >= sessions.max_connections || sessions.at_capacity()
+        };
+        if at_capacity {
+            return Err(AcceptError::TooManySessions);
+        }
+
+        match ListenerType::try_from(handoff.session.listener_type) {
+            Ok(ListenerType::Tcp) => self
+                .tcp
+                .borrow_mut()
+                .adopt_session(handoff, self.tcp.clone()),
+            Ok(ListenerType::Http) => self
+                .http
+                .borrow_mut()
+                .adopt_session(handoff, self.http.clone()),
+            _ => Err(AcceptError::WrongSocketAddress),
+        }?;
+        self.sessions.borrow_mut().incr();
+        Ok(())
+    }
+
     fn block_scm_socket(&mut self) {
         if let Err(e) = self.scm.set_blocking(true) {
             error!("Could not block scm socket: {}", e);
@@ -1467,65 +1917,36 @@ impl Server {
     }
 
     pub fn accept(&mut self, token: ListenToken, protocol: Protocol) {
-        match protocol {
-            Protocol::TCPListen => loop {
-                match self.tcp.borrow_mut().accept(token) {
-                    Ok(sock) => self.accept_queue.push_back((
-                        sock,
-                        token,
-                        Protocol::TCPListen,
-                        Instant::now(),
-                    )),
-                    Err(AcceptError::WouldBlock) => {
-                        self.accept_ready.remove(&token);
-                        break;
-                    }
-                    Err(other) => {
-                        error!("error accepting TCP sockets: {:?}", other);
-                        self.accept_ready.remove(&token);
-                        break;
-                    }
+        loop {
+            let accepted = match protocol {
+                Protocol::TCPListen => self.tcp.borrow_mut().accept(token),
+                Protocol::HTTPListen => self.http.borrow_mut().accept(token),
+                Protocol::HTTPSListen => self.https.borrow_mut().accept(token),
+                _ => panic!("should not call accept() on a HTTP, HTTPS or TCP session"),
+            };
+            match accepted {
+                Ok(sock) => self
+                    .accept_queue
+                    .push_back((sock, token, protocol, Instant::now())),
+                Err(AcceptError::WouldBlock) => {
+                    self.accept_ready.remove(&token);
+                    self.accept_paced.remove(&token);
+                    break;
                 }
-            },
-            Protocol::HTTPListen => loop {
-                match self.http.borrow_mut().accept(token) {
-                    Ok(sock) => self.accept_queue.push_back((
-                        sock,
-                        token,
-                        Protocol::HTTPListen,
-                        Instant::now(),
-                    )),
-                    Err(AcceptError::WouldBlock) => {
-                        self.accept_ready.remove(&token);
-                        break;
-                    }
-                    Err(other) => {
-                        error!("error accepting HTTP sockets: {:?}", other);
-                        self.accept_ready.remove(&token);
-                        break;
+                Err(AcceptError::Paced(retry_at)) => {
+                    // wait until the pacer of the listener allows new connections
+                    self.accept_ready.remove(&token);
+                    if self.accept_paced.insert(token, retry_at).is_none() {
+                        incr!("accept_queue.paced");
                     }
+                    break;
                 }
-            },
-            Protocol::HTTPSListen => loop {
-                match self.https.borrow_mut().accept(token) {
-                    Ok(sock) => self.accept_queue.push_back((
-                        sock,
-                        token,
-                        Protocol::HTTPSListen,
-                        Instant::now(),
-                    )),
-                    Err(AcceptError::WouldBlock) => {
-                        self.accept_ready.remove(&token);
-                        break;
-                    }
-                    Err(other) => {
-                        error!("error accepting HTTPS sockets: {:?}", other);
-                        self.accept_ready.remove(&token);
-                        break;
-                    }
+                Err(other) => {
+                    error!("error accepting sockets on {:?}: {:?}", protocol, other);
+                    self.accept_ready.remove(&token);
+                    break;
                 }
-            },
-            _ => panic!("should not call accept() on a HTTP, HTTPS or TCP session"),
+            }
         }
 
         gauge!("accept_queue.connections", self.accept_queue.len());
@@ -1647,7 +2068,55 @@ impl Server {
         }
     }
 
+    /// resume the sessions whose deadline is reached, or that were waiting for
+    /// a saturated backend that released a connection
+    pub fn wake_up_sessions(&mut self) {
+        let mut tokens = WOKEN_UP.with(|woken_up| std::mem::take(&mut *woken_up.borrow_mut()));
+
+        let now = Instant::now();
+        WAKE_UPS.with(|wake_ups| {
+            let mut wake_ups = wake_ups.borrow_mut();
+            while let Some((deadline, token)) = wake_ups.first().copied() {
+                if deadline > now {
+                    break;
+                }
+                wake_ups.pop_first();
+                if !tokens.contains(&token) {
+                    tokens.push(token);
+                }
+            }
+        });
+
+        for token in tokens {
+            let session = match self.sessions.borrow().slab.get(token.0) {
+                Some(session) => session.clone(),
+                None => continue,
+            };
+
+            let protocol = session.borrow().protocol();
+            if matches!(
+                protocol,
+                Protocol::HTTPListen | Protocol::HTTPSListen | Protocol::TCPListen
+            ) {
+                continue;
+            }
+
+            if session.borrow_mut().ready(session.clone()) {
+                self.kill_session(session);
+            }
+        }
+    }
+
     pub fn handle_remaining_readiness(&mut self) {
+        // paced listeners still have connections in their backlog
+        let now = Instant::now();
+        self.accept_ready.extend(
+            self.accept_paced
+                .iter()
+                .filter(|(_, retry_at)| **retry_at <= now)
+                .map(|(token, _)| *token),
+        );
+
         // try to accept again after handling all session events,
         // since we might have released a few session slots
         if self.sessions.borrow().can_accept && !self.accept_ready.is_empty() {
@@ -1688,6 +2157,16 @@ fn worker_response_error<S: ToString, T: ToString>(request_id: S, error: T) -> W
     WorkerResponse::error(request_id, error)
 }
 
+/// A deactivated listener whose established sessions are finishing
+struct Drain {
+    /// the DeactivateListener request, answered once the sessions are closed
+    request_id: String,
+    address: SocketAddr,
+    /// sessions still open at this point are closed
+    deadline: Instant,
+    last_report: Instant,
+}
+
 pub struct ListenSession {
     pub protocol: Protocol,
 }
@@ -1703,6 +2182,10 @@ impl ProxySession for ListenSession {
         Token(0)
     }
 
+    fn listener_address(&self) -> Option<SocketAddr> {
+        None
+    }
+
     fn protocol(&self) -> Protocol {
         self.protocol
     }
@@ -1727,3 +2210,44 @@ impl ProxySession for ListenSession {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_pacer() {
+        assert!(AcceptPacer::new(None, Some(10)).is_none());
+        assert!(AcceptPacer::new(Some(0), None).is_none());
+
+        let mut pacer = AcceptPacer::new(Some(1), Some(3)).unwrap();
+        for _ in 0..3 {
+            assert!(pacer.try_acquire());
+        }
+        assert!(!pacer.try_acquire());
+
+        pacer.release();
+        assert!(pacer.try_acquire());
+        // a token comes back after a second at 1 connection per second
+        let next_token_in = pacer
+            .next_token_at()
+            .saturating_duration_since(Instant::now());
+        assert!(
+            next_token_in > Duration::from_millis(900) && next_token_in <= Duration::from_secs(1)
+        );
+        assert!(matches!(
+            paced_accept(&mut Some(pacer), || Ok::<(), AcceptError>(())),
+            Err(AcceptError::Paced(_))
+        ));
+
+        let mut pacer = AcceptPacer::new(Some(1000), Some(1)).unwrap();
+        assert!(pacer.next_token_at() <= Instant::now());
+        assert!(pacer.try_acquire());
+        assert!(
+            pacer
+                .next_token_at()
+                .saturating_duration_since(Instant::now())
+                <= Duration::from_millis(1)
+        );
+    }
+}
@@ -20,6 +20,8 @@ pub enum ServerBindError {
     SetReuseAddress(std::io::Error),
     #[error("could not set reuse address: {0}")]
     SetReusePort(std::io::Error),
+    #[error("could not set IPv6 only: {0}")]
+    SetOnlyV6(std::io::Error),
     #[error("Could not create socket: {0}")]
     SocketCreationError(std::io::Error),
     #[error("Invalid socket address '{address}': {error}")]
@@ -466,7 +468,9 @@ impl SocketHandler for FrontRustls {
     }
 }
 
-pub fn server_bind(addr: SocketAddr) -> Result<TcpListener, ServerBindError> {
+/// binds a listening socket. On an IPv6 address, `v6only` decides if IPv4 clients
+/// are accepted too, the system setting applies if it is not set
+pub fn server_bind(addr: SocketAddr, v6only: Option<bool>) -> Result<TcpListener, ServerBindError> {
     let sock = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
         .map_err(ServerBindError::SocketCreationError)?;
 
@@ -479,6 +483,11 @@ pub fn server_bind(addr: SocketAddr) -> Result<TcpListener, ServerBindError> {
     sock.set_reuse_port(true)
         .map_err(ServerBindError::SetReusePort)?;
 
+    if let (true, Some(v6only)) = (addr.is_ipv6(), v6only) {
+        sock.set_only_v6(v6only)
+            .map_err(ServerBindError::SetOnlyV6)?;
+    }
+
     sock.bind(&addr.into())
         .map_err(ServerBindError::BindError)?;
 
@@ -492,6 +501,18 @@ pub fn server_bind(addr: SocketAddr) -> Result<TcpListener, ServerBindError> {
     Ok(TcpListener::from_std(sock.into()))
 }
 
+/// A dual-stack listener sees IPv4 clients as IPv4-mapped IPv6 addresses
+/// (`::ffff:a.b.c.d`), they are converted back to IPv4 for logs, metrics and headers
+pub fn canonical_address(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => address,
+        },
+        SocketAddr::V4(_) => address,
+    }
+}
+
 /// Socket statistics
 pub mod stats {
     use std::{os::fd::AsRawFd, time::Duration};
@@ -639,3 +660,34 @@ pub mod stats {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_address_unmaps_ipv4() {
+        let mapped: SocketAddr = "[::ffff:192.168.1.2]:8080".parse().unwrap();
+        assert_eq!(
+            canonical_address(mapped),
+            "192.168.1.2:8080".parse().unwrap()
+        );
+
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        assert_eq!(canonical_address(v6), v6);
+
+        let v4: SocketAddr = "10.0.0.1:80".parse().unwrap();
+        assert_eq!(canonical_address(v4), v4);
+    }
+
+    #[test]
+    fn dual_stack_listener_accepts_ipv4() {
+        let listener = server_bind("[::]:0".parse().unwrap(), Some(false)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(std::net::TcpStream::connect(("127.0.0.1", port)).is_ok());
+
+        let listener = server_bind("[::]:0".parse().unwrap(), Some(true)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(std::net::TcpStream::connect(("127.0.0.1", port)).is_err());
+    }
+}
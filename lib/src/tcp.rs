@@ -22,22 +22,26 @@ use sozu_command::{
 };
 
 use crate::{
-    backends::{Backend, BackendMap},
+    backends::{Backend, BackendMap, ConnectionAttempt, ConnectionRace},
+    handoff::{self, SessionHandoff},
     pool::{Checkout, Pool},
     protocol::{
-        pipe::WebSocketContext,
+        pipe::{ConnectionLimits, WebSocketContext},
         proxy_protocol::{
             expect::ExpectProxyProtocol, relay::RelayProxyProtocol, send::SendProxyProtocol,
         },
-        Pipe,
+        Pipe, SessionState,
     },
     retry::RetryPolicy,
-    server::{push_event, ListenToken, SessionManager, CONN_RETRIES, TIMER},
-    socket::{server_bind, stats::socket_rtt},
+    server::{
+        self, paced_accept, push_event, AcceptPacer, ListenToken, SessionManager, CONN_RETRIES,
+        TIMER,
+    },
+    socket::{canonical_address, server_bind, stats::socket_rtt},
     sozu_command::{
         proto::command::{
-            Event, EventKind, ProxyProtocolConfig, RequestTcpFrontend, TcpListenerConfig,
-            WorkerRequest, WorkerResponse,
+            Event, EventKind, ListenerType, ProxyProtocolConfig, RequestTcpFrontend,
+            TcpListenerConfig, WorkerRequest, WorkerResponse,
         },
         ready::Ready,
         state::ClusterId,
@@ -85,6 +89,10 @@ pub struct TcpSession {
     backend: Option<Rc<RefCell<Backend>>>,
     cluster_id: Option<String>,
     connection_attempt: u8,
+    /// idle timeout and max lifetime of the cluster, enforced once in the pipe
+    connection_limits: ConnectionLimits,
+    /// connection attempts to the other addresses of the backend
+    connection_race: Option<ConnectionRace>,
     container_backend_timeout: TimeoutContainer,
     container_frontend_timeout: TimeoutContainer,
     frontend_address: Option<SocketAddr>,
@@ -107,6 +115,7 @@ impl TcpSession {
         cluster_id: Option<String>,
         configured_backend_timeout: Duration,
         configured_frontend_timeout: Duration,
+        connection_limits: ConnectionLimits,
         frontend_buffer: Checkout,
         frontend_token: Token,
         listener: Rc<RefCell<TcpListener>>,
@@ -115,7 +124,7 @@ impl TcpSession {
         socket: MioTcpStream,
         wait_time: Duration,
     ) -> TcpSession {
-        let frontend_address = socket.peer_addr().ok();
+        let frontend_address = socket.peer_addr().ok().map(canonical_address);
         let mut frontend_buffer_session = None;
         let mut backend_buffer_session = None;
 
@@ -179,6 +188,7 @@ impl TcpSession {
                     WebSocketContext::Tcp,
                 );
                 pipe.set_cluster_id(cluster_id.clone());
+                pipe.set_connection_limits(connection_limits);
                 TcpStateMachine::Pipe(pipe)
             }
         };
@@ -194,6 +204,8 @@ impl TcpSession {
             backend: None,
             cluster_id,
             connection_attempt: 0,
+            connection_limits,
+            connection_race: None,
             container_backend_timeout,
             container_frontend_timeout,
             frontend_address,
@@ -212,7 +224,8 @@ impl TcpSession {
     fn log_request(&self) {
         let listener = self.listener.borrow();
         let context = self.log_context();
-        self.metrics.register_end_of_session(&context);
+        let tags = listener.get_tags(&listener.get_addr().to_string());
+        self.metrics.register_end_of_session(&context, tags);
         info_access!(
             on_failure: { incr!("unsent-access-logs") },
             message: None,
@@ -221,7 +234,7 @@ impl TcpSession {
             backend_address: None,
             protocol: "TCP",
             endpoint: EndpointRecord::Tcp,
-            tags: listener.get_tags(&listener.get_addr().to_string()),
+            tags,
             client_rtt: socket_rtt(self.state.front_socket()),
             server_rtt: None,
             user_agent: None,
@@ -349,6 +362,7 @@ impl TcpSession {
             );
 
             pipe.set_cluster_id(self.cluster_id.clone());
+            pipe.set_connection_limits(self.connection_limits);
             gauge_add!("protocol.proxy.send", -1);
             gauge_add!("protocol.tcp", 1);
             return Some(TcpStateMachine::Pipe(pipe));
@@ -366,6 +380,7 @@ impl TcpSession {
             let mut pipe =
                 rpp.into_pipe(self.backend_buffer.take().unwrap(), self.listener.clone());
             pipe.set_cluster_id(self.cluster_id.clone());
+            pipe.set_connection_limits(self.connection_limits);
             gauge_add!("protocol.proxy.relay", -1);
             gauge_add!("protocol.tcp", 1);
             return Some(TcpStateMachine::Pipe(pipe));
@@ -392,6 +407,7 @@ impl TcpSession {
             );
 
             pipe.set_cluster_id(self.cluster_id.clone());
+            pipe.set_connection_limits(self.connection_limits);
             gauge_add!("protocol.proxy.expect", -1);
             gauge_add!("protocol.tcp", 1);
             return Some(TcpStateMachine::Pipe(pipe));
@@ -495,11 +511,17 @@ impl TcpSession {
                         "backend server {} at {} is up",
                         backend.backend_id, backend.address
                     );
+                    backend.start_slow_start();
+                    if let Some(cluster_id) = self.cluster_id.as_deref() {
+                        server::backend_available(cluster_id);
+                    }
                     push_event(Event {
                         kind: EventKind::BackendUp as i32,
                         backend_id: Some(backend.backend_id.to_owned()),
                         address: Some(backend.address.into()),
                         cluster_id: None,
+                        crash_report: None,
+                        tags: self.listener.borrow().get_event_tags(),
                     });
                 }
 
@@ -514,9 +536,29 @@ impl TcpSession {
         }
     }
 
+    /// Attaches the established backend connection of a session handed off by another worker
+    fn adopt_backend(
+        &mut self,
+        socket: MioTcpStream,
+        token: Token,
+        backend: Option<Rc<RefCell<Backend>>>,
+    ) {
+        self.set_back_token(token);
+        self.set_back_socket(socket);
+        if let Some(backend_id) = self.backend_id.clone() {
+            self.metrics.backend_id = Some(backend_id.clone());
+            self.set_backend_id(backend_id);
+        }
+        self.metrics.backend_start();
+        self.backend = backend;
+        self.container_backend_timeout.set(token);
+        self.set_back_connected(BackendConnectionStatus::Connected);
+    }
+
     fn remove_backend(&mut self) {
         if let Some(backend) = self.backend.take() {
-            (*backend.borrow_mut()).dec_connections();
+            let cluster_id = self.cluster_id.as_deref().unwrap_or_default();
+            (*backend.borrow_mut()).release_connection(cluster_id);
         }
 
         self.backend_token = None;
@@ -526,6 +568,7 @@ impl TcpSession {
         if let Some(backend) = self.backend.as_ref() {
             let backend = &mut *backend.borrow_mut();
             backend.failures += 1;
+            backend.penalize_response_time();
 
             let already_unavailable = backend.retry_policy.is_down();
             backend.retry_policy.fail();
@@ -550,6 +593,8 @@ impl TcpSession {
                     backend_id: Some(backend.backend_id.to_owned()),
                     address: Some(backend.address.into()),
                     cluster_id: None,
+                    crash_report: None,
+                    tags: self.listener.borrow().get_event_tags(),
                 });
             }
         }
@@ -579,11 +624,18 @@ impl TcpSession {
     pub fn cancel_timeouts(&mut self) {
         self.container_frontend_timeout.cancel();
         self.container_backend_timeout.cancel();
+        if let TcpStateMachine::Pipe(pipe) = &mut self.state {
+            pipe.cancel_timeouts();
+        }
     }
 
     fn ready_inner(&mut self, session: Rc<RefCell<dyn ProxySession>>) -> SessionResult {
         let mut counter = 0;
 
+        if self.connection_race.is_some() {
+            self.race_backend_connection(session.clone());
+        }
+
         let back_connected = self.back_connected();
         if back_connected.is_connecting() {
             if self.back_readiness().unwrap().event.is_hup() && !self.test_back_socket() {
@@ -759,6 +811,7 @@ impl TcpSession {
 
     /// TCP session closes its backend on its own, without defering this task to the state
     fn close_backend(&mut self) {
+        self.close_connection_race();
         if let (Some(token), Some(fd)) = (
             self.backend_token,
             self.back_socket_mut().map(|s| s.as_raw_fd()),
@@ -809,6 +862,188 @@ impl TcpSession {
         self.set_back_connected(BackendConnectionStatus::NotConnected);
     }
 
+    /// Staggered connection attempts to the other addresses of the backend,
+    /// the first socket to connect becomes the backend socket
+    fn race_backend_connection(&mut self, session: Rc<RefCell<dyn ProxySession>>) {
+        if !self.back_connected().is_connecting() {
+            return self.close_connection_race();
+        }
+
+        let back_events = self
+            .back_readiness()
+            .map(|r| r.event)
+            .unwrap_or(Ready::EMPTY);
+        let first_failed = back_events.is_hup() && !self.test_back_socket();
+        if !first_failed && !back_events.is_empty() {
+            // the first connection attempt won
+            return self.close_connection_race();
+        }
+
+        let mut connection_race = match self.connection_race.take() {
+            Some(connection_race) => connection_race,
+            None => return,
+        };
+
+        for attempt in connection_race.take_failed() {
+            self.close_racing_socket(attempt.socket, attempt.token);
+        }
+
+        if let Some(attempt) = connection_race.take_connected() {
+            for attempt in connection_race.attempts {
+                self.close_racing_socket(attempt.socket, attempt.token);
+            }
+            return self.switch_to_connection_attempt(attempt);
+        }
+
+        if first_failed {
+            connection_race.first_failed = true;
+            connection_race.hurry();
+            // the other attempts may still connect, do not retry yet
+            if let Some(r) = self.back_readiness() {
+                r.event = Ready::EMPTY;
+            }
+        }
+
+        while let Some(address) = connection_race.next_address() {
+            let mut socket = match MioTcpStream::connect(address) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    debug!(
+                        "{} Could not connect to backend address {}: {}",
+                        log_context!(self),
+                        address,
+                        e
+                    );
+                    connection_race.hurry();
+                    continue;
+                }
+            };
+            if let Err(e) = socket.set_nodelay(true) {
+                error!(
+                    "{} Error setting nodelay on back socket({:?}): {:?}",
+                    log_context!(self),
+                    socket,
+                    e
+                );
+            }
+
+            let token = {
+                let proxy = self.proxy.borrow();
+                let mut s = proxy.sessions.borrow_mut();
+                let entry = s.slab.vacant_entry();
+                let token = Token(entry.key());
+                let _entry = entry.insert(session.clone());
+                token
+            };
+
+            if let Err(e) = self.proxy.borrow().registry.register(
+                &mut socket,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            ) {
+                error!(
+                    "{} Error registering back socket({:?}): {:?}",
+                    log_context!(self),
+                    socket,
+                    e
+                );
+            }
+
+            incr!(
+                "backend.connections.staggered",
+                self.cluster_id.as_deref(),
+                self.backend_id.as_deref()
+            );
+            connection_race.attempts.push(ConnectionAttempt {
+                address,
+                events: Ready::EMPTY,
+                socket,
+                token,
+            });
+        }
+
+        if connection_race.is_lost() {
+            // let the usual retry mechanism handle the failure of the first attempt
+            if let Some(r) = self.back_readiness() {
+                r.event.insert(Ready::HUP);
+            }
+            return;
+        }
+
+        if connection_race.has_addresses() {
+            server::wake_up_at(self.frontend_token, connection_race.next_attempt());
+        }
+        self.connection_race = Some(connection_race);
+    }
+
+    /// replaces the backend socket with a connection attempt that succeeded,
+    /// the backend connection slot is kept for the new socket
+    fn switch_to_connection_attempt(&mut self, attempt: ConnectionAttempt) {
+        let proxy = self.proxy.clone();
+        if let (Some(token), Some(socket)) = (self.backend_token, self.back_socket_mut()) {
+            let proxy = proxy.borrow();
+            if let Err(e) = proxy.registry.deregister(socket) {
+                error!("Error deregistering back socket({:?}): {:?}", socket, e);
+            }
+            if let Err(e) = socket.shutdown(Shutdown::Both) {
+                if e.kind() != ErrorKind::NotConnected {
+                    error!("Error shutting down back socket({:?}): {:?}", socket, e);
+                }
+            }
+            proxy.sessions.borrow_mut().slab.try_remove(token.0);
+        }
+
+        if let Some(backend) = &self.backend {
+            let mut backend = backend.borrow_mut();
+            info!(
+                "{} connected to backend {} on its address {}",
+                log_context!(self),
+                backend.backend_id,
+                attempt.address
+            );
+            backend.preferred_address = attempt.address;
+        }
+
+        self.set_back_socket(attempt.socket);
+        self.set_back_token(attempt.token);
+        if let Some(r) = self.back_readiness() {
+            r.event = attempt.events;
+        }
+        self.container_backend_timeout.set(attempt.token);
+    }
+
+    fn close_connection_race(&mut self) {
+        if let Some(connection_race) = self.connection_race.take() {
+            for attempt in connection_race.attempts {
+                self.close_racing_socket(attempt.socket, attempt.token);
+            }
+        }
+    }
+
+    /// closes a socket that lost the race to connect to the backend
+    fn close_racing_socket(&self, mut socket: MioTcpStream, token: Token) {
+        let proxy = self.proxy.borrow();
+        if let Err(e) = proxy.registry.deregister(&mut socket) {
+            error!(
+                "{} Error deregistering back socket({:?}): {:?}",
+                log_context!(self),
+                socket,
+                e
+            );
+        }
+        if let Err(e) = socket.shutdown(Shutdown::Both) {
+            if e.kind() != ErrorKind::NotConnected {
+                error!(
+                    "{} Error shutting down back socket({:?}): {:?}",
+                    log_context!(self),
+                    socket,
+                    e
+                );
+            }
+        }
+        proxy.sessions.borrow_mut().slab.try_remove(token.0);
+    }
+
     fn connect_to_backend(
         &mut self,
         session_rc: Rc<RefCell<dyn ProxySession>>,
@@ -837,6 +1072,18 @@ impl TcpSession {
             return Err(BackendConnectionError::MaxSessionsMemory);
         }
 
+        let backends = self.proxy.borrow().backends.clone();
+        if self.connection_attempt == 0 {
+            backends.borrow_mut().record_request(&cluster_id);
+        } else if !backends.borrow_mut().can_retry(&cluster_id) {
+            error!(
+                "{} Retry budget of cluster {} is exhausted",
+                log_context!(self),
+                cluster_id
+            );
+            return Err(BackendConnectionError::RetryBudgetExhausted(cluster_id));
+        }
+
         let (backend, mut stream) = self
             .proxy
             .borrow()
@@ -902,6 +1149,12 @@ impl TcpSession {
         self.metrics.backend_start();
         self.set_backend_id(backend.borrow().backend_id.clone());
 
+        self.connection_race = ConnectionRace::new(&backend.borrow());
+        if let Some(connection_race) = &self.connection_race {
+            server::wake_up_at(self.frontend_token, connection_race.next_attempt());
+        }
+        self.backend = Some(backend);
+
         Ok(BackendConnectAction::New)
     }
 }
@@ -974,6 +1227,13 @@ impl ProxySession for TcpSession {
 
     fn timeout(&mut self, token: Token) -> SessionIsToBeClosed {
         if self.frontend_token == token {
+            if let TcpStateMachine::Pipe(pipe) = &mut self.state {
+                if let Some(limit) = pipe.check_connection_limits() {
+                    pipe.log_connection_limit(&self.metrics, limit);
+                    return true;
+                }
+            }
+
             let dur = Instant::now() - self.last_event;
             let front_timeout = self.container_frontend_timeout.duration();
             if dur < front_timeout {
@@ -1009,6 +1269,8 @@ impl ProxySession for TcpSession {
             if let Some(r) = self.back_readiness() {
                 r.event |= events;
             }
+        } else if let Some(connection_race) = &mut self.connection_race {
+            connection_race.update_readiness(token, events);
         }
     }
 
@@ -1085,6 +1347,47 @@ impl ProxySession for TcpSession {
     fn frontend_token(&self) -> Token {
         self.frontend_token
     }
+
+    fn listener_address(&self) -> Option<SocketAddr> {
+        Some(*self.listener.borrow().get_addr())
+    }
+
+    fn hand_off(&mut self) -> Option<SessionHandoff> {
+        if self.has_been_closed
+            || self.connection_race.is_some()
+            || self.backend_connected != BackendConnectionStatus::Connected
+        {
+            return None;
+        }
+        let session = match &self.state {
+            TcpStateMachine::Pipe(pipe) if pipe.can_hand_off() => {
+                pipe.handoff_state(ListenerType::Tcp)
+            }
+            _ => return None,
+        };
+
+        gauge_add!("protocol.tcp", -1);
+        gauge_add!("backend.connections", -1);
+        gauge_add!(
+            "connections_per_backend",
+            -1,
+            self.cluster_id.as_deref(),
+            self.metrics.backend_id.as_deref()
+        );
+        self.cancel_timeouts();
+        self.remove_backend();
+        self.has_been_closed = true;
+
+        let TcpStateMachine::Pipe(pipe) = self.state.take() else {
+            unreachable!()
+        };
+        let (frontend, backend) = pipe.into_sockets()?;
+        Some(SessionHandoff {
+            session,
+            frontend,
+            backend,
+        })
+    }
 }
 
 pub struct TcpListener {
@@ -1093,7 +1396,10 @@ pub struct TcpListener {
     cluster_id: Option<String>,
     config: TcpListenerConfig,
     listener: Option<MioTcpListener>,
+    pacer: Option<AcceptPacer>,
     tags: BTreeMap<String, CachedTags>,
+    /// tags of the listener, merged into those of its frontends
+    default_tags: Option<CachedTags>,
     token: Token,
 }
 
@@ -1103,12 +1409,18 @@ impl ListenerHandler for TcpListener {
     }
 
     fn get_tags(&self, key: &str) -> Option<&CachedTags> {
-        self.tags.get(key)
+        self.tags.get(key).or(self.default_tags.as_ref())
+    }
+
+    fn get_default_tags(&self) -> Option<&CachedTags> {
+        self.default_tags.as_ref()
     }
 
     fn set_tags(&mut self, key: String, tags: Option<BTreeMap<String, String>>) {
         match tags {
-            Some(tags) => self.tags.insert(key, CachedTags::new(tags)),
+            Some(tags) => self
+                .tags
+                .insert(key, CachedTags::with_defaults(&self.config.tags, tags)),
             None => self.tags.remove(&key),
         };
     }
@@ -1121,6 +1433,8 @@ impl TcpListener {
             listener: None,
             token,
             address: config.address.clone().into(),
+            pacer: AcceptPacer::new(config.accept_rate, config.accept_burst),
+            default_tags: (!config.tags.is_empty()).then(|| CachedTags::new(config.tags.clone())),
             config,
             active: false,
             tags: BTreeMap::new(),
@@ -1140,7 +1454,8 @@ impl TcpListener {
             Some(listener) => listener,
             None => {
                 let address = self.config.address.clone().into();
-                server_bind(address).map_err(|e| ProxyError::BindToSocket(address, e))?
+                server_bind(address, self.config.v6only)
+                    .map_err(|e| ProxyError::BindToSocket(address, e))?
             }
         };
 
@@ -1175,6 +1490,7 @@ fn handle_connection_result(
 #[derive(Debug)]
 pub struct ClusterConfiguration {
     proxy_protocol: Option<ProxyProtocolConfig>,
+    connection_limits: ConnectionLimits,
     // Uncomment this when implementing new load balancing algorithms
     // load_balancing: LoadBalancingAlgorithms,
 }
@@ -1278,6 +1594,107 @@ impl TcpProxy {
         Ok((owned.token, taken_listener))
     }
 
+    /// Recreates a TCP session handed off by another worker, around its established sockets
+    pub fn adopt_session(
+        &mut self,
+        handoff: SessionHandoff,
+        proxy: Rc<RefCell<Self>>,
+    ) -> Result<(), AcceptError> {
+        let SessionHandoff {
+            session,
+            frontend: mut frontend_sock,
+            backend: mut backend_sock,
+        } = handoff;
+
+        let listener_address: SocketAddr = session.listener_address.into();
+        let listener = self
+            .listeners
+            .values()
+            .find(|listener| listener.borrow().address == listener_address)
+            .cloned()
+            .ok_or(AcceptError::WrongSocketAddress)?;
+
+        let mut pool = self.pool.borrow_mut();
+        let (front_buffer, back_buffer) = match (pool.checkout(), pool.checkout()) {
+            (Some(fb), Some(bb)) => (fb, bb),
+            _ => return Err(AcceptError::BufferCapacityReached),
+        };
+
+        let connection_limits = session
+            .cluster_id
+            .as_ref()
+            .and_then(|cluster_id| self.configs.get(cluster_id))
+            .map(|c| c.connection_limits)
+            .unwrap_or_default();
+        let backend = session.cluster_id.as_ref().and_then(|cluster_id| {
+            self.backends.borrow_mut().backend_of_connection(
+                cluster_id,
+                session.backend_id.as_deref(),
+                backend_sock.peer_addr().ok(),
+            )
+        });
+
+        let mut session_manager = self.sessions.borrow_mut();
+        let frontend_token = Token(session_manager.slab.vacant_key());
+        if let Err(register_error) = self.registry.register(
+            &mut frontend_sock,
+            frontend_token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            error!(
+                "error registering adopted front socket({:?}): {:?}",
+                frontend_sock, register_error
+            );
+            return Err(AcceptError::RegisterError);
+        }
+
+        let (back_timeout, front_timeout) = {
+            let listener = listener.borrow();
+            (
+                Duration::from_secs(listener.config.back_timeout as u64),
+                Duration::from_secs(listener.config.front_timeout as u64),
+            )
+        };
+        let mut tcp_session = TcpSession::new(
+            back_buffer,
+            session.backend_id.clone(),
+            session.cluster_id.clone(),
+            back_timeout,
+            front_timeout,
+            connection_limits,
+            front_buffer,
+            frontend_token,
+            listener,
+            None,
+            proxy,
+            frontend_sock,
+            Duration::ZERO,
+        );
+        tcp_session.request_id = handoff::request_id(&session);
+        if let TcpStateMachine::Pipe(pipe) = &mut tcp_session.state {
+            pipe.set_request_id(tcp_session.request_id);
+        }
+
+        let tcp_session = Rc::new(RefCell::new(tcp_session));
+        session_manager.slab.insert(tcp_session.clone());
+        let back_token = Token(session_manager.slab.insert(tcp_session.clone()));
+        if let Err(register_error) = self.registry.register(
+            &mut backend_sock,
+            back_token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            error!(
+                "error registering adopted back socket({:?}): {:?}",
+                backend_sock, register_error
+            );
+        }
+
+        tcp_session
+            .borrow_mut()
+            .adopt_backend(backend_sock, back_token, backend);
+        Ok(())
+    }
+
     pub fn add_tcp_front(&mut self, front: RequestTcpFrontend) -> Result<(), ProxyError> {
         let address = front.address.into();
 
@@ -1367,6 +1784,14 @@ impl ProxyConfiguration for TcpProxy {
                     proxy_protocol: cluster
                         .proxy_protocol
                         .and_then(|n| ProxyProtocolConfig::try_from(n).ok()),
+                    connection_limits: ConnectionLimits {
+                        idle_timeout: cluster
+                            .idle_timeout
+                            .map(|timeout| Duration::from_secs(timeout as u64)),
+                        max_lifetime: cluster
+                            .max_connection_lifetime
+                            .map(|lifetime| Duration::from_secs(lifetime as u64)),
+                    },
                     //load_balancing: cluster.load_balancing,
                 };
                 self.configs.insert(cluster.cluster_id, config);
@@ -1399,17 +1824,20 @@ impl ProxyConfiguration for TcpProxy {
     fn accept(&mut self, token: ListenToken) -> Result<MioTcpStream, AcceptError> {
         let internal_token = Token(token.0);
         if let Some(listener) = self.listeners.get(&internal_token) {
-            if let Some(tcp_listener) = &listener.borrow().listener {
-                tcp_listener
-                    .accept()
-                    .map(|(frontend_sock, _)| frontend_sock)
-                    .map_err(|e| match e.kind() {
-                        ErrorKind::WouldBlock => AcceptError::WouldBlock,
-                        _ => {
-                            error!("accept() IO error: {:?}", e);
-                            AcceptError::IoError
-                        }
-                    })
+            let listener = &mut *listener.borrow_mut();
+            if let Some(tcp_listener) = &listener.listener {
+                paced_accept(&mut listener.pacer, || {
+                    tcp_listener
+                        .accept()
+                        .map(|(frontend_sock, _)| frontend_sock)
+                        .map_err(|e| match e.kind() {
+                            ErrorKind::WouldBlock => AcceptError::WouldBlock,
+                            _ => {
+                                error!("accept() IO error: {:?}", e);
+                                AcceptError::IoError
+                            }
+                        })
+                })
             } else {
                 Err(AcceptError::IoError)
             }
@@ -1457,10 +1885,11 @@ impl ProxyConfiguration for TcpProxy {
             return Err(AcceptError::IoError);
         }
 
-        let proxy_protocol = self
-            .configs
-            .get(owned.cluster_id.as_ref().unwrap())
-            .and_then(|c| c.proxy_protocol);
+        let cluster_config = self.configs.get(owned.cluster_id.as_ref().unwrap());
+        let proxy_protocol = cluster_config.and_then(|c| c.proxy_protocol);
+        let connection_limits = cluster_config
+            .map(|c| c.connection_limits)
+            .unwrap_or_default();
 
         if let Err(e) = frontend_sock.set_nodelay(true) {
             error!(
@@ -1491,6 +1920,7 @@ impl ProxyConfiguration for TcpProxy {
             owned.cluster_id.clone(),
             Duration::from_secs(owned.config.back_timeout as u64),
             Duration::from_secs(owned.config.front_timeout as u64),
+            connection_limits,
             front_buffer,
             frontend_token,
             listener.clone(),
@@ -1575,12 +2005,13 @@ pub mod testing {
 #[cfg(test)]
 mod tests {
     use super::testing::start_tcp_worker;
-    use crate::testing::*;
+    use crate::{handoff::SessionHandoff, testing::*};
 
+    use rusty_ulid::Ulid;
     use sozu_command::proto::command::SocketAddress;
     use std::{
         io::{Read, Write},
-        net::{Shutdown, TcpListener, TcpStream},
+        net::{Shutdown, SocketAddr, TcpListener, TcpStream},
         str,
         sync::{
             atomic::{AtomicBool, Ordering},
@@ -1593,8 +2024,8 @@ mod tests {
         channel::Channel,
         config::ListenerBuilder,
         proto::command::{
-            request::RequestType, LoadBalancingParams, RequestTcpFrontend, WorkerRequest,
-            WorkerResponse,
+            request::RequestType, HandedOffSession, ListenerType, LoadBalancingParams,
+            RequestTcpFrontend, WorkerRequest, WorkerResponse,
         },
     };
     static TEST_FINISHED: AtomicBool = AtomicBool::new(false);
@@ -1740,6 +2171,8 @@ mod tests {
                 load_balancing_parameters: Some(LoadBalancingParams::default()),
                 sticky_id: None,
                 backup: None,
+                priority: None,
+                alternate_addresses: Vec::new(),
             };
 
             command
@@ -1768,6 +2201,8 @@ mod tests {
                 load_balancing_parameters: Some(LoadBalancingParams::default()),
                 sticky_id: None,
                 backup: None,
+                priority: None,
+                alternate_addresses: Vec::new(),
             };
             command
                 .write_message(&WorkerRequest {
@@ -1795,4 +2230,93 @@ mod tests {
 
         Ok(command)
     }
+
+    /// a connected pair of sockets, the first one on the side that accepted
+    fn socket_pair() -> (mio::net::TcpStream, mio::net::TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("could not bind");
+        let client = TcpStream::connect(listener.local_addr().unwrap()).expect("could not connect");
+        let (server, _) = listener.accept().expect("could not accept");
+        (
+            mio::net::TcpStream::from_std(server),
+            mio::net::TcpStream::from_std(client),
+        )
+    }
+
+    #[test]
+    fn handed_off_sessions_release_their_backend_connection() {
+        let ServerParts {
+            event_loop,
+            registry,
+            sessions,
+            pool,
+            backends,
+            ..
+        } = prebuild_server(10, 16384, false).expect("could not build the server");
+        let listener_address: SocketAddr = "127.0.0.1:1250".parse().unwrap();
+        let proxy = Rc::new(RefCell::new(TcpProxy::new(
+            registry,
+            sessions.clone(),
+            pool,
+            backends.clone(),
+        )));
+        proxy
+            .borrow_mut()
+            .add_listener(
+                ListenerBuilder::new_tcp(listener_address.into())
+                    .to_tcp(None)
+                    .unwrap(),
+                Token(10),
+            )
+            .unwrap();
+        let (frontend, _client) = socket_pair();
+        let (_backend_server, backend_socket) = socket_pair();
+        let backend_address = backend_socket.peer_addr().unwrap();
+        backends.borrow_mut().add_backend(
+            "cluster_0",
+            crate::backends::Backend::new("cluster_0-0", backend_address, None, None, None, None),
+        );
+        let active_connections = || {
+            backends.borrow().backends["cluster_0"].backends[0]
+                .borrow()
+                .active_connections
+        };
+
+        let handoff = SessionHandoff {
+            session: HandedOffSession {
+                listener_type: ListenerType::Tcp.into(),
+                listener_address: listener_address.into(),
+                cluster_id: Some("cluster_0".to_owned()),
+                backend_id: Some("cluster_0-0".to_owned()),
+                request_id: Ulid::generate().to_string(),
+                method: None,
+                authority: None,
+                path: None,
+                status: None,
+            },
+            frontend,
+            backend: backend_socket,
+        };
+        proxy
+            .borrow_mut()
+            .adopt_session(handoff, proxy.clone())
+            .expect("could not adopt the session");
+        assert_eq!(active_connections(), 1);
+
+        let session = sessions.borrow().slab.iter().last().unwrap().1.clone();
+        let mut handoff = session
+            .borrow_mut()
+            .hand_off()
+            .expect("the session should be handed off");
+        assert_eq!(active_connections(), 0);
+
+        // the sessions that could not be sent are adopted back by their worker
+        for socket in [&mut handoff.frontend, &mut handoff.backend] {
+            event_loop.registry().deregister(socket).unwrap();
+        }
+        proxy
+            .borrow_mut()
+            .adopt_session(handoff, proxy.clone())
+            .expect("could not adopt the session back");
+        assert_eq!(active_connections(), 1);
+    }
 }
@@ -16,15 +16,19 @@ use std::{
 use rustls::{
     crypto::ring::sign::any_supported_type,
     pki_types::{CertificateDer, PrivateKeyDer},
-    server::{ClientHello, ResolvesServerCert},
-    sign::CertifiedKey,
+    server::{ClientHello, ResolvesServerCert, ServerConfig},
+    sign::{CertifiedKey, SigningKey},
 };
 use sha2::{Digest, Sha256};
 use sozu_command::{
     certificate::{
-        get_cn_and_san_attributes, parse_pem, parse_x509, CertificateError, Fingerprint,
+        get_cn_and_san_attributes, name_covers_domain, parse_pem, parse_x509, CertificateError,
+        Fingerprint,
+    },
+    proto::command::{
+        AddCertificate, CertificateAndKey, FallbackCertificatePolicy, HttpsListenerConfig,
+        ReplaceCertificate, SocketAddress,
     },
-    proto::command::{AddCertificate, CertificateAndKey, ReplaceCertificate, SocketAddress},
 };
 
 use crate::router::pattern_trie::{Key, KeyValue, TrieNode};
@@ -40,6 +44,7 @@ static DEFAULT_CERTIFICATE: LazyLock<Option<Arc<CertifiedKey>>> = LazyLock::new(
             key: include_str!("../assets/key.pem").to_string(),
             versions: vec![],
             names: vec![],
+            cipher_list: vec![],
         },
         address: SocketAddress::new_v4(0, 0, 0, 0, 8080), // not used anyway
         expired_at: None,
@@ -61,6 +66,69 @@ pub enum CertificateResolverError {
     ParsePem(CertificateError),
     #[error("error parsing overriding names in new certificate: {0}")]
     ParseOverridingNames(CertificateError),
+    #[error("no certificate with fingerprint {0}")]
+    UnknownCertificate(Fingerprint),
+    #[error("certificate {fingerprint} does not cover the domain {domain}")]
+    PinnedCertificateMismatch {
+        fingerprint: Fingerprint,
+        domain: String,
+    },
+    #[error("no key provider registered for the scheme of the key URI {0}")]
+    NoKeyProvider(String),
+    #[error("the key provider could not load the key: {0}")]
+    KeyProvider(String),
+}
+
+// -----------------------------------------------------------------------------
+// Key providers
+
+/// Gives access to private keys that are not stored in the configuration, like those
+/// of a PKCS#11 token or an HSM. Such a key is referenced by a URI in the `key` field
+/// of a `CertificateAndKey`, for instance `pkcs11:token=sozu;object=example-org`,
+/// and never leaves its provider: rustls calls the signing key for each handshake.
+pub trait KeyProvider: Send + Sync {
+    /// the signing key referenced by this URI
+    fn load_signing_key(&self, uri: &str) -> Result<Arc<dyn SigningKey>, CertificateResolverError>;
+}
+
+/// URI scheme -> provider of the keys referenced with this scheme
+static KEY_PROVIDERS: LazyLock<Mutex<HashMap<String, Arc<dyn KeyProvider>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// make the keys referenced by URIs of this scheme (like `pkcs11`) available to certificates.
+/// Providers are shared by all the listeners of the process, register them before
+/// adding certificates that use them
+pub fn register_key_provider(scheme: &str, provider: Arc<dyn KeyProvider>) {
+    if let Ok(mut providers) = KEY_PROVIDERS.lock() {
+        providers.insert(scheme.to_ascii_lowercase(), provider);
+    }
+}
+
+/// the scheme of a key given as a URI, None for a PEM key
+pub fn key_uri_scheme(key: &str) -> Option<&str> {
+    let (scheme, _) = key.trim().split_once(':')?;
+    let mut chars = scheme.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    valid.then_some(scheme)
+}
+
+/// the key URI without its query attributes, that may hold a PIN, to show it in logs
+pub fn key_uri_for_logs(uri: &str) -> &str {
+    let uri = uri.trim();
+    uri.split_once('?').map_or(uri, |(path, _)| path)
+}
+
+fn load_provided_signing_key(
+    uri: &str,
+    scheme: &str,
+) -> Result<Arc<dyn SigningKey>, CertificateResolverError> {
+    let provider = KEY_PROVIDERS
+        .lock()
+        .ok()
+        .and_then(|providers| providers.get(&scheme.to_ascii_lowercase()).cloned())
+        .ok_or_else(|| CertificateResolverError::NoKeyProvider(key_uri_for_logs(uri).to_owned()))?;
+    provider.load_signing_key(uri.trim())
 }
 
 /// A wrapper around the Rustls
@@ -109,37 +177,122 @@ impl TryFrom<&AddCertificate> for CertifiedKeyWrapper {
             chain.push(CertificateDer::from(chain_link));
         }
 
-        let mut key_reader = BufReader::new(cert.key.as_bytes());
-
-        let item = match rustls_pemfile::read_one(&mut key_reader)
-            .map_err(|_| CertificateResolverError::EmptyKeys)?
-        {
-            Some(item) => item,
-            None => return Err(CertificateResolverError::EmptyKeys),
+        let signing_key = match key_uri_scheme(&cert.key) {
+            Some(scheme) => load_provided_signing_key(&cert.key, scheme)?,
+            None => parse_signing_key(&cert.key)?,
         };
 
-        let private_key = match item {
-            rustls_pemfile::Item::Pkcs1Key(rsa_key) => PrivateKeyDer::from(rsa_key),
-            rustls_pemfile::Item::Pkcs8Key(pkcs8_key) => PrivateKeyDer::from(pkcs8_key),
-            rustls_pemfile::Item::Sec1Key(ec_key) => PrivateKeyDer::from(ec_key),
-            _ => return Err(CertificateResolverError::EmptyKeys),
-        };
+        Ok(CertifiedKeyWrapper {
+            inner: Arc::new(CertifiedKey::new(chain, signing_key)),
+            names: overriding_names,
+            expiration,
+            fingerprint,
+        })
+    }
+}
+
+/// Support RSA and ECDSA PEM keys
+fn parse_signing_key(key: &str) -> Result<Arc<dyn SigningKey>, CertificateResolverError> {
+    let mut key_reader = BufReader::new(key.as_bytes());
 
-        match any_supported_type(&private_key) {
-            Ok(signing_key) => {
-                let stored_certificate = CertifiedKeyWrapper {
-                    inner: Arc::new(CertifiedKey::new(chain, signing_key)),
-                    names: overriding_names,
-                    expiration,
-                    fingerprint,
+    let item = match rustls_pemfile::read_one(&mut key_reader)
+        .map_err(|_| CertificateResolverError::EmptyKeys)?
+    {
+        Some(item) => item,
+        None => return Err(CertificateResolverError::EmptyKeys),
+    };
+
+    let private_key = match item {
+        rustls_pemfile::Item::Pkcs1Key(rsa_key) => PrivateKeyDer::from(rsa_key),
+        rustls_pemfile::Item::Pkcs8Key(pkcs8_key) => PrivateKeyDer::from(pkcs8_key),
+        rustls_pemfile::Item::Sec1Key(ec_key) => PrivateKeyDer::from(ec_key),
+        _ => return Err(CertificateResolverError::EmptyKeys),
+    };
+
+    any_supported_type(&private_key)
+        .map_err(|sign_error| CertificateResolverError::InvalidPrivateKey(sign_error.to_string()))
+}
+
+/// What the resolver answers to handshakes that have no certificate for their SNI
+#[derive(Clone, Debug)]
+pub struct CertificateFallback {
+    /// when the client sends no SNI
+    pub no_sni: FallbackCertificatePolicy,
+    /// when the SNI matches no certificate
+    pub unknown_sni: FallbackCertificatePolicy,
+    /// the certificate of the listener, or the one bundled with Sōzu
+    pub default_certificate: Option<Arc<CertifiedKey>>,
+}
+
+impl Default for CertificateFallback {
+    fn default() -> Self {
+        Self {
+            no_sni: FallbackCertificatePolicy::Reject,
+            unknown_sni: FallbackCertificatePolicy::DefaultCertificate,
+            default_certificate: DEFAULT_CERTIFICATE.clone(),
+        }
+    }
+}
+
+impl CertificateFallback {
+    /// the policies of the listener, and its certificate if it has one
+    pub fn try_from_config(config: &HttpsListenerConfig) -> Result<Self, CertificateResolverError> {
+        let default_certificate = match (&config.certificate, &config.key) {
+            (Some(certificate), Some(key)) => {
+                let add = AddCertificate {
+                    certificate: CertificateAndKey {
+                        certificate: certificate.to_owned(),
+                        certificate_chain: config.certificate_chain.clone(),
+                        key: key.to_owned(),
+                        ..Default::default()
+                    },
+                    address: config.address,
+                    expired_at: None,
                 };
-                Ok(stored_certificate)
+                Some(CertifiedKeyWrapper::try_from(&add)?.inner)
+            }
+            _ => DEFAULT_CERTIFICATE.clone(),
+        };
+
+        Ok(Self {
+            no_sni: config.no_sni_policy(),
+            unknown_sni: config.unknown_sni_policy(),
+            default_certificate,
+        })
+    }
+
+    fn for_missing_sni(&self) -> Option<Arc<CertifiedKey>> {
+        match self.no_sni {
+            FallbackCertificatePolicy::DefaultCertificate => {
+                incr!("tls.sni.missing.default_cert");
+                self.serve_default_certificate()
+            }
+            FallbackCertificatePolicy::Reject => {
+                incr!("tls.sni.missing.rejected");
+                None
+            }
+        }
+    }
+
+    fn for_unknown_sni(&self) -> Option<Arc<CertifiedKey>> {
+        match self.unknown_sni {
+            FallbackCertificatePolicy::DefaultCertificate => {
+                incr!("tls.sni.unknown.default_cert");
+                self.serve_default_certificate()
+            }
+            FallbackCertificatePolicy::Reject => {
+                incr!("tls.sni.unknown.rejected");
+                None
             }
-            Err(sign_error) => Err(CertificateResolverError::InvalidPrivateKey(
-                sign_error.to_string(),
-            )),
         }
     }
+
+    fn serve_default_certificate(&self) -> Option<Arc<CertifiedKey>> {
+        // This certificate is used for TLS tunneling with another TLS termination endpoint
+        // Note that this is unsafe and you should provide a valid certificate
+        incr!("tls.default_cert_used");
+        self.default_certificate.clone()
+    }
 }
 
 /// Parses and stores TLS certificates, makes them available to Rustls for TLS handshakes
@@ -158,6 +311,10 @@ pub struct CertificateResolver {
     /// map of domain_name -> all fingerprints (and expiration) linked to this domain name
     //  the vector of (fingerprint, expiration) is sorted by expiration
     name_fingerprint_idx: HashMap<String, Vec<(Fingerprint, i64)>>,
+    /// domain name -> fingerprint of the certificate forced for this domain
+    pins: HashMap<String, Fingerprint>,
+    /// what to answer to handshakes without a certificate for their SNI
+    pub fallback: CertificateFallback,
 }
 
 impl CertificateResolver {
@@ -189,28 +346,18 @@ impl CertificateResolver {
             fingerprints_for_this_name
                 .push((cert_to_add.fingerprint.clone(), cert_to_add.expiration));
 
-            // sort expiration ascending (longest-lived to the right)
-            fingerprints_for_this_name.sort_by_key(|t| t.1);
-
-            let longest_lived_cert = match fingerprints_for_this_name.last() {
-                Some(cert) => cert,
-                None => {
-                    error!("no fingerprint for this name, this should not happen");
-                    continue;
-                }
-            };
-
-            // update the longest lived certificate in the TriNode
-            self.domains.remove(&new_name.to_owned().into_bytes());
-            self.domains.insert(
-                new_name.to_owned().into_bytes(),
-                longest_lived_cert.0.to_owned(),
-            );
+            // sort expiration ascending (longest-lived to the right),
+            // the fingerprint decides between certificates expiring at the same time
+            fingerprints_for_this_name.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
         }
 
         self.certificates
             .insert(cert_to_add.fingerprint.to_owned(), cert_to_add.clone());
 
+        for new_name in &cert_to_add.names {
+            self.update_domain(new_name);
+        }
+
         trace!("{:#?}", self);
 
         Ok(cert_to_add.fingerprint)
@@ -223,25 +370,22 @@ impl CertificateResolver {
         fingerprint: &Fingerprint,
     ) -> Result<(), CertificateResolverError> {
         if let Some(certificate_to_remove) = self.get_certificate(fingerprint) {
-            for name in certificate_to_remove.names {
-                self.domains.domain_remove(&name.clone().into_bytes());
+            self.certificates.remove(fingerprint);
 
+            for name in certificate_to_remove.names {
                 if let Some(fingerprints_and_exp) = self.name_fingerprint_idx.get_mut(&name) {
                     // remove fingerprints from the index for this name
-                    *fingerprints_and_exp = fingerprints_and_exp
-                        .drain(..)
-                        .filter(|t| &t.0 != fingerprint)
-                        .collect();
-
-                    // if present, reinsert the longest lived certificate in the TrieNode
-                    if let Some(longest_lived_cert) = fingerprints_and_exp.last() {
-                        self.domains
-                            .insert(name.into_bytes(), longest_lived_cert.0.to_owned());
-                    }
+                    fingerprints_and_exp.retain(|t| &t.0 != fingerprint);
                 }
+                // if present, reinsert the longest lived certificate in the TrieNode
+                self.update_domain(&name);
             }
 
-            self.certificates.remove(fingerprint);
+            // the pins of this certificate go with it
+            for domain in self.pinned_domains(fingerprint) {
+                self.pins.remove(&domain);
+                self.update_domain(&domain);
+            }
         }
         trace!("{:#?}", self);
 
@@ -255,18 +399,93 @@ impl CertificateResolver {
         &mut self,
         replace: &ReplaceCertificate,
     ) -> Result<Fingerprint, CertificateResolverError> {
+        let mut pinned_domains = Vec::new();
         match Fingerprint::from_str(&replace.old_fingerprint) {
-            Ok(old_fingerprint) => self.remove_certificate(&old_fingerprint)?,
+            Ok(old_fingerprint) => {
+                pinned_domains = self.pinned_domains(&old_fingerprint);
+                self.remove_certificate(&old_fingerprint)?
+            }
             Err(err) => {
                 error!("failed to parse fingerprint, {}", err);
             }
         }
 
-        self.add_certificate(&AddCertificate {
+        let new_fingerprint = self.add_certificate(&AddCertificate {
             address: replace.address.to_owned(),
             certificate: replace.new_certificate.to_owned(),
             expired_at: replace.new_expired_at.to_owned(),
-        })
+        })?;
+
+        // the pins follow the renewed certificate, if it still covers their domain
+        for domain in pinned_domains {
+            if let Err(e) = self.pin_certificate(&domain, Some(new_fingerprint.clone())) {
+                info!("dropping the pin of {}: {}", domain, e);
+            }
+        }
+
+        Ok(new_fingerprint)
+    }
+
+    /// forces the certificate served for a domain, among those that cover it,
+    /// or removes the pin of the domain
+    pub fn pin_certificate(
+        &mut self,
+        domain: &str,
+        fingerprint: Option<Fingerprint>,
+    ) -> Result<(), CertificateResolverError> {
+        match fingerprint {
+            Some(fingerprint) => {
+                let certificate = self.certificates.get(&fingerprint).ok_or(
+                    CertificateResolverError::UnknownCertificate(fingerprint.clone()),
+                )?;
+
+                if !certificate
+                    .names
+                    .iter()
+                    .any(|name| name_covers_domain(name, domain))
+                {
+                    return Err(CertificateResolverError::PinnedCertificateMismatch {
+                        fingerprint,
+                        domain: domain.to_owned(),
+                    });
+                }
+                self.pins.insert(domain.to_owned(), fingerprint);
+            }
+            None => {
+                self.pins.remove(domain);
+            }
+        }
+
+        self.update_domain(domain);
+        Ok(())
+    }
+
+    fn pinned_domains(&self, fingerprint: &Fingerprint) -> Vec<String> {
+        self.pins
+            .iter()
+            .filter(|(_, pinned)| *pinned == fingerprint)
+            .map(|(domain, _)| domain.to_owned())
+            .collect()
+    }
+
+    /// points the name to its pinned certificate, or to the longest-lived one
+    fn update_domain(&mut self, name: &str) {
+        let key = name.as_bytes().to_vec();
+        self.domains.domain_remove(&key);
+
+        let pinned = self
+            .pins
+            .get(name)
+            .filter(|fingerprint| self.certificates.contains_key(*fingerprint));
+        let longest_lived = self
+            .name_fingerprint_idx
+            .get(name)
+            .and_then(|fingerprints| fingerprints.last())
+            .map(|(fingerprint, _)| fingerprint);
+
+        if let Some(fingerprint) = pinned.or(longest_lived) {
+            self.domains.domain_insert(key, fingerprint.to_owned());
+        }
     }
 
     /// return all fingerprints that are available for these domain names,
@@ -316,47 +535,101 @@ impl CertificateResolver {
 #[derive(Default)]
 pub struct MutexCertificateResolver(pub Mutex<CertificateResolver>);
 
+impl MutexCertificateResolver {
+    pub fn new(fallback: CertificateFallback) -> Self {
+        Self(Mutex::new(CertificateResolver {
+            fallback,
+            ..Default::default()
+        }))
+    }
+}
+
 impl ResolvesServerCert for MutexCertificateResolver {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
         let server_name = client_hello.server_name();
         let sigschemes = client_hello.signature_schemes();
 
-        if server_name.is_none() {
-            error!("cannot look up certificate: no SNI from session");
-            return None;
-        }
+        let resolver = match self.0.try_lock() {
+            Ok(resolver) => resolver,
+            Err(e) => {
+                error!(
+                    "cannot look up certificate: could not lock the resolver: {}",
+                    e
+                );
+                return None;
+            }
+        };
+
+        let name: &str = match server_name {
+            Some(name) => name,
+            None => {
+                debug!(
+                    "no SNI from session, applying {:?}",
+                    resolver.fallback.no_sni
+                );
+                return resolver.fallback.for_missing_sni();
+            }
+        };
 
-        let name: &str = server_name.unwrap();
         trace!(
             "trying to resolve name: {:?} for signature scheme: {:?}",
             name,
             sigschemes
         );
-        if let Ok(ref mut resolver) = self.0.try_lock() {
-            //resolver.domains.print();
-            if let Some((_, fingerprint)) = resolver.domains.domain_lookup(name.as_bytes(), true) {
-                trace!(
-                    "looking for certificate for {:?} with fingerprint {:?}",
-                    name,
-                    fingerprint
-                );
+        //resolver.domains.print();
+        if let Some((_, fingerprint)) = resolver.domains.domain_lookup(name.as_bytes(), true) {
+            trace!(
+                "looking for certificate for {:?} with fingerprint {:?}",
+                name,
+                fingerprint
+            );
 
-                let cert = resolver
-                    .certificates
-                    .get(fingerprint)
-                    .map(|cert| cert.inner.clone());
+            let cert = resolver
+                .certificates
+                .get(fingerprint)
+                .map(|cert| cert.inner.clone());
 
-                trace!("Found for fingerprint {}: {}", fingerprint, cert.is_some());
-                return cert;
-            }
+            trace!("Found for fingerprint {}: {}", fingerprint, cert.is_some());
+            incr!("tls.sni.found");
+            return cert;
         }
 
-        // error!("could not look up a certificate for server name '{}'", name);
-        // This certificate is used for TLS tunneling with another TLS termination endpoint
-        // Note that this is unsafe and you should provide a valid certificate
-        debug!("Default certificate is used for {}", name);
-        incr!("tls.default_cert_used");
-        DEFAULT_CERTIFICATE.clone()
+        debug!(
+            "no certificate for {}, applying {:?}",
+            name, resolver.fallback.unknown_sni
+        );
+        resolver.fallback.for_unknown_sni()
+    }
+}
+
+impl MutexCertificateResolver {
+    /// fingerprint of the certificate that a handshake for this server name would use
+    pub fn fingerprint_for(&self, server_name: &str) -> Option<Fingerprint> {
+        let resolver = self.0.try_lock().ok()?;
+        resolver
+            .domain_lookup(server_name.as_bytes(), true)
+            .map(|(_, fingerprint)| fingerprint.to_owned())
+    }
+}
+
+/// The rustls configurations of a listener: its own, and those of the certificates
+/// that have their own TLS versions or cipher suites
+#[derive(Clone)]
+pub struct TlsConfigs {
+    pub resolver: Arc<MutexCertificateResolver>,
+    pub listener: Arc<ServerConfig>,
+    /// fingerprint -> configuration of the handshakes with this certificate
+    pub certificates: Arc<HashMap<Fingerprint, Arc<ServerConfig>>>,
+}
+
+impl TlsConfigs {
+    /// configuration of the handshake with the certificate of this server name
+    pub fn for_server_name(&self, server_name: Option<&str>) -> Arc<ServerConfig> {
+        server_name
+            .and_then(|name| self.resolver.fingerprint_for(name))
+            .and_then(|fingerprint| self.certificates.get(&fingerprint))
+            .unwrap_or(&self.listener)
+            .clone()
     }
 }
 
@@ -374,13 +647,24 @@ mod tests {
     use std::{
         collections::HashSet,
         error::Error,
+        sync::Arc,
         time::{Duration, SystemTime},
     };
 
-    use super::CertificateResolver;
+    use rustls::sign::SigningKey;
+
+    use super::{
+        parse_signing_key, register_key_provider, CertificateFallback, CertificateResolver,
+        CertificateResolverError, KeyProvider, DEFAULT_CERTIFICATE,
+    };
 
     // use rand::{seq::SliceRandom, thread_rng};
-    use sozu_command::proto::command::{AddCertificate, CertificateAndKey, SocketAddress};
+    use sozu_command::{
+        config::ListenerBuilder,
+        proto::command::{
+            AddCertificate, CertificateAndKey, FallbackCertificatePolicy, SocketAddress,
+        },
+    };
 
     #[test]
     fn lifecycle() -> Result<(), Box<dyn Error + Send + Sync>> {
@@ -677,4 +961,151 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn pin_certificate() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let address = SocketAddress::new_v4(127, 0, 0, 1, 8080);
+        let mut resolver = CertificateResolver::default();
+
+        // *.example.org
+        let wildcard_fingerprint = resolver.add_certificate(&AddCertificate {
+            address,
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/tests/certificate-3.pem")),
+                key: String::from(include_str!("../assets/tests/key.pem")),
+                ..Default::default()
+            },
+            expired_at: None,
+        })?;
+
+        // example.org and www.example.org
+        let www_fingerprint = resolver.add_certificate(&AddCertificate {
+            address,
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/tests/certificate-2.pem")),
+                key: String::from(include_str!("../assets/tests/key.pem")),
+                ..Default::default()
+            },
+            expired_at: None,
+        })?;
+
+        // the exact name is more specific than the wildcard
+        assert_eq!(
+            resolver
+                .domain_lookup(b"www.example.org", true)
+                .map(|(_, fingerprint)| fingerprint),
+            Some(&www_fingerprint)
+        );
+
+        resolver.pin_certificate("www.example.org", Some(wildcard_fingerprint.clone()))?;
+        assert_eq!(
+            resolver
+                .domain_lookup(b"www.example.org", true)
+                .map(|(_, fingerprint)| fingerprint),
+            Some(&wildcard_fingerprint)
+        );
+
+        // the wildcard does not cover the apex domain
+        assert!(resolver
+            .pin_certificate("example.org", Some(wildcard_fingerprint.clone()))
+            .is_err());
+
+        resolver.pin_certificate("www.example.org", None)?;
+        assert_eq!(
+            resolver
+                .domain_lookup(b"www.example.org", true)
+                .map(|(_, fingerprint)| fingerprint),
+            Some(&www_fingerprint)
+        );
+
+        // removing a pinned certificate removes its pins
+        resolver.pin_certificate("test.example.org", Some(wildcard_fingerprint.clone()))?;
+        resolver.remove_certificate(&wildcard_fingerprint)?;
+        assert_eq!(
+            resolver
+                .domain_lookup(b"test.example.org", true)
+                .map(|(_, fingerprint)| fingerprint),
+            None
+        );
+        assert_eq!(
+            resolver
+                .domain_lookup(b"www.example.org", true)
+                .map(|(_, fingerprint)| fingerprint),
+            Some(&www_fingerprint)
+        );
+
+        Ok(())
+    }
+
+    /// holds the test key, as a token would
+    struct TestKeyProvider;
+
+    impl KeyProvider for TestKeyProvider {
+        fn load_signing_key(
+            &self,
+            uri: &str,
+        ) -> Result<Arc<dyn SigningKey>, CertificateResolverError> {
+            match uri {
+                "test-token:object=localhost" => {
+                    parse_signing_key(include_str!("../assets/key.pem"))
+                }
+                _ => Err(CertificateResolverError::InvalidPrivateKey(uri.to_owned())),
+            }
+        }
+    }
+
+    #[test]
+    fn provided_key() {
+        let address = SocketAddress::new_v4(127, 0, 0, 1, 8080);
+        let mut resolver = CertificateResolver::default();
+        let with_key = |key: &str| AddCertificate {
+            address,
+            certificate: CertificateAndKey {
+                certificate: String::from(include_str!("../assets/certificate.pem")),
+                key: key.to_owned(),
+                ..Default::default()
+            },
+            expired_at: None,
+        };
+
+        assert!(matches!(
+            resolver.add_certificate(&with_key("test-token:object=localhost")),
+            Err(CertificateResolverError::NoKeyProvider(_))
+        ));
+
+        register_key_provider("test-token", Arc::new(TestKeyProvider));
+        assert!(resolver
+            .add_certificate(&with_key("test-token:object=unknown"))
+            .is_err());
+        let fingerprint = resolver
+            .add_certificate(&with_key("test-token:object=localhost"))
+            .expect("the provider should give the key");
+        assert!(resolver.get_certificate(&fingerprint).is_some());
+    }
+
+    #[test]
+    fn fallback_policies() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let fallback = CertificateFallback::default();
+        assert!(fallback.for_missing_sni().is_none());
+        assert!(fallback.for_unknown_sni().is_some());
+
+        let mut config = ListenerBuilder::new_https(SocketAddress::new_v4(127, 0, 0, 1, 8443))
+            .with_no_sni_policy(Some(FallbackCertificatePolicy::DefaultCertificate))
+            .with_unknown_sni_policy(Some(FallbackCertificatePolicy::Reject))
+            .to_tls(None)?;
+        config.certificate = Some(include_str!("../assets/tests/certificate-1y.pem").to_owned());
+        config.key = Some(include_str!("../assets/tests/key-1y.pem").to_owned());
+
+        let fallback = CertificateFallback::try_from_config(&config)?;
+        assert!(fallback.for_unknown_sni().is_none());
+        let default_certificate = fallback
+            .for_missing_sni()
+            .ok_or("the listener certificate should be served")?;
+        let bundled_certificate = DEFAULT_CERTIFICATE
+            .clone()
+            .ok_or("the bundled certificate should parse")?;
+        assert_ne!(default_certificate.cert, bundled_certificate.cert);
+
+        Ok(())
+    }
 }